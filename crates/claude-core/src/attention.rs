@@ -1,21 +1,46 @@
-use tch::{nn, Tensor, Kind, IndexOp};
-use crate::config::ModelConfig;
+use std::sync::Arc;
+use tch::{nn, Device, Tensor, Kind, IndexOp};
+use crate::config::{ModelConfig, QkvLayout};
 use crate::rotary::RotaryEmbedding;
+use crate::transformer::{linear_parameters, softcap};
 
 pub struct CausalSelfAttention {
     c_attn: nn::Linear,
     c_proj: nn::Linear,
     n_head: i64,
+    qkv_layout: QkvLayout,
     dropout: f64,
-    bias: Tensor,
+    /// Dropout on the projected output, matching the MLP's output dropout.
+    resid_dropout: f64,
+    attn_logit_softcap: Option<f64>,
+    /// The model-wide causal mask from [`causal_mask_buffer`], shared by every layer.
+    bias: Arc<Tensor>,
     rotary_emb: std::sync::Arc<RotaryEmbedding>,
+    /// `[1, n_head, 1, 1]` multiplier (1.0 active, 0.0 pruned) applied to the per-head
+    /// outputs before `c_proj`. `None` means every head is active.
+    head_mask: Option<Tensor>,
+}
+
+/// The `[1, 1, max_seq_len, max_seq_len]` causal mask (1.0 where a query may attend to a
+/// key). A model builds it once and hands the same buffer to all of its layers.
+pub fn causal_mask_buffer(max_seq_len: i64, device: Device) -> Arc<Tensor> {
+    let mask = Tensor::ones([max_seq_len, max_seq_len], (Kind::Bool, device))
+        .tril(0)
+        .reshape([1, 1, max_seq_len, max_seq_len]);
+    Arc::new(mask.to_kind(Kind::Float))
 }
 
 impl CausalSelfAttention {
+    /// A standalone layer with its own causal mask; layers of a model should share one
+    /// through [`CausalSelfAttention::with_causal_mask`].
     pub fn new(vs: &nn::Path, config: &ModelConfig) -> Self {
+        Self::with_causal_mask(vs, config, causal_mask_buffer(config.max_seq_len, vs.device()))
+    }
+
+    /// Uses `causal_mask` (from [`causal_mask_buffer`]) instead of allocating a mask.
+    pub fn with_causal_mask(vs: &nn::Path, config: &ModelConfig, causal_mask: Arc<Tensor>) -> Self {
         let n_embd = config.n_embd;
         let n_head = config.n_head;
-        let head_dim = n_embd / n_head;
         
         let linear_config = nn::LinearConfig {
             bias: config.use_bias,
@@ -25,35 +50,196 @@ impl CausalSelfAttention {
         let c_attn = nn::linear(vs / "c_attn", n_embd, 3 * n_embd, linear_config);
         let c_proj = nn::linear(vs / "c_proj", n_embd, n_embd, linear_config);
         
-        let rotary_emb = std::sync::Arc::new(RotaryEmbedding::new(head_dim, vs.device()));
-
-        // Causal mask
-        let mask = Tensor::ones(&[config.max_seq_len, config.max_seq_len], (Kind::Bool, vs.device()))
-            .tril(0)
-            .reshape(&[1, 1, config.max_seq_len, config.max_seq_len]);
+        let rotary_emb = Arc::new(RotaryEmbedding::new(config.rotary_dim(), config.rope_theta, vs.device()));
 
         Self {
             c_attn,
             c_proj,
             n_head,
+            qkv_layout: config.qkv_layout,
             dropout: config.dropout,
-            bias: mask.to_kind(Kind::Float),
+            resid_dropout: config.resid_dropout(),
+            attn_logit_softcap: config.attn_logit_softcap,
+            bias: causal_mask,
             rotary_emb,
+            head_mask: None,
         }
     }
 
-    pub fn forward(&self, x: &Tensor, cache: Option<&mut crate::kv_cache::KVCache>) -> Tensor {
-        let (b, t, c) = x.size3().unwrap(); 
-        
-        let qkv = x.apply(&self.c_attn);
-        let chunks = qkv.chunk(3, -1);
-        let (q, k, v) = (&chunks[0], &chunks[1], &chunks[2]);
-        
+    /// Disables the heads whose entry in `active` is `false` by zeroing their output
+    /// before the output projection. `None` re-enables every head.
+    pub fn set_active_heads(&mut self, active: Option<&[bool]>) -> anyhow::Result<()> {
+        self.head_mask = match active {
+            None => None,
+            Some(active) => {
+                anyhow::ensure!(
+                    active.len() as i64 == self.n_head,
+                    "expected {} head flags, got {}",
+                    self.n_head,
+                    active.len()
+                );
+                let flags: Vec<f32> = active.iter().map(|&on| if on { 1.0 } else { 0.0 }).collect();
+                Some(
+                    Tensor::from_slice(&flags)
+                        .view([1, self.n_head, 1, 1])
+                        .to(self.bias.device()),
+                )
+            }
+        };
+        Ok(())
+    }
+
+    /// Applies the head mask to per-head outputs of shape `[b, n_head, t, head_size]`.
+    fn mask_heads(&self, y: Tensor) -> Tensor {
+        match &self.head_mask {
+            Some(mask) => y * mask,
+            None => y,
+        }
+    }
+
+    /// The shared causal mask buffer.
+    pub(crate) fn mask_buffer(&self) -> &Arc<Tensor> {
+        &self.bias
+    }
+
+    /// Trainable parameters (the causal mask buffer is not counted).
+    pub fn num_parameters(&self) -> i64 {
+        linear_parameters(&self.c_attn) + linear_parameters(&self.c_proj)
+    }
+
+    /// The projection weights and biases, named as in the VarStore relative to this module.
+    pub fn named_tensors(&self) -> Vec<(String, Tensor)> {
+        let mut tensors = linear_tensors("c_attn", &self.c_attn);
+        tensors.extend(linear_tensors("c_proj", &self.c_proj));
+        tensors
+    }
+
+    /// `train` enables dropout; inference and KV-cached passes run without it. Fails only
+    /// if `cache` can't take the new positions (see [`crate::kv_cache::KVCacheOverflow`]).
+    pub fn forward(
+        &self,
+        x: &Tensor,
+        cache: Option<&mut crate::kv_cache::KVCache>,
+        train: bool,
+    ) -> anyhow::Result<Tensor> {
+        if x.size()[1] == 1 {
+            self.forward_decode_step(x, cache, train)
+        } else {
+            self.forward_general(x, cache, train)
+        }
+    }
+
+    /// Splits the fused projection `[b, t, 3 * c]` into queries, keys and values of shape
+    /// `[b, n_head, t, head_size]`, following `self.qkv_layout`.
+    fn split_qkv(&self, qkv: &Tensor) -> (Tensor, Tensor, Tensor) {
+        let (b, t, three_c) = qkv.size3().unwrap();
+        let head_size = three_c / 3 / self.n_head;
+        let parts = match self.qkv_layout {
+            QkvLayout::Concat => qkv
+                .chunk(3, -1)
+                .iter()
+                .map(|part| part.view([b, t, self.n_head, head_size]))
+                .collect::<Vec<_>>(),
+            QkvLayout::Interleaved => qkv.view([b, t, self.n_head, 3 * head_size]).split(head_size, -1),
+        };
+        let [q, k, v]: [Tensor; 3] = parts.try_into().expect("three QKV parts");
+        (q.transpose(1, 2), k.transpose(1, 2), v.transpose(1, 2))
+    }
+
+    fn cap_scores(&self, att: Tensor) -> Tensor {
+        match self.attn_logit_softcap {
+            Some(cap) => softcap(&att, cap),
+            None => att,
+        }
+    }
+
+    /// Single-token decode path (t == 1).
+    /// With one position, the attention output `[b, n_head, 1, head_size]` maps back to
+    /// `[b, 1, c]` without a `contiguous()` copy.
+    fn forward_decode_step(
+        &self,
+        x: &Tensor,
+        cache: Option<&mut crate::kv_cache::KVCache>,
+        train: bool,
+    ) -> anyhow::Result<Tensor> {
+        let (b, _, c) = x.size3().unwrap();
         let head_size = c / self.n_head;
+
+        let (q, k, v) = self.split_qkv(&x.apply(&self.c_attn));
+
+        let past_len = match cache {
+            Some(ref c) => c.length as i64,
+            None => 0,
+        };
+
+        let q = self.rotary_emb.forward_from(&q, past_len);
+        let k = self.rotary_emb.forward_from(&k, past_len);
+
+        let (k_full, v_full) = match cache {
+            Some(c) => {
+                c.update(&k, &v)?;
+                c.get_view()
+            },
+            None => (k, v),
+        };
+
+        let att = self.cap_scores(q.matmul(&k_full.transpose(-2, -1)) * (1.0 / (head_size as f64).sqrt()));
+        let att = att.softmax(-1, Kind::Float);
+        let att = att.dropout(self.dropout, train);
+        Ok(self
+            .mask_heads(att.matmul(&v_full))
+            .view([b, 1, c])
+            .apply(&self.c_proj)
+            .dropout(self.resid_dropout, train))
+    }
+
+    /// Single-token decode step for several independent sequences: `x` is `[b, 1, c]` and
+    /// row `i` attends over `caches[i]` at that cache's position. The projections run once
+    /// for the whole batch; only the attention over each row's cache runs per row, since
+    /// the rows may sit at different positions. Inference only, so without dropout.
+    pub fn forward_decode_rows(
+        &self,
+        x: &Tensor,
+        caches: &mut [&mut crate::kv_cache::KVCache],
+    ) -> anyhow::Result<Tensor> {
+        let (b, _, c) = x.size3()?;
+        anyhow::ensure!(
+            caches.len() as i64 == b,
+            "expected one cache per row ({}), got {}",
+            b,
+            caches.len()
+        );
+        let head_size = c / self.n_head;
+
+        let (q, k, v) = self.split_qkv(&x.apply(&self.c_attn));
+
+        let mut rows = Vec::with_capacity(caches.len());
+        for (i, cache) in caches.iter_mut().enumerate() {
+            let row = |t: &Tensor| t.narrow(0, i as i64, 1);
+            let past_len = cache.length as i64;
+            let q = self.rotary_emb.forward_from(&row(&q), past_len);
+            let k = self.rotary_emb.forward_from(&row(&k), past_len);
+            cache.update(&k, &row(&v))?;
+            let (k_full, v_full) = cache.get_view();
+
+            let att = self.cap_scores(q.matmul(&k_full.transpose(-2, -1)) * (1.0 / (head_size as f64).sqrt()));
+            rows.push(att.softmax(-1, Kind::Float).matmul(&v_full));
+        }
+        Ok(self
+            .mask_heads(Tensor::cat(&rows, 0))
+            .view([b, 1, c])
+            .apply(&self.c_proj))
+    }
+
+    fn forward_general(
+        &self,
+        x: &Tensor,
+        cache: Option<&mut crate::kv_cache::KVCache>,
+        train: bool,
+    ) -> anyhow::Result<Tensor> {
+        let (b, t, c) = x.size3().unwrap(); 
         
-        let mut k = k.view([b, t, self.n_head, head_size]).transpose(1, 2);
-        let mut q = q.view([b, t, self.n_head, head_size]).transpose(1, 2);
-        let v = v.view([b, t, self.n_head, head_size]).transpose(1, 2);
+        let (q, k, v) = self.split_qkv(&x.apply(&self.c_attn));
 
         // Apply RoPE
         let past_len = match cache {
@@ -61,37 +247,281 @@ impl CausalSelfAttention {
             None => 0,
         };
         
-        q = self.rotary_emb.forward(&q, t + past_len).i((.., .., past_len.., ..));
-        k = self.rotary_emb.forward(&k, t + past_len).i((.., .., past_len.., ..));
+        let q = self.rotary_emb.forward_from(&q, past_len);
+        let k = self.rotary_emb.forward_from(&k, past_len);
 
         // KV Cache handling
         let (k_full, v_full) = match cache {
             Some(c) => {
-                c.update(&k, &v);
+                c.update(&k, &v)?;
                 c.get_view()
             },
             None => (k, v),
         };
         
-        let att = q.matmul(&k_full.transpose(-2, -1)) * (1.0 / (head_size as f64).sqrt());
+        let y = self.mask_heads(self.attend(&q, &k_full, &v_full, past_len, train));
+        let y = y.transpose(1, 2).contiguous().view([b, t, c]);
+        Ok(y.apply(&self.c_proj).dropout(self.resid_dropout, train))
+    }
+
+    /// Causal attention of `q` (`[b, heads, t, head_size]`, at positions `past_len..`) over
+    /// `k` and `v`, for any subset of the heads. `train` enables dropout on the weights.
+    fn attend(&self, q: &Tensor, k: &Tensor, v: &Tensor, past_len: i64, train: bool) -> Tensor {
+        let t = q.size()[2];
+        let head_size = q.size()[3];
+        let att = self.cap_scores(q.matmul(&k.transpose(-2, -1)) * (1.0 / (head_size as f64).sqrt()));
         
-        let total_t = k_full.size()[2];
+        let total_t = k.size()[2];
         
-        // Apply mask only if we are the first step (past_len == 0) and T > 1
-        if past_len == 0 && t > 1 {
-             let mask = self.bias.i((.., .., ..total_t, ..total_t));
-             let att = att.masked_fill(&mask.eq(0.0), f64::NEG_INFINITY);
-             let att = att.softmax(-1, Kind::Float);
-             let att = att.dropout(self.dropout, true);
-             let y = att.matmul(&v_full);
-             let y = y.transpose(1, 2).contiguous().view([b, t, c]);
-             y.apply(&self.c_proj)
+        let att = if t > 1 {
+            att.masked_fill(&self.causal_mask(past_len, total_t).eq(0.0), f64::NEG_INFINITY)
         } else {
-             let att = att.softmax(-1, Kind::Float);
-             let att = att.dropout(self.dropout, true);
-             let y = att.matmul(&v_full);
-             let y = y.transpose(1, 2).contiguous().view([b, t, c]);
-             y.apply(&self.c_proj)
+            att
+        };
+        let att = att.softmax(-1, Kind::Float);
+        let att = att.dropout(self.dropout, train);
+        att.matmul(v)
+    }
+
+    /// Rows `past_len..total_t` of the causal mask, as `[1, 1, total_t - past_len, total_t]`.
+    /// Queries sit at those positions, which also covers chunked prefill, where a chunk
+    /// follows earlier cached chunks. Sequences longer than the precomputed buffer get a
+    /// mask built on the fly instead of an out-of-range slice.
+    fn causal_mask(&self, past_len: i64, total_t: i64) -> Tensor {
+        if total_t <= self.bias.size()[2] {
+            return self.bias.i((.., .., past_len..total_t, ..total_t));
+        }
+        let device = self.bias.device();
+        let keys = Tensor::arange(total_t, (Kind::Int64, device)).unsqueeze(0);
+        let queries = Tensor::arange_start(past_len, total_t, (Kind::Int64, device)).unsqueeze(1);
+        keys.le_tensor(&queries)
+            .to_kind(Kind::Float)
+            .view([1, 1, total_t - past_len, total_t])
+    }
+
+    /// Uncached forward pass that splits the heads into `partitions` equal groups, attends
+    /// within each group independently and concatenates the group outputs before `c_proj`.
+    /// Heads never interact inside attention, so the result matches [`Self::forward`] at
+    /// inference; this checks the math of a future tensor-parallel split without needing
+    /// several devices.
+    pub fn forward_partitioned(&self, x: &Tensor, partitions: i64) -> anyhow::Result<Tensor> {
+        anyhow::ensure!(
+            partitions > 0 && self.n_head % partitions == 0,
+            "{} heads cannot be split into {} equal partitions",
+            self.n_head,
+            partitions
+        );
+        let (b, t, c) = x.size3()?;
+        let (q, k, v) = self.split_qkv(&x.apply(&self.c_attn));
+        let q = self.rotary_emb.forward_from(&q, 0);
+        let k = self.rotary_emb.forward_from(&k, 0);
+
+        let heads_per_partition = self.n_head / partitions;
+        let outputs: Vec<Tensor> = (0..partitions)
+            .map(|p| {
+                let heads = |t: &Tensor| t.narrow(1, p * heads_per_partition, heads_per_partition);
+                self.attend(&heads(&q), &heads(&k), &heads(&v), 0, false)
+            })
+            .collect();
+        let y = self.mask_heads(Tensor::cat(&outputs, 1));
+        let y = y.transpose(1, 2).contiguous().view([b, t, c]);
+        Ok(y.apply(&self.c_proj))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv_cache::KVCache;
+    use tch::Device;
+
+    #[test]
+    fn decode_step_matches_general_path() {
+        tch::manual_seed(0);
+        let config = ModelConfig::tiny(16);
+        let vs = nn::VarStore::new(Device::Cpu);
+        let attn = CausalSelfAttention::new(&vs.root(), &config);
+        let new_cache = || KVCache::new(32, config.n_head, config.head_size(), Device::Cpu, Kind::Float);
+
+        let prompt = Tensor::randn([1, 3, config.n_embd], (Kind::Float, Device::Cpu));
+        let step = Tensor::randn([1, 1, config.n_embd], (Kind::Float, Device::Cpu));
+
+        // Without a cache
+        let fast = attn.forward_decode_step(&step, None, false).expect("decode step");
+        let general = attn.forward_general(&step, None, false).expect("general");
+        assert!(fast.allclose(&general, 1e-6, 1e-6, false));
+
+        // After a prefill
+        let mut fast_cache = new_cache();
+        let mut general_cache = new_cache();
+        attn.forward_general(&prompt, Some(&mut fast_cache), false).expect("prefill");
+        attn.forward_general(&prompt, Some(&mut general_cache), false).expect("prefill");
+
+        let fast = attn.forward_decode_step(&step, Some(&mut fast_cache), false).expect("decode step");
+        let general = attn.forward_general(&step, Some(&mut general_cache), false).expect("general");
+        assert_eq!(fast.size(), vec![1, 1, config.n_embd]);
+        assert!(fast.allclose(&general, 1e-6, 1e-6, false));
+    }
+
+    #[test]
+    fn batched_decode_rows_match_single_row_steps() {
+        tch::manual_seed(0);
+        let config = ModelConfig::tiny(16);
+        let vs = nn::VarStore::new(Device::Cpu);
+        let attn = CausalSelfAttention::new(&vs.root(), &config);
+        let new_cache = || KVCache::new(32, config.n_head, config.head_size(), Device::Cpu, Kind::Float);
+
+        // Rows at different positions: prompts of three and five tokens.
+        let prompts = [3, 5].map(|len| Tensor::randn([1, len, config.n_embd], (Kind::Float, Device::Cpu)));
+        let mut single_caches = [new_cache(), new_cache()];
+        let mut batched_caches = [new_cache(), new_cache()];
+        for ((prompt, single), batched) in prompts.iter().zip(&mut single_caches).zip(&mut batched_caches) {
+            attn.forward(prompt, Some(single), false).expect("prefill");
+            attn.forward(prompt, Some(batched), false).expect("prefill");
+        }
+        let step = Tensor::randn([2, 1, config.n_embd], (Kind::Float, Device::Cpu));
+
+        let [first, second] = &mut batched_caches;
+        let batched = attn.forward_decode_rows(&step, &mut [first, second]).expect("batched step");
+
+        assert_eq!(batched.size(), vec![2, 1, config.n_embd]);
+        for (i, cache) in single_caches.iter_mut().enumerate() {
+            let single = attn.forward(&step.narrow(0, i as i64, 1), Some(cache), false).expect("single step");
+            assert!(batched.narrow(0, i as i64, 1).allclose(&single, 1e-5, 1e-6, false));
+            assert_eq!(batched_caches[i].length, cache.length);
+        }
+    }
+
+    #[test]
+    fn sequences_longer_than_the_mask_buffer_stay_causal() {
+        tch::manual_seed(0);
+        let config = ModelConfig {
+            max_seq_len: 8,
+            ..ModelConfig::tiny(16)
+        };
+        let vs = nn::VarStore::new(Device::Cpu);
+        let attn = CausalSelfAttention::new(&vs.root(), &config);
+        let x = Tensor::randn([1, 12, config.n_embd], (Kind::Float, Device::Cpu));
+
+        let long = attn.forward(&x, None, false).expect("forward");
+        let prefix = attn.forward(&x.narrow(1, 0, 8), None, false).expect("forward");
+
+        assert_eq!(long.size(), vec![1, 12, config.n_embd]);
+        assert!(long.narrow(1, 0, 8).allclose(&prefix, 1e-6, 1e-6, false));
+        assert!(attn.causal_mask(8, 12).equal(&attn.causal_mask(0, 12).narrow(2, 8, 4)));
+    }
+
+    #[test]
+    fn partitioned_heads_match_the_monolithic_path() {
+        tch::manual_seed(0);
+        let config = ModelConfig::tiny(16);
+        let vs = nn::VarStore::new(Device::Cpu);
+        let attn = CausalSelfAttention::new(&vs.root(), &config);
+        let x = Tensor::randn([2, 5, config.n_embd], (Kind::Float, Device::Cpu));
+
+        let monolithic = attn.forward(&x, None, false).expect("forward");
+        let partitioned = attn.forward_partitioned(&x, 2).expect("two partitions");
+
+        assert!(partitioned.allclose(&monolithic, 1e-6, 1e-6, false));
+        assert!(attn.forward_partitioned(&x, 3).is_err());
+    }
+
+    #[test]
+    fn split_qkv_follows_the_configured_layout() {
+        for layout in [QkvLayout::Concat, QkvLayout::Interleaved] {
+            let config = ModelConfig {
+                use_bias: false,
+                qkv_layout: layout,
+                ..ModelConfig::tiny(16)
+            };
+            let (c, head_size) = (config.n_embd, config.head_size());
+            let vs = nn::VarStore::new(Device::Cpu);
+            let mut attn = CausalSelfAttention::new(&vs.root(), &config);
+            // Row i of the weight is i / c everywhere, so an all-ones input projects to 0, 1, 2, ...
+            let weight = (Tensor::arange(3 * c, (Kind::Float, Device::Cpu)) / c as f64)
+                .unsqueeze(1)
+                .expand([3 * c, c], false);
+            tch::no_grad(|| attn.c_attn.ws.copy_(&weight));
+
+            let qkv = Tensor::ones([1, 1, c], (Kind::Float, Device::Cpu)).apply(&attn.c_attn);
+            let (q, k, v) = attn.split_qkv(&qkv);
+
+            for head in 0..config.n_head {
+                let (q_start, stride) = match layout {
+                    QkvLayout::Concat => (head * head_size, c),
+                    QkvLayout::Interleaved => (head * 3 * head_size, head_size),
+                };
+                for (i, part) in [&q, &k, &v].into_iter().enumerate() {
+                    let start = (q_start + i as i64 * stride) as f64;
+                    let expected = Tensor::arange_start(start, start + head_size as f64, (Kind::Float, Device::Cpu));
+                    assert!(part.i((0, head, 0)).equal(&expected), "{layout:?} head {head} part {i}");
+                }
+            }
         }
     }
+
+    #[test]
+    fn masking_all_heads_zeroes_attention_output() {
+        let config = ModelConfig {
+            use_bias: false,
+            ..ModelConfig::tiny(16)
+        };
+        let vs = nn::VarStore::new(Device::Cpu);
+        let mut attn = CausalSelfAttention::new(&vs.root(), &config);
+        let x = Tensor::randn([1, 4, config.n_embd], (Kind::Float, Device::Cpu));
+
+        let mut active = vec![true; config.n_head as usize];
+        active[0] = false;
+        attn.set_active_heads(Some(&active)).expect("valid mask");
+        assert!(attn.forward(&x, None, false).expect("forward").abs().sum(Kind::Float).double_value(&[]) > 0.0);
+
+        attn.set_active_heads(Some(&vec![false; config.n_head as usize])).expect("valid mask");
+        assert_eq!(attn.forward(&x, None, false).expect("forward").abs().sum(Kind::Float).double_value(&[]), 0.0);
+        assert_eq!(attn.forward(&x.narrow(1, 0, 1), None, false).expect("forward").abs().sum(Kind::Float).double_value(&[]), 0.0);
+
+        assert!(attn.set_active_heads(Some(&[true])).is_err());
+    }
+
+    #[test]
+    fn output_dropout_is_applied_after_the_projection() {
+        let x = Tensor::randn([2, 5, 128], (Kind::Float, Device::Cpu));
+        let attention = |resid_dropout| {
+            let config = ModelConfig {
+                resid_dropout: Some(resid_dropout),
+                ..ModelConfig::tiny(16)
+            };
+            let vs = nn::VarStore::new(Device::Cpu);
+            CausalSelfAttention::new(&vs.root(), &config)
+        };
+
+        let y = attention(0.5).forward(&x, None, true).expect("forward");
+        assert_eq!(y.size(), vec![2, 5, 128]);
+        assert_eq!(y.isfinite().all().int64_value(&[]), 1);
+
+        // The projection has a bias, so only dropout after it can zero the whole output.
+        for input in [x.shallow_clone(), x.narrow(1, 0, 1)] {
+            let y = attention(1.0).forward(&input, None, true).expect("forward");
+            assert_eq!(y.abs().sum(Kind::Float).double_value(&[]), 0.0);
+        }
+    }
+
+    #[test]
+    fn inference_and_cached_passes_skip_dropout() {
+        let config = ModelConfig {
+            dropout: 1.0,
+            ..ModelConfig::tiny(16)
+        };
+        let vs = nn::VarStore::new(Device::Cpu);
+        let attn = CausalSelfAttention::new(&vs.root(), &config);
+        let mut cache = KVCache::new(32, config.n_head, config.head_size(), Device::Cpu, Kind::Float);
+        let prompt = Tensor::randn([1, 3, config.n_embd], (Kind::Float, Device::Cpu));
+        let step = Tensor::randn([1, 1, config.n_embd], (Kind::Float, Device::Cpu));
+
+        let prefill = attn.forward(&prompt, Some(&mut cache), false).expect("prefill");
+        let decoded = attn.forward(&step, Some(&mut cache), false).expect("decode step");
+
+        let full = attn.forward(&Tensor::cat(&[&prompt, &step], 1), None, false).expect("forward");
+        assert!(full.narrow(1, 0, 3).allclose(&prefill, 1e-6, 1e-6, false));
+        assert!(full.narrow(1, 3, 1).allclose(&decoded, 1e-6, 1e-6, false));
+    }
 }
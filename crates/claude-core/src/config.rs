@@ -1,4 +1,6 @@
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
+use std::path::Path;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ModelConfig {
@@ -12,12 +14,64 @@ pub struct ModelConfig {
     pub vocab_size: i64,
     /// Maximum context window size (max sequence length).
     pub max_seq_len: i64,
-    /// Dropout probability (applied to attention and residual connections).
+    /// Dropout probability (applied to the embeddings and the attention probabilities, and
+    /// to the residual stream unless `resid_dropout` is set).
     pub dropout: f64,
     /// RMSNorm epsilon value (for numerical stability).
     pub layer_norm_epsilon: f64,
     /// Whether to use bias in linear layers (typically false in modern LLMs like Llama/PaLM).
     pub use_bias: bool,
+    /// If set, final logits are soft-capped to `cap * tanh(logits / cap)` (Gemma-style).
+    #[serde(default)]
+    pub final_logit_softcap: Option<f64>,
+    /// If set, attention scores are soft-capped the same way before the softmax.
+    #[serde(default)]
+    pub attn_logit_softcap: Option<f64>,
+    /// End-of-sequence token. Generation stops once it is sampled.
+    #[serde(default)]
+    pub eos_token_id: Option<i64>,
+    /// Multiplier on the attention and MLP outputs before each residual add (default 1.0).
+    /// Deep stacks train more stably with a downscale such as `1 / sqrt(2 * n_layer)`.
+    #[serde(default)]
+    pub residual_scale: Option<f64>,
+    /// Dropout on the attention and MLP outputs before each residual add. Defaults to `dropout`.
+    #[serde(default)]
+    pub resid_dropout: Option<f64>,
+    /// How the fused `c_attn` projection lays out queries, keys and values.
+    #[serde(default)]
+    pub qkv_layout: QkvLayout,
+    /// If set, `wte` and `lm_head` are built for `vocab_size` rounded up to a multiple of
+    /// this (e.g. 64), so the LM head matmul suits GPU tensor cores. The extra logits are
+    /// always `-inf`, so they are never sampled; token IDs stay below `vocab_size`.
+    #[serde(default)]
+    pub pad_vocab_to: Option<i64>,
+    /// Fraction of each head's dimensions that RoPE rotates (default 1.0). GPT-NeoX and
+    /// Pythia rotate only the first `rotary_pct * head_size` and pass the rest through.
+    #[serde(default = "default_rotary_pct")]
+    pub rotary_pct: f64,
+    /// Base of the RoPE frequencies (default 10000.0). Long-context models use a larger
+    /// base, e.g. 500000.0 for Llama 3.
+    #[serde(default = "default_rope_theta")]
+    pub rope_theta: f64,
+}
+
+fn default_rotary_pct() -> f64 {
+    1.0
+}
+
+fn default_rope_theta() -> f64 {
+    10000.0
+}
+
+/// Output layout of the fused QKV projection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QkvLayout {
+    /// All query heads, then all key heads, then all value heads (GPT-2).
+    #[default]
+    Concat,
+    /// Per head: that head's query, key and value next to each other (GPT-NeoX, BLOOM).
+    Interleaved,
 }
 
 impl Default for ModelConfig {
@@ -31,12 +85,83 @@ impl Default for ModelConfig {
             dropout: 0.0,
             layer_norm_epsilon: 1e-5,
             use_bias: false, 
+            final_logit_softcap: None,
+            attn_logit_softcap: None,
+            eos_token_id: None,
+            residual_scale: None,
+            resid_dropout: None,
+            qkv_layout: QkvLayout::Concat,
+            pad_vocab_to: None,
+            rotary_pct: default_rotary_pct(),
+            rope_theta: default_rope_theta(),
         }
     }
 }
 
 impl ModelConfig {
+    /// Canonical small config used by the binaries when no trained model or config is available.
+    pub fn tiny(vocab_size: i64) -> Self {
+        Self {
+            n_embd: 128,
+            n_head: 4,
+            n_layer: 4,
+            vocab_size,
+            max_seq_len: 512,
+            dropout: 0.0,
+            layer_norm_epsilon: 1e-5,
+            use_bias: true,
+            final_logit_softcap: None,
+            attn_logit_softcap: None,
+            eos_token_id: None,
+            residual_scale: None,
+            resid_dropout: None,
+            qkv_layout: QkvLayout::Concat,
+            pad_vocab_to: None,
+            rotary_pct: default_rotary_pct(),
+            rope_theta: default_rope_theta(),
+        }
+    }
+
+    /// Loads a config from a `.json` file, or from YAML for any other extension.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read model config at {:?}", path))?;
+
+        let config = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(&content)
+                .with_context(|| format!("Failed to parse JSON model config {:?}", path))?
+        } else {
+            serde_yaml::from_str(&content)
+                .with_context(|| format!("Failed to parse YAML model config {:?}", path))?
+        };
+        Ok(config)
+    }
+
+    /// Number of embedding rows and logits: `vocab_size` rounded up to a multiple of
+    /// `pad_vocab_to`, if set.
+    pub fn padded_vocab_size(&self) -> i64 {
+        match self.pad_vocab_to {
+            Some(multiple) if multiple > 1 && self.vocab_size % multiple != 0 => {
+                self.vocab_size + multiple - self.vocab_size % multiple
+            }
+            _ => self.vocab_size,
+        }
+    }
+
+    /// Dropout applied to the attention and MLP outputs.
+    pub fn resid_dropout(&self) -> f64 {
+        self.resid_dropout.unwrap_or(self.dropout)
+    }
+
     pub fn head_size(&self) -> i64 {
         self.n_embd / self.n_head
     }
+
+    /// Number of leading dimensions of each head that RoPE rotates: `rotary_pct` of the
+    /// head size, rounded down to an even number since dimensions rotate in pairs.
+    pub fn rotary_dim(&self) -> i64 {
+        let dim = (self.head_size() as f64 * self.rotary_pct.clamp(0.0, 1.0)) as i64;
+        dim - dim % 2
+    }
 }
@@ -0,0 +1,35 @@
+use tch::{Device, Kind, Tensor};
+
+/// Returns `preferred` if a tensor can actually be allocated on it, otherwise logs a
+/// warning and falls back to CPU.
+///
+/// `Device::cuda_if_available` only checks that CUDA is visible; a driver/runtime mismatch
+/// then surfaces as a panic on the first allocation. Probing with a one-element tensor
+/// turns that into a clean fallback at startup. CPU is returned without probing.
+pub fn resolve_device(preferred: Device) -> Device {
+    if preferred == Device::Cpu {
+        return Device::Cpu;
+    }
+
+    match Tensor::f_zeros([1], (Kind::Float, preferred)) {
+        Ok(_) => preferred,
+        Err(e) => {
+            tracing::warn!(
+                device = ?preferred,
+                error = %e,
+                "could not allocate a tensor, falling back to CPU"
+            );
+            Device::Cpu
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_device_keeps_cpu() {
+        assert_eq!(resolve_device(Device::Cpu), Device::Cpu);
+    }
+}
@@ -0,0 +1,330 @@
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use tch::{nn, Kind, Tensor};
+use tokenizer::{is_special_token, BPE};
+
+use crate::config::ModelConfig;
+
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+const GGUF_VERSION: u32 = 3;
+const ALIGNMENT: u64 = 32;
+/// Value of `general.architecture`, and the prefix of the model hyperparameter keys.
+pub const ARCHITECTURE: &str = "claude-rust";
+
+// GGUF metadata value types.
+const TYPE_UINT32: u32 = 4;
+const TYPE_FLOAT32: u32 = 6;
+const TYPE_STRING: u32 = 8;
+const TYPE_ARRAY: u32 = 9;
+
+// ggml tensor types.
+const GGML_TYPE_F32: u32 = 0;
+
+enum Value {
+    U32(u32),
+    F32(f32),
+    Str(String),
+    StrArray(Vec<String>),
+}
+
+/// Writes the weights in `vs`, the hyperparameters of `config` and the vocabulary and merges
+/// of `tokenizer` to `out_path` as a GGUF v3 file, for llama.cpp-based tooling.
+///
+/// Tensors are stored as F32 under llama.cpp-style names (`token_embd.weight`,
+/// `blk.N.attn_qkv.weight`, ...). There is no matching llama.cpp architecture, so
+/// `general.architecture` is `claude-rust`; the QKV projection stays fused as `attn_qkv` in
+/// the layout given by `config.qkv_layout`.
+pub fn export<P: AsRef<Path>>(vs: &nn::VarStore, config: &ModelConfig, tokenizer: &BPE, out_path: P) -> Result<()> {
+    let out_path = out_path.as_ref();
+    let metadata = metadata(config, tokenizer)?;
+
+    let mut tensors = BTreeMap::new();
+    for (name, tensor) in vs.variables() {
+        let gguf_name = tensor_name(&name).with_context(|| format!("no GGUF name for tensor {name}"))?;
+        tensors.insert(gguf_name, tensor);
+    }
+
+    let file = File::create(out_path).with_context(|| format!("Failed to create {}", out_path.display()))?;
+    let mut w = BufWriter::new(file);
+    w.write_all(GGUF_MAGIC)?;
+    w.write_all(&GGUF_VERSION.to_le_bytes())?;
+    w.write_all(&(tensors.len() as u64).to_le_bytes())?;
+    w.write_all(&(metadata.len() as u64).to_le_bytes())?;
+    for (key, value) in &metadata {
+        write_string(&mut w, key)?;
+        write_value(&mut w, value)?;
+    }
+
+    let mut written = 4 + 4 + 8 + 8 + metadata.iter().map(|(k, v)| 8 + k.len() as u64 + value_len(v)).sum::<u64>();
+    let mut offset = 0u64;
+    for (name, tensor) in &tensors {
+        let shape = tensor.size();
+        write_string(&mut w, name)?;
+        w.write_all(&(shape.len() as u32).to_le_bytes())?;
+        // ggml lists dimensions fastest-varying first, the reverse of torch.
+        for &dim in shape.iter().rev() {
+            w.write_all(&(dim as u64).to_le_bytes())?;
+        }
+        w.write_all(&GGML_TYPE_F32.to_le_bytes())?;
+        w.write_all(&offset.to_le_bytes())?;
+        written += 8 + name.len() as u64 + 4 + 8 * shape.len() as u64 + 4 + 8;
+        offset = align(offset + 4 * tensor.numel() as u64);
+    }
+
+    // Only one tensor at a time is copied out as f32.
+    pad(&mut w, written)?;
+    for (name, tensor) in &tensors {
+        let values = Vec::<f32>::try_from(tensor.to_kind(Kind::Float).flatten(0, -1))
+            .with_context(|| format!("Failed to read tensor {name}"))?;
+        for value in &values {
+            w.write_all(&value.to_le_bytes())?;
+        }
+        pad(&mut w, 4 * values.len() as u64)?;
+    }
+    w.flush()?;
+
+    tracing::info!(path = %out_path.display(), tensors = tensors.len(), "exported GGUF");
+    Ok(())
+}
+
+fn metadata(config: &ModelConfig, tokenizer: &BPE) -> Result<Vec<(String, Value)>> {
+    let u32_of = |value: i64, what: &str| u32::try_from(value).with_context(|| format!("{what} does not fit in u32"));
+    let arch = |key: &str| format!("{ARCHITECTURE}.{key}");
+
+    let mut metadata = vec![
+        ("general.architecture".to_string(), Value::Str(ARCHITECTURE.to_string())),
+        ("general.alignment".to_string(), Value::U32(ALIGNMENT as u32)),
+        (arch("vocab_size"), Value::U32(u32_of(config.padded_vocab_size(), "vocab_size")?)),
+        (arch("context_length"), Value::U32(u32_of(config.max_seq_len, "max_seq_len")?)),
+        (arch("embedding_length"), Value::U32(u32_of(config.n_embd, "n_embd")?)),
+        (arch("block_count"), Value::U32(u32_of(config.n_layer, "n_layer")?)),
+        (arch("feed_forward_length"), Value::U32(u32_of(4 * config.n_embd, "feed_forward_length")?)),
+        (arch("attention.head_count"), Value::U32(u32_of(config.n_head, "n_head")?)),
+        (
+            arch("attention.layer_norm_rms_epsilon"),
+            Value::F32(config.layer_norm_epsilon as f32),
+        ),
+        (arch("rope.dimension_count"), Value::U32(u32_of(config.rotary_dim(), "rotary_dim")?)),
+        (arch("rope.freq_base"), Value::F32(config.rope_theta as f32)),
+        ("tokenizer.ggml.model".to_string(), Value::Str("gpt2".to_string())),
+        ("tokenizer.ggml.tokens".to_string(), Value::StrArray(tokens(tokenizer, config.padded_vocab_size()))),
+        ("tokenizer.ggml.merges".to_string(), Value::StrArray(merges(tokenizer))),
+    ];
+    if let Some(eos) = config.eos_token_id {
+        metadata.push(("tokenizer.ggml.eos_token_id".to_string(), Value::U32(u32_of(eos, "eos_token_id")?)));
+    }
+    Ok(metadata)
+}
+
+/// Tokens ordered by ID, covering the whole embedding table, in the byte-level form a `gpt2`
+/// tokenizer expects (see [`byte_level`]). IDs the tokenizer doesn't use get a placeholder
+/// so every row still has a token.
+fn tokens(tokenizer: &BPE, vocab_size: i64) -> Vec<String> {
+    let max_id = tokenizer.vocab.id_to_token.keys().max().map_or(0, |&id| id as i64 + 1);
+    (0..vocab_size.max(max_id) as u32)
+        .map(|id| match tokenizer.vocab.get_token(id) {
+            Some(token) => byte_level(token),
+            None => format!("[UNUSED_{id}]"),
+        })
+        .collect()
+}
+
+/// Merges as `"left right"`, highest priority (lowest rank) first. Both sides are byte-level
+/// encoded, so neither contains the separating space.
+fn merges(tokenizer: &BPE) -> Vec<String> {
+    let mut merges: Vec<_> = tokenizer.merges.iter().collect();
+    merges.sort_by_key(|(_, rank)| **rank);
+    merges
+        .into_iter()
+        .map(|((a, b), _)| format!("{} {}", byte_level(a), byte_level(b)))
+        .collect()
+}
+
+/// Rewrites every byte of `token` with GPT-2's byte-to-unicode table, e.g. `" the"` becomes
+/// `"Ġthe"`. Special tokens and `<0xNN>` byte tokens are kept as they are.
+fn byte_level(token: &str) -> String {
+    if is_special_token(token) || is_byte_token(token) {
+        return token.to_string();
+    }
+    token.bytes().map(byte_to_unicode).collect()
+}
+
+fn is_byte_token(token: &str) -> bool {
+    token
+        .strip_prefix("<0x")
+        .and_then(|rest| rest.strip_suffix('>'))
+        .is_some_and(|hex| hex.len() == 2 && u8::from_str_radix(hex, 16).is_ok())
+}
+
+/// GPT-2's byte-to-unicode table: printable Latin-1 bytes stand for themselves and every
+/// other byte, in order, for a code point from U+0100 on (a space is `Ġ`, a newline `Ċ`).
+fn byte_to_unicode(byte: u8) -> char {
+    let printable = |b: u8| matches!(b, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+    if printable(byte) {
+        return byte as char;
+    }
+    let rank = (0..byte).filter(|&b| !printable(b)).count() as u32;
+    char::from_u32(256 + rank).expect("below U+0200")
+}
+
+/// Maps a VarStore variable name to its llama.cpp-style GGUF name.
+fn tensor_name(name: &str) -> Option<String> {
+    let mapped = match name {
+        "wte.weight" => "token_embd.weight".to_string(),
+        "ln_f.weight" => "output_norm.weight".to_string(),
+        "lm_head.weight" => "output.weight".to_string(),
+        _ => {
+            let rest = name.strip_prefix("h.")?;
+            let (layer, rest) = rest.split_once('.')?;
+            let layer: usize = layer.parse().ok()?;
+            let (module, param) = rest.rsplit_once('.')?;
+            let module = match module {
+                "ln_1" => "attn_norm",
+                "attn.c_attn" => "attn_qkv",
+                "attn.c_proj" => "attn_output",
+                "ln_2" => "ffn_norm",
+                "mlp.c_fc" => "ffn_up",
+                "mlp.c_proj" => "ffn_down",
+                _ => return None,
+            };
+            format!("blk.{layer}.{module}.{param}")
+        }
+    };
+    Some(mapped)
+}
+
+fn align(offset: u64) -> u64 {
+    offset.div_ceil(ALIGNMENT) * ALIGNMENT
+}
+
+/// Zero-pads after `written` bytes up to the next alignment boundary.
+fn pad(w: &mut impl Write, written: u64) -> Result<()> {
+    let padding = align(written) - written;
+    w.write_all(&vec![0u8; padding as usize])?;
+    Ok(())
+}
+
+fn write_string(w: &mut impl Write, s: &str) -> Result<()> {
+    w.write_all(&(s.len() as u64).to_le_bytes())?;
+    w.write_all(s.as_bytes())?;
+    Ok(())
+}
+
+fn write_value(w: &mut impl Write, value: &Value) -> Result<()> {
+    match value {
+        Value::U32(v) => {
+            w.write_all(&TYPE_UINT32.to_le_bytes())?;
+            w.write_all(&v.to_le_bytes())?;
+        }
+        Value::F32(v) => {
+            w.write_all(&TYPE_FLOAT32.to_le_bytes())?;
+            w.write_all(&v.to_le_bytes())?;
+        }
+        Value::Str(s) => {
+            w.write_all(&TYPE_STRING.to_le_bytes())?;
+            write_string(w, s)?;
+        }
+        Value::StrArray(items) => {
+            w.write_all(&TYPE_ARRAY.to_le_bytes())?;
+            w.write_all(&TYPE_STRING.to_le_bytes())?;
+            w.write_all(&(items.len() as u64).to_le_bytes())?;
+            for item in items {
+                write_string(w, item)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Encoded size of `value`, type tag included.
+fn value_len(value: &Value) -> u64 {
+    4 + match value {
+        Value::U32(_) | Value::F32(_) => 4,
+        Value::Str(s) => 8 + s.len() as u64,
+        Value::StrArray(items) => 4 + 8 + items.iter().map(|s| 8 + s.len() as u64).sum::<u64>(),
+    }
+}
+
+/// Reads the tensor count from the header of a GGUF file, checking the magic and version.
+pub fn read_tensor_count<P: AsRef<Path>>(path: P) -> Result<u64> {
+    let bytes = std::fs::read(path.as_ref()).with_context(|| format!("Failed to read {}", path.as_ref().display()))?;
+    if bytes.len() < 24 || &bytes[..4] != GGUF_MAGIC {
+        bail!("{} is not a GGUF file", path.as_ref().display());
+    }
+    let version = u32::from_le_bytes(bytes[4..8].try_into()?);
+    if version != GGUF_VERSION {
+        bail!("unsupported GGUF version {version}");
+    }
+    Ok(u64::from_le_bytes(bytes[8..16].try_into()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ClaudeTransformer;
+    use std::collections::HashMap;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tch::Device;
+    use tokenizer::Vocab;
+
+    #[test]
+    fn export_writes_a_gguf_header_and_every_tensor() {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_nanos();
+        let path = std::env::temp_dir().join(format!("claude_core_gguf_test_{unique}.gguf"));
+
+        let mut vocab = Vocab::new();
+        for id in 0..16u32 {
+            vocab.insert(((b'a' + id as u8) as char).to_string(), id);
+        }
+        let tokenizer = BPE::new(vocab, HashMap::from([(("a".to_string(), "b".to_string()), 0)]));
+        let config = ModelConfig::tiny(16);
+        let vs = nn::VarStore::new(Device::Cpu);
+        let _model = ClaudeTransformer::new_seeded(&vs.root(), &config, 0);
+
+        export(&vs, &config, &tokenizer, &path).expect("export gguf");
+
+        let bytes = std::fs::read(&path).expect("read gguf");
+        assert_eq!(&bytes[..4], b"GGUF");
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()), 3);
+        let tensor_count = read_tensor_count(&path).expect("read header");
+        assert_eq!(tensor_count, vs.variables().len() as u64);
+        let weights: u64 = vs.variables().values().map(|t| 4 * t.numel() as u64).sum();
+        assert!(bytes.len() as u64 >= weights);
+        assert!(bytes.windows(17).any(|w| w == b"token_embd.weight"));
+        assert_eq!(bytes.len() % ALIGNMENT as usize, 0);
+
+        std::fs::remove_file(&path).expect("cleanup temp gguf");
+    }
+
+    #[test]
+    fn tokens_and_merges_are_byte_level_encoded() {
+        let mut vocab = Vocab::new();
+        for (id, token) in [" ", "t", "he", " t", " the", "\n", "<|endoftext|>", "<0x20>"].iter().enumerate() {
+            vocab.insert(token.to_string(), id as u32);
+        }
+        let merges = HashMap::from([((" ".to_string(), "t".to_string()), 0), ((" t".to_string(), "he".to_string()), 1)]);
+        let tokenizer = BPE::new(vocab, merges);
+
+        assert_eq!(
+            tokens(&tokenizer, 9),
+            ["Ġ", "t", "he", "Ġt", "Ġthe", "Ċ", "<|endoftext|>", "<0x20>", "[UNUSED_8]"]
+        );
+        assert_eq!(super::merges(&tokenizer), ["Ġ t", "Ġt he"]);
+        assert_eq!(byte_to_unicode(b'a'), 'a');
+        assert_eq!(byte_to_unicode(0xAD), '\u{143}');
+    }
+
+    #[test]
+    fn tensor_names_follow_llama_cpp_conventions() {
+        assert_eq!(tensor_name("h.3.attn.c_attn.bias").as_deref(), Some("blk.3.attn_qkv.bias"));
+        assert_eq!(tensor_name("h.0.mlp.c_proj.weight").as_deref(), Some("blk.0.ffn_down.weight"));
+        assert_eq!(tensor_name("lm_head.weight").as_deref(), Some("output.weight"));
+        assert_eq!(tensor_name("h.0.unknown.weight"), None);
+    }
+}
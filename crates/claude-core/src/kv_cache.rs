@@ -1,13 +1,29 @@
 use tch::{Tensor, Device, Kind};
 
+/// Returned (inside the `anyhow::Error`) by [`KVCache::update`] when the new positions
+/// don't fit, so callers can tell a full cache apart from other failures with
+/// `err.is::<KVCacheOverflow>()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("KV cache overflow: {cached} cached plus {incoming} new positions exceed the capacity of {capacity}")]
+pub struct KVCacheOverflow {
+    pub cached: usize,
+    pub incoming: usize,
+    pub capacity: usize,
+}
+
 pub struct KVCache {
     pub k: Tensor,
     pub v: Tensor,
     pub length: usize,
     pub max_capacity: usize,
+    /// Dtype of the incoming K/V tensors, which `get_view` casts back to.
+    compute_kind: Kind,
 }
 
 impl KVCache {
+    /// Allocates a cache stored in `kind`, which may differ from the model's dtype (e.g. an
+    /// f16 cache for an f32 model halves cache memory). K/V are cast to `kind` on `update`
+    /// and back to the dtype they arrived in on `get_view`.
     pub fn new(max_capacity: usize, n_head: i64, head_dim: i64, device: Device, kind: Kind) -> Self {
         let k = Tensor::zeros(&[1, n_head, max_capacity as i64, head_dim], (kind, device));
         let v = Tensor::zeros(&[1, n_head, max_capacity as i64, head_dim], (kind, device));
@@ -16,38 +32,179 @@ impl KVCache {
             v,
             length: 0,
             max_capacity,
+            compute_kind: kind,
         }
     }
 
-    pub fn update(&mut self, new_k: &Tensor, new_v: &Tensor) {
-        let _batch_size = new_k.size()[0];
-        let _n_head = new_k.size()[1];
+    /// Appends `new_k`/`new_v` (`[batch, n_head, seq_len, head_dim]`) after the cached
+    /// positions. Fails if the batch size, head count or head size differ from the ones
+    /// the cache was allocated with, since copying would silently mix up sequences, and
+    /// with [`KVCacheOverflow`] if the positions don't fit; the cache is unchanged either way.
+    pub fn update(&mut self, new_k: &Tensor, new_v: &Tensor) -> anyhow::Result<()> {
+        let allocated = self.k.size();
+        for (name, new) in [("key", new_k), ("value", new_v)] {
+            let size = new.size();
+            anyhow::ensure!(
+                size.len() == 4,
+                "KVCache expects 4-d {} tensors [batch, n_head, seq_len, head_dim], got {:?}",
+                name,
+                size
+            );
+            anyhow::ensure!(
+                size[0] == allocated[0],
+                "KVCache was allocated for batch size {} but got a {} tensor with batch size {}",
+                allocated[0],
+                name,
+                size[0]
+            );
+            anyhow::ensure!(
+                size[1] == allocated[1] && size[3] == allocated[3],
+                "KVCache holds {} heads of size {}, but the {} tensor has shape {:?}",
+                allocated[1],
+                allocated[3],
+                name,
+                size
+            );
+        }
         let seq_len = new_k.size()[2];
-        let _head_dim = new_k.size()[3];
 
-        // If batch size changes or we exceed capacity, we might need a more complex strategy
-        // For now, assume batch size 1 and increment length
         let start = self.length as i64;
         let end = start + seq_len;
 
         if end > self.max_capacity as i64 {
-            // Simple truncation for now (FIFO-ish) - in reality we'd error or rotate
-            return;
+            return Err(KVCacheOverflow {
+                cached: self.length,
+                incoming: seq_len as usize,
+                capacity: self.max_capacity,
+            }
+            .into());
         }
 
+        self.compute_kind = new_k.kind();
         let _ = self.k.narrow(2, start, seq_len).copy_(new_k);
         let _ = self.v.narrow(2, start, seq_len).copy_(new_v);
         
         self.length += seq_len as usize;
+        Ok(())
     }
 
     pub fn get_view(&self) -> (Tensor, Tensor) {
         let k = self.k.narrow(2, 0, self.length as i64);
         let v = self.v.narrow(2, 0, self.length as i64);
-        (k, v)
+        if self.k.kind() == self.compute_kind {
+            (k, v)
+        } else {
+            (k.to_kind(self.compute_kind), v.to_kind(self.compute_kind))
+        }
     }
     
+    /// Copies the cache into freshly allocated tensors, so that updating the copy
+    /// leaves `self` untouched (a plain clone would share storage).
+    pub fn deep_clone(&self) -> Self {
+        Self {
+            k: self.k.copy(),
+            v: self.v.copy(),
+            length: self.length,
+            max_capacity: self.max_capacity,
+            compute_kind: self.compute_kind,
+        }
+    }
+
     pub fn clear(&mut self) {
         self.length = 0;
     }
+
+    /// Positions that can still be appended before [`KVCache::update`] overflows.
+    pub fn remaining(&self) -> usize {
+        self.max_capacity - self.length
+    }
+
+    /// Reallocates the cache to hold `max_capacity` positions, keeping the cached ones.
+    /// Fails if that is fewer than are cached.
+    pub fn resize(&mut self, max_capacity: usize) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            max_capacity >= self.length,
+            "cannot shrink a KV cache holding {} positions to a capacity of {}",
+            self.length,
+            max_capacity
+        );
+        let resized = |old: &Tensor| {
+            let mut size = old.size();
+            size[2] = max_capacity as i64;
+            let new = Tensor::zeros(size.as_slice(), (old.kind(), old.device()));
+            let len = self.length as i64;
+            let _ = new.narrow(2, 0, len).copy_(&old.narrow(2, 0, len));
+            new
+        };
+        self.k = resized(&self.k);
+        self.v = resized(&self.v);
+        self.max_capacity = max_capacity;
+        Ok(())
+    }
+
+    /// Forgets every position from `len` on, e.g. to undo a speculative update. The
+    /// dropped positions are overwritten by the next `update`.
+    pub fn truncate(&mut self, len: usize) {
+        self.length = self.length.min(len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update_rejects_batch_size_mismatch() {
+        let mut cache = KVCache::new(8, 2, 4, Device::Cpu, Kind::Float);
+        let batch_of_two = Tensor::ones([2, 2, 3, 4], (Kind::Float, Device::Cpu));
+
+        let err = cache.update(&batch_of_two, &batch_of_two).unwrap_err().to_string();
+        assert!(err.contains("batch size 1"), "{err}");
+        assert_eq!(cache.length, 0);
+        assert_eq!(cache.k.abs().sum(Kind::Float).double_value(&[]), 0.0);
+
+        let batch_of_one = Tensor::ones([1, 2, 3, 4], (Kind::Float, Device::Cpu));
+        cache.update(&batch_of_one, &batch_of_one).expect("matching batch size");
+        assert_eq!(cache.length, 3);
+    }
+
+    #[test]
+    fn update_past_capacity_reports_overflow_without_writing() {
+        let mut cache = KVCache::new(4, 2, 4, Device::Cpu, Kind::Float);
+        let three = Tensor::ones([1, 2, 3, 4], (Kind::Float, Device::Cpu));
+        cache.update(&three, &three).expect("fits");
+        assert_eq!(cache.remaining(), 1);
+
+        let two = Tensor::full([1, 2, 2, 4], 2.0, (Kind::Float, Device::Cpu));
+        let err = cache.update(&two, &two).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<KVCacheOverflow>(),
+            Some(&KVCacheOverflow {
+                cached: 3,
+                incoming: 2,
+                capacity: 4
+            })
+        );
+        assert_eq!(cache.length, 3);
+        assert_eq!(cache.k.narrow(2, 3, 1).abs().sum(Kind::Float).double_value(&[]), 0.0);
+
+        cache.resize(5).expect("grow");
+        cache.update(&two, &two).expect("fits after growing");
+        let (k, _) = cache.get_view();
+        assert_eq!(k.narrow(2, 0, 3).mean(Kind::Float).double_value(&[]), 1.0);
+        assert_eq!(k.narrow(2, 3, 2).mean(Kind::Float).double_value(&[]), 2.0);
+        assert!(cache.resize(4).is_err());
+    }
+
+    #[test]
+    fn half_precision_cache_returns_compute_dtype() {
+        let mut cache = KVCache::new(8, 2, 4, Device::Cpu, Kind::Half);
+        let k = Tensor::randn([1, 2, 3, 4], (Kind::Float, Device::Cpu));
+        cache.update(&k, &k).expect("update");
+
+        let (k_view, _) = cache.get_view();
+        assert_eq!(cache.k.kind(), Kind::Half);
+        assert_eq!(k_view.kind(), Kind::Float);
+        assert!(k_view.allclose(&k, 1e-2, 1e-3, false));
+    }
 }
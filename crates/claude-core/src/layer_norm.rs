@@ -16,6 +16,15 @@ impl RMSNorm {
         }
     }
 
+    pub fn num_parameters(&self) -> i64 {
+        crate::transformer::numel(&self.weight)
+    }
+
+    /// The weight, named as in the VarStore relative to this module.
+    pub fn named_tensors(&self) -> Vec<(String, Tensor)> {
+        vec![("weight".to_string(), self.weight.shallow_clone())]
+    }
+
     /// Forward pass:
     /// x: [batch, seq_len, n_embd]
     pub fn forward(&self, x: &Tensor) -> Tensor {
@@ -2,10 +2,13 @@ pub mod transformer;
 pub mod layer_norm;
 pub mod attention;
 pub mod config;
+pub mod device;
 pub mod rotary;
 pub mod kv_cache;
 pub mod safetensors_util;
+pub mod gguf;
+pub mod tensor_util;
 
 pub use transformer::ClaudeTransformer;
-pub use config::ModelConfig;
-pub use kv_cache::KVCache;
+pub use config::{ModelConfig, QkvLayout};
+pub use kv_cache::{KVCache, KVCacheOverflow};
@@ -2,25 +2,49 @@ use tch::{Tensor, Kind, Device};
 
 pub struct RotaryEmbedding {
     inv_freq: Tensor,
+    /// Number of leading head dimensions that are rotated; the rest pass through.
+    dim: i64,
 }
 
 impl RotaryEmbedding {
-    pub fn new(dim: i64, device: Device) -> Self {
-        // inv_freq = 1.0 / (10000 ^ (2i / dim))
+    /// Rotates the first `dim` dimensions of each head (all of them for full RoPE, fewer for
+    /// NeoX-style partial rotary, see [`crate::ModelConfig::rotary_dim`]), with frequencies
+    /// based on `theta` ([`crate::ModelConfig::rope_theta`]).
+    pub fn new(dim: i64, theta: f64, device: Device) -> Self {
+        // inv_freq = 1.0 / (theta ^ (2i / dim))
         let inv_freq: Vec<f32> = (0..dim)
             .step_by(2)
-            .map(|i| 1.0 / (10000.0f32.powf(i as f32 / dim as f32)))
+            .map(|i| 1.0 / ((theta as f32).powf(i as f32 / dim as f32)))
             .collect();
         let inv_freq = Tensor::from_slice(&inv_freq).to(device);
         
-        Self { inv_freq }
+        Self { inv_freq, dim }
     }
 
     /// x: [batch, n_head, seq_len, head_dim]
     pub fn forward(&self, x: &Tensor, seq_len: i64) -> Tensor {
-        let device = x.device();
-        let t = Tensor::arange(seq_len, (Kind::Float, device));
-        
+        let t = Tensor::arange(seq_len, (Kind::Float, x.device()));
+        self.rotate(x, &t)
+    }
+
+    /// Rotates `x` as if its positions start at `offset` (e.g. the current KV cache length).
+    /// x: [batch, n_head, t, head_dim]
+    pub fn forward_from(&self, x: &Tensor, offset: i64) -> Tensor {
+        let seq_len = x.size()[2];
+        let t = Tensor::arange_start(offset, offset + seq_len, (Kind::Float, x.device()));
+        self.rotate(x, &t)
+    }
+
+    fn rotate(&self, x: &Tensor, t: &Tensor) -> Tensor {
+        let head_dim = x.size()[x.dim() - 1];
+        if self.dim == 0 {
+            return x.shallow_clone();
+        }
+        if self.dim < head_dim {
+            let rotated = self.rotate(&x.narrow(-1, 0, self.dim), t);
+            return Tensor::cat(&[rotated, x.narrow(-1, self.dim, head_dim - self.dim)], -1);
+        }
+
         // freqs: [seq_len, dim/2]
         let freqs = t.outer(&self.inv_freq);
         
@@ -47,3 +71,47 @@ impl RotaryEmbedding {
         Tensor::cat(&[&-x2, &x1], -1)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ModelConfig;
+
+    #[test]
+    fn partial_rotary_leaves_the_trailing_dims_unrotated() {
+        let config = ModelConfig {
+            rotary_pct: 0.5,
+            ..ModelConfig::tiny(16)
+        };
+        let head_size = config.head_size();
+        assert_eq!(config.rotary_dim(), head_size / 2);
+        let x = Tensor::randn([1, 2, 5, head_size], (Kind::Float, Device::Cpu));
+
+        let partial = RotaryEmbedding::new(config.rotary_dim(), config.rope_theta, Device::Cpu).forward_from(&x, 3);
+        let slice_only = RotaryEmbedding::new(config.rotary_dim(), config.rope_theta, Device::Cpu)
+            .forward_from(&x.narrow(-1, 0, config.rotary_dim()), 3);
+
+        let half = head_size / 2;
+        assert!(partial.narrow(-1, half, half).equal(&x.narrow(-1, half, half)));
+        assert!(partial.narrow(-1, 0, half).allclose(&slice_only, 1e-6, 1e-6, false));
+        assert!(!partial.narrow(-1, 0, half).allclose(&x.narrow(-1, 0, half), 1e-3, 1e-3, false));
+    }
+
+    #[test]
+    fn rope_theta_sets_the_frequency_base() {
+        // Configs written before `rope_theta` existed keep the original base.
+        let mut json = serde_json::to_value(ModelConfig::tiny(16)).expect("serialize config");
+        json.as_object_mut().expect("config object").remove("rope_theta");
+        let config: ModelConfig = serde_json::from_value(json).expect("deserialize config");
+        assert_eq!(config.rope_theta, 10000.0);
+
+        let inv_freq = |theta: f64| {
+            Vec::<f32>::try_from(&RotaryEmbedding::new(4, theta, Device::Cpu).inv_freq).expect("inv_freq")
+        };
+        for theta in [10000.0, 500000.0] {
+            let freqs = inv_freq(theta);
+            assert_eq!(freqs[0], 1.0);
+            assert!((freqs[1] - 1.0 / (theta as f32).sqrt()).abs() < 1e-7, "{freqs:?}");
+        }
+    }
+}
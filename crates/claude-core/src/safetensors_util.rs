@@ -1,11 +1,29 @@
-use std::path::Path;
-use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use safetensors::tensor::TensorView;
 use safetensors::SafeTensors;
-use tch::{Tensor, nn, Kind};
+use serde::Deserialize;
+use tch::{Tensor, nn, Kind, Device};
 use std::fs::File;
 use memmap2::MmapOptions;
 
+/// Index file mapping tensor names to shard files in a sharded checkpoint directory.
+pub const SHARD_INDEX_FILE: &str = "model.safetensors.index.json";
+
+#[derive(Deserialize)]
+struct ShardIndex {
+    weight_map: HashMap<String, String>,
+}
+
 pub fn load_safetensors<P: AsRef<Path>>(vs: &mut nn::VarStore, path: P) -> Result<()> {
+    load_safetensors_filtered(vs, path, &[])
+}
+
+/// Like [`load_safetensors`], but leaves every variable whose name starts with one of the
+/// `skip` prefixes (e.g. `"lm_head."` or `"h.11."`) at its initialized value, to warm-start
+/// most of the model while re-initializing a part of it.
+pub fn load_safetensors_filtered<P: AsRef<Path>>(vs: &mut nn::VarStore, path: P, skip: &[&str]) -> Result<()> {
     let file = File::open(path)?;
     let buffer = unsafe { MmapOptions::new().map(&file)? };
     let tensors = SafeTensors::deserialize(&buffer)?;
@@ -14,31 +32,252 @@ pub fn load_safetensors<P: AsRef<Path>>(vs: &mut nn::VarStore, path: P) -> Resul
     let device = vs.device();
 
     for (name, view) in tensors.tensors() {
-        if let Some(var) = variables.get_mut(&name) {
-            let shape: Vec<i64> = view.shape().iter().map(|&x| x as i64).collect();
-            let kind = match view.dtype() {
-                safetensors::Dtype::F32 => Kind::Float,
-                safetensors::Dtype::F16 => Kind::Half,
-                safetensors::Dtype::BF16 => Kind::BFloat16,
-                _ => return Err(anyhow::anyhow!("Unsupported dtype: {:?}", view.dtype())),
-            };
-
-            let data = view.data();
-            let tch_tensor = Tensor::from_data_size(data, &shape, kind).to_device(device);
-            
-            tch::no_grad(|| {
-                var.copy_(&tch_tensor);
-            });
-            println!("Loaded tensor: {}", name);
-        } else {
-            println!("Warning: Tensor {} found in safetensors but not in model", name);
+        if skip.iter().any(|prefix| name.starts_with(prefix)) {
+            tracing::debug!(tensor = %name, "skipped tensor");
+            continue;
         }
+        copy_into_variable(&mut variables, &name, &view, device)?;
     }
 
     Ok(())
 }
 
-pub fn save_safetensors<P: AsRef<Path>>(_vs: &nn::VarStore, _path: P) -> Result<()> {
-    // For now, let's focus on loading since it's more critical for using pretrained weights
+/// Loads a checkpoint split across several `*.safetensors` shards, using the
+/// `model.safetensors.index.json` weight map to find each tensor's shard.
+/// Falls back to the latest single `.safetensors` file in `dir` when no index is present.
+pub fn load_safetensors_sharded<P: AsRef<Path>>(vs: &mut nn::VarStore, dir: P) -> Result<()> {
+    let dir = dir.as_ref();
+    let index_path = dir.join(SHARD_INDEX_FILE);
+    if !index_path.exists() {
+        let path = latest_safetensors(dir)?
+            .with_context(|| format!("No shard index or .safetensors file found in {:?}", dir))?;
+        return load_safetensors(vs, path);
+    }
+
+    let index_str = std::fs::read_to_string(&index_path)
+        .with_context(|| format!("Failed to read shard index {:?}", index_path))?;
+    let index: ShardIndex = serde_json::from_str(&index_str)
+        .with_context(|| format!("Failed to parse shard index {:?}", index_path))?;
+
+    let mut names_by_shard: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for (name, shard) in &index.weight_map {
+        names_by_shard.entry(shard.as_str()).or_default().push(name.as_str());
+    }
+
+    let mut variables = vs.variables();
+    let device = vs.device();
+
+    for (shard, names) in names_by_shard {
+        let shard_path = dir.join(shard);
+        let file = File::open(&shard_path)
+            .with_context(|| format!("Failed to open shard {:?}", shard_path))?;
+        let buffer = unsafe { MmapOptions::new().map(&file)? };
+        let tensors = SafeTensors::deserialize(&buffer)?;
+
+        for name in names {
+            let view = tensors
+                .tensor(name)
+                .with_context(|| format!("Tensor {} missing from shard {:?}", name, shard_path))?;
+            copy_into_variable(&mut variables, name, &view, device)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The newest `.safetensors` file in `dir`: the one whose stem ends in the highest number
+/// (`checkpoint_epoch_10` after `checkpoint_epoch_9`), then the most recently modified.
+fn latest_safetensors(dir: &Path) -> Result<Option<PathBuf>> {
+    let latest = std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "safetensors"))
+        .max_by_key(|p| {
+            let modified = std::fs::metadata(p).and_then(|m| m.modified()).ok();
+            (trailing_number(p), modified, p.clone())
+        });
+    Ok(latest)
+}
+
+fn trailing_number(path: &Path) -> Option<u64> {
+    let stem = path.file_stem()?.to_str()?;
+    let prefix = stem.trim_end_matches(|c: char| c.is_ascii_digit());
+    stem[prefix.len()..].parse().ok()
+}
+
+fn copy_into_variable(
+    variables: &mut HashMap<String, Tensor>,
+    name: &str,
+    view: &TensorView,
+    device: Device,
+) -> Result<()> {
+    if let Some(var) = variables.get_mut(name) {
+        let shape: Vec<i64> = view.shape().iter().map(|&x| x as i64).collect();
+        let kind = match view.dtype() {
+            safetensors::Dtype::F32 => Kind::Float,
+            safetensors::Dtype::F16 => Kind::Half,
+            safetensors::Dtype::BF16 => Kind::BFloat16,
+            _ => return Err(anyhow::anyhow!("Unsupported dtype: {:?}", view.dtype())),
+        };
+
+        let data = view.data();
+        let tch_tensor = Tensor::from_data_size(data, &shape, kind).to_device(device);
+        
+        tch::no_grad(|| {
+            var.copy_(&tch_tensor);
+        });
+        tracing::debug!(tensor = %name, "loaded tensor");
+    } else {
+        tracing::warn!(tensor = %name, "tensor found in safetensors but not in model");
+    }
+    Ok(())
+}
+
+/// Writes every variable of `vs` to a single `.safetensors` file that [`load_safetensors`]
+/// reads back. Variables on CUDA are copied to the CPU first; F32, F16 and BF16 are supported.
+pub fn save_safetensors<P: AsRef<Path>>(vs: &nn::VarStore, path: P) -> Result<()> {
+    let variables: BTreeMap<String, Tensor> = vs.variables().into_iter().collect();
+
+    let mut buffers = Vec::with_capacity(variables.len());
+    for (name, var) in &variables {
+        let tensor = var.detach().to_device(Device::Cpu).contiguous();
+        let dtype = match tensor.kind() {
+            Kind::Float => safetensors::Dtype::F32,
+            Kind::Half => safetensors::Dtype::F16,
+            Kind::BFloat16 => safetensors::Dtype::BF16,
+            kind => return Err(anyhow::anyhow!("Unsupported dtype {:?} for tensor {}", kind, name)),
+        };
+        let shape: Vec<usize> = tensor.size().iter().map(|&x| x as usize).collect();
+        let numel = tensor.numel();
+        let mut data = vec![0u8; numel * tensor.kind().elt_size_in_bytes()];
+        tensor.copy_data_u8(&mut data, numel);
+        buffers.push((name.as_str(), dtype, shape, data));
+    }
+
+    let views = buffers
+        .iter()
+        .map(|(name, dtype, shape, data)| Ok((*name, TensorView::new(*dtype, shape.clone(), data)?)))
+        .collect::<Result<Vec<_>>>()?;
+    safetensors::serialize_to_file(views, &None, path.as_ref())
+        .with_context(|| format!("Failed to write safetensors checkpoint {:?}", path.as_ref()))?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn f32_bytes(values: &[f32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn load_safetensors_filtered_keeps_skipped_tensors_at_their_init_values() {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_nanos();
+        let path = std::env::temp_dir().join(format!("claude_core_filtered_load_test_{unique}.safetensors"));
+        let body_bytes = f32_bytes(&[1.0, 2.0]);
+        let head_bytes = f32_bytes(&[3.0, 4.0]);
+        let body = TensorView::new(safetensors::Dtype::F32, vec![2], &body_bytes).expect("view body");
+        let head = TensorView::new(safetensors::Dtype::F32, vec![2], &head_bytes).expect("view head");
+        safetensors::serialize_to_file([("h.0.weight", body), ("lm_head.weight", head)], &None, &path)
+            .expect("write checkpoint");
+
+        let mut vs = nn::VarStore::new(Device::Cpu);
+        let body_var = (vs.root() / "h" / 0).zeros("weight", &[2]);
+        let head_var = (vs.root() / "lm_head").ones("weight", &[2]);
+        load_safetensors_filtered(&mut vs, &path, &["lm_head."]).expect("load checkpoint");
+
+        assert_eq!(Vec::<f32>::try_from(&body_var).expect("body values"), vec![1.0, 2.0]);
+        assert_eq!(Vec::<f32>::try_from(&head_var).expect("head values"), vec![1.0, 1.0]);
+
+        fs::remove_file(&path).expect("cleanup temp checkpoint");
+    }
+
+    #[test]
+    fn save_safetensors_round_trips_through_load_safetensors() {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_nanos();
+        let path = std::env::temp_dir().join(format!("claude_core_save_test_{unique}.safetensors"));
+
+        let saved = nn::VarStore::new(Device::Cpu);
+        let weight = (saved.root() / "h" / 0).randn_standard("weight", &[2, 3]);
+        save_safetensors(&saved, &path).expect("save checkpoint");
+
+        let mut loaded = nn::VarStore::new(Device::Cpu);
+        let loaded_weight = (loaded.root() / "h" / 0).zeros("weight", &[2, 3]);
+        load_safetensors(&mut loaded, &path).expect("load checkpoint");
+        assert!(loaded_weight.equal(&weight));
+
+        // Half-precision weights are written as F16 and cast back on load.
+        let mut half = nn::VarStore::new(Device::Cpu);
+        let _ = half.root().ones("scale", &[4]);
+        half.half();
+        save_safetensors(&half, &path).expect("save half checkpoint");
+        let mut loaded = nn::VarStore::new(Device::Cpu);
+        let scale = loaded.root().zeros("scale", &[4]);
+        load_safetensors(&mut loaded, &path).expect("load half checkpoint");
+        assert_eq!(Vec::<f32>::try_from(&scale).expect("scale values"), vec![1.0; 4]);
+
+        fs::remove_file(&path).expect("cleanup temp checkpoint");
+    }
+
+    #[test]
+    fn latest_safetensors_orders_checkpoints_by_their_number() {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("claude_core_latest_test_{unique}"));
+        fs::create_dir_all(&dir).expect("create temp test dir");
+        for epoch in [10, 9, 2] {
+            fs::write(dir.join(format!("checkpoint_epoch_{epoch}.safetensors")), b"").expect("write checkpoint");
+        }
+        fs::write(dir.join("notes.txt"), b"").expect("write other file");
+
+        let latest = latest_safetensors(&dir).expect("read dir");
+        assert_eq!(latest, Some(dir.join("checkpoint_epoch_10.safetensors")));
+
+        fs::remove_dir_all(&dir).expect("cleanup temp test dir");
+    }
+
+    #[test]
+    fn load_safetensors_sharded_reads_each_tensor_from_its_shard() {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("claude_core_shard_test_{unique}"));
+        fs::create_dir_all(&dir).expect("create temp test dir");
+
+        let a_bytes = f32_bytes(&[1.0, 2.0]);
+        let b_bytes = f32_bytes(&[3.0, 4.0, 5.0]);
+        let a = TensorView::new(safetensors::Dtype::F32, vec![2], &a_bytes).expect("view a");
+        let b = TensorView::new(safetensors::Dtype::F32, vec![3], &b_bytes).expect("view b");
+        safetensors::serialize_to_file([("a", a)], &None, &dir.join("model-00001-of-00002.safetensors"))
+            .expect("write shard 1");
+        safetensors::serialize_to_file([("b", b)], &None, &dir.join("model-00002-of-00002.safetensors"))
+            .expect("write shard 2");
+        fs::write(
+            dir.join(SHARD_INDEX_FILE),
+            r#"{"metadata": {}, "weight_map": {"a": "model-00001-of-00002.safetensors", "b": "model-00002-of-00002.safetensors"}}"#,
+        )
+        .expect("write index");
+
+        let mut vs = nn::VarStore::new(Device::Cpu);
+        let var_a = vs.root().zeros("a", &[2]);
+        let var_b = vs.root().zeros("b", &[3]);
+        load_safetensors_sharded(&mut vs, &dir).expect("load sharded checkpoint");
+
+        assert_eq!(Vec::<f32>::try_from(&var_a).expect("a values"), vec![1.0, 2.0]);
+        assert_eq!(Vec::<f32>::try_from(&var_b).expect("b values"), vec![3.0, 4.0, 5.0]);
+
+        fs::remove_dir_all(&dir).expect("cleanup temp test dir");
+    }
+}
@@ -0,0 +1,68 @@
+use anyhow::{Context, Result};
+use tch::kind::Element;
+use tch::{Device, Kind, Tensor};
+
+const FLOAT_KINDS: [Kind; 4] = [Kind::Half, Kind::BFloat16, Kind::Float, Kind::Double];
+const INT_KINDS: [Kind; 5] = [Kind::Uint8, Kind::Int8, Kind::Int16, Kind::Int, Kind::Int64];
+
+/// Copies a 1-d floating-point tensor on any device into a `Vec<f32>`.
+pub fn tensor_to_vec_f32(tensor: &Tensor) -> Result<Vec<f32>> {
+    to_host_vec(tensor, Kind::Float, &FLOAT_KINDS, "floating-point")
+}
+
+/// Copies a 1-d floating-point tensor on any device into a `Vec<f64>`.
+pub fn tensor_to_vec_f64(tensor: &Tensor) -> Result<Vec<f64>> {
+    to_host_vec(tensor, Kind::Double, &FLOAT_KINDS, "floating-point")
+}
+
+/// Copies a 1-d integer tensor (e.g. token IDs or `topk` indices) on any device into a
+/// `Vec<i64>`.
+pub fn tensor_to_vec_i64(tensor: &Tensor) -> Result<Vec<i64>> {
+    to_host_vec(tensor, Kind::Int64, &INT_KINDS, "integer")
+}
+
+/// Moves `tensor` to the CPU as a contiguous `kind` tensor and copies it out. Fails
+/// instead of truncating or reinterpreting when the tensor isn't 1-d or its kind isn't in
+/// `accepted`, e.g. float scores read as indices.
+fn to_host_vec<T: Element>(tensor: &Tensor, kind: Kind, accepted: &[Kind], what: &str) -> Result<Vec<T>> {
+    let size = tensor.size();
+    anyhow::ensure!(size.len() == 1, "expected a 1-d {} tensor, got shape {:?}", what, size);
+    anyhow::ensure!(
+        accepted.contains(&tensor.kind()),
+        "expected a {} tensor, got {:?}",
+        what,
+        tensor.kind()
+    );
+    let host = tensor.to_device(Device::Cpu).to_kind(kind).contiguous();
+    Vec::<T>::try_from(&host)
+        .with_context(|| format!("copying a {:?} tensor of shape {:?} to the host", tensor.kind(), size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_tensors_from_any_device() {
+        let device = Device::cuda_if_available();
+        let scores = Tensor::from_slice(&[0.5f32, -1.0, 2.0]).to(device);
+        // A strided view is made contiguous before copying.
+        let every_other = Tensor::arange(6, (Kind::Int64, device)).view([3, 2]).select(1, 0);
+
+        assert_eq!(tensor_to_vec_f32(&scores).expect("f32"), vec![0.5, -1.0, 2.0]);
+        assert_eq!(tensor_to_vec_f64(&scores.to_kind(Kind::Half)).expect("f64"), vec![0.5, -1.0, 2.0]);
+        assert_eq!(tensor_to_vec_i64(&every_other).expect("i64"), vec![0, 2, 4]);
+    }
+
+    #[test]
+    fn rejects_wrong_shapes_and_kinds() {
+        let matrix = Tensor::zeros([2, 2], (Kind::Float, Device::Cpu));
+        let err = tensor_to_vec_f32(&matrix).expect_err("2-d tensor").to_string();
+        assert!(err.contains("[2, 2]"), "{err}");
+
+        let scores = Tensor::from_slice(&[0.5f64, 1.5]);
+        let err = tensor_to_vec_i64(&scores).expect_err("float tensor read as indices").to_string();
+        assert!(err.contains("Double"), "{err}");
+        assert!(tensor_to_vec_f64(&Tensor::from_slice(&[1i64, 2])).is_err());
+    }
+}
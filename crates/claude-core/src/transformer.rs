@@ -1,8 +1,41 @@
-use tch::{nn, Tensor};
+use anyhow::Result;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tch::{nn, Device, Kind, Tensor};
 use crate::config::ModelConfig;
-use crate::attention::CausalSelfAttention;
+use crate::attention::{causal_mask_buffer, CausalSelfAttention};
 use crate::layer_norm::RMSNorm;
 
+/// Soft-caps values to `(-cap, cap)` with `cap * tanh(x / cap)`.
+pub fn softcap(x: &Tensor, cap: f64) -> Tensor {
+    (x / cap).tanh() * cap
+}
+
+/// Number of elements in a tensor.
+pub(crate) fn numel(t: &Tensor) -> i64 {
+    t.size().iter().product()
+}
+
+/// Weight plus (optional) bias elements of a linear layer.
+pub(crate) fn linear_parameters(linear: &nn::Linear) -> i64 {
+    numel(&linear.ws) + linear.bs.as_ref().map_or(0, numel)
+}
+
+/// Weight and (optional) bias of a linear layer, named `{name}.weight` and `{name}.bias`.
+pub(crate) fn linear_tensors(name: &str, linear: &nn::Linear) -> Vec<(String, Tensor)> {
+    let mut tensors = vec![(format!("{name}.weight"), linear.ws.shallow_clone())];
+    if let Some(bs) = &linear.bs {
+        tensors.push((format!("{name}.bias"), bs.shallow_clone()));
+    }
+    tensors
+}
+
+/// Prepends `prefix` and a dot to every name in `tensors`.
+fn prefixed(prefix: &str, tensors: Vec<(String, Tensor)>) -> impl Iterator<Item = (String, Tensor)> + '_ {
+    tensors.into_iter().map(move |(name, tensor)| (format!("{prefix}.{name}"), tensor))
+}
+
 /// FeedForward block (MLP)
 pub struct MLP {
     c_fc: nn::Linear,
@@ -21,12 +54,24 @@ impl MLP {
         Self {
             c_fc,
             c_proj,
-            dropout: config.dropout,
+            dropout: config.resid_dropout(),
         }
     }
 
-    pub fn forward(&self, x: &Tensor) -> Tensor {
-        x.apply(&self.c_fc).gelu("none").apply(&self.c_proj).dropout(self.dropout, true)
+    pub fn num_parameters(&self) -> i64 {
+        linear_parameters(&self.c_fc) + linear_parameters(&self.c_proj)
+    }
+
+    /// The layer weights and biases, named as in the VarStore relative to this module.
+    pub fn named_tensors(&self) -> Vec<(String, Tensor)> {
+        let mut tensors = linear_tensors("c_fc", &self.c_fc);
+        tensors.extend(linear_tensors("c_proj", &self.c_proj));
+        tensors
+    }
+
+    /// `train` enables the output dropout.
+    pub fn forward(&self, x: &Tensor, train: bool) -> Tensor {
+        x.apply(&self.c_fc).gelu("none").apply(&self.c_proj).dropout(self.dropout, train)
     }
 }
 
@@ -40,12 +85,14 @@ pub struct Block {
     attn: CausalSelfAttention,
     ln_2: RMSNorm,
     mlp: MLP,
+    residual_scale: f64,
 }
 
 impl Block {
-    pub fn new(vs: &nn::Path, config: &ModelConfig) -> Self {
+    /// `causal_mask` is the model's shared buffer from [`causal_mask_buffer`].
+    pub fn new(vs: &nn::Path, config: &ModelConfig, causal_mask: &Arc<Tensor>) -> Self {
         let ln_1 = RMSNorm::new(&(vs / "ln_1"), config);
-        let attn = CausalSelfAttention::new(&(vs / "attn"), config);
+        let attn = CausalSelfAttention::with_causal_mask(&(vs / "attn"), config, Arc::clone(causal_mask));
         let ln_2 = RMSNorm::new(&(vs / "ln_2"), config);
         let mlp = MLP::new(&(vs / "mlp"), config);
         
@@ -54,22 +101,62 @@ impl Block {
             attn,
             ln_2,
             mlp,
+            residual_scale: config.residual_scale.unwrap_or(1.0),
         }
     }
 
-    pub fn forward(&self, x: &Tensor, cache: Option<&mut crate::kv_cache::KVCache>) -> Tensor {
+    pub fn set_active_heads(&mut self, active: Option<&[bool]>) -> Result<()> {
+        self.attn.set_active_heads(active)
+    }
+
+    pub fn num_parameters(&self) -> i64 {
+        self.ln_1.num_parameters()
+            + self.attn.num_parameters()
+            + self.ln_2.num_parameters()
+            + self.mlp.num_parameters()
+    }
+
+    /// Every weight of the block, named as in the VarStore relative to this module.
+    pub fn named_tensors(&self) -> Vec<(String, Tensor)> {
+        prefixed("ln_1", self.ln_1.named_tensors())
+            .chain(prefixed("attn", self.attn.named_tensors()))
+            .chain(prefixed("ln_2", self.ln_2.named_tensors()))
+            .chain(prefixed("mlp", self.mlp.named_tensors()))
+            .collect()
+    }
+
+    /// `train` enables dropout in the attention and MLP sublayers.
+    pub fn forward(&self, x: &Tensor, cache: Option<&mut crate::kv_cache::KVCache>, train: bool) -> Result<Tensor> {
         let residual = x;
         let x_ln = self.ln_1.forward(x);
         
-        let attn_out = self.attn.forward(&x_ln, cache);
+        let attn_out = self.attn.forward(&x_ln, cache, train)?;
         
-        let x = residual + attn_out;
+        let x = residual + self.scale_residual(attn_out);
         
         let residual = &x;
         let x_ln = self.ln_2.forward(&x);
-        let mlp_out = self.mlp.forward(&x_ln);
+        let mlp_out = self.mlp.forward(&x_ln, train);
         
-        residual + mlp_out
+        Ok(residual + self.scale_residual(mlp_out))
+    }
+
+    /// [`Block::forward`] for one new token of each of several sequences; see
+    /// [`CausalSelfAttention::forward_decode_rows`].
+    pub fn forward_decode_rows(&self, x: &Tensor, caches: &mut [&mut crate::kv_cache::KVCache]) -> Result<Tensor> {
+        let attn_out = self.attn.forward_decode_rows(&self.ln_1.forward(x), caches)?;
+        let x = x + self.scale_residual(attn_out);
+
+        let mlp_out = self.mlp.forward(&self.ln_2.forward(&x), false);
+        Ok(&x + self.scale_residual(mlp_out))
+    }
+
+    fn scale_residual(&self, branch: Tensor) -> Tensor {
+        if self.residual_scale == 1.0 {
+            branch
+        } else {
+            branch * self.residual_scale
+        }
     }
 }
 
@@ -89,16 +176,18 @@ pub struct ClaudeTransformer {
 
 impl ClaudeTransformer {
     pub fn new(vs: &nn::Path, config: &ModelConfig) -> Self {
-        let wte = nn::embedding(vs / "wte", config.vocab_size, config.n_embd, Default::default());
+        let wte = nn::embedding(vs / "wte", config.padded_vocab_size(), config.n_embd, Default::default());
         let drop = config.dropout;
         
+        // One causal mask for all layers instead of a [max_seq_len, max_seq_len] copy each.
+        let causal_mask = causal_mask_buffer(config.max_seq_len, vs.device());
         let mut blocks = Vec::new();
         for i in 0..config.n_layer {
-            blocks.push(Block::new(&(vs / "h" / i), config));
+            blocks.push(Block::new(&(vs / "h" / i), config, &causal_mask));
         }
 
         let ln_f = RMSNorm::new(&(vs / "ln_f"), config);
-        let lm_head = nn::linear(vs / "lm_head", config.n_embd, config.vocab_size, nn::LinearConfig { bias: false, ..Default::default() });
+        let lm_head = nn::linear(vs / "lm_head", config.n_embd, config.padded_vocab_size(), nn::LinearConfig { bias: false, ..Default::default() });
 
         Self {
             wte,
@@ -110,28 +199,507 @@ impl ClaudeTransformer {
         }
     }
 
+    /// Like [`ClaudeTransformer::new`], but seeds tch's global RNG first so the initial
+    /// weights are the same on every run.
+    pub fn new_seeded(vs: &nn::Path, config: &ModelConfig, seed: u64) -> Self {
+        tch::manual_seed(seed as i64);
+        Self::new(vs, config)
+    }
+
+    /// Builds a randomly initialised model from a YAML or JSON config file.
+    pub fn from_config_file<P: AsRef<Path>>(path: P, device: Device) -> Result<(Self, ModelConfig)> {
+        let config = ModelConfig::from_file(path)?;
+        let vs = nn::VarStore::new(device);
+        let model = Self::new(&vs.root(), &config);
+        Ok((model, config))
+    }
+
+    /// Prunes attention heads of block `layer` at runtime: heads flagged `false` in
+    /// `active` (one flag per head) contribute nothing to the block. `None` restores all heads.
+    pub fn set_active_heads(&mut self, layer: usize, active: Option<&[bool]>) -> Result<()> {
+        let n_layer = self.blocks.len();
+        let block = self
+            .blocks
+            .get_mut(layer)
+            .ok_or_else(|| anyhow::anyhow!("layer {} out of range ({} layers)", layer, n_layer))?;
+        block.set_active_heads(active)
+    }
+
+    /// Runs a throwaway prefill of `seq_len` dummy tokens plus one cached decode step under
+    /// `no_grad`, so that lazy kernel initialization happens before the first real request.
+    /// Returns how long the warm-up took.
+    pub fn warmup(&self, device: Device, seq_len: i64) -> Result<Duration> {
+        let start = Instant::now();
+        let _guard = tch::no_grad_guard();
+        let config = &self.config;
+        // Leave room for the decode step.
+        let seq_len = seq_len.clamp(1, (config.max_seq_len - 1).max(1));
+
+        let mut caches: Vec<crate::kv_cache::KVCache> = (0..config.n_layer)
+            .map(|_| crate::kv_cache::KVCache::new(
+                config.max_seq_len as usize,
+                config.n_head,
+                config.head_size(),
+                device,
+                Kind::Float,
+            ))
+            .collect();
+        let prompt = Tensor::zeros([1, seq_len], (Kind::Int64, device));
+        self.forward(&prompt, Some(&mut caches[..]))?;
+        let step = Tensor::zeros([1, 1], (Kind::Int64, device));
+        self.forward(&step, Some(&mut caches[..]))?;
+
+        Ok(start.elapsed())
+    }
+
+    /// Total number of trainable parameters.
+    pub fn num_parameters(&self) -> i64 {
+        numel(&self.wte.ws)
+            + self.blocks.iter().map(Block::num_parameters).sum::<i64>()
+            + self.ln_f.num_parameters()
+            + linear_parameters(&self.lm_head)
+    }
+
+    /// Every weight of the model under its VarStore name (e.g. `h.0.attn.c_attn.weight`).
+    /// The tensors share storage with the model, so writing to them (under `tch::no_grad`)
+    /// changes its weights.
+    pub fn named_tensors(&self) -> Vec<(String, Tensor)> {
+        let mut tensors = vec![("wte.weight".to_string(), self.wte.ws.shallow_clone())];
+        for (i, block) in self.blocks.iter().enumerate() {
+            tensors.extend(prefixed(&format!("h.{i}"), block.named_tensors()));
+        }
+        tensors.extend(prefixed("ln_f", self.ln_f.named_tensors()));
+        tensors.extend(linear_tensors("lm_head", &self.lm_head));
+        tensors
+    }
+
+    /// Sets every weight to `value`, for forward passes whose output can be worked out by
+    /// hand in tests.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn set_all_constant(&mut self, value: f64) {
+        tch::no_grad(|| {
+            for (_, mut tensor) in self.named_tensors() {
+                let _ = tensor.fill_(value);
+            }
+        });
+    }
+
+    /// Human-readable table of every module with its output shape for a
+    /// `[batch_size, seq_len]` input and its parameter count, followed by the total
+    /// and the memory needed for the weights in f32.
+    pub fn describe(&self, batch_size: i64, seq_len: i64) -> String {
+        let hidden = format!("[{}, {}, {}]", batch_size, seq_len, self.config.n_embd);
+        let mut rows: Vec<(String, String, i64)> = vec![(
+            "wte (Embedding)".to_string(),
+            hidden.clone(),
+            numel(&self.wte.ws),
+        )];
+        for (i, block) in self.blocks.iter().enumerate() {
+            rows.push((format!("h.{i}.ln_1 (RMSNorm)"), hidden.clone(), block.ln_1.num_parameters()));
+            rows.push((format!("h.{i}.attn (Attention)"), hidden.clone(), block.attn.num_parameters()));
+            rows.push((format!("h.{i}.ln_2 (RMSNorm)"), hidden.clone(), block.ln_2.num_parameters()));
+            rows.push((format!("h.{i}.mlp (MLP)"), hidden.clone(), block.mlp.num_parameters()));
+        }
+        rows.push(("ln_f (RMSNorm)".to_string(), hidden, self.ln_f.num_parameters()));
+        rows.push((
+            "lm_head (Linear)".to_string(),
+            format!("[{}, {}, {}]", batch_size, seq_len, self.config.padded_vocab_size()),
+            linear_parameters(&self.lm_head),
+        ));
+
+        let total = self.num_parameters();
+        let rule = "-".repeat(66);
+        let mut out = format!("{:<28} {:<22} {:>14}\n{}\n", "Module", "Output shape", "Params", rule);
+        for (name, shape, params) in rows {
+            out.push_str(&format!("{:<28} {:<22} {:>14}\n", name, shape, params));
+        }
+        out.push_str(&format!("{}\n", rule));
+        out.push_str(&format!("Total parameters: {}\n", total));
+        out.push_str(&format!(
+            "Estimated weight memory (f32): {:.2} MiB\n",
+            (total * 4) as f64 / (1024.0 * 1024.0)
+        ));
+        out
+    }
+
     /// past_key_values: Optional mutable slice of KVCache objects, one per layer.
     /// Returns: logits tensor
-    pub fn forward(&self, idx: &Tensor, mut caches: Option<&mut [crate::kv_cache::KVCache]>) -> Tensor {
+    /// Fails with [`crate::kv_cache::KVCacheOverflow`] if the new positions don't fit in the
+    /// caches; without caches it can't fail.
+    pub fn forward(&self, idx: &Tensor, caches: Option<&mut [crate::kv_cache::KVCache]>) -> Result<Tensor> {
+        self.forward_truncated(idx, caches, None)
+    }
+
+    /// Uncached forward pass through every layer for training: unlike the other forward
+    /// passes, which run in inference mode, it applies dropout when `train` is set.
+    pub fn forward_t(&self, idx: &Tensor, train: bool) -> Tensor {
+        self.logits(&self.hidden_states(idx, None, self.blocks.len(), train).expect("no caches to overflow"))
+    }
+
+    /// Like [`ClaudeTransformer::forward`], but runs only the first `num_layers_override`
+    /// blocks (all of them if `None`) before `ln_f` and the LM head, e.g. for cheap draft
+    /// outputs from a truncated model. `caches` needs one entry per layer that runs. Fails
+    /// with [`crate::kv_cache::KVCacheOverflow`] if the new positions don't fit in the
+    /// caches; since every layer's cache holds the same positions, none of them is updated then.
+    pub fn forward_truncated(
+        &self,
+        idx: &Tensor,
+        caches: Option<&mut [crate::kv_cache::KVCache]>,
+        num_layers_override: Option<usize>,
+    ) -> Result<Tensor> {
+        let num_layers = self.num_layers(num_layers_override)?;
+        Ok(self.logits(&self.hidden_states(idx, caches, num_layers, false)?))
+    }
+
+    /// Logits `[batch, 1, vocab]` for one new token of each of several sequences: `idx` is
+    /// `[batch, 1]` and row `i` continues the sequence cached in `caches[i]` (one cache per
+    /// layer that runs). The rows may sit at different positions; everything but attention
+    /// runs batched. Fails with [`crate::kv_cache::KVCacheOverflow`], before any cache is
+    /// updated, if a row's caches are full.
+    pub fn forward_decode_batch(
+        &self,
+        idx: &Tensor,
+        caches: &mut [&mut [crate::kv_cache::KVCache]],
+        num_layers_override: Option<usize>,
+    ) -> Result<Tensor> {
+        let num_layers = self.num_layers(num_layers_override)?;
+        let (batch, seq_len) = idx.size2()?;
+        anyhow::ensure!(seq_len == 1, "batched decoding takes one token per sequence, got {}", seq_len);
+        anyhow::ensure!(
+            caches.len() as i64 == batch,
+            "expected one set of caches per row ({}), got {}",
+            batch,
+            caches.len()
+        );
+        for row in caches.iter() {
+            anyhow::ensure!(
+                row.len() >= num_layers,
+                "{} layers run, but a row has only {} caches",
+                num_layers,
+                row.len()
+            );
+            if let Some(cache) = row[..num_layers].iter().find(|cache| cache.length >= cache.max_capacity) {
+                return Err(crate::kv_cache::KVCacheOverflow {
+                    cached: cache.length,
+                    incoming: 1,
+                    capacity: cache.max_capacity,
+                }
+                .into());
+            }
+        }
+
+        let mut x = idx.apply(&self.wte);
+        for (i, block) in self.blocks.iter().take(num_layers).enumerate() {
+            let mut layer_caches: Vec<&mut crate::kv_cache::KVCache> = caches.iter_mut().map(|row| &mut row[i]).collect();
+            x = block.forward_decode_rows(&x, &mut layer_caches)?;
+        }
+        Ok(self.logits(&self.ln_f.forward(&x)))
+    }
+
+    /// Like [`ClaudeTransformer::forward_truncated`], but also returns the hidden states the
+    /// logits were computed from: `(logits, hidden)` with `hidden` as [`ClaudeTransformer::encode`]
+    /// would return it, e.g. for decoding strategies that compare representations.
+    pub fn forward_with_hidden(
+        &self,
+        idx: &Tensor,
+        caches: Option<&mut [crate::kv_cache::KVCache]>,
+        num_layers_override: Option<usize>,
+    ) -> Result<(Tensor, Tensor)> {
+        let num_layers = self.num_layers(num_layers_override)?;
+        let hidden = self.hidden_states(idx, caches, num_layers, false)?;
+        Ok((self.logits(&hidden), hidden))
+    }
+
+    /// Number of blocks a forward pass with `num_layers_override` runs; errors if the
+    /// override exceeds `n_layer`.
+    pub fn num_layers(&self, num_layers_override: Option<usize>) -> Result<usize> {
+        let n_layer = self.blocks.len();
+        match num_layers_override {
+            Some(k) if k > n_layer => anyhow::bail!("cannot run {} layers of a {}-layer model", k, n_layer),
+            Some(k) => Ok(k),
+            None => Ok(n_layer),
+        }
+    }
+
+    fn logits(&self, hidden: &Tensor) -> Tensor {
+        let logits = hidden.apply(&self.lm_head);
+        
+        let logits = match self.config.final_logit_softcap {
+            Some(cap) => softcap(&logits, cap),
+            None => logits,
+        };
+
+        // Padding rows aren't tokens; masking after the softcap keeps them at -inf.
+        let padded = self.config.padded_vocab_size();
+        if padded == self.config.vocab_size {
+            return logits;
+        }
+        let padding = Tensor::arange(padded, (Kind::Int64, logits.device())).ge(self.config.vocab_size);
+        logits.masked_fill(&padding, f64::NEG_INFINITY)
+    }
+
+    /// Final hidden states `[batch, seq_len, n_embd]` (after the last norm, before the LM
+    /// head), e.g. for pooling into sequence embeddings. Attention is causal, so each
+    /// position only sees the tokens up to it and right padding doesn't change them.
+    pub fn encode(&self, idx: &Tensor) -> Tensor {
+        self.hidden_states(idx, None, self.blocks.len(), false)
+            .expect("no caches to overflow")
+    }
+
+    fn hidden_states(
+        &self,
+        idx: &Tensor,
+        mut caches: Option<&mut [crate::kv_cache::KVCache]>,
+        num_layers: usize,
+        train: bool,
+    ) -> Result<Tensor> {
         let tok_emb = idx.apply(&self.wte); 
-        let mut x = tok_emb.dropout(self.drop, true);
+        let mut x = tok_emb.dropout(self.drop, train);
         
-        for (i, block) in self.blocks.iter().enumerate() {
+        for (i, block) in self.blocks.iter().take(num_layers).enumerate() {
             let layer_cache = match caches {
                 Some(ref mut c) => Some(&mut c[i]),
                 None => None,
             };
             
-            x = block.forward(&x, layer_cache);
+            x = block.forward(&x, layer_cache, train)?;
         }
 
-        x = self.ln_f.forward(&x); 
-        let logits = x.apply(&self.lm_head);
-        
-        logits
+        Ok(self.ln_f.forward(&x))
     }
 }
 
 unsafe impl Send for ClaudeTransformer {}
 unsafe impl Sync for ClaudeTransformer {}
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tch::Kind;
+
+    #[test]
+    fn from_config_file_builds_runnable_model_from_yaml() {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("claude_core_config_test_{unique}"));
+        fs::create_dir_all(&dir).expect("create temp test dir");
+
+        let config_path = dir.join("model_config.yaml");
+        let yaml = serde_yaml::to_string(&ModelConfig::tiny(32)).expect("serialize config");
+        fs::write(&config_path, yaml).expect("write config");
+
+        let (model, config) = ClaudeTransformer::from_config_file(&config_path, Device::Cpu)
+            .expect("build model from config");
+        let idx = Tensor::from_slice(&[1i64, 2, 3]).view([1, 3]);
+        let logits = model.forward(&idx, None).expect("forward");
+
+        assert_eq!(logits.size(), vec![1, 3, config.vocab_size]);
+        assert_eq!(logits.kind(), Kind::Float);
+
+        fs::remove_dir_all(&dir).expect("cleanup temp test dir");
+    }
+
+    #[test]
+    fn final_logit_softcap_bounds_logits() {
+        let large = Tensor::from_slice(&[-1e4f32, -3.0, 0.0, 3.0, 1e4]);
+        let capped = softcap(&large, 2.0);
+        assert!(capped.abs().max().double_value(&[]) <= 2.0);
+
+        let config = ModelConfig {
+            final_logit_softcap: Some(0.5),
+            attn_logit_softcap: Some(1.0),
+            ..ModelConfig::tiny(32)
+        };
+        let vs = nn::VarStore::new(Device::Cpu);
+        let model = ClaudeTransformer::new(&vs.root(), &config);
+        let idx = Tensor::from_slice(&[1i64, 2, 3]).view([1, 3]);
+        let logits = model.forward(&idx, None).expect("forward");
+
+        assert!(logits.abs().max().double_value(&[]) <= 0.5);
+    }
+
+    #[test]
+    fn seeded_construction_is_reproducible() {
+        let config = ModelConfig::tiny(32);
+        let vs_a = nn::VarStore::new(Device::Cpu);
+        let _model_a = ClaudeTransformer::new_seeded(&vs_a.root(), &config, 7);
+        let vs_b = nn::VarStore::new(Device::Cpu);
+        let _model_b = ClaudeTransformer::new_seeded(&vs_b.root(), &config, 7);
+
+        let weights_b = vs_b.variables();
+        for (name, weight_a) in vs_a.variables() {
+            let weight_b = weights_b.get(&name).expect("same variable names");
+            assert!(weight_a.equal(weight_b), "{name} differs between seeded models");
+        }
+    }
+
+    #[test]
+    fn describe_total_matches_num_parameters() {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let model = ClaudeTransformer::new(&vs.root(), &ModelConfig::tiny(32));
+
+        let trainable: i64 = vs.trainable_variables().iter().map(numel).sum();
+        assert_eq!(model.num_parameters(), trainable);
+
+        let summary = model.describe(2, 16);
+        assert!(summary.contains(&format!("Total parameters: {}\n", model.num_parameters())));
+        assert!(summary.contains("h.3.mlp (MLP)"));
+        assert!(summary.contains("[2, 16, 32]"), "lm_head output shape");
+    }
+
+    #[test]
+    fn named_tensors_cover_every_variable() {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let model = ClaudeTransformer::new(&vs.root(), &ModelConfig::tiny(32));
+
+        let mut names: Vec<String> = model.named_tensors().into_iter().map(|(name, _)| name).collect();
+        names.sort();
+        let mut expected: Vec<String> = vs.variables().into_keys().collect();
+        expected.sort();
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn constant_weights_give_hand_computable_logits() {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let config = ModelConfig::tiny(16);
+        let mut model = ClaudeTransformer::new(&vs.root(), &config);
+        model.set_all_constant(0.5);
+
+        // Every position carries the same positive constant vector through every layer, so
+        // ln_f maps it to 0.5 everywhere (up to eps) and each logit is n_embd * 0.5 * 0.5.
+        let logits = model.forward(&Tensor::from_slice(&[1i64, 2, 3]).view([1, 3]), None).expect("forward");
+        let expected = Tensor::full([1, 3, 16], config.n_embd as f64 * 0.25, (Kind::Float, Device::Cpu));
+        assert!(logits.allclose(&expected, 1e-4, 1e-3, false));
+    }
+
+    #[test]
+    fn block_with_all_heads_masked_is_identity_plus_mlp() {
+        let config = ModelConfig {
+            use_bias: false,
+            ..ModelConfig::tiny(16)
+        };
+        let vs = nn::VarStore::new(Device::Cpu);
+        let mut model = ClaudeTransformer::new(&vs.root(), &config);
+        model
+            .set_active_heads(1, Some(&vec![false; config.n_head as usize]))
+            .expect("mask layer 1");
+        assert!(model.set_active_heads(config.n_layer as usize, None).is_err());
+
+        let block = &model.blocks[1];
+        let x = Tensor::randn([1, 5, config.n_embd], (Kind::Float, Device::Cpu));
+        let expected = &x + block.mlp.forward(&block.ln_2.forward(&x), false);
+        assert!(block.forward(&x, None, false).expect("forward").allclose(&expected, 1e-6, 1e-6, false));
+    }
+
+    #[test]
+    fn residual_scale_multiplies_attention_and_mlp_outputs() {
+        let config = ModelConfig {
+            residual_scale: Some(0.25),
+            ..ModelConfig::tiny(16)
+        };
+        let vs = nn::VarStore::new(Device::Cpu);
+        let model = ClaudeTransformer::new(&vs.root(), &config);
+
+        let block = &model.blocks[0];
+        let x = Tensor::randn([1, 5, config.n_embd], (Kind::Float, Device::Cpu));
+        let h = &x + block.attn.forward(&block.ln_1.forward(&x), None, false).expect("attention") * 0.25;
+        let expected = &h + block.mlp.forward(&block.ln_2.forward(&h), false) * 0.25;
+        assert!(block.forward(&x, None, false).expect("forward").allclose(&expected, 1e-6, 1e-6, false));
+    }
+
+    #[test]
+    fn truncated_forward_runs_only_the_first_layers() {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let config = ModelConfig::tiny(16);
+        let model = ClaudeTransformer::new_seeded(&vs.root(), &config, 0);
+        let idx = Tensor::from_slice(&[1i64, 2, 3]).view([1, 3]);
+        let n_layer = config.n_layer as usize;
+
+        let full = model.forward(&idx, None).expect("forward");
+        let all_layers = model.forward_truncated(&idx, None, Some(n_layer)).expect("n_layer layers");
+        let draft = model.forward_truncated(&idx, None, Some(1)).expect("one layer");
+
+        assert!(all_layers.allclose(&full, 1e-6, 1e-6, false));
+        assert_eq!(draft.size(), full.size());
+        assert!(!draft.allclose(&full, 1e-6, 1e-6, false));
+        assert!(model.forward_truncated(&idx, None, Some(n_layer + 1)).is_err());
+    }
+
+    #[test]
+    fn batched_decode_matches_decoding_each_sequence_alone() {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let config = ModelConfig::tiny(16);
+        let model = ClaudeTransformer::new_seeded(&vs.root(), &config, 0);
+        let new_caches = |capacity: usize| -> Vec<crate::kv_cache::KVCache> {
+            (0..config.n_layer)
+                .map(|_| crate::kv_cache::KVCache::new(capacity, config.n_head, config.head_size(), Device::Cpu, Kind::Float))
+                .collect()
+        };
+        let prompts: [&[i64]; 2] = [&[1, 2, 3], &[4, 5, 6, 7, 8]];
+        let mut single = [new_caches(16), new_caches(16)];
+        let mut batched = [new_caches(16), new_caches(16)];
+        for (prompt, caches) in prompts.iter().cycle().zip(single.iter_mut().chain(batched.iter_mut())) {
+            let idx = Tensor::from_slice(prompt).view([1, prompt.len() as i64]);
+            model.forward(&idx, Some(&mut caches[..])).expect("prefill");
+        }
+        let next = [9i64, 10];
+
+        let [first, second] = &mut batched;
+        let idx = Tensor::from_slice(&next).view([2, 1]);
+        let logits = model.forward_decode_batch(&idx, &mut [&mut first[..], &mut second[..]], None).expect("batched step");
+
+        assert_eq!(logits.size(), vec![2, 1, 16]);
+        for (i, caches) in single.iter_mut().enumerate() {
+            let idx = Tensor::from_slice(&next[i..i + 1]).view([1, 1]);
+            let alone = model.forward(&idx, Some(&mut caches[..])).expect("single step");
+            assert!(logits.narrow(0, i as i64, 1).allclose(&alone, 1e-5, 1e-6, false));
+        }
+
+        // A full row fails the whole step without touching any cache.
+        let mut full = new_caches(3);
+        let mut roomy = new_caches(16);
+        model.forward(&Tensor::from_slice(&[1i64, 2, 3]).view([1, 3]), Some(&mut full[..])).expect("fill");
+        let err = model
+            .forward_decode_batch(&idx, &mut [&mut roomy[..], &mut full[..]], None)
+            .expect_err("full cache");
+        assert!(err.is::<crate::kv_cache::KVCacheOverflow>());
+        assert!(roomy.iter().all(|cache| cache.length == 0));
+    }
+
+    #[test]
+    fn warmup_runs_and_leaves_model_usable() {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let model = ClaudeTransformer::new(&vs.root(), &ModelConfig::tiny(16));
+
+        model.warmup(Device::Cpu, 8).expect("warm up");
+        // Over-long warm-up lengths are clamped to the context window.
+        model.warmup(Device::Cpu, 10_000).expect("clamped warm-up");
+
+        let input = Tensor::from_slice(&[1i64, 2, 3]).view([1, 3]);
+        assert_eq!(model.forward(&input, None).expect("forward").size(), vec![1, 3, 16]);
+    }
+
+    #[test]
+    fn every_layer_shares_one_causal_mask() {
+        for n_layer in [1, 6] {
+            let config = ModelConfig {
+                n_layer,
+                ..ModelConfig::tiny(16)
+            };
+            let vs = nn::VarStore::new(Device::Cpu);
+            let model = ClaudeTransformer::new(&vs.root(), &config);
+
+            let mask = model.blocks[0].attn.mask_buffer();
+            assert!(model.blocks.iter().all(|block| Arc::ptr_eq(block.attn.mask_buffer(), mask)));
+            // Held only by the layers: no per-layer copies and no extra owner.
+            assert_eq!(Arc::strong_count(mask), n_layer as usize);
+            assert_eq!(mask.size(), vec![1, 1, config.max_seq_len, config.max_seq_len]);
+        }
+    }
+}
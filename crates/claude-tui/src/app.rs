@@ -1,9 +1,12 @@
+use inference::{ChatConfig, ChatMessage, ChatTemplate, Role};
 use tui_input::Input;
 
 #[derive(Clone)]
 pub enum Sender {
     User,
     Bot,
+    /// Notices from the TUI itself, such as prompt-length warnings.
+    System,
 }
 
 #[derive(Clone)]
@@ -19,6 +22,46 @@ pub struct App {
     pub input: Input,
     /// Is the bot currently "thinking"?
     pub is_loading: bool,
+    /// Refuse prompts longer than the context window instead of truncating them.
+    pub refuse_long_prompts: bool,
+    /// Wraps each user message into the prompt the model sees.
+    pub template: Box<dyn ChatTemplate>,
+    /// System prompt, prefixes and stop strings applied around the template.
+    pub chat: ChatConfig,
+}
+
+/// What to do with a prompt, given its token count and the model's context window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PromptFit {
+    Fits,
+    /// Keep only the last `limit` tokens.
+    Truncate { limit: usize },
+    /// Don't generate at all.
+    Refuse { tokens: usize, limit: usize },
+}
+
+impl PromptFit {
+    pub fn check(prompt_tokens: usize, limit: usize, refuse_long_prompts: bool) -> Self {
+        if prompt_tokens <= limit {
+            PromptFit::Fits
+        } else if refuse_long_prompts {
+            PromptFit::Refuse { tokens: prompt_tokens, limit }
+        } else {
+            PromptFit::Truncate { limit }
+        }
+    }
+
+    /// The notice to show in the chat pane, if any.
+    pub fn warning(&self) -> Option<String> {
+        match *self {
+            PromptFit::Fits => None,
+            PromptFit::Truncate { limit } => Some(format!("Prompt truncated to fit {} tokens.", limit)),
+            PromptFit::Refuse { tokens, limit } => Some(format!(
+                "Prompt is {} tokens long but the model fits {}; shorten it and try again.",
+                tokens, limit
+            )),
+        }
+    }
 }
 
 impl App {
@@ -32,7 +75,36 @@ impl App {
             ],
             input: Input::default(),
             is_loading: false,
+            refuse_long_prompts: false,
+            template: Box::new(inference::templates::Raw),
+            chat: ChatConfig::default(),
+        }
+    }
+
+    /// The prompt for a user message: the system prompt and the prefixed message rendered
+    /// with the template, followed by the assistant prefix.
+    pub fn prompt(&self, text: &str) -> String {
+        let mut messages = Vec::new();
+        if !self.chat.system_prompt.is_empty() {
+            messages.push(ChatMessage::new(Role::System, self.chat.system_prompt.clone()));
         }
+        messages.push(ChatMessage::new(Role::User, format!("{}{}", self.chat.user_prefix, text)));
+        let mut prompt = self.template.render(&messages, true);
+        prompt.push_str(&self.chat.assistant_prefix);
+        prompt
+    }
+
+    pub fn push_system(&mut self, content: String) {
+        self.messages.push(Message {
+            sender: Sender::System,
+            content,
+        });
+    }
+
+    /// Ends a generation that failed, with a notice in the chat pane saying why.
+    pub fn generation_failed(&mut self, error: &str) {
+        self.is_loading = false;
+        self.push_system(format!("Generation failed: {}", error));
     }
 
     pub fn append_token(&mut self, token: &str) {
@@ -48,3 +120,50 @@ impl App {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_prompts_are_truncated_or_refused() {
+        assert_eq!(PromptFit::check(512, 512, false), PromptFit::Fits);
+        assert_eq!(PromptFit::check(512, 512, false).warning(), None);
+
+        let truncate = PromptFit::check(600, 512, false);
+        assert_eq!(truncate, PromptFit::Truncate { limit: 512 });
+        assert_eq!(truncate.warning().as_deref(), Some("Prompt truncated to fit 512 tokens."));
+
+        let refuse = PromptFit::check(600, 512, true);
+        assert_eq!(refuse, PromptFit::Refuse { tokens: 600, limit: 512 });
+        assert!(refuse.warning().expect("refusal notice").contains("600"));
+    }
+
+    #[test]
+    fn chat_config_wraps_the_prompt() {
+        let mut app = App::new();
+        assert_eq!(app.prompt("Hi"), "Hi");
+
+        app.chat = ChatConfig {
+            system_prompt: "Be brief.".to_string(),
+            user_prefix: "User: ".to_string(),
+            assistant_prefix: "\nAssistant:".to_string(),
+            stop_strings: vec!["\nUser:".to_string()],
+        };
+        assert_eq!(app.prompt("Hi"), "Be brief.\nUser: Hi\nAssistant:");
+    }
+
+    #[test]
+    fn failed_generation_stops_loading_and_says_why() {
+        let mut app = App::new();
+        app.is_loading = true;
+        app.append_token("Hel");
+
+        app.generation_failed("Sampling probabilities are not finite");
+
+        assert!(!app.is_loading);
+        let notice = app.messages.last().expect("notice");
+        assert!(matches!(notice.sender, Sender::System));
+        assert_eq!(notice.content, "Generation failed: Sampling probabilities are not finite");
+    }
+}
@@ -15,46 +15,41 @@ use std::sync::Arc;
 
 // Local crate imports
 use claude_core::{ClaudeTransformer, ModelConfig};
-use inference::{Generator, SamplingParams};
-use tokenizer::{BPE, Vocab};
+use inference::{Generator, OverflowPolicy, SamplingParams, StreamOptions};
+use tokenizer::{StreamDecoder, BPE};
 use tch::{nn, Device};
 use tui_input::backend::crossterm::EventHandler;
 
 mod app;
 mod ui;
 
-use app::{App, Message, Sender};
+use app::{App, Message, PromptFit, Sender};
 
 #[derive(Debug)]
 enum Action {
     Tick,
     TokenGenerated(String),
     GenerationFinished,
+    GenerationFailed(String),
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     // 0. Initialize Model & Tokenizer
-    let device = Device::cuda_if_available();
+    let device = inference::resolve_device(Device::cuda_if_available());
     println!("Using device: {:?}", device);
     
     let vocab_path = "data/vocab.json";
     let checkpoint_dir = std::path::Path::new("checkpoints");
     
     // Load Tokenizer
-    let tokenizer = if std::path::Path::new(vocab_path).exists() {
+    let vocab_bin_path = std::path::Path::new(vocab_path).with_extension("bin");
+    let tokenizer = if std::path::Path::new(vocab_path).exists() || vocab_bin_path.exists() {
         println!("Loading tokenizer from {}", vocab_path);
-        Arc::new(BPE::load(vocab_path)?)
+        Arc::new(BPE::load_prefer_bin(vocab_path)?)
     } else {
-        println!("Warning: Tokenizer vocab not found at {}. Using minimal fallback.", vocab_path);
-        let mut vocab = Vocab::new();
-        vocab.insert(" ".to_string(), 32);
-        for i in 65..123 {
-            let c = i as u8 as char;
-            vocab.insert(c.to_string(), i as u32);
-        }
-        vocab.insert("<UNK>".to_string(), 0);
-        Arc::new(BPE::new(vocab, std::collections::HashMap::new()))
+        println!("Warning: Tokenizer vocab not found at {}. Using byte-level fallback.", vocab_path);
+        Arc::new(BPE::byte_level_identity())
     };
 
     // Load Model
@@ -62,20 +57,14 @@ async fn main() -> Result<(), Box<dyn Error>> {
         Arc::new(inference::load_model(checkpoint_dir, device)?)
     } else {
         println!("Warning: No trained model found in {:?}. Initializing random model.", checkpoint_dir);
-        let config = ModelConfig {
-            n_embd: 128,
-            n_head: 4,
-            n_layer: 4,
-            vocab_size: tokenizer.vocab.len() as i64,
-            max_seq_len: 512,
-            dropout: 0.1,
-            use_bias: true,
-            layer_norm_epsilon: 1e-5,
-        };
+        let config = ModelConfig::tiny(tokenizer.vocab.len() as i64);
         let vs = nn::VarStore::new(device);
-        Arc::new(ClaudeTransformer::new(&vs.root(), &config))
+        Arc::new(ClaudeTransformer::new_seeded(&vs.root(), &config, 0))
     };
 
+    let warmup = model.warmup(device, 16)?;
+    println!("Model warmed up in {:.1} ms", warmup.as_secs_f64() * 1000.0);
+
     // 1. Setup terminal (raw mode, alternate screen)
     enable_raw_mode()?;
     let mut stdout = io::stdout();
@@ -86,6 +75,12 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // 2. Setup channels and app
     let (tx, mut rx) = mpsc::channel(32);
     let mut app = App::new();
+    app.refuse_long_prompts = std::env::var("TUI_REFUSE_LONG_PROMPTS")
+        .is_ok_and(|value| value == "1" || value.eq_ignore_ascii_case("true"));
+    if let Ok(name) = std::env::var("TUI_TEMPLATE") {
+        app.template = inference::template_by_name(&name)?;
+    }
+    app.chat = inference::ChatConfig::from_env()?;
 
     // 3. Event Loop
     let mut reader = EventStream::new();
@@ -147,6 +142,9 @@ async fn run_app(
                     Action::GenerationFinished => {
                         app.is_loading = false;
                     }
+                    Action::GenerationFailed(error) => {
+                        app.generation_failed(&error);
+                    }
                 }
             }
             // User Input
@@ -166,38 +164,69 @@ async fn run_app(
                                         content: text.clone(),
                                     });
                                     app.input.reset();
+
+                                    // 1. Render and tokenize prompt, and check it against the context window
+                                    let prompt = app.prompt(&text);
+                                    let input_ids: Vec<i64> = tokenizer.encode(&prompt).iter().map(|&id| id as i64).collect();
+                                    let fit = PromptFit::check(
+                                        input_ids.len(),
+                                        model.config.max_seq_len as usize,
+                                        app.refuse_long_prompts,
+                                    );
+                                    if let Some(warning) = fit.warning() {
+                                        app.push_system(warning);
+                                    }
+                                    if let PromptFit::Refuse { .. } = fit {
+                                        continue;
+                                    }
                                     app.is_loading = true;
                                     
                                     let tx_action = tx.clone();
                                     let model = Arc::clone(&model);
                                     let tokenizer = Arc::clone(&tokenizer);
-                                    let prompt = text.clone();
+                                    let mut decoder = StreamDecoder::new();
+                                    let mut stop = app.chat.stop_sequences();
                                     
                                     tokio::spawn(async move {
                                         let mut generator = Generator::new(Arc::clone(&model), device);
                                         let params = SamplingParams::default();
                                         
-                                        // 1. Tokenize prompt
-                                        let input_ids: Vec<i64> = tokenizer.encode(&prompt).iter().map(|&id| id as i64).collect();
-                                        
                                         // 2. Setup internal stream channel
                                         let (token_tx, mut token_rx) = mpsc::channel(100);
                                         
-                                        // 3. Start generation in a blocking-safe way if necessary or just await
-                                        // Since we are already in an async spawn, we can run generate_stream
+                                        // 3. Run generation on a blocking worker; its result says whether it failed
                                         let tokenizer_clone = Arc::clone(&tokenizer);
                                         let tx_action_clone = tx_action.clone();
                                         
-                                        tokio::spawn(async move {
-                                            let _ = generator.generate_stream(&input_ids, 50, &params, token_tx);
+                                        let generation = tokio::task::spawn_blocking(move || {
+                                            let options = StreamOptions {
+                                                overflow: OverflowPolicy::TruncateLeft,
+                                                ..StreamOptions::default()
+                                            };
+                                            generator.generate_stream(&input_ids, 50, &params, options, token_tx)
                                         });
 
                                         while let Some(token_id) = token_rx.recv().await {
-                                            let text = tokenizer_clone.decode(&[token_id as u32]);
+                                            if let Some(text) = stop.push(&decoder.push(&tokenizer_clone, token_id as u32)) {
+                                                let _ = tx_action_clone.send(Action::TokenGenerated(text)).await;
+                                            }
+                                            if stop.stopped() {
+                                                // Closing the channel ends generation.
+                                                token_rx.close();
+                                                break;
+                                            }
+                                        }
+                                        let tail = decoder.finish();
+                                        for text in stop.push(&tail).into_iter().chain(stop.finish()) {
                                             let _ = tx_action_clone.send(Action::TokenGenerated(text)).await;
                                         }
                                         
-                                        let _ = tx_action.send(Action::GenerationFinished).await;
+                                        let action = match generation.await {
+                                            Ok(Ok(_)) => Action::GenerationFinished,
+                                            Ok(Err(e)) => Action::GenerationFailed(format!("{:#}", e)),
+                                            Err(e) => Action::GenerationFailed(e.to_string()),
+                                        };
+                                        let _ = tx_action.send(action).await;
                                     });
                                 }
                             }
@@ -31,6 +31,7 @@ pub fn draw(f: &mut Frame, app: &mut App) {
             let (prefix, color) = match m.sender {
                 Sender::User => ("You: ", Color::Yellow),
                 Sender::Bot => ("Claude: ", Color::Cyan),
+                Sender::System => ("System: ", Color::Red),
             };
 
             let content = vec![Line::from(vec![
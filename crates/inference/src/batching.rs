@@ -0,0 +1,216 @@
+use crate::generator::{FinishReason, Generator, StreamRequest};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+/// How long the [`Batcher`] waits for more requests, and how many it runs together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchConfig {
+    pub max_batch_size: usize,
+    /// Time to wait after the first request of a batch for others to join it.
+    pub batch_wait: Duration,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_batch_size: 8,
+            batch_wait: Duration::from_millis(5),
+        }
+    }
+}
+
+/// How a request submitted to the [`Batcher`] finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchOutcome {
+    pub finish_reason: FinishReason,
+    /// Number of requests in the batch this one ran in.
+    pub batch_size: usize,
+}
+
+struct QueuedRequest {
+    request: StreamRequest,
+    done: oneshot::Sender<anyhow::Result<BatchOutcome>>,
+}
+
+/// Coalesces generation requests that arrive within [`BatchConfig::batch_wait`] of each
+/// other into one [`Generator::generate_batch_stream`] call, whose decode steps run the
+/// whole batch through one forward pass. Every batch gets its own blocking worker, so a
+/// running batch never holds up the next one.
+#[derive(Clone)]
+pub struct Batcher {
+    queue: mpsc::UnboundedSender<QueuedRequest>,
+}
+
+impl Batcher {
+    /// Starts the batching task on the current Tokio runtime. `make_generator` is called
+    /// once per batch, so a batch always runs on the model that is current when it starts.
+    pub fn spawn<F>(config: BatchConfig, make_generator: F) -> Self
+    where
+        F: Fn() -> Generator + Send + 'static,
+    {
+        let (queue, rx) = mpsc::unbounded_channel();
+        tokio::spawn(collect_batches(rx, config, make_generator));
+        Self { queue }
+    }
+
+    /// Queues `request`; its tokens are streamed into `request.tx` once its batch runs.
+    /// The returned channel resolves when the request has finished.
+    pub fn submit(&self, request: StreamRequest) -> oneshot::Receiver<anyhow::Result<BatchOutcome>> {
+        let (done, rx) = oneshot::channel();
+        // If the batching task is gone, `done` is dropped and the receiver reports it.
+        let _ = self.queue.send(QueuedRequest { request, done });
+        rx
+    }
+}
+
+async fn collect_batches<F>(mut queue: mpsc::UnboundedReceiver<QueuedRequest>, config: BatchConfig, make_generator: F)
+where
+    F: Fn() -> Generator + Send + 'static,
+{
+    let max_batch_size = config.max_batch_size.max(1);
+    while let Some(first) = queue.recv().await {
+        let mut batch = vec![first];
+        let window = tokio::time::sleep(config.batch_wait);
+        tokio::pin!(window);
+        while batch.len() < max_batch_size {
+            tokio::select! {
+                _ = &mut window => break,
+                next = queue.recv() => match next {
+                    Some(queued) => batch.push(queued),
+                    None => break,
+                },
+            }
+        }
+        tokio::spawn(run_batch(batch, make_generator()));
+    }
+}
+
+/// Decodes `batch` on a blocking worker and reports every request's outcome.
+async fn run_batch(batch: Vec<QueuedRequest>, mut generator: Generator) {
+    let batch_size = batch.len();
+    let (requests, senders): (Vec<_>, Vec<_>) = batch
+        .into_iter()
+        .map(|queued| (queued.request, queued.done))
+        .unzip();
+    let results = tokio::task::spawn_blocking(move || generator.generate_batch_stream(&requests)).await;
+
+    match results {
+        Ok(results) => {
+            for (done, result) in senders.into_iter().zip(results) {
+                let _ = done.send(result.map(|finish_reason| BatchOutcome {
+                    finish_reason,
+                    batch_size,
+                }));
+            }
+        }
+        Err(e) => {
+            tracing::error!(error = %e, batch_size, "batch worker failed");
+            for done in senders {
+                let _ = done.send(Err(anyhow::anyhow!("batch worker failed: {}", e)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::{OverflowPolicy, StreamOptions};
+    use crate::sampling::SamplingParams;
+    use claude_core::{ClaudeTransformer, ModelConfig};
+    use std::sync::Arc;
+    use tch::Device;
+
+    fn tiny_model() -> Arc<ClaudeTransformer> {
+        let vs = tch::nn::VarStore::new(Device::Cpu);
+        Arc::new(ClaudeTransformer::new_seeded(&vs.root(), &ModelConfig::tiny(16), 0))
+    }
+
+    fn greedy() -> SamplingParams {
+        SamplingParams {
+            temperature: 0.0,
+            ..SamplingParams::default()
+        }
+    }
+
+    fn request(prompt_ids: Vec<i64>) -> (StreamRequest, mpsc::Receiver<i64>) {
+        let (tx, rx) = mpsc::channel(16);
+        let request = StreamRequest {
+            prompt_ids,
+            max_new_tokens: 4,
+            params: greedy(),
+            overflow: OverflowPolicy::Error,
+            deadline: None,
+            tx,
+        };
+        (request, rx)
+    }
+
+    fn drain(rx: &mut mpsc::Receiver<i64>) -> Vec<i64> {
+        let mut tokens = Vec::new();
+        while let Ok(token) = rx.try_recv() {
+            tokens.push(token);
+        }
+        tokens
+    }
+
+    #[tokio::test]
+    async fn requests_arriving_together_share_batched_steps() {
+        let model = tiny_model();
+        let batch_model = Arc::clone(&model);
+        let batcher = Batcher::spawn(
+            BatchConfig {
+                max_batch_size: 4,
+                batch_wait: Duration::from_millis(200),
+            },
+            move || Generator::new(Arc::clone(&batch_model), Device::Cpu),
+        );
+        let prompts = [vec![1, 2, 3], vec![4, 5]];
+        let (first, mut first_rx) = request(prompts[0].clone());
+        let (second, mut second_rx) = request(prompts[1].clone());
+
+        let first = batcher.submit(first);
+        let second = batcher.submit(second);
+        let first = first.await.expect("batcher running").expect("first request");
+        let second = second.await.expect("batcher running").expect("second request");
+
+        assert_eq!(first.batch_size, 2);
+        assert_eq!(second.batch_size, 2);
+        assert_eq!(first.finish_reason, FinishReason::Length);
+        assert_eq!(second.finish_reason, FinishReason::Length);
+        // Each stream gets exactly the tokens its request would have produced on its own.
+        for (prompt_ids, rx) in prompts.into_iter().zip([&mut first_rx, &mut second_rx]) {
+            let (tx, mut alone) = mpsc::channel(16);
+            let mut generator = Generator::new(Arc::clone(&model), Device::Cpu);
+            tokio::task::spawn_blocking(move || {
+                generator.generate_stream(&prompt_ids, 4, &greedy(), StreamOptions::default(), tx)
+            })
+            .await
+            .expect("generation worker")
+            .expect("generate stream");
+            let batched = drain(rx);
+            assert_eq!(batched.len(), 4);
+            assert_eq!(batched, drain(&mut alone));
+        }
+    }
+
+    #[tokio::test]
+    async fn max_batch_size_splits_batches() {
+        let model = tiny_model();
+        let batcher = Batcher::spawn(
+            BatchConfig {
+                max_batch_size: 1,
+                batch_wait: Duration::from_millis(200),
+            },
+            move || Generator::new(Arc::clone(&model), Device::Cpu),
+        );
+        let (first, _first_rx) = request(vec![1, 2, 3]);
+        let (second, _second_rx) = request(vec![4, 5]);
+
+        let first = batcher.submit(first);
+        let second = batcher.submit(second);
+
+        assert_eq!(first.await.expect("batcher running").expect("first request").batch_size, 1);
+        assert_eq!(second.await.expect("batcher running").expect("second request").batch_size, 1);
+    }
+}
@@ -0,0 +1,49 @@
+use clap::Parser;
+use inference::cli::{last_position_logits, top_logits};
+use inference::{load_model, resolve_device};
+use std::path::PathBuf;
+use tch::Device;
+
+/// Runs one forward pass over exact token IDs and prints the last position's logits,
+/// for checking numerics against a reference implementation.
+#[derive(Parser)]
+struct Cli {
+    /// Comma-separated token IDs, e.g. 1,2,3
+    #[arg(long, value_delimiter = ',', required = true)]
+    tokens: Vec<i64>,
+    /// Directory with config.json and the checkpoint to load
+    #[arg(long, default_value = "checkpoints")]
+    checkpoint_dir: PathBuf,
+    /// Number of highest logits to print
+    #[arg(long, default_value_t = 10)]
+    top: usize,
+    /// Also write the full logit vector to this file as a JSON array
+    #[arg(long)]
+    out: Option<PathBuf>,
+    /// Run on the CPU even if CUDA is available
+    #[arg(long)]
+    cpu: bool,
+}
+
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let cli = Cli::parse();
+    let device = if cli.cpu {
+        Device::Cpu
+    } else {
+        resolve_device(Device::cuda_if_available())
+    };
+
+    let model = load_model(&cli.checkpoint_dir, device)?;
+    let logits = last_position_logits(&model, &cli.tokens, device)?;
+
+    println!("{:>8}  {:>14}", "token", "logit");
+    for (token, logit) in top_logits(&logits, cli.top) {
+        println!("{:>8}  {:>14.6}", token, logit);
+    }
+    if let Some(path) = &cli.out {
+        std::fs::write(path, serde_json::to_string(&logits)?)?;
+        println!("Wrote {} logits to {}", logits.len(), path.display());
+    }
+    Ok(())
+}
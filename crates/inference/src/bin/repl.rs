@@ -0,0 +1,49 @@
+use claude_core::{ClaudeTransformer, ModelConfig};
+use inference::{cli::run_repl, load_model, resolve_device, ChatConfig, Generator, SamplingParams};
+use std::sync::Arc;
+use tch::Device;
+use tokenizer::BPE;
+
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let device = resolve_device(Device::cuda_if_available());
+    println!("Using device: {:?}", device);
+
+    let checkpoint_dir = std::path::Path::new("checkpoints");
+    let vocab_path = "data/vocab.json";
+
+    // 1. Load Tokenizer
+    let vocab_bin_path = std::path::Path::new(vocab_path).with_extension("bin");
+    let tokenizer = if std::path::Path::new(vocab_path).exists() || vocab_bin_path.exists() {
+        println!("Loading tokenizer from {}", vocab_path);
+        BPE::load_prefer_bin(vocab_path)?
+    } else {
+        println!("Warning: Tokenizer not found. Using byte-level fallback.");
+        BPE::byte_level_identity()
+    };
+
+    // 2. Load Model
+    let model = if checkpoint_dir.exists() && checkpoint_dir.join("config.json").exists() {
+        Arc::new(load_model(checkpoint_dir, device)?)
+    } else {
+        println!("No model found. Initializing random one.");
+        let config = ModelConfig::tiny(tokenizer.vocab.len() as i64);
+        let vs = tch::nn::VarStore::new(device);
+        Arc::new(ClaudeTransformer::new_seeded(&vs.root(), &config, 0))
+    };
+
+    let config = ChatConfig::from_env()?;
+
+    println!("Type a message and press Enter. /reset clears history, Ctrl-D exits.");
+    let mut generator = Generator::new(model, device);
+    let stdin = std::io::stdin();
+    run_repl(
+        &mut generator,
+        &tokenizer,
+        &SamplingParams::default(),
+        50,
+        &config,
+        stdin.lock(),
+        std::io::stdout(),
+    )
+}
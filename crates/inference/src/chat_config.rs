@@ -0,0 +1,84 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::streaming::StopSequences;
+
+/// Environment variable naming the chat config file of the REPL and TUI.
+pub const CHAT_CONFIG_ENV: &str = "CHAT_CONFIG";
+/// Read when [`CHAT_CONFIG_ENV`] is unset, if it exists.
+pub const DEFAULT_CHAT_CONFIG_PATH: &str = "configs/chat.json";
+
+/// How the REPL and TUI wrap what the user types into a prompt, and when they cut the reply
+/// off. Read from JSON, e.g. `{"user_prefix": "User: ", "assistant_prefix": "Assistant:",
+/// "stop_strings": ["\nUser:"]}`; missing fields keep their defaults, which leave prompts
+/// unchanged and never stop early.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChatConfig {
+    /// Starts every prompt (as the system message in the TUI).
+    pub system_prompt: String,
+    /// Put before each user message.
+    pub user_prefix: String,
+    /// Opens the reply: in the REPL on the line after the user message, in the TUI after
+    /// the rendered chat template.
+    pub assistant_prefix: String,
+    /// Generation stops as soon as the reply contains one of these; the stop string itself
+    /// is not shown or kept in the history.
+    pub stop_strings: Vec<String>,
+}
+
+impl ChatConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path).with_context(|| format!("Failed to read chat config {:?}", path))?;
+        serde_json::from_str(&json).with_context(|| format!("Failed to parse chat config {:?}", path))
+    }
+
+    /// Loads the file named by `$CHAT_CONFIG`, which must exist, or else
+    /// [`DEFAULT_CHAT_CONFIG_PATH`] if it exists, or else returns the defaults.
+    pub fn from_env() -> Result<Self> {
+        match std::env::var(CHAT_CONFIG_ENV) {
+            Ok(path) => Self::load(path),
+            Err(_) if Path::new(DEFAULT_CHAT_CONFIG_PATH).exists() => Self::load(DEFAULT_CHAT_CONFIG_PATH),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// The text a conversation starts with: the system prompt on its own line, if any.
+    pub fn preamble(&self) -> String {
+        if self.system_prompt.is_empty() {
+            String::new()
+        } else {
+            format!("{}\n", self.system_prompt)
+        }
+    }
+
+    /// A matcher for [`ChatConfig::stop_strings`], fresh for one reply.
+    pub fn stop_sequences(&self) -> StopSequences {
+        StopSequences::new(self.stop_strings.iter().cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_fields_keep_their_defaults() {
+        let config: ChatConfig =
+            serde_json::from_str(r#"{"assistant_prefix": "Bot:", "stop_strings": ["\nYou:"]}"#).expect("parse config");
+
+        assert_eq!(
+            config,
+            ChatConfig {
+                assistant_prefix: "Bot:".to_string(),
+                stop_strings: vec!["\nYou:".to_string()],
+                ..ChatConfig::default()
+            }
+        );
+        assert_eq!(config.preamble(), "");
+        assert_eq!(ChatConfig { system_prompt: "Be brief.".to_string(), ..config }.preamble(), "Be brief.\n");
+        assert!(ChatConfig::load("no/such/chat.json").is_err());
+    }
+}
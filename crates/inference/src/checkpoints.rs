@@ -0,0 +1,232 @@
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tch::{Kind, Tensor};
+
+/// On-disk format of a checkpoint file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckpointFormat {
+    Safetensors,
+    /// Native `VarStore::save` output (`.ot` / `.pt`).
+    Torch,
+}
+
+impl CheckpointFormat {
+    fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "safetensors" => Some(CheckpointFormat::Safetensors),
+            "ot" | "pt" => Some(CheckpointFormat::Torch),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckpointInfo {
+    pub path: PathBuf,
+    pub epoch: Option<usize>,
+    pub step: Option<usize>,
+    pub size_bytes: u64,
+    pub format: CheckpointFormat,
+}
+
+/// Lists checkpoint files in `dir`, sorted numerically by epoch then step.
+///
+/// Epoch and step are parsed from `epoch_<n>` / `step_<n>` parts of the file name
+/// (e.g. `checkpoint_epoch_9.safetensors`, `model_epoch_1_step_500.ot`); files without
+/// them sort first. An unreadable directory yields an empty list.
+pub fn list_checkpoints(dir: &Path) -> Vec<CheckpointInfo> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    let mut checkpoints: Vec<CheckpointInfo> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|entry| {
+            let path = entry.path();
+            let format = CheckpointFormat::from_path(&path)?;
+            let metadata = entry.metadata().ok()?;
+            if !metadata.is_file() {
+                return None;
+            }
+            let stem = path.file_stem()?.to_str()?;
+            Some(CheckpointInfo {
+                epoch: parse_counter(stem, "epoch"),
+                step: parse_counter(stem, "step"),
+                size_bytes: metadata.len(),
+                format,
+                path,
+            })
+        })
+        .collect();
+
+    checkpoints.sort_by(|a, b| (a.epoch, a.step, &a.path).cmp(&(b.epoch, b.step, &b.path)));
+    checkpoints
+}
+
+/// Averages the tensors of several `.safetensors` checkpoints element-wise (a "model soup")
+/// and writes the result to `out`. Every checkpoint must hold the same tensor names with the
+/// same shapes. Sums are accumulated in double precision and each averaged tensor is stored
+/// in the dtype it has in the first checkpoint.
+pub fn average_checkpoints(paths: &[PathBuf], out: &Path) -> anyhow::Result<()> {
+    let (first, rest) = paths
+        .split_first()
+        .context("average_checkpoints needs at least one checkpoint")?;
+
+    let mut sums: BTreeMap<String, (Tensor, Kind)> = read_checkpoint(first)?
+        .into_iter()
+        .map(|(name, tensor)| {
+            let kind = tensor.kind();
+            (name, (tensor.to_kind(Kind::Double), kind))
+        })
+        .collect();
+
+    for path in rest {
+        let tensors = read_checkpoint(path)?;
+        anyhow::ensure!(
+            tensors.len() == sums.len(),
+            "{:?} has {} tensors but {:?} has {}",
+            path,
+            tensors.len(),
+            first,
+            sums.len()
+        );
+        for (name, tensor) in tensors {
+            let (sum, _) = sums
+                .get_mut(&name)
+                .with_context(|| format!("Tensor {} in {:?} is missing from {:?}", name, path, first))?;
+            anyhow::ensure!(
+                tensor.size() == sum.size(),
+                "Tensor {} has shape {:?} in {:?} but {:?} in {:?}",
+                name,
+                sum.size(),
+                first,
+                tensor.size(),
+                path
+            );
+            *sum += tensor.to_kind(Kind::Double);
+        }
+    }
+
+    let count = paths.len() as f64;
+    let averaged: Vec<(String, Tensor)> = sums
+        .into_iter()
+        .map(|(name, (sum, kind))| (name, (sum / count).to_kind(kind)))
+        .collect();
+    Tensor::write_safetensors(&averaged, out)
+        .with_context(|| format!("Failed to write averaged checkpoint {:?}", out))
+}
+
+fn read_checkpoint(path: &Path) -> anyhow::Result<Vec<(String, Tensor)>> {
+    Tensor::read_safetensors(path).with_context(|| format!("Failed to read checkpoint {:?}", path))
+}
+
+/// Finds `<name>_<n>`, `<name>-<n>` or `<name><n>` in a file stem.
+fn parse_counter(stem: &str, name: &str) -> Option<usize> {
+    let parts: Vec<&str> = stem.split(['_', '-']).collect();
+    parts.iter().enumerate().find_map(|(i, part)| {
+        let rest = part.strip_prefix(name)?;
+        if rest.is_empty() {
+            parts.get(i + 1)?.parse().ok()
+        } else {
+            rest.parse().ok()
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn list_checkpoints_parses_and_sorts_numerically() {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("inference_checkpoints_test_{unique}"));
+        fs::create_dir_all(&dir).expect("create temp test dir");
+
+        fs::write(dir.join("checkpoint_epoch_10.safetensors"), [0u8; 10]).expect("write");
+        fs::write(dir.join("checkpoint_epoch_9.safetensors"), [0u8; 9]).expect("write");
+        fs::write(dir.join("checkpoint_epoch_2.safetensors"), [0u8; 2]).expect("write");
+        fs::write(dir.join("model_epoch_1_step_500.ot"), [0u8; 7]).expect("write");
+        fs::write(dir.join("config.json"), "{}").expect("write");
+
+        let checkpoints = list_checkpoints(&dir);
+        let names: Vec<&str> = checkpoints
+            .iter()
+            .map(|c| c.path.file_name().and_then(|n| n.to_str()).expect("file name"))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "model_epoch_1_step_500.ot",
+                "checkpoint_epoch_2.safetensors",
+                "checkpoint_epoch_9.safetensors",
+                "checkpoint_epoch_10.safetensors",
+            ]
+        );
+
+        assert_eq!(checkpoints[0].epoch, Some(1));
+        assert_eq!(checkpoints[0].step, Some(500));
+        assert_eq!(checkpoints[0].format, CheckpointFormat::Torch);
+        assert_eq!(checkpoints[0].size_bytes, 7);
+        assert_eq!(checkpoints[3].epoch, Some(10));
+        assert_eq!(checkpoints[3].step, None);
+        assert_eq!(checkpoints[3].format, CheckpointFormat::Safetensors);
+        assert_eq!(checkpoints[3].size_bytes, 10);
+
+        fs::remove_dir_all(&dir).expect("cleanup temp test dir");
+    }
+
+    #[test]
+    fn average_checkpoints_writes_the_element_wise_mean() {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("inference_soup_test_{unique}"));
+        fs::create_dir_all(&dir).expect("create temp test dir");
+
+        let first = dir.join("checkpoint_epoch_1.safetensors");
+        let second = dir.join("checkpoint_epoch_2.safetensors");
+        Tensor::write_safetensors(
+            &[
+                ("w", Tensor::from_slice(&[1.0f32, 2.0, 3.0, 4.0]).view([2, 2])),
+                ("b", Tensor::from_slice(&[0.0f32, 10.0])),
+            ],
+            &first,
+        )
+        .expect("write first checkpoint");
+        Tensor::write_safetensors(
+            &[
+                ("w", Tensor::from_slice(&[3.0f32, 4.0, 5.0, 6.0]).view([2, 2])),
+                ("b", Tensor::from_slice(&[2.0f32, -10.0])),
+            ],
+            &second,
+        )
+        .expect("write second checkpoint");
+
+        let out = dir.join("soup.safetensors");
+        average_checkpoints(&[first.clone(), second], &out).expect("average checkpoints");
+
+        let averaged: BTreeMap<String, Tensor> = Tensor::read_safetensors(&out)
+            .expect("read averaged checkpoint")
+            .into_iter()
+            .collect();
+        assert_eq!(averaged["w"].size(), vec![2, 2]);
+        assert_eq!(averaged["w"].kind(), Kind::Float);
+        assert_eq!(Vec::<f32>::try_from(averaged["w"].view([-1])).expect("w"), vec![2.0, 3.0, 4.0, 5.0]);
+        assert_eq!(Vec::<f32>::try_from(&averaged["b"]).expect("b"), vec![1.0, 0.0]);
+
+        let mismatched = dir.join("mismatched.safetensors");
+        Tensor::write_safetensors(&[("w", Tensor::zeros([4], (Kind::Float, tch::Device::Cpu)))], &mismatched)
+            .expect("write mismatched checkpoint");
+        assert!(average_checkpoints(&[first, mismatched], &dir.join("bad.safetensors")).is_err());
+
+        fs::remove_dir_all(&dir).expect("cleanup temp test dir");
+    }
+}
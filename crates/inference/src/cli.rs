@@ -0,0 +1,248 @@
+use std::io::{BufRead, Write};
+
+use anyhow::Result;
+use claude_core::tensor_util::tensor_to_vec_f32;
+use claude_core::ClaudeTransformer;
+use tch::{Device, IndexOp, Tensor};
+use tokenizer::{StreamDecoder, BPE};
+
+use crate::chat_config::ChatConfig;
+use crate::generator::{Generator, OverflowPolicy, StreamOptions};
+use crate::sampling::SamplingParams;
+use crate::streaming::StopSequences;
+
+/// Runs a line-based chat loop: read a line from `input`, stream the completion to `output`,
+/// repeat until EOF (Ctrl-D). `/reset` clears the conversation history. Lines and replies are
+/// wrapped in `config`'s prefixes, and a reply ends early at any of its stop strings.
+pub fn run_repl<R: BufRead, W: Write>(
+    generator: &mut Generator,
+    tokenizer: &BPE,
+    params: &SamplingParams,
+    max_new_tokens: usize,
+    config: &ChatConfig,
+    input: R,
+    mut output: W,
+) -> Result<()> {
+    let mut history = config.preamble();
+    let mut lines = input.lines();
+
+    loop {
+        write!(output, "> ")?;
+        output.flush()?;
+
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => {
+                writeln!(output)?;
+                break;
+            }
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line == "/reset" {
+            history = config.preamble();
+            writeln!(output, "(history cleared)")?;
+            continue;
+        }
+
+        history.push_str(&config.user_prefix);
+        history.push_str(line);
+        history.push('\n');
+        history.push_str(&config.assistant_prefix);
+
+        let prompt_ids: Vec<i64> = tokenizer.encode(&history).iter().map(|&id| id as i64).collect();
+        if prompt_ids.is_empty() {
+            continue;
+        }
+
+        let stop = config.stop_sequences();
+        let completion =
+            stream_completion(generator, tokenizer, &prompt_ids, max_new_tokens, params, stop, &mut output)?;
+        writeln!(output)?;
+
+        history.push_str(&completion);
+        history.push('\n');
+    }
+
+    Ok(())
+}
+
+/// Runs `generate_stream` on a worker thread and writes each decoded token to `output`
+/// as it arrives, ending generation once `stop` sees a stop string. Returns the completion
+/// text up to the stop string.
+fn stream_completion<W: Write>(
+    generator: &mut Generator,
+    tokenizer: &BPE,
+    prompt_ids: &[i64],
+    max_new_tokens: usize,
+    params: &SamplingParams,
+    mut stop: StopSequences,
+    output: &mut W,
+) -> Result<String> {
+    let (tx, mut rx) = tokio::sync::mpsc::channel(max_new_tokens.max(1));
+    let mut decoder = StreamDecoder::new();
+    let mut completion = String::new();
+
+    std::thread::scope(|scope| -> Result<()> {
+        let worker = scope.spawn(move || {
+            // The REPL history only grows, so keep the most recent turns.
+            let options = StreamOptions {
+                overflow: OverflowPolicy::TruncateLeft,
+                ..StreamOptions::default()
+            };
+            generator
+                .generate_stream(prompt_ids, max_new_tokens, params, options, tx)
+                .map(|_| ())
+        });
+
+        while let Some(token_id) = rx.blocking_recv() {
+            if let Some(text) = stop.push(&decoder.push(tokenizer, token_id as u32)) {
+                write!(output, "{}", text)?;
+                output.flush()?;
+                completion.push_str(&text);
+            }
+            if stop.stopped() {
+                // The worker's next send fails, which ends generation.
+                rx.close();
+                break;
+            }
+        }
+        // A character the last token left unfinished, then any text held back as a
+        // possible stop string.
+        let tail = decoder.finish();
+        for text in stop.push(&tail).into_iter().chain(stop.finish()) {
+            write!(output, "{}", text)?;
+            completion.push_str(&text);
+        }
+
+        worker
+            .join()
+            .map_err(|_| anyhow::anyhow!("generation thread panicked"))?
+    })?;
+
+    Ok(completion)
+}
+
+/// Runs one forward pass over exactly `token_ids` (no sampling, no KV cache) and returns
+/// the raw logits of the last position, for comparing numerics against a reference.
+pub fn last_position_logits(model: &ClaudeTransformer, token_ids: &[i64], device: Device) -> Result<Vec<f32>> {
+    anyhow::ensure!(!token_ids.is_empty(), "at least one token is required");
+    anyhow::ensure!(
+        token_ids.len() as i64 <= model.config.max_seq_len,
+        "{} tokens exceed the context limit of {}",
+        token_ids.len(),
+        model.config.max_seq_len
+    );
+    let _guard = tch::no_grad_guard();
+    let input = Tensor::from_slice(token_ids).view([1, token_ids.len() as i64]).to(device);
+    tensor_to_vec_f32(&model.forward(&input, None)?.i((0, -1, ..)))
+}
+
+/// The `n` largest logits as `(token_id, logit)`, highest first.
+pub fn top_logits(logits: &[f32], n: usize) -> Vec<(usize, f32)> {
+    let mut ranked: Vec<(usize, f32)> = logits.iter().copied().enumerate().collect();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    ranked.truncate(n);
+    ranked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::stop_in_reply;
+    use claude_core::{ClaudeTransformer, ModelConfig};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tch::Device;
+    use tokenizer::Vocab;
+
+    #[test]
+    fn repl_answers_one_line_then_exits_on_eof() {
+        let mut vocab = Vocab::new();
+        for (id, c) in "abcdefghij \n".chars().enumerate() {
+            vocab.insert(c.to_string(), id as u32);
+        }
+        let tokenizer = BPE::new(vocab, HashMap::new());
+
+        tch::manual_seed(0);
+        let vs = tch::nn::VarStore::new(Device::Cpu);
+        let model = ClaudeTransformer::new(&vs.root(), &ModelConfig::tiny(tokenizer.vocab.len() as i64));
+        let mut generator = Generator::new(Arc::new(model), Device::Cpu);
+
+        let input = b"abc\n".as_slice();
+        let mut output = Vec::new();
+        run_repl(
+            &mut generator,
+            &tokenizer,
+            &SamplingParams::default(),
+            4,
+            &ChatConfig::default(),
+            input,
+            &mut output,
+        )
+        .expect("repl run");
+
+        let output = String::from_utf8(output).expect("utf8 output");
+        let turns: Vec<&str> = output.split("> ").collect();
+        // One prompt answered, then a final prompt that hit EOF.
+        assert_eq!(turns.len(), 3);
+        assert!(!turns[1].trim().is_empty());
+        assert_eq!(turns[2], "\n");
+    }
+
+    #[test]
+    fn config_stop_string_ends_the_reply() {
+        let mut vocab = Vocab::new();
+        for (id, c) in "abcdefghij".chars().enumerate() {
+            vocab.insert(c.to_string(), id as u32);
+        }
+        let tokenizer = BPE::new(vocab, HashMap::new());
+        let vs = tch::nn::VarStore::new(Device::Cpu);
+        let model = Arc::new(ClaudeTransformer::new_seeded(&vs.root(), &ModelConfig::tiny(10), 0));
+        let greedy = SamplingParams {
+            temperature: 0.0,
+            ..SamplingParams::default()
+        };
+        // The text of the one reply to "abc", without its trailing newline.
+        let reply = |config: &ChatConfig| {
+            let mut generator = Generator::new(Arc::clone(&model), Device::Cpu);
+            let mut output = Vec::new();
+            run_repl(&mut generator, &tokenizer, &greedy, 8, config, b"abc\n".as_slice(), &mut output)
+                .expect("repl run");
+            let output = String::from_utf8(output).expect("utf8 output");
+            output.split("> ").nth(1).expect("one reply").trim_end_matches('\n').to_string()
+        };
+
+        let full = reply(&ChatConfig::default());
+        assert_eq!(full.len(), 8, "one letter per token");
+        let (stop, expected) = stop_in_reply(&full);
+        let config = ChatConfig {
+            stop_strings: vec![stop],
+            ..ChatConfig::default()
+        };
+
+        assert_eq!(reply(&config), expected);
+    }
+
+    #[test]
+    fn seeded_model_has_a_stable_top_logit() {
+        let logits = || {
+            let vs = tch::nn::VarStore::new(Device::Cpu);
+            let model = ClaudeTransformer::new_seeded(&vs.root(), &ModelConfig::tiny(16), 0);
+            last_position_logits(&model, &[1, 2, 3], Device::Cpu).expect("forward pass")
+        };
+
+        let first = logits();
+        let second = logits();
+
+        assert_eq!(first.len(), 16);
+        assert_eq!(first, second);
+        let top = top_logits(&first, 10);
+        assert_eq!(top.len(), 10);
+        assert_eq!(top[0].0, top_logits(&second, 1)[0].0);
+        assert!(top.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+        assert!(first.iter().all(|&logit| logit <= top[0].1));
+    }
+}
@@ -1,73 +1,1482 @@
-use tch::{Tensor, Device, IndexOp};
+use tch::{Tensor, Device, IndexOp, Kind};
+use claude_core::kv_cache::KVCacheOverflow;
 use claude_core::ClaudeTransformer;
-use crate::sampling::{Sampler, SamplingParams};
+use claude_core::tensor_util::{tensor_to_vec_f64, tensor_to_vec_i64};
+use crate::sampling::{Sampler, SamplingParams, StepTrace};
+use crate::session::SessionState;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
 
 use std::sync::Arc;
+use std::time::Instant;
 
 pub struct Generator {
     model: Arc<ClaudeTransformer>,
     device: Device,
+    prefill_chunk_size: Option<usize>,
+    kv_cache_dtype: Kind,
+    backpressure: Backpressure,
+    num_layers_override: Option<usize>,
+    contrastive: Option<(usize, f64)>,
+    kv_cache_capacity: Option<usize>,
+}
+
+/// What the generator does when a token channel is full because its consumer is slow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backpressure {
+    /// Wait until the consumer makes room, so no token is ever lost.
+    #[default]
+    Block,
+    /// Drop the token and keep generating, so a slow consumer never stalls the model.
+    DropOnFull,
+}
+
+impl Backpressure {
+    /// Sends `token` according to the policy. Returns `false` only once the receiver is gone.
+    fn send(self, tx: &tokio::sync::mpsc::Sender<i64>, token: i64) -> bool {
+        match self {
+            Backpressure::Block => tx.blocking_send(token).is_ok(),
+            Backpressure::DropOnFull => !matches!(
+                tx.try_send(token),
+                Err(tokio::sync::mpsc::error::TrySendError::Closed(_))
+            ),
+        }
+    }
+}
+
+/// What to do with a prompt that doesn't fit in the model's context window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Drop the oldest tokens and keep the end of the prompt.
+    TruncateLeft,
+    /// Keep the start of the prompt and drop the tail.
+    TruncateRight,
+    /// Refuse over-length prompts with an error.
+    #[default]
+    Error,
+}
+
+impl OverflowPolicy {
+    /// Fits `prompt_ids` into at most `limit` tokens according to the policy.
+    pub fn apply(self, prompt_ids: &[i64], limit: usize) -> anyhow::Result<Vec<i64>> {
+        if prompt_ids.len() <= limit {
+            return Ok(prompt_ids.to_vec());
+        }
+        match self {
+            OverflowPolicy::TruncateLeft => Ok(prompt_ids[prompt_ids.len() - limit..].to_vec()),
+            OverflowPolicy::TruncateRight => Ok(prompt_ids[..limit].to_vec()),
+            OverflowPolicy::Error => anyhow::bail!(
+                "prompt is {} tokens long, but at most {} input tokens are allowed; overflow policy \"error\" \
+                 rejects it instead of truncating (use \"truncate_left\" or \"truncate_right\")",
+                prompt_ids.len(),
+                limit
+            ),
+        }
+    }
+}
+
+/// Per-call settings of [`Generator::generate_stream`] beyond the prompt and sampling.
+#[derive(Debug, Default)]
+pub struct StreamOptions<'a> {
+    /// What to do with a prompt that doesn't fit in the context window.
+    pub overflow: OverflowPolicy,
+    /// Checked before every forward pass; once it passes, generation stops with
+    /// [`FinishReason::Timeout`].
+    pub deadline: Option<Instant>,
+    /// Receives a [`StepTrace`] for every sampled token.
+    pub trace: Option<&'a mut Vec<StepTrace>>,
+}
+
+/// Why a generation stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// Produced `max_new_tokens` or filled the context window.
+    Length,
+    /// The consumer stopped accepting tokens (e.g. the receiver was dropped).
+    Cancelled,
+    /// The deadline passed before generation finished.
+    Timeout,
+    /// The model sampled its end-of-sequence token, or (in the server) the output reached
+    /// one of the request's stop strings.
+    Stop,
+    /// The KV caches ran out of room before the context window did (see
+    /// [`Generator::with_kv_cache_capacity`]).
+    ContextFull,
+}
+
+/// Outcome of one [`Generator::generate_session`] turn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionTurn {
+    pub finish_reason: FinishReason,
+    /// Tokens run through the prefill: the turn's prompt plus whatever of the history
+    /// was not cached yet.
+    pub prefilled_tokens: usize,
+}
+
+/// Per-generation sampling state: parameters, RNG and an optional trace sink.
+struct StepSampler<'a, R: ?Sized> {
+    params: &'a SamplingParams,
+    rng: &'a mut R,
+    trace: Option<&'a mut Vec<StepTrace>>,
+}
+
+impl<R: Rng + ?Sized> StepSampler<'_, R> {
+    fn sample(&mut self, logits: &Tensor, history: &[i64], eos_token_id: Option<i64>) -> anyhow::Result<i64> {
+        let biased;
+        let logits = match eos_token_id {
+            Some(eos) if self.params.eos_bias != 0.0 => {
+                biased = Sampler::apply_eos_bias(logits, eos, self.params.eos_bias);
+                &biased
+            }
+            _ => logits,
+        };
+        match self.trace.as_deref_mut() {
+            None => Sampler::sample_with_rng(logits, self.params, history, &mut *self.rng),
+            Some(trace) => {
+                let mut step = Sampler::sample_traced(logits, self.params, history, &mut *self.rng)?;
+                step.step = trace.len();
+                let token = step.token;
+                trace.push(step);
+                Ok(token)
+            }
+        }
+    }
+}
+
+/// Seed used for the sequence at `index` of a batch generated with `base_seed`.
+pub fn sequence_seed(base_seed: u64, index: usize) -> u64 {
+    base_seed.wrapping_add(index as u64)
+}
+
+fn deadline_passed(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/// How one generation runs contrastive search: see [`Generator::with_contrastive`].
+#[derive(Debug, Clone, Copy)]
+struct ContrastiveSettings {
+    top_k: usize,
+    alpha: f64,
+    /// Ends the sequence when picked, like EOS in sampling.
+    eos_token_id: Option<i64>,
+}
+
+/// What [`Generator::contrastive_step`] carries from one step to the next.
+struct ContrastiveState {
+    /// Predicts the next token.
+    logits: Tensor,
+    /// Unit-length hidden states of the context, one row per position.
+    context: Tensor,
+}
+
+/// One request of [`Generator::generate_batch_stream`].
+pub struct StreamRequest {
+    pub prompt_ids: Vec<i64>,
+    pub max_new_tokens: usize,
+    pub params: SamplingParams,
+    /// How to fit a prompt that is longer than the context window.
+    pub overflow: OverflowPolicy,
+    /// Stop with [`FinishReason::Timeout`] once this passes.
+    pub deadline: Option<Instant>,
+    /// Receives the sampled tokens.
+    pub tx: tokio::sync::mpsc::Sender<i64>,
+}
+
+/// A sequence decoded alongside others by [`Generator::decode_lockstep`].
+struct LockstepSequence<'a> {
+    session: SessionState,
+    prompt_ids: &'a [i64],
+    max_new_tokens: usize,
+    params: &'a SamplingParams,
+    deadline: Option<Instant>,
+    rng: StdRng,
+    emit: Box<dyn FnMut(i64) -> bool + 'a>,
 }
 
 impl Generator {
     pub fn new(model: Arc<ClaudeTransformer>, device: Device) -> Self {
-        Self { model, device }
+        Self {
+            model,
+            device,
+            prefill_chunk_size: None,
+            kv_cache_dtype: Kind::Float,
+            backpressure: Backpressure::default(),
+            num_layers_override: None,
+            contrastive: None,
+            kv_cache_capacity: None,
+        }
+    }
+
+    /// Allocates KV caches for at most `capacity` positions instead of the model's whole
+    /// context, bounding cache memory per sequence. A generation that outgrows its caches
+    /// stops with [`FinishReason::ContextFull`].
+    pub fn with_kv_cache_capacity(mut self, capacity: usize) -> Self {
+        self.kv_cache_capacity = Some(capacity.max(1));
+        self
+    }
+
+    /// Decodes with contrastive search instead of sampling when set to `(top_k, alpha)`:
+    /// each step picks, among the `top_k` most likely tokens, the one maximizing
+    /// `(1 - alpha) * prob - alpha * max_cosine_similarity(candidate_hidden, previous_hidden)`,
+    /// which penalizes tokens whose representation repeats the context. Deterministic, so
+    /// [`SamplingParams`] are ignored. Applies to [`Generator::generate_stream`] and
+    /// [`Generator::generate_session`] (where only this turn's tokens and the uncached
+    /// history count as context); batched generation keeps sampling.
+    ///
+    /// Every step runs `top_k + 1` single-token forward passes. Errors if `top_k` is 0 or
+    /// `alpha` is outside `[0, 1]`.
+    pub fn with_contrastive(mut self, contrastive: Option<(usize, f64)>) -> anyhow::Result<Self> {
+        if let Some((top_k, alpha)) = contrastive {
+            anyhow::ensure!(top_k > 0, "contrastive search needs at least one candidate");
+            anyhow::ensure!(
+                (0.0..=1.0).contains(&alpha),
+                "contrastive alpha must be in [0, 1], got {}",
+                alpha
+            );
+        }
+        self.contrastive = contrastive;
+        Ok(self)
+    }
+
+    /// Runs only the first `num_layers` blocks on every forward pass (all of them if
+    /// `None`), allocating one KV cache per layer that runs. Useful for cheap draft outputs
+    /// from a truncated model. Errors if `num_layers` exceeds the model's `n_layer`.
+    pub fn with_num_layers_override(mut self, num_layers: Option<usize>) -> anyhow::Result<Self> {
+        self.model.num_layers(num_layers)?;
+        self.num_layers_override = num_layers;
+        Ok(self)
+    }
+
+    /// How token channels that are full are handled (default: [`Backpressure::Block`]).
+    pub fn with_backpressure(mut self, backpressure: Backpressure) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+
+    /// Stores the KV cache in `kind` (default `Float`). `Kind::Half` halves cache memory;
+    /// attention still runs in the model's dtype.
+    pub fn with_kv_cache_dtype(mut self, kind: Kind) -> Self {
+        self.kv_cache_dtype = kind;
+        self
+    }
+
+    /// Prefills prompts in chunks of at most `size` tokens instead of one forward pass,
+    /// bounding the attention matrix to `[chunk, prompt_len]` at some cost in speed.
+    pub fn with_prefill_chunk_size(mut self, size: usize) -> Self {
+        self.prefill_chunk_size = Some(size.max(1));
+        self
+    }
+
+    /// Maximum number of prompt tokens the model accepts.
+    pub fn context_limit(&self) -> usize {
+        self.model.config.max_seq_len as usize
+    }
+
+    /// Log-probability of each of `token_ids` given the tokens before it, from a single
+    /// teacher-forced forward pass, e.g. for re-ranking candidates. The first token has no
+    /// prefix to condition on, so its entry is NaN.
+    pub fn score(&self, token_ids: &[i64]) -> anyhow::Result<Vec<f64>> {
+        anyhow::ensure!(!token_ids.is_empty(), "cannot score an empty sequence");
+        anyhow::ensure!(
+            token_ids.len() <= self.context_limit(),
+            "sequence is {} tokens long, which exceeds the context limit of {} tokens",
+            token_ids.len(),
+            self.context_limit()
+        );
+        let _guard = tch::no_grad_guard();
+        let n = token_ids.len() as i64;
+        let input = Tensor::from_slice(token_ids).view([1, n]).to(self.device);
+        let logits = self.model.forward_truncated(&input, None, self.num_layers_override)?;
+        // Position i predicts token i + 1.
+        let log_probs = logits.i((0, ..n - 1, ..)).log_softmax(-1, Kind::Double);
+        let targets = Tensor::from_slice(&token_ids[1..]).to(self.device).unsqueeze(-1);
+        let scores = tensor_to_vec_f64(&log_probs.gather(-1, &targets, false).view([-1]))?;
+        Ok(std::iter::once(f64::NAN).chain(scores).collect())
     }
 
+    /// An empty conversation with one KV cache per layer that runs.
+    pub fn new_session(&self) -> SessionState {
+        let config = &self.model.config;
+        let capacity = self
+            .kv_cache_capacity
+            .map_or(self.context_limit(), |capacity| capacity.min(self.context_limit()));
+        let caches = (0..self.num_layers())
+            .map(|_| claude_core::kv_cache::KVCache::new(
+                capacity,
+                config.n_head,
+                config.n_embd / config.n_head,
+                self.device,
+                self.kv_cache_dtype
+            ))
+            .collect();
+        SessionState {
+            tokens: Vec::new(),
+            caches,
+        }
+    }
+
+    /// Streams sampled tokens into `tx`, fitting the prompt, stopping at the deadline and
+    /// tracing steps as `options` say.
     pub fn generate_stream(
         &mut self,
         prompt_ids: &[i64],
         max_new_tokens: usize,
         params: &SamplingParams,
+        options: StreamOptions<'_>,
         tx: tokio::sync::mpsc::Sender<i64>,
-    ) -> anyhow::Result<()> {
-        let mut tokens = prompt_ids.to_vec();
-        
-        // Initialize KV Caches for each layer
-        let mut caches: Vec<claude_core::kv_cache::KVCache> = (0..self.model.config.n_layer)
-            .map(|_| claude_core::kv_cache::KVCache::new(
-                self.model.config.max_seq_len as usize,
-                self.model.config.n_head,
-                self.model.config.n_embd / self.model.config.n_head,
-                self.device,
-                tch::Kind::Float
-            ))
+    ) -> anyhow::Result<FinishReason> {
+        let span = tracing::info_span!("generate_stream", prompt_len = prompt_ids.len(), max_new_tokens);
+        let _guard = span.enter();
+        let StreamOptions { overflow, deadline, trace } = options;
+        let prompt_ids = overflow.apply(prompt_ids, self.context_limit())?;
+        let mut rng = rand::thread_rng();
+        let mut sampler = StepSampler {
+            params,
+            rng: &mut rng,
+            trace,
+        };
+        let mut session = self.new_session();
+        let backpressure = self.backpressure;
+        let finish_reason = self.decode(&mut session, &prompt_ids, max_new_tokens, deadline, &mut sampler, |token| {
+            backpressure.send(&tx, token)
+        })?;
+        tracing::debug!(?finish_reason, "generation finished");
+        Ok(finish_reason)
+    }
+
+    /// Runs one conversation turn: `turn_ids` are appended to the session's history and
+    /// only they (plus any history not cached yet) are prefilled, reusing the session's
+    /// KV caches. The reply is streamed into `tx` and appended to `session.tokens`.
+    ///
+    /// Fails if the history plus the new turn no longer fits in the context window.
+    pub fn generate_session(
+        &mut self,
+        session: &mut SessionState,
+        turn_ids: &[i64],
+        max_new_tokens: usize,
+        params: &SamplingParams,
+        deadline: Option<Instant>,
+        tx: tokio::sync::mpsc::Sender<i64>,
+    ) -> anyhow::Result<SessionTurn> {
+        let prefilled_tokens = session.tokens.len() - session.cached_len() + turn_ids.len();
+        let mut rng = rand::thread_rng();
+        let mut sampler = StepSampler {
+            params,
+            rng: &mut rng,
+            trace: None,
+        };
+        let backpressure = self.backpressure;
+        let finish_reason = self.decode(session, turn_ids, max_new_tokens, deadline, &mut sampler, |token| {
+            backpressure.send(&tx, token)
+        })?;
+        Ok(SessionTurn {
+            finish_reason,
+            prefilled_tokens,
+        })
+    }
+
+    /// Generates a completion for each prompt and returns the new token IDs per sequence.
+    ///
+    /// Every sequence samples from its own `StdRng` seeded with
+    /// [`sequence_seed`]`(base_seed, index)`, so a sequence's output depends only on its
+    /// prompt and seed, not on the other sequences in the batch.
+    pub fn generate_batch(
+        &mut self,
+        prompts: &[Vec<i64>],
+        max_new_tokens: usize,
+        params: &SamplingParams,
+        overflow: OverflowPolicy,
+        base_seed: u64,
+    ) -> anyhow::Result<Vec<Vec<i64>>> {
+        let prompts = prompts
+            .iter()
+            .map(|prompt_ids| overflow.apply(prompt_ids, self.context_limit()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut outputs = vec![Vec::new(); prompts.len()];
+        let sequences = prompts
+            .iter()
+            .zip(outputs.iter_mut())
+            .enumerate()
+            .map(|(index, (prompt_ids, generated))| LockstepSequence {
+                session: self.new_session(),
+                prompt_ids,
+                max_new_tokens,
+                params,
+                deadline: None,
+                rng: StdRng::seed_from_u64(sequence_seed(base_seed, index)),
+                emit: Box::new(move |token| {
+                    generated.push(token);
+                    true
+                }),
+            })
             .collect();
+        for result in self.decode_lockstep(sequences) {
+            result?;
+        }
+        Ok(outputs)
+    }
 
-        // 1. Prefill
-        let input_tensor = Tensor::from_slice(&tokens).view([1, tokens.len() as i64]).to(self.device);
-        let logits = self.model.forward(&input_tensor, Some(&mut caches));
-        
-        // Sample first new token
-        let next_token_logits = logits.i((0, -1, ..)); 
-        let mut next_token = Sampler::sample(&next_token_logits, params, &tokens)?;
-        
-        // Yield first token
-        let _ = tx.blocking_send(next_token);
-        tokens.push(next_token);
+    /// Samples `n` alternative completions of one prompt, decoded together like
+    /// [`Generator::generate_batch`]: completion `i` samples with seed
+    /// [`sequence_seed`]`(base_seed, i)`. Returns each completion's tokens and why it stopped.
+    pub fn generate_n(
+        &mut self,
+        prompt_ids: &[i64],
+        n: usize,
+        max_new_tokens: usize,
+        params: &SamplingParams,
+        deadline: Option<Instant>,
+        base_seed: u64,
+    ) -> anyhow::Result<Vec<(Vec<i64>, FinishReason)>> {
+        let mut outputs = vec![Vec::new(); n];
+        let sequences = outputs
+            .iter_mut()
+            .enumerate()
+            .map(|(index, generated)| LockstepSequence {
+                session: self.new_session(),
+                prompt_ids,
+                max_new_tokens,
+                params,
+                deadline,
+                rng: StdRng::seed_from_u64(sequence_seed(base_seed, index)),
+                emit: Box::new(move |token| {
+                    generated.push(token);
+                    true
+                }),
+            })
+            .collect();
+        let finish_reasons = self
+            .decode_lockstep(sequences)
+            .into_iter()
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(outputs.into_iter().zip(finish_reasons).collect())
+    }
+
+    /// Streams a completion for every request into its own channel. The requests are
+    /// decoded in lockstep, so each batched forward pass advances every unfinished sequence
+    /// by one token and all streams make progress together.
+    ///
+    /// Returns one result per request: a request that fails (e.g. its prompt doesn't fit
+    /// the context window) doesn't affect the others. With [`Backpressure::Block`] a full
+    /// channel stalls the whole batch, so each `tx` should have room for `max_new_tokens`.
+    pub fn generate_batch_stream(&mut self, requests: &[StreamRequest]) -> Vec<anyhow::Result<FinishReason>> {
+        let span = tracing::info_span!("generate_batch_stream", batch_size = requests.len());
+        let _guard = span.enter();
+        let context_limit = self.context_limit();
+        let prompts: Vec<anyhow::Result<Vec<i64>>> = requests
+            .iter()
+            .map(|request| request.overflow.apply(&request.prompt_ids, context_limit))
+            .collect();
+        let backpressure = self.backpressure;
+        let sequences = requests
+            .iter()
+            .zip(&prompts)
+            .filter_map(|(request, prompt_ids)| {
+                Some(LockstepSequence {
+                    session: self.new_session(),
+                    prompt_ids: prompt_ids.as_ref().ok()?,
+                    max_new_tokens: request.max_new_tokens,
+                    params: &request.params,
+                    deadline: request.deadline,
+                    rng: StdRng::from_entropy(),
+                    emit: Box::new(move |token| backpressure.send(&request.tx, token)),
+                })
+            })
+            .collect();
+        let mut finished = self.decode_lockstep(sequences).into_iter();
+        prompts
+            .into_iter()
+            .map(|prompt_ids| {
+                prompt_ids.and_then(|_| finished.next().expect("one result per decoded sequence"))
+            })
+            .collect()
+    }
 
-        // 2. Decode Loop
-        for _ in 0..max_new_tokens {
-            let input_tensor = Tensor::from_slice(&[next_token]).view([1, 1]).to(self.device);
-            let logits = self.model.forward(&input_tensor, Some(&mut caches));
-            
-            let next_token_logits = logits.i((0, -1, ..));
-            next_token = Sampler::sample(&next_token_logits, params, &tokens)?;
-            
-            // Yield token
-            if tx.blocking_send(next_token).is_err() {
-                break; // Receiver dropped
+    /// Decodes `sequences` in rounds: after every sequence has been prefilled, each round
+    /// feeds the last token of every sequence that hasn't finished yet through one batched
+    /// forward pass (see [`ClaudeTransformer::forward_decode_batch`]) and samples each
+    /// sequence's next token.
+    fn decode_lockstep(&self, mut sequences: Vec<LockstepSequence<'_>>) -> Vec<anyhow::Result<FinishReason>> {
+        let mut results: Vec<Option<anyhow::Result<FinishReason>>> = Vec::with_capacity(sequences.len());
+        for seq in &mut sequences {
+            if seq.max_new_tokens == 0 {
+                results.push(Some(Ok(FinishReason::Length)));
+                continue;
             }
-            tokens.push(next_token);
-            
-            if tokens.len() >= self.model.config.max_seq_len as usize {
+            let mut sampler = StepSampler {
+                params: seq.params,
+                rng: &mut seq.rng,
+                trace: None,
+            };
+            results.push(
+                self.start_decode(&mut seq.session, seq.prompt_ids, seq.deadline, &mut sampler, &mut seq.emit)
+                    .transpose(),
+            );
+        }
+
+        // The token `start_decode` sampled counts toward each sequence's limit.
+        let mut steps = vec![1; sequences.len()];
+        loop {
+            let mut batch = Vec::new();
+            for (index, (seq, result)) in sequences.iter().zip(&mut results).enumerate() {
+                if result.is_some() {
+                    continue;
+                }
+                let cache_full = seq.session.caches.iter().any(|cache| cache.length >= cache.max_capacity);
+                match self.begin_step(&seq.session, &mut steps[index], seq.max_new_tokens, seq.deadline) {
+                    Some(reason) => *result = Some(Ok(reason)),
+                    None if cache_full => *result = Some(Ok(FinishReason::ContextFull)),
+                    None => batch.push(index),
+                }
+            }
+            if batch.is_empty() {
                 break;
             }
+
+            let logits = match self.forward_rows(&mut sequences, &batch) {
+                Ok(logits) => logits,
+                Err(err) => {
+                    let err = format!("{:#}", err);
+                    for &index in &batch {
+                        results[index] = Some(Err(anyhow::anyhow!("batched decode step failed: {}", err)));
+                    }
+                    continue;
+                }
+            };
+            for (row, &index) in batch.iter().enumerate() {
+                let seq = &mut sequences[index];
+                let mut sampler = StepSampler {
+                    params: seq.params,
+                    rng: &mut seq.rng,
+                    trace: None,
+                };
+                results[index] = self
+                    .accept_next(&mut seq.session.tokens, &logits.i((row as i64, -1, ..)), &mut sampler, &mut seq.emit)
+                    .transpose();
+            }
+        }
+
+        results
+            .into_iter()
+            .map(|result| result.expect("every sequence has finished"))
+            .collect()
+    }
+
+    /// Feeds the last sampled token of each of `sequences[batch]` through the model in one
+    /// batched forward pass. Row `i` of the returned logits belongs to `batch[i]`.
+    fn forward_rows(&self, sequences: &mut [LockstepSequence<'_>], batch: &[usize]) -> anyhow::Result<Tensor> {
+        let last_tokens: Vec<i64> = batch
+            .iter()
+            .map(|&index| *sequences[index].session.tokens.last().expect("a token was sampled before decoding"))
+            .collect();
+        let input = Tensor::from_slice(&last_tokens).view([batch.len() as i64, 1]).to(self.device);
+        let mut caches: Vec<&mut [claude_core::kv_cache::KVCache]> = sequences
+            .iter_mut()
+            .enumerate()
+            .filter(|(index, _)| batch.contains(index))
+            .map(|(_, seq)| &mut seq.session.caches[..])
+            .collect();
+        self.model.forward_decode_batch(&input, &mut caches, self.num_layers_override)
+    }
+
+    /// Shared prefill + decode loop. Feeds `new_ids` after the session's history and
+    /// appends every sampled token to it. `emit` receives each sampled token and returns
+    /// `false` to stop generation early (e.g. the receiver went away).
+    fn decode<R: Rng + ?Sized>(
+        &self,
+        session: &mut SessionState,
+        new_ids: &[i64],
+        max_new_tokens: usize,
+        deadline: Option<Instant>,
+        sampler: &mut StepSampler<'_, R>,
+        mut emit: impl FnMut(i64) -> bool,
+    ) -> anyhow::Result<FinishReason> {
+        if max_new_tokens == 0 {
+            return Ok(FinishReason::Length);
+        }
+        if let Some((top_k, alpha)) = self.contrastive {
+            let settings = ContrastiveSettings {
+                top_k,
+                alpha,
+                eos_token_id: self.eos_token_id(sampler.params),
+            };
+            return self.decode_contrastive(session, new_ids, max_new_tokens, deadline, settings, &mut emit);
+        }
+        if let Some(reason) = self.start_decode(session, new_ids, deadline, sampler, &mut emit)? {
+            return Ok(reason);
+        }
+        // The token `start_decode` sampled counts toward the limit.
+        let mut steps = 1;
+        loop {
+            if let Some(reason) = self.decode_step(session, &mut steps, max_new_tokens, deadline, sampler, &mut emit)? {
+                return Ok(reason);
+            }
+        }
+    }
+
+    /// Prefills whatever the session's caches haven't seen plus `new_ids`, then samples
+    /// the first new token. Returns the finish reason if the sequence already ended.
+    fn start_decode<R: Rng + ?Sized>(
+        &self,
+        session: &mut SessionState,
+        new_ids: &[i64],
+        deadline: Option<Instant>,
+        sampler: &mut StepSampler<'_, R>,
+        emit: &mut impl FnMut(i64) -> bool,
+    ) -> anyhow::Result<Option<FinishReason>> {
+        if deadline_passed(deadline) {
+            return Ok(Some(FinishReason::Timeout));
+        }
+
+        let context_limit = self.context_limit();
+        anyhow::ensure!(
+            session.tokens.len() + new_ids.len() <= context_limit,
+            "conversation of {} tokens plus a turn of {} tokens exceeds the context limit of {} tokens",
+            session.tokens.len(),
+            new_ids.len(),
+            context_limit
+        );
+
+        // Only the tokens the caches haven't seen need a forward pass.
+        let pending: Vec<i64> = session.tokens[session.cached_len()..]
+            .iter()
+            .chain(new_ids)
+            .copied()
+            .collect();
+        anyhow::ensure!(!pending.is_empty(), "nothing to generate from: the prompt is empty");
+
+        let SessionState { tokens, caches } = session;
+        tokens.extend_from_slice(new_ids);
+
+        let Some(logits) = unless_cache_full(self.prefill(&pending, caches))? else {
+            return Ok(Some(FinishReason::ContextFull));
+        };
+        self.accept_next(tokens, &logits.i((0, -1, ..)), sampler, emit)
+    }
+
+    /// [`Generator::decode`] for contrastive search: prefills like [`Generator::start_decode`],
+    /// keeping the hidden states of every fed position, then picks tokens with
+    /// [`Generator::contrastive_step`].
+    fn decode_contrastive(
+        &self,
+        session: &mut SessionState,
+        new_ids: &[i64],
+        max_new_tokens: usize,
+        deadline: Option<Instant>,
+        settings: ContrastiveSettings,
+        emit: &mut impl FnMut(i64) -> bool,
+    ) -> anyhow::Result<FinishReason> {
+        if deadline_passed(deadline) {
+            return Ok(FinishReason::Timeout);
+        }
+        let context_limit = self.context_limit();
+        anyhow::ensure!(
+            session.tokens.len() + new_ids.len() <= context_limit,
+            "conversation of {} tokens plus a turn of {} tokens exceeds the context limit of {} tokens",
+            session.tokens.len(),
+            new_ids.len(),
+            context_limit
+        );
+        let pending: Vec<i64> = session.tokens[session.cached_len()..]
+            .iter()
+            .chain(new_ids)
+            .copied()
+            .collect();
+        anyhow::ensure!(!pending.is_empty(), "nothing to generate from: the prompt is empty");
+
+        let _guard = tch::no_grad_guard();
+        let SessionState { tokens, caches } = session;
+        tokens.extend_from_slice(new_ids);
+
+        let chunk_size = self.prefill_chunk_size.unwrap_or(pending.len()).max(1);
+        let mut logits = None;
+        let mut hidden = Vec::new();
+        for chunk in pending.chunks(chunk_size) {
+            let input = Tensor::from_slice(chunk).view([1, chunk.len() as i64]).to(self.device);
+            let Some((chunk_logits, chunk_hidden)) = unless_cache_full(self.forward_with_hidden(&input, caches))?
+            else {
+                return Ok(FinishReason::ContextFull);
+            };
+            logits = Some(chunk_logits.i((0, -1, ..)));
+            hidden.push(unit_rows(&chunk_hidden.i(0)));
+        }
+        let mut state = ContrastiveState {
+            logits: logits.expect("prompt must not be empty"),
+            context: Tensor::cat(&hidden, 0),
+        };
+
+        let mut steps = 0;
+        while steps < max_new_tokens {
+            if steps > 0 {
+                if tokens.len() >= context_limit {
+                    return Ok(FinishReason::Length);
+                }
+                if deadline_passed(deadline) {
+                    return Ok(FinishReason::Timeout);
+                }
+            }
+            steps += 1;
+            if let Some(reason) = self.contrastive_step(tokens, caches, &mut state, settings, emit)? {
+                return Ok(reason);
+            }
+        }
+        Ok(FinishReason::Length)
+    }
+
+    /// Scores the `top_k` most likely tokens under `state.logits` by contrastive search, emits
+    /// the best one and feeds it through the model, advancing `state` past it.
+    fn contrastive_step(
+        &self,
+        tokens: &mut Vec<i64>,
+        caches: &mut [claude_core::kv_cache::KVCache],
+        state: &mut ContrastiveState,
+        settings: ContrastiveSettings,
+        emit: &mut impl FnMut(i64) -> bool,
+    ) -> anyhow::Result<Option<FinishReason>> {
+        let ContrastiveSettings { top_k, alpha, eos_token_id } = settings;
+        let ContrastiveState { logits, context } = state;
+        let vocab_size = logits.size()[0];
+        let probs = logits.softmax(-1, Kind::Float);
+        let (top_probs, top_ids) = probs.topk((top_k as i64).min(vocab_size), -1, true, true);
+        let candidates = tensor_to_vec_i64(&top_ids)?;
+
+        // Look one token ahead for every candidate, rolling the caches back after each.
+        let cached_len = caches.first().map_or(0, |cache| cache.length);
+        let mut candidate_logits = Vec::with_capacity(candidates.len());
+        let mut candidate_hidden = Vec::with_capacity(candidates.len());
+        for &candidate in &candidates {
+            let input = Tensor::from_slice(&[candidate]).view([1, 1]).to(self.device);
+            let Some((next_logits, hidden)) = unless_cache_full(self.forward_with_hidden(&input, caches))?
+            else {
+                return Ok(Some(FinishReason::ContextFull));
+            };
+            for cache in caches.iter_mut() {
+                cache.truncate(cached_len);
+            }
+            candidate_logits.push(next_logits.i((0, -1, ..)));
+            candidate_hidden.push(hidden.i((0, -1, ..)));
+        }
+        let candidate_hidden = unit_rows(&Tensor::stack(&candidate_hidden, 0));
+        let (degeneration, _) = candidate_hidden.matmul(&context.tr()).max_dim(-1, false);
+        let scores = top_probs * (1.0 - alpha) - degeneration * alpha;
+        let best = scores.argmax(0, false).int64_value(&[]) as usize;
+        let next_token = candidates[best];
+
+        // EOS ends the sequence; it is kept in the history but not emitted.
+        if eos_token_id == Some(next_token) {
+            tokens.push(next_token);
+            return Ok(Some(FinishReason::Stop));
+        }
+        if !emit(next_token) {
+            return Ok(Some(FinishReason::Cancelled)); // Receiver dropped
+        }
+        tokens.push(next_token);
+        // Feed the winner for real so the caches hold it; it fit during the look-ahead.
+        let input = Tensor::from_slice(&[next_token]).view([1, 1]).to(self.device);
+        self.forward(&input, caches)?;
+        *logits = candidate_logits.swap_remove(best);
+        *context = Tensor::cat(&[&*context, &candidate_hidden.i(best as i64..best as i64 + 1)], 0);
+        Ok(None)
+    }
+
+    /// Feeds the last sampled token through the model and samples the next one. `steps`
+    /// counts the tokens sampled so far, including the first; the sequence ends with
+    /// [`FinishReason::Length`] once it reaches `max_new_tokens` or fills the context.
+    fn decode_step<R: Rng + ?Sized>(
+        &self,
+        session: &mut SessionState,
+        steps: &mut usize,
+        max_new_tokens: usize,
+        deadline: Option<Instant>,
+        sampler: &mut StepSampler<'_, R>,
+        emit: &mut impl FnMut(i64) -> bool,
+    ) -> anyhow::Result<Option<FinishReason>> {
+        if let Some(reason) = self.begin_step(session, steps, max_new_tokens, deadline) {
+            return Ok(Some(reason));
+        }
+
+        let SessionState { tokens, caches } = session;
+        let last_token = *tokens.last().expect("a token was sampled before decoding");
+        let input_tensor = Tensor::from_slice(&[last_token]).view([1, 1]).to(self.device);
+        let Some(logits) = unless_cache_full(self.forward(&input_tensor, &mut caches[..]))? else {
+            return Ok(Some(FinishReason::ContextFull));
+        };
+        self.accept_next(tokens, &logits.i((0, -1, ..)), sampler, emit)
+    }
+
+    /// Whether the sequence in `session` ends before its next decode step, and why. If it
+    /// doesn't, the step about to run is counted in `steps`.
+    fn begin_step(
+        &self,
+        session: &SessionState,
+        steps: &mut usize,
+        max_new_tokens: usize,
+        deadline: Option<Instant>,
+    ) -> Option<FinishReason> {
+        if *steps >= max_new_tokens || session.tokens.len() >= self.context_limit() {
+            return Some(FinishReason::Length);
         }
+        if deadline_passed(deadline) {
+            return Some(FinishReason::Timeout);
+        }
+        *steps += 1;
+        None
+    }
 
-        Ok(())
+    /// The token that ends generation under `params`: its own `eos_token_id`, or else the
+    /// model config's.
+    fn eos_token_id(&self, params: &SamplingParams) -> Option<i64> {
+        params.eos_token_id.or(self.model.config.eos_token_id)
     }
+
+    /// Samples a token from `logits`, emits it and appends it to `tokens`. Returns the
+    /// finish reason if the token ends the sequence.
+    fn accept_next<R: Rng + ?Sized>(
+        &self,
+        tokens: &mut Vec<i64>,
+        logits: &Tensor,
+        sampler: &mut StepSampler<'_, R>,
+        emit: &mut impl FnMut(i64) -> bool,
+    ) -> anyhow::Result<Option<FinishReason>> {
+        let eos_token_id = self.eos_token_id(sampler.params);
+        let next_token = sampler.sample(logits, tokens, eos_token_id)?;
+
+        // EOS ends the sequence; it is kept in the history but not emitted.
+        if eos_token_id == Some(next_token) {
+            tokens.push(next_token);
+            return Ok(Some(FinishReason::Stop));
+        }
+        if !emit(next_token) {
+            return Ok(Some(FinishReason::Cancelled)); // Receiver dropped
+        }
+        tokens.push(next_token);
+        Ok(None)
+    }
+
+    /// Runs the prompt through the model, filling `caches`, and returns the logits of the
+    /// last forward pass (whose final position predicts the first new token).
+    fn prefill(&self, prompt_ids: &[i64], caches: &mut [claude_core::kv_cache::KVCache]) -> anyhow::Result<Tensor> {
+        let chunk_size = self.prefill_chunk_size.unwrap_or(prompt_ids.len()).max(1);
+        let mut logits = None;
+        for chunk in prompt_ids.chunks(chunk_size) {
+            let input_tensor = Tensor::from_slice(chunk).view([1, chunk.len() as i64]).to(self.device);
+            logits = Some(self.forward(&input_tensor, &mut *caches)?);
+        }
+        Ok(logits.expect("prompt must not be empty"))
+    }
+
+    fn num_layers(&self) -> usize {
+        self.num_layers_override.unwrap_or(self.model.config.n_layer as usize)
+    }
+
+    /// Forward pass through the layers that run. The layer count was validated by
+    /// [`Generator::with_num_layers_override`], so this only fails on a cache overflow.
+    fn forward(&self, input: &Tensor, caches: &mut [claude_core::kv_cache::KVCache]) -> anyhow::Result<Tensor> {
+        self.model.forward_truncated(input, Some(caches), self.num_layers_override)
+    }
+
+    fn forward_with_hidden(
+        &self,
+        input: &Tensor,
+        caches: &mut [claude_core::kv_cache::KVCache],
+    ) -> anyhow::Result<(Tensor, Tensor)> {
+        self.model.forward_with_hidden(input, Some(caches), self.num_layers_override)
+    }
+}
+
+/// `Ok(None)` if `result` failed because a KV cache is full, so the caller can stop with
+/// [`FinishReason::ContextFull`]; any other error is passed on.
+fn unless_cache_full<T>(result: anyhow::Result<T>) -> anyhow::Result<Option<T>> {
+    match result {
+        Err(err) if err.is::<KVCacheOverflow>() => Ok(None),
+        result => result.map(Some),
+    }
+}
+
+/// Scales every row of `x` (`[rows, n_embd]`) to unit length, in f32.
+fn unit_rows(x: &Tensor) -> Tensor {
+    let x = x.to_kind(Kind::Float);
+    let norm = x.pow_tensor_scalar(2.0).sum_dim_intlist(Some(&[-1][..]), true, Kind::Float).sqrt();
+    &x / (norm + 1e-8)
 }
 
 unsafe impl Send for Generator {}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claude_core::ModelConfig;
+
+    fn tiny_generator() -> Generator {
+        let vs = tch::nn::VarStore::new(Device::Cpu);
+        let model = ClaudeTransformer::new_seeded(&vs.root(), &ModelConfig::tiny(16), 0);
+        Generator::new(Arc::new(model), Device::Cpu)
+    }
+
+    #[test]
+    fn generate_batch_is_independent_of_batch_position() {
+        let mut generator = tiny_generator();
+        let params = SamplingParams { temperature: 1.0, ..Default::default() };
+        let prompt_a = vec![1, 2, 3];
+        let prompt_b = vec![4, 5];
+
+        let seed = 42;
+        let first = generator
+            .generate_batch(
+                &[prompt_a.clone(), prompt_b.clone()],
+                8,
+                &params,
+                OverflowPolicy::Error,
+                seed,
+            )
+            .expect("generate batch");
+        // Shift the base seed so that prompt_a at index 1 gets the same per-sequence seed.
+        let swapped = generator
+            .generate_batch(&[prompt_b, prompt_a], 8, &params, OverflowPolicy::Error, seed - 1)
+            .expect("generate swapped batch");
+
+        assert_eq!(sequence_seed(seed, 0), sequence_seed(seed - 1, 1));
+        assert_eq!(first[0], swapped[1]);
+    }
+
+    #[test]
+    fn generate_n_returns_one_completion_per_seed() {
+        let mut generator = tiny_generator();
+        let params = SamplingParams { temperature: 1.0, ..Default::default() };
+        let prompt = [1, 2, 3];
+
+        let completions = generator
+            .generate_n(&prompt, 3, 8, &params, None, 11)
+            .expect("generate n");
+        let batch = generator
+            .generate_batch(&[prompt.to_vec(), prompt.to_vec(), prompt.to_vec()], 8, &params, OverflowPolicy::Error, 11)
+            .expect("generate batch");
+
+        assert_eq!(completions.len(), 3);
+        for ((tokens, finish_reason), batched) in completions.iter().zip(&batch) {
+            assert_eq!(tokens, batched);
+            assert_eq!(tokens.len(), 8);
+            assert_eq!(*finish_reason, FinishReason::Length);
+        }
+        // Differently seeded alternatives of a near-uniform tiny model don't all agree.
+        assert!(completions.windows(2).any(|pair| pair[0].0 != pair[1].0), "{completions:?}");
+    }
+
+    #[test]
+    fn generate_batch_stream_matches_streaming_each_request_alone() {
+        let mut generator = tiny_generator();
+        let greedy = SamplingParams { temperature: 0.0, ..Default::default() };
+        let too_long: Vec<i64> = (0..generator.context_limit() as i64 + 1).map(|i| i % 16).collect();
+        let prompts = [vec![1, 2, 3], too_long, vec![4, 5, 6, 7, 8]];
+        let drain = |rx: &mut tokio::sync::mpsc::Receiver<i64>| {
+            let mut tokens = Vec::new();
+            while let Ok(token) = rx.try_recv() {
+                tokens.push(token);
+            }
+            tokens
+        };
+
+        let (requests, mut receivers): (Vec<_>, Vec<_>) = prompts
+            .iter()
+            .map(|prompt_ids| {
+                let (tx, rx) = tokio::sync::mpsc::channel(16);
+                let request = StreamRequest {
+                    prompt_ids: prompt_ids.clone(),
+                    max_new_tokens: 4,
+                    params: greedy.clone(),
+                    overflow: OverflowPolicy::Error,
+                    deadline: None,
+                    tx,
+                };
+                (request, rx)
+            })
+            .unzip();
+        let results = generator.generate_batch_stream(&requests);
+
+        // The over-long prompt fails on its own; the others run as if they were alone.
+        assert!(results[1].is_err());
+        assert!(drain(&mut receivers[1]).is_empty());
+        for index in [0, 2] {
+            assert_eq!(results[index].as_ref().expect("prompt fits"), &FinishReason::Length);
+            let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+            generator
+                .generate_stream(&prompts[index], 4, &greedy, StreamOptions::default(), tx)
+                .expect("generate stream");
+            let batched = drain(&mut receivers[index]);
+            assert_eq!(batched.len(), 4);
+            assert_eq!(batched, drain(&mut rx));
+        }
+    }
+
+    #[test]
+    fn num_layers_override_allocates_and_runs_only_the_first_layers() {
+        let generator = tiny_generator();
+        let n_layer = generator.model.config.n_layer as usize;
+        assert!(Generator::new(Arc::clone(&generator.model), Device::Cpu)
+            .with_num_layers_override(Some(n_layer + 1))
+            .is_err());
+
+        let draft = Generator::new(Arc::clone(&generator.model), Device::Cpu)
+            .with_num_layers_override(Some(1))
+            .expect("one layer");
+        let mut session = draft.new_session();
+        assert_eq!(session.caches.len(), 1);
+
+        let logits = draft.prefill(&[1, 2, 3], &mut session.caches).expect("prefill");
+        let mut full_session = generator.new_session();
+        let full_logits = generator.prefill(&[1, 2, 3], &mut full_session.caches).expect("prefill");
+        assert_eq!(logits.size(), full_logits.size());
+        assert!(!logits.allclose(&full_logits, 1e-6, 1e-6, false));
+    }
+
+    #[test]
+    fn overflow_policy_truncate_left_keeps_prompt_tail() {
+        let prompt: Vec<i64> = (0..10).collect();
+        let fitted = OverflowPolicy::TruncateLeft.apply(&prompt, 4).expect("truncate left");
+        assert_eq!(fitted, vec![6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn overflow_policy_truncate_right_keeps_prompt_head() {
+        let prompt: Vec<i64> = (0..10).collect();
+        let fitted = OverflowPolicy::TruncateRight.apply(&prompt, 4).expect("truncate right");
+        assert_eq!(fitted, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn overflow_policy_error_reports_length_and_limit() {
+        let prompt: Vec<i64> = (0..10).collect();
+        let err = OverflowPolicy::default().apply(&prompt, 4).unwrap_err().to_string();
+        assert!(err.contains("10 tokens"), "{err}");
+        assert!(err.contains("at most 4 input tokens"), "{err}");
+        assert!(err.contains("\"error\""), "{err}");
+        assert_eq!(OverflowPolicy::Error.apply(&prompt[..4], 4).expect("fits"), prompt[..4]);
+    }
+
+    #[test]
+    fn generate_batch_applies_overflow_policy_to_long_prompts() {
+        let mut generator = tiny_generator();
+        let params = SamplingParams::default();
+        let too_long: Vec<i64> = (0..generator.context_limit() as i64 + 5).map(|i| i % 16).collect();
+
+        assert!(generator
+            .generate_batch(&[too_long.clone()], 2, &params, OverflowPolicy::Error, 0)
+            .is_err());
+        for policy in [OverflowPolicy::TruncateLeft, OverflowPolicy::TruncateRight] {
+            let outputs = generator
+                .generate_batch(&[too_long.clone()], 2, &params, policy, 0)
+                .expect("truncated prompt generates");
+            assert_eq!(outputs.len(), 1);
+            assert!(!outputs[0].is_empty());
+        }
+    }
+
+    #[test]
+    fn chunked_prefill_matches_single_shot_prefill() {
+        let single = tiny_generator();
+        let chunked = Generator::new(Arc::clone(&single.model), Device::Cpu).with_prefill_chunk_size(3);
+        let prompt: Vec<i64> = (0..10).collect();
+        let new_caches = || -> Vec<claude_core::kv_cache::KVCache> {
+            let config = &single.model.config;
+            (0..config.n_layer)
+                .map(|_| claude_core::kv_cache::KVCache::new(
+                    config.max_seq_len as usize,
+                    config.n_head,
+                    config.n_embd / config.n_head,
+                    Device::Cpu,
+                    tch::Kind::Float,
+                ))
+                .collect()
+        };
+
+        let mut single_caches = new_caches();
+        let mut chunked_caches = new_caches();
+        let single_logits = single.prefill(&prompt, &mut single_caches).expect("prefill").i((0, -1, ..));
+        let chunked_logits = chunked.prefill(&prompt, &mut chunked_caches).expect("prefill").i((0, -1, ..));
+
+        assert_eq!(chunked_caches[0].length, prompt.len());
+        assert!(chunked_logits.allclose(&single_logits, 1e-5, 1e-5, false));
+    }
+
+    #[test]
+    fn half_precision_kv_cache_stays_close_to_full_precision() {
+        let full = tiny_generator().with_prefill_chunk_size(4);
+        let half = Generator::new(Arc::clone(&full.model), Device::Cpu)
+            .with_prefill_chunk_size(4)
+            .with_kv_cache_dtype(Kind::Half);
+        let prompt: Vec<i64> = (0..10).collect();
+
+        // Later chunks attend over the cached keys and values of earlier ones.
+        let mut full_session = full.new_session();
+        let mut half_session = half.new_session();
+        let full_logits = full.prefill(&prompt, &mut full_session.caches).expect("prefill").i((0, -1, ..));
+        let half_logits = half.prefill(&prompt, &mut half_session.caches).expect("prefill").i((0, -1, ..));
+
+        assert_eq!(half_session.caches[0].k.kind(), Kind::Half);
+        assert_eq!(half_logits.kind(), Kind::Float);
+        assert_eq!(half_logits.isfinite().all().int64_value(&[]), 1);
+        assert!(half_logits.allclose(&full_logits, 1e-2, 1e-2, false));
+
+        let mut half = half;
+        let params = SamplingParams::default();
+        let (tx, _rx) = tokio::sync::mpsc::channel(64);
+        let reason = half
+            .generate_stream(&prompt, 4, &params, StreamOptions::default(), tx)
+            .expect("generate with half cache");
+        assert_eq!(reason, FinishReason::Length);
+    }
+
+    #[test]
+    fn generate_session_continues_without_reprefilling_history() {
+        let mut generator = tiny_generator();
+        let params = SamplingParams { temperature: 0.0, repetition_penalty: 1.0, ..Default::default() };
+        let collect = |mut rx: tokio::sync::mpsc::Receiver<i64>| {
+            let mut tokens = Vec::new();
+            while let Ok(token) = rx.try_recv() {
+                tokens.push(token);
+            }
+            tokens
+        };
+
+        let mut session = generator.new_session();
+        let (tx, rx) = tokio::sync::mpsc::channel(64);
+        let first = generator
+            .generate_session(&mut session, &[1, 2, 3], 4, &params, None, tx)
+            .expect("first turn");
+        assert_eq!(first.prefilled_tokens, 3);
+        let first_reply = collect(rx);
+        assert_eq!(session.tokens.len(), 3 + first_reply.len());
+
+        let history = session.tokens.clone();
+        let (tx, rx) = tokio::sync::mpsc::channel(64);
+        let second = generator
+            .generate_session(&mut session, &[4, 5], 4, &params, None, tx)
+            .expect("second turn");
+        let second_reply = collect(rx);
+
+        // Only the last sampled token of the first reply and the new turn are prefilled.
+        assert_eq!(second.prefilled_tokens, 1 + 2);
+        assert_eq!(session.cached_len(), session.tokens.len() - 1);
+
+        // Greedy decoding over the whole transcript from scratch gives the same reply.
+        let full_prompt: Vec<i64> = history.iter().chain(&[4, 5]).copied().collect();
+        let (tx, rx) = tokio::sync::mpsc::channel(64);
+        generator
+            .generate_stream(&full_prompt, 4, &params, StreamOptions::default(), tx)
+            .expect("fresh generation");
+        let fresh_reply = collect(rx);
+        assert_eq!(second_reply, fresh_reply);
+    }
+
+    #[test]
+    fn eos_bias_controls_early_stopping() {
+        let vs = tch::nn::VarStore::new(Device::Cpu);
+        let config = ModelConfig {
+            eos_token_id: Some(0),
+            ..ModelConfig::tiny(16)
+        };
+        let model = ClaudeTransformer::new_seeded(&vs.root(), &config, 0);
+        let mut generator = Generator::new(Arc::new(model), Device::Cpu);
+        let run = |generator: &mut Generator, eos_bias: f64| {
+            let params = SamplingParams { temperature: 1.0, eos_bias, ..Default::default() };
+            let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+            let reason = generator
+                .generate_stream(&[1, 2, 3], 16, &params, StreamOptions::default(), tx)
+                .expect("generate stream");
+            let mut generated = Vec::new();
+            while let Ok(token) = rx.try_recv() {
+                generated.push(token);
+            }
+            (reason, generated)
+        };
+
+        let (reason, generated) = run(&mut generator, 1e4);
+        assert_eq!(reason, FinishReason::Stop);
+        assert!(generated.is_empty());
+
+        let (reason, generated) = run(&mut generator, -1e4);
+        assert_eq!(reason, FinishReason::Length);
+        assert!(!generated.contains(&0));
+        assert_eq!(generated.len(), 16);
+    }
+
+    #[test]
+    fn sampling_eos_token_stops_generation_without_being_emitted() {
+        // The tiny model's config has no EOS token; the sampling parameters supply one.
+        let mut generator = tiny_generator();
+        let params = SamplingParams { temperature: 1.0, eos_bias: 1e4, eos_token_id: Some(0), ..Default::default() };
+        let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+        let reason = generator
+            .generate_stream(&[1, 2, 3], 16, &params, StreamOptions::default(), tx)
+            .expect("generate stream");
+
+        // The very first token, sampled right after the prefill, is already EOS.
+        assert_eq!(reason, FinishReason::Stop);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn generate_stream_stops_at_deadline() {
+        let mut generator = tiny_generator();
+        let params = SamplingParams::default();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+
+        let reason = generator
+            .generate_stream(
+                &[1, 2, 3],
+                32,
+                &params,
+                StreamOptions { deadline: Some(Instant::now()), ..Default::default() },
+                tx,
+            )
+            .expect("generate stream");
+
+        assert_eq!(reason, FinishReason::Timeout);
+        assert!(rx.try_recv().is_err(), "no tokens after the deadline");
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn generate_stream_runs_in_a_span_with_prompt_and_budget_fields() {
+        let mut generator = tiny_generator();
+        let params = SamplingParams::default();
+        let (tx, _rx) = tokio::sync::mpsc::channel(64);
+
+        generator
+            .generate_stream(&[1, 2, 3], 4, &params, StreamOptions::default(), tx)
+            .expect("generate stream");
+
+        assert!(logs_contain("generate_stream{prompt_len=3 max_new_tokens=4}"));
+    }
+
+    #[test]
+    fn generate_stream_without_deadline_finishes_on_length() {
+        let mut generator = tiny_generator();
+        let params = SamplingParams::default();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+        let far_future = Instant::now() + std::time::Duration::from_secs(3600);
+
+        let reason = generator
+            .generate_stream(
+                &[1, 2, 3],
+                4,
+                &params,
+                StreamOptions { deadline: Some(far_future), ..Default::default() },
+                tx,
+            )
+            .expect("generate stream");
+
+        assert_eq!(reason, FinishReason::Length);
+        let mut received = 0;
+        while rx.try_recv().is_ok() {
+            received += 1;
+        }
+        assert!(received > 0);
+    }
+
+    #[test]
+    fn generate_stream_trace_records_every_sampled_token() {
+        let mut generator = tiny_generator();
+        let params = SamplingParams { temperature: 1.0, top_k: 0, top_p: 1.0, ..Default::default() };
+        let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+        let mut trace = Vec::new();
+
+        generator
+            .generate_stream(
+                &[1, 2, 3],
+                6,
+                &params,
+                StreamOptions { trace: Some(&mut trace), ..Default::default() },
+                tx,
+            )
+            .expect("generate stream");
+
+        let mut generated = Vec::new();
+        while let Ok(token) = rx.try_recv() {
+            generated.push(token);
+        }
+        assert_eq!(trace.len(), generated.len());
+        for (i, (step, &token)) in trace.iter().zip(&generated).enumerate() {
+            assert_eq!(step.step, i);
+            assert_eq!(step.token, token);
+            assert!(step.candidates.iter().any(|&(id, _)| id == token));
+            assert!(step.draw.is_some_and(|draw| (0.0..1.0).contains(&draw)));
+        }
+    }
+
+    #[test]
+    fn score_matches_the_log_probs_of_sampled_tokens() {
+        let mut generator = tiny_generator();
+        let params = SamplingParams {
+            temperature: 1.0,
+            top_k: 0,
+            top_p: 1.0,
+            repetition_penalty: 1.0,
+            ..Default::default()
+        };
+        let prompt = [1, 2, 3];
+        let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+        let mut trace = Vec::new();
+        generator
+            .generate_stream(&prompt, 5, &params, StreamOptions { trace: Some(&mut trace), ..Default::default() }, tx)
+            .expect("generate stream");
+        let mut sequence = prompt.to_vec();
+        while let Ok(token) = rx.try_recv() {
+            sequence.push(token);
+        }
+
+        let scores = generator.score(&sequence).expect("score");
+
+        assert_eq!(scores.len(), sequence.len());
+        assert!(scores[0].is_nan());
+        let step_wise: Vec<f64> = trace
+            .iter()
+            .map(|step| {
+                let (_, prob) = step.candidates.iter().find(|&&(id, _)| id == step.token).expect("sampled token");
+                prob.ln()
+            })
+            .collect();
+        let scored = &scores[prompt.len()..];
+        assert_eq!(scored.len(), step_wise.len());
+        for (scored, step) in scored.iter().zip(&step_wise) {
+            assert!((scored - step).abs() < 1e-4, "{scored} vs {step}");
+        }
+        let total: f64 = scored.iter().sum();
+        assert!((total - step_wise.iter().sum::<f64>()).abs() < 1e-3);
+        assert!(generator.score(&[]).is_err());
+    }
+
+    #[test]
+    fn blocking_backpressure_delivers_every_token_to_a_slow_consumer() {
+        let params = SamplingParams { temperature: 1.0, top_k: 0, top_p: 1.0, ..Default::default() };
+        let run = |backpressure: Backpressure, slow_consumer: bool| {
+            let mut generator = tiny_generator().with_backpressure(backpressure);
+            let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+            let consumer = std::thread::spawn(move || {
+                let mut received = Vec::new();
+                if slow_consumer {
+                    while let Some(token) = rx.blocking_recv() {
+                        std::thread::sleep(std::time::Duration::from_millis(2));
+                        received.push(token);
+                    }
+                }
+                (received, rx)
+            });
+            let mut trace = Vec::new();
+            let reason = generator
+                .generate_stream(
+                    &[1, 2, 3],
+                    8,
+                    &params,
+                    StreamOptions { trace: Some(&mut trace), ..Default::default() },
+                    tx,
+                )
+                .expect("generate stream");
+            let (mut received, mut rx) = consumer.join().expect("consumer thread");
+            while let Ok(token) = rx.try_recv() {
+                received.push(token);
+            }
+            let sampled: Vec<i64> = trace.iter().map(|step| step.token).collect();
+            (reason, sampled, received)
+        };
+
+        let (reason, sampled, received) = run(Backpressure::Block, true);
+        assert_eq!(reason, FinishReason::Length);
+        assert_eq!(received, sampled);
+
+        // Nobody reads until generation is over, so only the first token fits in the channel.
+        let (reason, sampled, received) = run(Backpressure::DropOnFull, false);
+        assert_eq!(reason, FinishReason::Length);
+        assert!(sampled.len() > 1);
+        assert_eq!(received, sampled[..1]);
+    }
+
+    #[test]
+    fn contrastive_search_generates_the_requested_length() {
+        let collect = |generator: &mut Generator, params: &SamplingParams| {
+            let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+            let reason = generator
+                .generate_stream(&[1, 2, 3], 6, params, StreamOptions::default(), tx)
+                .expect("generate stream");
+            let mut received = Vec::new();
+            while let Ok(token) = rx.try_recv() {
+                received.push(token);
+            }
+            (reason, received)
+        };
+        assert!(tiny_generator().with_contrastive(Some((0, 0.5))).is_err());
+        assert!(tiny_generator().with_contrastive(Some((4, 1.5))).is_err());
+
+        let mut contrastive = tiny_generator().with_contrastive(Some((4, 0.6))).expect("valid options");
+        let (reason, first) = collect(&mut contrastive, &SamplingParams::default());
+        assert_eq!(reason, FinishReason::Length);
+        assert_eq!(first.len(), 6);
+        assert_eq!(collect(&mut contrastive, &SamplingParams::default()).1, first);
+
+        // Without the degeneration penalty it is greedy decoding.
+        let mut no_penalty = tiny_generator().with_contrastive(Some((4, 0.0))).expect("valid options");
+        let greedy = SamplingParams {
+            temperature: 0.0,
+            repetition_penalty: 1.0,
+            ..Default::default()
+        };
+        assert_eq!(collect(&mut no_penalty, &greedy).1, collect(&mut tiny_generator(), &greedy).1);
+    }
+
+    #[test]
+    fn outgrowing_a_small_kv_cache_stops_with_context_full() {
+        let mut generator = tiny_generator().with_kv_cache_capacity(6);
+        assert_eq!(generator.new_session().caches[0].max_capacity, 6);
+        let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+
+        let reason = generator
+            .generate_stream(&[1, 2, 3], 8, &SamplingParams::default(), StreamOptions::default(), tx)
+            .expect("generate stream");
+
+        assert_eq!(reason, FinishReason::ContextFull);
+        let mut received = 0;
+        while rx.try_recv().is_ok() {
+            received += 1;
+        }
+        // The caches hold the prompt plus the first three sampled tokens; the fourth can be
+        // sampled but not fed back.
+        assert_eq!(received, 4);
+
+        let batch = generator
+            .generate_batch(&[vec![1, 2, 3]], 8, &SamplingParams::default(), OverflowPolicy::Error, 0)
+            .expect("generate batch");
+        assert_eq!(batch[0].len(), 4);
+    }
+
+    #[test]
+    fn padded_vocab_logits_are_never_sampled() {
+        let config = ModelConfig {
+            pad_vocab_to: Some(64),
+            ..ModelConfig::tiny(10)
+        };
+        let vs = tch::nn::VarStore::new(Device::Cpu);
+        let model = ClaudeTransformer::new_seeded(&vs.root(), &config, 0);
+        let logits = model.forward(&Tensor::from_slice(&[1i64, 2, 3]).view([1, 3]), None).expect("forward");
+        assert_eq!(logits.size(), vec![1, 3, 64]);
+        assert_eq!(logits.narrow(-1, 10, 54).isinf().all().int64_value(&[]), 1);
+
+        let mut generator = Generator::new(Arc::new(model), Device::Cpu);
+        // A flat distribution over the whole (padded) vocab would pick padding often.
+        let params = SamplingParams {
+            temperature: 5.0,
+            top_k: 0,
+            top_p: 1.0,
+            repetition_penalty: 1.0,
+            ..Default::default()
+        };
+        let outputs = generator
+            .generate_batch(&[vec![1, 2, 3], vec![4, 5], vec![6], vec![7, 8, 9]], 40, &params, OverflowPolicy::Error, 7)
+            .expect("generate batch");
+
+        assert!(outputs.iter().flatten().all(|&token| (0..10).contains(&token)), "{outputs:?}");
+    }
+}
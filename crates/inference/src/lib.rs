@@ -1,17 +1,36 @@
 use anyhow::{Result, Context};
 use tch::Device;
 
+pub mod batching;
+pub mod chat_config;
+pub mod checkpoints;
+pub mod cli;
 pub mod kv_cache;
 pub mod sampling;
 pub mod server;
+pub mod session;
+pub mod streaming;
+pub mod templates;
 pub mod generator;
+#[cfg(test)]
+mod test_util;
 
 // Re-export common types
+pub use claude_core::device::resolve_device;
+pub use batching::{BatchConfig, BatchOutcome, Batcher};
+pub use chat_config::ChatConfig;
+pub use checkpoints::{average_checkpoints, list_checkpoints, CheckpointFormat, CheckpointInfo};
 pub use kv_cache::KVCache;
-pub use sampling::{Sampler, SamplingParams};
-pub use generator::Generator;
+pub use sampling::{Sampler, SamplingParams, StepTrace, MIN_SAMPLING_TEMPERATURE};
+pub use generator::{
+    Backpressure, FinishReason, Generator, OverflowPolicy, SessionTurn, StreamOptions, StreamRequest,
+};
+pub use session::{SessionState, SessionStore};
+pub use streaming::{StopSequences, StreamGranularity, TextChunker, TextStream};
+pub use templates::{template_by_name, ChatMessage, ChatTemplate, Role, TEMPLATE_NAMES};
 
 /// Helper function to load model from checkpoint
+#[tracing::instrument(skip_all, fields(dir = %dir.display(), ?device))]
 pub fn load_model(dir: &std::path::Path, device: Device) -> Result<claude_core::ClaudeTransformer> {
     let config_path = dir.join("config.json");
     
@@ -21,28 +40,27 @@ pub fn load_model(dir: &std::path::Path, device: Device) -> Result<claude_core::
     let config: claude_core::ModelConfig = serde_json::from_str(&config_str)
         .context("Failed to parse model config.json")?;
         
-    // 2. Find latest checkpoint
-    let mut checkpoint_path = None;
-    if let Ok(entries) = std::fs::read_dir(dir) {
-        let mut checkpoints: Vec<_> = entries
-            .filter_map(|e| e.ok())
-            .filter(|e| e.path().extension().map_or(false, |ext| ext == "safetensors"))
-            .collect();
-        
-        checkpoints.sort_by_key(|e| e.path());
-        checkpoint_path = checkpoints.last().map(|e| e.path());
-    }
+    // 2. Find latest checkpoint (numeric epoch/step order, so epoch_10 beats epoch_9)
+    let checkpoint_path = list_checkpoints(dir)
+        .into_iter()
+        .rev()
+        .find(|c| c.format == CheckpointFormat::Safetensors)
+        .map(|c| c.path);
 
     // 3. Initialize Model
     let mut vs = tch::nn::VarStore::new(device);
     let model = claude_core::ClaudeTransformer::new(&vs.root(), &config);
     
-    if let Some(path) = checkpoint_path {
-        println!("Loading weights from {:?}", path);
+    if dir.join(claude_core::safetensors_util::SHARD_INDEX_FILE).exists() {
+        tracing::info!("loading sharded weights");
+        claude_core::safetensors_util::load_safetensors_sharded(&mut vs, dir)
+            .context("Failed to load sharded safetensors checkpoint")?;
+    } else if let Some(path) = checkpoint_path {
+        tracing::info!(checkpoint = %path.display(), "loading weights");
         claude_core::safetensors_util::load_safetensors(&mut vs, path)
             .context("Failed to load safetensors checkpoint")?;
     } else {
-        println!("Warning: No .safetensors checkpoint found in {:?}. Using random weights.", dir);
+        tracing::warn!("no .safetensors checkpoint found, using random weights");
     }
     
     Ok(model)
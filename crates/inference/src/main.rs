@@ -1,112 +1,955 @@
-use axum::{extract::State, routing::post, Json, Router};
-use claude_core::ClaudeTransformer;
-use inference::{load_model, Generator, SamplingParams};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use clap::Parser;
+use claude_core::{ClaudeTransformer, ModelConfig};
+use inference::{
+    load_model, resolve_device, template_by_name, BatchConfig, Batcher, ChatMessage, ChatTemplate,
+    FinishReason, Generator, OverflowPolicy, SamplingParams, SessionState, SessionStore,
+    StopSequences, StreamGranularity, StreamRequest, TextStream, TEMPLATE_NAMES,
+};
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 use tch::Device;
-use tokenizer::BPE;
+use tokenizer::{StreamDecoder, BPE};
+use uuid::Uuid;
+
+/// Sessions idle for longer than this are dropped along with their KV caches.
+const SESSION_TTL: Duration = Duration::from_secs(10 * 60);
 
 #[derive(Clone)]
 struct AppState {
-    model: Arc<ClaudeTransformer>,
+    /// Swapped wholesale by `/reload`; requests clone the inner `Arc` and keep using
+    /// the model they started with.
+    model: Arc<RwLock<Arc<ClaudeTransformer>>>,
     tokenizer: Arc<BPE>,
     device: Device,
+    checkpoint_dir: PathBuf,
+    sessions: Arc<Mutex<SessionStore>>,
+    /// Runs single-completion requests without a session in batches.
+    batcher: Batcher,
+    /// Renders the `messages` of chat requests into a prompt.
+    template: Arc<dyn ChatTemplate>,
+    /// Stream untyped `data:` events as before typed [`SseEvent`]s existed.
+    legacy_sse: bool,
 }
 
-#[derive(Deserialize)]
+impl AppState {
+    fn current_model(&self) -> Arc<ClaudeTransformer> {
+        Arc::clone(&self.model.read().expect("model lock poisoned"))
+    }
+}
+
+#[derive(Deserialize, Default)]
 struct GenRequest {
+    #[serde(default)]
     prompt: String,
+    /// Chat turns to render with the server's `--template` and use instead of `prompt`.
+    messages: Option<Vec<ChatMessage>>,
     max_new_tokens: Option<usize>,
     max_input_tokens: Option<usize>,
+    /// Must be >= 0; 0 means greedy decoding.
     temperature: Option<f64>,
+    /// Keep only the `top_k` most likely tokens (0 disables); at most the model's vocab size.
+    top_k: Option<usize>,
+    /// Clamped to at most 1; must be > 0.
     top_p: Option<f64>,
+    /// Added to the EOS token's logit: positive stops sooner, negative runs longer.
+    eos_bias: Option<f64>,
+    /// Token IDs that are never generated.
+    suppress_tokens: Option<Vec<i64>>,
+    /// End the completion as soon as its text contains one of these strings (finish reason
+    /// `stop`). The stop string itself is not returned.
+    stop: Option<Vec<String>>,
+    /// Never generate the tokenizer's special tokens (padding, `<UNK>`, ...), except EOS.
+    #[serde(default)]
+    suppress_special: bool,
+    /// How to handle prompts longer than the context window (default: `error`).
+    overflow_policy: Option<OverflowPolicy>,
+    /// Stop generating after this many milliseconds.
+    timeout_ms: Option<u64>,
+    /// Stream tokens as SSE events (default) or return a single JSON response.
+    stream: Option<bool>,
+    /// Also return the raw token IDs (per event when streaming with `token` granularity).
+    #[serde(default)]
+    include_token_ids: bool,
+    /// When streaming, attach `ms_since_start` and `inter_token_ms` to every event and end
+    /// the stream with a `timing` summary event.
+    #[serde(default)]
+    include_timing: bool,
+    /// When streaming, flush events per `token` (default), `word` or `sentence`.
+    stream_granularity: Option<StreamGranularity>,
+    /// Continue the conversation stored under this id, reusing its KV cache. An unknown
+    /// (new or expired) id starts a fresh session under that id.
+    session_id: Option<Uuid>,
+    /// Number of alternative completions to sample (default 1, at most [`MAX_CHOICES`]).
+    /// More than one needs `stream: false` and no `session_id`.
+    n: Option<usize>,
 }
 
+/// Upper bound on a request's `n`.
+const MAX_CHOICES: usize = 16;
+
+/// `text`, `finish_reason` and `token_ids` repeat the first of `choices`.
 #[derive(Serialize)]
-#[allow(dead_code)]
 struct GenResponse {
     text: String,
+    finish_reason: FinishReason,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_ids: Option<Vec<i64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prompt_token_ids: Option<Vec<i64>>,
+    choices: Vec<Choice>,
+}
+
+/// One of the `n` completions of a non-streaming request.
+#[derive(Serialize)]
+struct Choice {
+    index: usize,
+    text: String,
+    finish_reason: FinishReason,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_ids: Option<Vec<i64>>,
+}
+
+/// SSE payload when `include_token_ids` or `include_timing` is set, and of every typed
+/// `token` event.
+#[derive(Serialize, Deserialize, Debug)]
+struct TokenEvent {
+    token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<i64>,
+    #[serde(flatten)]
+    timing: Option<TokenTiming>,
+}
+
+/// A streamed event whose `event:` field names its kind and whose `data:` is JSON.
+#[derive(Debug)]
+enum SseEvent {
+    /// Generated text, as in the legacy stream.
+    Token(TokenEvent),
+    /// Sent once, when the first token arrives.
+    Meta(MetaEvent),
+    /// Sent just before `done` if generation failed.
+    Error(ErrorEvent),
+    /// Always the last event.
+    Done(DoneEvent),
+}
+
+impl SseEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            SseEvent::Token(_) => "token",
+            SseEvent::Meta(_) => "meta",
+            SseEvent::Error(_) => "error",
+            SseEvent::Done(_) => "done",
+        }
+    }
+
+    fn into_event(self) -> Event {
+        let event = Event::default().event(self.name());
+        match self {
+            SseEvent::Token(payload) => event.json_data(payload),
+            SseEvent::Meta(payload) => event.json_data(payload),
+            SseEvent::Error(payload) => event.json_data(payload),
+            SseEvent::Done(payload) => event.json_data(payload),
+        }
+        .expect("SSE payload serializes")
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct MetaEvent {
+    prompt_tokens: usize,
+    /// Time from the request arriving to the first generated token.
+    first_token_ms: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ErrorEvent {
+    error: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct DoneEvent {
+    /// `None` if generation failed; the preceding `error` event says why.
+    finish_reason: Option<FinishReason>,
+    tokens: usize,
+    /// Only with `include_timing`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timing: Option<TimingSummary>,
+}
+
+/// When an SSE event was sent, relative to the start of the request and to the previous event.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+struct TokenTiming {
+    ms_since_start: f64,
+    inter_token_ms: f64,
+}
+
+/// Payload of the final `timing` SSE event.
+#[derive(Serialize, Deserialize, Debug)]
+struct TimingSummary {
+    /// Time until the first generated token arrived, including queueing and the prompt pass.
+    prefill_ms: f64,
+    total_ms: f64,
+    tokens: usize,
+    tokens_per_sec: f64,
+}
+
+/// Timestamps the tokens and events of one streamed response.
+struct StreamTimer {
+    start: Instant,
+    last_event: Instant,
+    first_token: Option<Instant>,
+    tokens: usize,
+}
+
+impl StreamTimer {
+    fn new(start: Instant) -> Self {
+        Self {
+            start,
+            last_event: start,
+            first_token: None,
+            tokens: 0,
+        }
+    }
+
+    fn record_token(&mut self) {
+        self.first_token.get_or_insert_with(Instant::now);
+        self.tokens += 1;
+    }
+
+    fn event(&mut self) -> TokenTiming {
+        let now = Instant::now();
+        let timing = TokenTiming {
+            ms_since_start: millis(now - self.start),
+            inter_token_ms: millis(now - self.last_event),
+        };
+        self.last_event = now;
+        timing
+    }
+
+    fn summary(&self) -> TimingSummary {
+        let total = self.start.elapsed();
+        TimingSummary {
+            prefill_ms: self.first_token.map_or(0.0, |first| millis(first - self.start)),
+            total_ms: millis(total),
+            tokens: self.tokens,
+            tokens_per_sec: if total.is_zero() {
+                0.0
+            } else {
+                self.tokens as f64 / total.as_secs_f64()
+            },
+        }
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[derive(Deserialize)]
+struct ScoreRequest {
+    text: String,
+}
+
+#[derive(Serialize)]
+struct ScoreResponse {
+    token_ids: Vec<i64>,
+    /// Log-probability of each token given the ones before it; `null` for the first token.
+    logprobs: Vec<Option<f64>>,
+    /// Sum of the non-null `logprobs`.
+    total_logprob: f64,
+}
+
+#[derive(Deserialize, Default)]
+struct ReloadRequest {
+    checkpoint_dir: Option<PathBuf>,
+}
+
+#[derive(Serialize)]
+struct ModelInfo {
+    config: ModelConfig,
+    tokenizer_vocab_size: usize,
 }
 
 use axum::response::sse::{Event, Sse};
-use futures::stream::{self, Stream};
+use axum::response::{IntoResponse, Response};
+use futures::stream::{self, BoxStream, StreamExt};
 use std::convert::Infallible;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 
-async fn generate_handler(
-    State(state): State<AppState>,
-    Json(req): Json<GenRequest>,
-) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
-    let mut generator = Generator::new(Arc::clone(&state.model), state.device);
+/// A validated generation request, ready to run.
+struct PreparedRequest {
+    generator: Generator,
+    params: SamplingParams,
+    input_ids: Vec<i64>,
+    max_tokens: usize,
+    overflow: OverflowPolicy,
+    deadline: Option<std::time::Instant>,
+    /// The session this turn continues, and the store it is written back to afterwards.
+    session: Option<(Uuid, SessionState)>,
+    sessions: Arc<Mutex<SessionStore>>,
+    /// Decodes a session's reply to cut it at `stop` before it is stored.
+    tokenizer: Arc<BPE>,
+    stop: StopSequences,
+    batcher: Batcher,
+}
+
+fn prepare_request(state: &AppState, req: &GenRequest) -> Result<PreparedRequest, (StatusCode, String)> {
+    let model = state.current_model();
+    let vocab_size = model.config.vocab_size as usize;
+    let eos_token_id = model.config.eos_token_id;
+    let generator = Generator::new(model, state.device);
     let mut params = SamplingParams::default();
     if let Some(t) = req.temperature {
         params.temperature = t;
     }
+    if let Some(k) = req.top_k {
+        if k > vocab_size {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("top_k must be at most the vocab size ({}), got {}", vocab_size, k),
+            ));
+        }
+        params.top_k = k;
+    }
     if let Some(p) = req.top_p {
         params.top_p = p;
     }
+    if let Some(bias) = req.eos_bias {
+        params.eos_bias = bias;
+    }
+    if let Some(tokens) = &req.suppress_tokens {
+        params.suppress_tokens = tokens.clone();
+    }
+    if req.suppress_special {
+        params.suppress_special_tokens(&state.tokenizer, eos_token_id);
+    }
+    params
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    let n = req.n.unwrap_or(1);
+    if n == 0 || n > MAX_CHOICES {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("n must be between 1 and {}, got {}", MAX_CHOICES, n),
+        ));
+    }
+    if n > 1 && req.stream.unwrap_or(true) {
+        return Err((StatusCode::BAD_REQUEST, "n > 1 requires \"stream\": false".to_string()));
+    }
+    if n > 1 && req.session_id.is_some() {
+        return Err((StatusCode::BAD_REQUEST, "n > 1 can't continue a session".to_string()));
+    }
 
-    let max_tokens = req.max_new_tokens.unwrap_or(50);
-    let max_input_tokens = req.max_input_tokens.unwrap_or(1024);
+    let session = req.session_id.map(|id| {
+        let stored = state.sessions.lock().expect("session store poisoned").checkout(&id);
+        (id, stored.unwrap_or_else(|| generator.new_session()))
+    });
+    // The history of a session takes up part of the context window.
+    let history_len = session.as_ref().map_or(0, |(_, session)| session.tokens.len());
+    let remaining_context = generator.context_limit().saturating_sub(history_len);
+    if session.is_some() && remaining_context == 0 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "session has filled the context window; start a new session".to_string(),
+        ));
+    }
 
-    let input_ids: Vec<i64> = state
+    let max_input_tokens = req
+        .max_input_tokens
+        .unwrap_or(1024)
+        .min(remaining_context);
+    let overflow = req.overflow_policy.unwrap_or_default();
+
+    let rendered;
+    let prompt = match &req.messages {
+        Some(messages) => {
+            rendered = state.template.render(messages, true);
+            &rendered
+        }
+        None => &req.prompt,
+    };
+    let prompt_ids: Vec<i64> = state
         .tokenizer
-        .encode_with_max_tokens(&req.prompt, max_input_tokens)
+        .encode(prompt)
         .iter()
         .map(|&id| id as i64)
         .collect();
+    let input_ids = overflow
+        .apply(&prompt_ids, max_input_tokens)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    Ok(PreparedRequest {
+        generator,
+        params,
+        input_ids,
+        max_tokens: req.max_new_tokens.unwrap_or(50),
+        overflow,
+        deadline: req
+            .timeout_ms
+            .map(|ms| std::time::Instant::now() + std::time::Duration::from_millis(ms)),
+        session,
+        sessions: Arc::clone(&state.sessions),
+        tokenizer: Arc::clone(&state.tokenizer),
+        stop: stop_sequences(req),
+        batcher: state.batcher.clone(),
+    })
+}
+
+/// Runs generation in the background, returning the token channel and a handle that
+/// resolves once generation has finished. Session turns run on their own blocking worker;
+/// everything else goes through the batcher.
+fn spawn_generation(
+    prepared: PreparedRequest,
+) -> (mpsc::Receiver<i64>, JoinHandle<anyhow::Result<FinishReason>>) {
+    let PreparedRequest {
+        mut generator,
+        params,
+        input_ids,
+        max_tokens,
+        overflow,
+        deadline,
+        session,
+        sessions,
+        tokenizer,
+        stop,
+        batcher,
+    } = prepared;
+    let (tx, rx) = mpsc::channel(max_tokens.max(1));
 
-    if input_ids.is_empty() {
-        let stream = stream::iter([Ok(Event::default().data(""))]);
-        return Sse::new(stream);
+    let handle = tokio::spawn(async move {
+        let result = match session {
+            Some((id, mut session)) => tokio::task::spawn_blocking(move || {
+                let reply_start = session.tokens.len() + input_ids.len();
+                let result = generator
+                    .generate_session(&mut session, &input_ids, max_tokens, &params, deadline, tx)
+                    .map(|turn| {
+                        tracing::debug!(%id, prefilled = turn.prefilled_tokens, "session turn finished");
+                        turn.finish_reason
+                    });
+                if result.is_ok() {
+                    trim_reply_at_stop(&mut session, reply_start, &tokenizer, &stop);
+                    sessions.lock().expect("session store poisoned").insert(id, session);
+                }
+                result
+            })
+            .await?,
+            None => {
+                let request = StreamRequest {
+                    prompt_ids: input_ids,
+                    max_new_tokens: max_tokens,
+                    params,
+                    overflow,
+                    deadline,
+                    tx,
+                };
+                batcher
+                    .submit(request)
+                    .await
+                    .map_err(|_| anyhow::anyhow!("batcher has shut down"))?
+                    .map(|outcome| {
+                        tracing::debug!(batch_size = outcome.batch_size, "batched request finished");
+                        outcome.finish_reason
+                    })
+            }
+        };
+        if let Ok(FinishReason::Timeout) = result {
+            tracing::warn!("generation stopped early: request timeout reached");
+        }
+        result
+    });
+
+    (rx, handle)
+}
+
+/// Cuts the reply that starts at `reply_start` back to the tokens that decode to text before
+/// its first stop string, so that the stored history ends where the client's reply did
+/// rather than wherever the generator stopped. A token that straddles the stop string is
+/// dropped along with it.
+fn trim_reply_at_stop(session: &mut SessionState, reply_start: usize, tokenizer: &BPE, stop: &StopSequences) {
+    let mut decoder = StreamDecoder::new();
+    let mut text = String::new();
+    let mut text_ends = Vec::new();
+    for &id in &session.tokens[reply_start..] {
+        text.push_str(&decoder.push(tokenizer, id as u32));
+        text_ends.push(text.len());
+        if let Some(pos) = stop.find(&text) {
+            let kept = text_ends.iter().take_while(|&&end| end <= pos).count();
+            session.truncate(reply_start + kept);
+            return;
+        }
     }
+}
 
-    let (tx, rx) = tokio::sync::mpsc::channel(max_tokens + 1);
+async fn generate_handler(
+    State(state): State<AppState>,
+    Json(req): Json<GenRequest>,
+) -> Result<Response, (StatusCode, String)> {
+    if req.stream.unwrap_or(true) {
+        generate_sse(&state, &req).map(IntoResponse::into_response)
+    } else {
+        generate_text(&state, &req).await.map(IntoResponse::into_response)
+    }
+}
 
-    let input_ids_clone = input_ids.clone();
+type EventStream = BoxStream<'static, Result<Event, Infallible>>;
 
-    tokio::task::spawn_blocking(move || {
-        let _ = generator.generate_stream(&input_ids_clone, max_tokens, &params, tx);
-    });
+fn generate_sse(state: &AppState, req: &GenRequest) -> Result<Sse<EventStream>, (StatusCode, String)> {
+    let start = Instant::now();
+    let prepared = prepare_request(state, req)?;
+    let stream = if state.legacy_sse {
+        legacy_event_stream(state, req, prepared, start)
+    } else {
+        typed_event_stream(state, req, prepared, start)
+    };
+    Ok(Sse::new(stream))
+}
+
+/// State of a [`typed_event_stream`] between events.
+struct TypedStream {
+    rx: mpsc::Receiver<i64>,
+    /// Taken once generation has finished and its outcome is queued.
+    handle: Option<JoinHandle<anyhow::Result<FinishReason>>>,
+    tokenizer: Arc<BPE>,
+    text: TextStream,
+    timer: StreamTimer,
+    prompt_tokens: usize,
+    include_token_ids: bool,
+    include_timing: bool,
+    /// Events produced but not sent yet (the first token yields `meta` and `token`).
+    pending: std::collections::VecDeque<SseEvent>,
+}
+
+/// `token` events for the generated text, a `meta` event when the first token arrives and
+/// a final `done` event with the finish reason, preceded by an `error` event if generation
+/// failed.
+fn typed_event_stream(state: &AppState, req: &GenRequest, prepared: PreparedRequest, start: Instant) -> EventStream {
+    let prompt_tokens = prepared.input_ids.len();
+    if prompt_tokens == 0 {
+        let done = SseEvent::Done(DoneEvent {
+            finish_reason: Some(FinishReason::Length),
+            tokens: 0,
+            timing: None,
+        });
+        return stream::iter([Ok(done.into_event())]).boxed();
+    }
+
+    let (rx, handle) = spawn_generation(prepared);
+    let granularity = req.stream_granularity.unwrap_or_default();
+    let typed = TypedStream {
+        rx,
+        handle: Some(handle),
+        tokenizer: Arc::clone(&state.tokenizer),
+        text: TextStream::new(granularity, stop_sequences(req)),
+        timer: StreamTimer::new(start),
+        prompt_tokens,
+        include_token_ids: req.include_token_ids && granularity == StreamGranularity::Token,
+        include_timing: req.include_timing,
+        pending: Default::default(),
+    };
+    stream::unfold(typed, |mut s| async move {
+        loop {
+            if let Some(event) = s.pending.pop_front() {
+                return Some((Ok(event.into_event()), s));
+            }
+            let Some(token_id) = s.rx.recv().await else {
+                // Generation finished: flush whatever is still buffered, then report how it
+                // ended. `recv` keeps returning `None` and the stream ends after `done`.
+                if let Some(rest) = s.text.finish() {
+                    let event = typed_token(&mut s, rest, None);
+                    s.pending.push_back(event);
+                    continue;
+                }
+                let handle = s.handle.take()?;
+                let finish_reason = match generation_outcome(handle).await {
+                    Ok(_) if s.text.stopped() => Some(FinishReason::Stop),
+                    Ok(reason) => Some(reason),
+                    Err(error) => {
+                        s.pending.push_back(SseEvent::Error(ErrorEvent { error }));
+                        None
+                    }
+                };
+                s.pending.push_back(SseEvent::Done(DoneEvent {
+                    finish_reason,
+                    tokens: s.timer.tokens,
+                    timing: s.include_timing.then(|| s.timer.summary()),
+                }));
+                continue;
+            };
+            if s.text.stopped() {
+                // Tokens that were already queued when a stop string closed the channel.
+                continue;
+            }
+            let first = s.timer.first_token.is_none();
+            s.timer.record_token();
+            if first {
+                s.pending.push_back(SseEvent::Meta(MetaEvent {
+                    prompt_tokens: s.prompt_tokens,
+                    first_token_ms: s.timer.summary().prefill_ms,
+                }));
+            }
+            let chunk = s.text.push(&s.tokenizer, token_id as u32);
+            if s.text.stopped() {
+                // Ends generation; the finish reason is reported as `stop`.
+                s.rx.close();
+            }
+            if let Some(chunk) = chunk {
+                let event = typed_token(&mut s, chunk, Some(token_id));
+                s.pending.push_back(event);
+            }
+        }
+    })
+    .boxed()
+}
+
+/// How a spawned generation ended, with a failure (or a panicked task) as its message.
+async fn generation_outcome(handle: JoinHandle<anyhow::Result<FinishReason>>) -> Result<FinishReason, String> {
+    let error = match handle.await {
+        Ok(Ok(reason)) => return Ok(reason),
+        Ok(Err(e)) => format!("{:#}", e),
+        Err(e) => e.to_string(),
+    };
+    tracing::error!(%error, "generation failed");
+    Err(error)
+}
+
+fn typed_token(s: &mut TypedStream, token: String, id: Option<i64>) -> SseEvent {
+    SseEvent::Token(TokenEvent {
+        token,
+        id: id.filter(|_| s.include_token_ids),
+        timing: s.include_timing.then(|| s.timer.event()),
+    })
+}
+
+/// Untyped events for `--legacy-sse`: plain `data:` text per chunk, JSON only when token
+/// IDs or timing are attached, an `event: error` with the message if generation failed and
+/// an `event: timing` summary if requested.
+fn legacy_event_stream(state: &AppState, req: &GenRequest, prepared: PreparedRequest, start: Instant) -> EventStream {
+    if prepared.input_ids.is_empty() {
+        return stream::iter([Ok(Event::default().data(""))]).boxed();
+    }
+
+    let (rx, handle) = spawn_generation(prepared);
 
     let tokenizer = Arc::clone(&state.tokenizer);
-    let stream = stream::unfold(rx, move |mut rx| {
+    let granularity = req.stream_granularity.unwrap_or_default();
+    // Per-event token IDs only line up with the text when every token is its own event.
+    let include_token_ids = req.include_token_ids && granularity == StreamGranularity::Token;
+    let text = TextStream::new(granularity, stop_sequences(req));
+    let timer = req.include_timing.then(|| StreamTimer::new(start));
+    stream::unfold(Some((rx, text, timer, Some(handle))), move |state| {
         let tokenizer = Arc::clone(&tokenizer);
         async move {
-            match rx.recv().await {
-                Some(token_id) => {
-                    let text = tokenizer.decode(&[token_id as u32]);
-                    let event = Event::default().data(text);
-                    Some((Ok(event), rx))
+            let (mut rx, mut text, mut timer, mut handle) = state?;
+            while let Some(token_id) = rx.recv().await {
+                if text.stopped() {
+                    continue;
+                }
+                if let Some(timer) = timer.as_mut() {
+                    timer.record_token();
+                }
+                let chunk = text.push(&tokenizer, token_id as u32);
+                if text.stopped() {
+                    rx.close();
                 }
-                None => None,
+                let Some(chunk) = chunk else {
+                    continue;
+                };
+                let id = include_token_ids.then_some(token_id);
+                let event = token_event(chunk, id, timer.as_mut().map(StreamTimer::event));
+                return Some((Ok(event), Some((rx, text, timer, handle))));
             }
+            // Generation finished: flush whatever is still buffered, report a failure as an
+            // `event: error`, then send the timing summary (if requested) and end the stream.
+            // `recv` keeps returning `None`.
+            if let Some(rest) = text.finish() {
+                let event = token_event(rest, None, timer.as_mut().map(StreamTimer::event));
+                return Some((Ok(event), Some((rx, text, timer, handle))));
+            }
+            if let Some(handle) = handle.take() {
+                if let Err(error) = generation_outcome(handle).await {
+                    let event = Event::default().event("error").data(error);
+                    return Some((Ok(event), Some((rx, text, timer, None))));
+                }
+            }
+            timer.map(|timer| {
+                let event = Event::default()
+                    .event("timing")
+                    .json_data(timer.summary())
+                    .expect("timing summary serializes");
+                (Ok(event), None)
+            })
         }
-    });
+    })
+    .boxed()
+}
+
+/// A plain text event, or a JSON [`TokenEvent`] when there is an ID or timing to attach.
+fn token_event(token: String, id: Option<i64>, timing: Option<TokenTiming>) -> Event {
+    if id.is_none() && timing.is_none() {
+        return Event::default().data(token);
+    }
+    Event::default()
+        .json_data(TokenEvent { token, id, timing })
+        .expect("token event serializes")
+}
+
+async fn generate_text(state: &AppState, req: &GenRequest) -> Result<Json<GenResponse>, (StatusCode, String)> {
+    let prepared = prepare_request(state, req)?;
+    let prompt_ids = prepared.input_ids.clone();
+    let n = req.n.unwrap_or(1);
+
+    let completions = if prompt_ids.is_empty() {
+        vec![(Vec::new(), FinishReason::Length); n]
+    } else if n > 1 {
+        generate_choices(prepared, n).await?
+    } else {
+        let (mut rx, handle) = spawn_generation(prepared);
+        let mut decoder = StreamDecoder::new();
+        let mut stop = stop_sequences(req);
+        let mut token_ids = Vec::new();
+        while let Some(token_id) = rx.recv().await {
+            token_ids.push(token_id);
+            stop.push(&decoder.push(&state.tokenizer, token_id as u32));
+            if stop.stopped() {
+                // Dropping the receiver ends generation; the text is trimmed below.
+                break;
+            }
+        }
+        drop(rx);
+        let finish_reason = handle
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)))?;
+        vec![(token_ids, finish_reason)]
+    };
+
+    let stop = stop_sequences(req);
+    let choices: Vec<Choice> = completions
+        .into_iter()
+        .enumerate()
+        .map(|(index, (token_ids, mut finish_reason))| {
+            let ids: Vec<u32> = token_ids.iter().map(|&id| id as u32).collect();
+            let mut text = state.tokenizer.decode(&ids);
+            if let Some(pos) = stop.find(&text) {
+                text.truncate(pos);
+                finish_reason = FinishReason::Stop;
+            }
+            Choice {
+                index,
+                text,
+                finish_reason,
+                token_ids: req.include_token_ids.then_some(token_ids),
+            }
+        })
+        .collect();
 
-    Sse::new(stream)
+    Ok(Json(GenResponse {
+        text: choices[0].text.clone(),
+        finish_reason: choices[0].finish_reason,
+        token_ids: choices[0].token_ids.clone(),
+        prompt_token_ids: req.include_token_ids.then_some(prompt_ids),
+        choices,
+    }))
+}
+
+/// The request's stop strings, for trimming its output.
+fn stop_sequences(req: &GenRequest) -> StopSequences {
+    StopSequences::new(req.stop.iter().flatten().cloned())
+}
+
+/// Samples `n` completions of the prompt on a blocking worker. They are decoded together
+/// with [`Generator::generate_n`], each with its own seed.
+async fn generate_choices(
+    prepared: PreparedRequest,
+    n: usize,
+) -> Result<Vec<(Vec<i64>, FinishReason)>, (StatusCode, String)> {
+    let PreparedRequest {
+        mut generator,
+        params,
+        input_ids,
+        max_tokens,
+        deadline,
+        ..
+    } = prepared;
+    let base_seed = rand::random();
+    tokio::task::spawn_blocking(move || generator.generate_n(&input_ids, n, max_tokens, &params, deadline, base_seed))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)))
+}
+
+/// Scores `text` under the current model instead of generating from it.
+async fn score_handler(
+    State(state): State<AppState>,
+    Json(req): Json<ScoreRequest>,
+) -> Result<Json<ScoreResponse>, (StatusCode, String)> {
+    let token_ids: Vec<i64> = state.tokenizer.encode(&req.text).into_iter().map(i64::from).collect();
+    if token_ids.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "text encodes to no tokens".to_string()));
+    }
+    let generator = Generator::new(state.current_model(), state.device);
+    if token_ids.len() > generator.context_limit() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "text is {} tokens long, which exceeds the context limit of {} tokens",
+                token_ids.len(),
+                generator.context_limit()
+            ),
+        ));
+    }
+
+    let ids = token_ids.clone();
+    let scores = tokio::task::spawn_blocking(move || generator.score(&ids))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)))?;
+    let logprobs: Vec<Option<f64>> = scores.into_iter().map(|score| (!score.is_nan()).then_some(score)).collect();
+
+    Ok(Json(ScoreResponse {
+        total_logprob: logprobs.iter().flatten().sum(),
+        token_ids,
+        logprobs,
+    }))
+}
+
+async fn model_info_handler(State(state): State<AppState>) -> Json<ModelInfo> {
+    Json(ModelInfo {
+        config: state.current_model().config.clone(),
+        tokenizer_vocab_size: state.tokenizer.vocab.len(),
+    })
+}
+
+/// Loads a model from `checkpoint_dir` (default: the directory the server started with)
+/// and swaps it in once it has loaded and matches the tokenizer. Only the server's
+/// checkpoint directory and directories below it can be loaded, and load failures are
+/// logged rather than returned, so clients learn nothing about the rest of the filesystem.
+async fn reload_handler(
+    State(state): State<AppState>,
+    req: Option<Json<ReloadRequest>>,
+) -> Result<Json<ModelInfo>, (StatusCode, String)> {
+    let dir = match req.and_then(|Json(req)| req.checkpoint_dir) {
+        Some(dir) => checkpoint_dir_under(&state.checkpoint_dir, &dir)?,
+        None => state.checkpoint_dir.clone(),
+    };
+    let device = state.device;
+
+    let model = tokio::task::spawn_blocking(move || {
+        load_model(&dir, device).map_err(|e| {
+            tracing::error!(dir = %dir.display(), error = %format!("{:#}", e), "reload failed");
+        })
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map_err(|()| (StatusCode::BAD_REQUEST, "failed to load a model from checkpoint_dir".to_string()))?;
+
+    let tokenizer_vocab_size = state.tokenizer.vocab.len();
+    if (model.config.vocab_size as usize) < tokenizer_vocab_size {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "model vocab_size {} is smaller than the tokenizer vocabulary ({} tokens)",
+                model.config.vocab_size, tokenizer_vocab_size
+            ),
+        ));
+    }
+
+    let config = model.config.clone();
+    *state.model.write().expect("model lock poisoned") = Arc::new(model);
+    tracing::info!(?config, "reloaded model");
+
+    Ok(Json(ModelInfo {
+        config,
+        tokenizer_vocab_size,
+    }))
+}
+
+/// Resolves `requested` (following `..` and symlinks) and accepts it only if it is `root`
+/// or a directory below it.
+fn checkpoint_dir_under(root: &Path, requested: &Path) -> Result<PathBuf, (StatusCode, String)> {
+    let rejected = || {
+        (
+            StatusCode::BAD_REQUEST,
+            "checkpoint_dir must be a directory under the server's checkpoint directory".to_string(),
+        )
+    };
+    let root = root.canonicalize().map_err(|_| rejected())?;
+    let dir = requested.canonicalize().map_err(|_| rejected())?;
+    if dir.is_dir() && dir.starts_with(&root) {
+        Ok(dir)
+    } else {
+        Err(rejected())
+    }
+}
+
+#[derive(Parser)]
+struct Args {
+    /// Chat template for requests with `messages`
+    #[arg(long, default_value = "raw", value_parser = parse_template)]
+    template: String,
+    /// Stream plain `data:` events instead of typed `token`/`meta`/`done` events
+    #[arg(long)]
+    legacy_sse: bool,
+    /// Most requests decoded together in one batch
+    #[arg(long, default_value_t = BatchConfig::default().max_batch_size)]
+    max_batch_size: usize,
+    /// How long a batch waits for more requests after its first one, in milliseconds
+    #[arg(long, default_value_t = BatchConfig::default().batch_wait.as_millis() as u64)]
+    batch_wait_ms: u64,
+}
+
+/// Starts a batcher that runs every batch on whichever model `/reload` last swapped in.
+fn spawn_batcher(model: Arc<RwLock<Arc<ClaudeTransformer>>>, device: Device, config: BatchConfig) -> Batcher {
+    Batcher::spawn(config, move || {
+        let model = Arc::clone(&model.read().expect("model lock poisoned"));
+        Generator::new(model, device)
+    })
+}
+
+fn parse_template(name: &str) -> Result<String, String> {
+    template_by_name(name).map(|template| template.name().to_string()).map_err(|e| e.to_string())
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
+    let args = Args::parse();
+    let template: Arc<dyn ChatTemplate> = Arc::from(template_by_name(&args.template)?);
+    println!("Using chat template: {} (available: {})", template.name(), TEMPLATE_NAMES.join(", "));
 
-    let device = Device::cuda_if_available();
+    let device = resolve_device(Device::cuda_if_available());
     println!("Using device: {:?}", device);
 
     let checkpoint_dir = std::path::Path::new("checkpoints");
     let vocab_path = "data/vocab.json";
 
     // 1. Load Tokenizer
-    let tokenizer = if std::path::Path::new(vocab_path).exists() {
+    let vocab_bin_path = std::path::Path::new(vocab_path).with_extension("bin");
+    let tokenizer = if std::path::Path::new(vocab_path).exists() || vocab_bin_path.exists() {
         println!("Loading tokenizer from {}", vocab_path);
-        Arc::new(BPE::load(vocab_path)?)
+        let start = std::time::Instant::now();
+        let tokenizer = BPE::load_prefer_bin(vocab_path)?;
+        println!(
+            "Tokenizer loaded in {:.1} ms ({})",
+            start.elapsed().as_secs_f64() * 1000.0,
+            if vocab_bin_path.exists() { "binary" } else { "json" }
+        );
+        Arc::new(tokenizer)
     } else {
-        println!("Warning: Tokenizer not found. Server may produce garbage.");
-        Arc::new(BPE::new(
-            tokenizer::Vocab::new(),
-            std::collections::HashMap::new(),
-        ))
+        println!("Warning: Tokenizer not found. Using byte-level fallback.");
+        Arc::new(BPE::byte_level_identity())
     };
 
     // 2. Load Model
@@ -114,28 +957,53 @@ async fn main() -> anyhow::Result<()> {
         Arc::new(load_model(checkpoint_dir, device)?)
     } else {
         println!("No model found. Initializing random one.");
-        let config = claude_core::ModelConfig {
-            n_embd: 128,
-            n_head: 4,
-            n_layer: 4,
-            vocab_size: tokenizer.vocab.len() as i64,
-            max_seq_len: 512,
-            dropout: 0.0,
-            use_bias: true,
-            layer_norm_epsilon: 1e-5,
-        };
+        let config = claude_core::ModelConfig::tiny(tokenizer.vocab.len() as i64);
         let vs = tch::nn::VarStore::new(device);
-        Arc::new(ClaudeTransformer::new(&vs.root(), &config))
+        Arc::new(ClaudeTransformer::new_seeded(&vs.root(), &config, 0))
     };
 
+    let warmup = model.warmup(device, 16)?;
+    println!("Model warmed up in {:.1} ms", warmup.as_secs_f64() * 1000.0);
+
+    let batch_config = BatchConfig {
+        max_batch_size: args.max_batch_size,
+        batch_wait: Duration::from_millis(args.batch_wait_ms),
+    };
+    println!(
+        "Batching up to {} requests, waiting up to {} ms",
+        batch_config.max_batch_size, args.batch_wait_ms
+    );
+    let model = Arc::new(RwLock::new(model));
+    let batcher = spawn_batcher(Arc::clone(&model), device, batch_config);
     let state = AppState {
         model,
         tokenizer,
         device,
+        checkpoint_dir: checkpoint_dir.to_path_buf(),
+        sessions: Arc::new(Mutex::new(SessionStore::new(SESSION_TTL))),
+        batcher,
+        template,
+        legacy_sse: args.legacy_sse,
     };
 
+    // Drop idle sessions in the background so their KV caches don't pile up.
+    let sessions = Arc::clone(&state.sessions);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            let evicted = sessions.lock().expect("session store poisoned").evict_idle();
+            if evicted > 0 {
+                tracing::info!(evicted, "evicted idle sessions");
+            }
+        }
+    });
+
     let app = Router::new()
         .route("/generate", post(generate_handler))
+        .route("/score", post(score_handler))
+        .route("/model_info", get(model_info_handler))
+        .route("/reload", post(reload_handler))
         .with_state(state);
 
     let addr = SocketAddr::from(([0, 0, 0, 0], 8000));
@@ -147,3 +1015,518 @@ async fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+#[path = "test_util.rs"]
+mod test_util;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::stop_in_reply;
+    use std::fs;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tokenizer::Vocab;
+
+    fn test_state(vocab_size: usize) -> AppState {
+        let mut vocab = Vocab::new();
+        for id in 0..vocab_size {
+            vocab.insert(((b'a' + id as u8) as char).to_string(), id as u32);
+        }
+        let tokenizer = BPE::new(vocab, std::collections::HashMap::new());
+        let vs = tch::nn::VarStore::new(Device::Cpu);
+        let model = ClaudeTransformer::new(&vs.root(), &ModelConfig::tiny(vocab_size as i64));
+        let model = Arc::new(RwLock::new(Arc::new(model)));
+        let batcher = spawn_batcher(Arc::clone(&model), Device::Cpu, BatchConfig::default());
+        AppState {
+            model,
+            tokenizer: Arc::new(tokenizer),
+            device: Device::Cpu,
+            checkpoint_dir: PathBuf::from("checkpoints"),
+            sessions: Arc::new(Mutex::new(SessionStore::new(SESSION_TTL))),
+            batcher,
+            template: Arc::from(template_by_name("raw").expect("preset")),
+            legacy_sse: false,
+        }
+    }
+
+    /// A fresh checkpoint root holding a `run` directory with `config`; `state` serves from
+    /// that root. Returns the root.
+    fn checkpoint_dir_with_config(state: &mut AppState, config: &ModelConfig) -> PathBuf {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_nanos();
+        let root = std::env::temp_dir().join(format!("inference_reload_test_{unique}"));
+        fs::create_dir_all(root.join("run")).expect("create temp test dir");
+        let json = serde_json::to_string(config).expect("serialize config");
+        fs::write(root.join("run").join("config.json"), json).expect("write config.json");
+        state.checkpoint_dir = root.clone();
+        root
+    }
+
+    #[tokio::test]
+    async fn reload_swaps_model_and_updates_model_info() {
+        let mut state = test_state(16);
+        let new_config = ModelConfig {
+            n_layer: 2,
+            ..ModelConfig::tiny(16)
+        };
+        let dir = checkpoint_dir_with_config(&mut state, &new_config);
+        let old_model = state.current_model();
+
+        let request = ReloadRequest {
+            checkpoint_dir: Some(dir.join("run")),
+        };
+        reload_handler(State(state.clone()), Some(Json(request)))
+            .await
+            .expect("reload");
+
+        let Json(info) = model_info_handler(State(state)).await;
+        assert_eq!(info.config.n_layer, 2);
+        // Requests that grabbed the old model keep it alive.
+        assert_eq!(old_model.config.n_layer, ModelConfig::tiny(16).n_layer);
+
+        fs::remove_dir_all(&dir).expect("cleanup temp test dir");
+    }
+
+    #[tokio::test]
+    async fn reload_rejects_model_smaller_than_tokenizer_vocab() {
+        let mut state = test_state(16);
+        let dir = checkpoint_dir_with_config(&mut state, &ModelConfig::tiny(8));
+
+        let request = ReloadRequest {
+            checkpoint_dir: Some(dir.join("run")),
+        };
+        let err = reload_handler(State(state.clone()), Some(Json(request)))
+            .await
+            .err()
+            .expect("vocab mismatch is rejected");
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+        assert_eq!(state.current_model().config.vocab_size, 16);
+
+        fs::remove_dir_all(&dir).expect("cleanup temp test dir");
+    }
+
+    #[tokio::test]
+    async fn reload_only_accepts_directories_under_the_checkpoint_dir() {
+        let mut state = test_state(16);
+        let dir = checkpoint_dir_with_config(&mut state, &ModelConfig::tiny(16));
+        let outside = dir.with_file_name(format!("{}_outside", dir.file_name().unwrap().to_string_lossy()));
+        fs::create_dir_all(&outside).expect("create outside dir");
+
+        for requested in [outside.clone(), dir.join("run/../.."), dir.join("missing")] {
+            let request = ReloadRequest {
+                checkpoint_dir: Some(requested),
+            };
+            let (status, message) = reload_handler(State(state.clone()), Some(Json(request)))
+                .await
+                .err()
+                .expect("path outside the checkpoint dir is rejected");
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert!(!message.contains(&*dir.to_string_lossy()), "{message} leaks a path");
+        }
+
+        // Inside the root but no checkpoint: the load error stays in the server log.
+        let request = ReloadRequest {
+            checkpoint_dir: Some(outside.clone()),
+        };
+        state.checkpoint_dir = outside.clone();
+        let (_, message) = reload_handler(State(state.clone()), Some(Json(request))).await.err().expect("no config");
+        assert_eq!(message, "failed to load a model from checkpoint_dir");
+
+        fs::remove_dir_all(&dir).expect("cleanup temp test dir");
+        fs::remove_dir_all(&outside).expect("cleanup temp test dir");
+    }
+
+    #[tokio::test]
+    async fn score_returns_a_log_prob_per_token_after_the_first() {
+        let state = test_state(16);
+
+        let Json(scored) = score_handler(State(state.clone()), Json(ScoreRequest { text: "abcd".to_string() }))
+            .await
+            .expect("score");
+
+        assert_eq!(scored.token_ids, vec![0, 1, 2, 3]);
+        assert_eq!(scored.logprobs.len(), 4);
+        assert!(scored.logprobs[0].is_none());
+        assert!(scored.logprobs[1..].iter().all(|lp| lp.is_some_and(|lp| lp < 0.0)));
+        let total: f64 = scored.logprobs.iter().flatten().sum();
+        assert!((scored.total_logprob - total).abs() < 1e-12);
+
+        let empty = score_handler(State(state), Json(ScoreRequest { text: String::new() })).await;
+        assert_eq!(empty.err().expect("empty text is rejected").0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn generate_text_token_ids_decode_to_text() {
+        let state = test_state(16);
+        let req = GenRequest {
+            prompt: "abc".to_string(),
+            messages: None,
+            max_new_tokens: Some(5),
+            max_input_tokens: None,
+            temperature: None,
+            top_k: None,
+            top_p: None,
+            eos_bias: None,
+            suppress_tokens: None,
+            stop: None,
+            suppress_special: false,
+            overflow_policy: None,
+            timeout_ms: None,
+            stream: Some(false),
+            include_token_ids: true,
+            include_timing: false,
+            stream_granularity: None,
+            session_id: None,
+            n: None,
+        };
+
+        let Json(response) = generate_text(&state, &req).await.expect("generate");
+
+        let token_ids = response.token_ids.expect("token ids requested");
+        let ids: Vec<u32> = token_ids.iter().map(|&id| id as u32).collect();
+        assert!(!token_ids.is_empty());
+        assert_eq!(state.tokenizer.decode(&ids), response.text);
+        assert_eq!(response.prompt_token_ids, Some(vec![0, 1, 2]));
+    }
+
+    #[tokio::test]
+    async fn chat_messages_are_rendered_with_the_server_template() {
+        let state = test_state(16);
+        let req = GenRequest {
+            messages: Some(vec![ChatMessage::new(inference::Role::User, "cab")]),
+            max_new_tokens: Some(2),
+            stream: Some(false),
+            include_token_ids: true,
+            ..GenRequest::default()
+        };
+
+        let Json(response) = generate_text(&state, &req).await.expect("generate");
+
+        assert_eq!(response.prompt_token_ids, Some(vec![2, 0, 1]));
+        assert!(Args::try_parse_from(["inference-server", "--template", "vicuna"]).is_err());
+        let args = Args::try_parse_from(["inference-server", "--template", "ChatML"]).expect("preset");
+        assert_eq!(args.template, "chatml");
+    }
+
+    #[tokio::test]
+    async fn session_requests_continue_the_stored_conversation() {
+        let state = test_state(16);
+        let session_id = Uuid::new_v4();
+        let request = |prompt: &str| GenRequest {
+            prompt: prompt.to_string(),
+            messages: None,
+            max_new_tokens: Some(3),
+            max_input_tokens: None,
+            temperature: Some(0.0),
+            top_k: None,
+            top_p: None,
+            eos_bias: None,
+            suppress_tokens: None,
+            stop: None,
+            suppress_special: false,
+            overflow_policy: None,
+            timeout_ms: None,
+            stream: Some(false),
+            include_token_ids: true,
+            include_timing: false,
+            stream_granularity: None,
+            session_id: Some(session_id),
+            n: None,
+        };
+
+        let Json(first) = generate_text(&state, &request("abc")).await.expect("first turn");
+        let Json(second) = generate_text(&state, &request("de")).await.expect("second turn");
+
+        let session = state
+            .sessions
+            .lock()
+            .expect("session store poisoned")
+            .checkout(&session_id)
+            .expect("session stored");
+        let expected: Vec<i64> = [
+            first.prompt_token_ids.expect("prompt ids"),
+            first.token_ids.expect("token ids"),
+            second.prompt_token_ids.expect("prompt ids"),
+            second.token_ids.expect("token ids"),
+        ]
+        .concat();
+        assert_eq!(session.tokens, expected);
+        // Everything but the last sampled token is already cached for the next turn.
+        assert_eq!(session.cached_len(), expected.len() - 1);
+    }
+
+    #[tokio::test]
+    async fn session_history_ends_before_the_stop_string() {
+        let state = test_state(16);
+        let session_id = Uuid::new_v4();
+        let request = |stop: Option<Vec<String>>, session_id: Option<Uuid>| GenRequest {
+            prompt: "abc".to_string(),
+            max_new_tokens: Some(8),
+            temperature: Some(0.0),
+            stop,
+            stream: Some(false),
+            include_token_ids: true,
+            session_id,
+            ..GenRequest::default()
+        };
+        let Json(full) = generate_text(&state, &request(None, None)).await.expect("generate");
+        assert_eq!(full.token_ids.expect("token ids").len(), 8);
+        let (stop, expected_text) = stop_in_reply(&full.text);
+        let kept = expected_text.len();
+
+        let Json(reply) = generate_text(&state, &request(Some(vec![stop]), Some(session_id)))
+            .await
+            .expect("session turn");
+
+        let session = state
+            .sessions
+            .lock()
+            .expect("session store poisoned")
+            .checkout(&session_id)
+            .expect("session stored");
+        let reply_ids = reply.token_ids.expect("token ids");
+        let expected = [reply.prompt_token_ids.expect("prompt ids"), reply_ids[..kept].to_vec()].concat();
+        assert_eq!(reply.text, expected_text);
+        assert_eq!(session.tokens, expected);
+        assert!(session.cached_len() <= expected.len());
+    }
+
+    #[tokio::test]
+    async fn n_returns_that_many_choices() {
+        let state = test_state(16);
+        let request = |n: usize| GenRequest {
+            prompt: "abc".to_string(),
+            max_new_tokens: Some(4),
+            temperature: Some(1.0),
+            stream: Some(false),
+            include_token_ids: true,
+            n: Some(n),
+            ..GenRequest::default()
+        };
+
+        let Json(response) = generate_text(&state, &request(3)).await.expect("generate");
+
+        assert_eq!(response.choices.len(), 3);
+        for (index, choice) in response.choices.iter().enumerate() {
+            assert_eq!(choice.index, index);
+            let ids: Vec<u32> = choice.token_ids.as_ref().expect("token ids").iter().map(|&id| id as u32).collect();
+            assert_eq!(state.tokenizer.decode(&ids), choice.text);
+        }
+        assert_eq!(response.text, response.choices[0].text);
+
+        for bad in [
+            request(0),
+            GenRequest { stream: Some(true), ..request(2) },
+            GenRequest { session_id: Some(Uuid::new_v4()), ..request(2) },
+        ] {
+            let err = generate_text(&state, &bad).await.err().expect("rejected");
+            assert_eq!(err.0, StatusCode::BAD_REQUEST, "{}", err.1);
+        }
+    }
+
+    #[tokio::test]
+    async fn negative_temperature_is_rejected() {
+        let state = test_state(16);
+        let req = GenRequest {
+            prompt: "abc".to_string(),
+            temperature: Some(-1.0),
+            stream: Some(false),
+            ..GenRequest::default()
+        };
+
+        let err = generate_text(&state, &req).await.err().expect("negative temperature is rejected");
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+        assert!(err.1.contains("temperature"), "{}", err.1);
+    }
+
+    #[tokio::test]
+    async fn over_length_prompts_are_rejected_unless_truncation_is_requested() {
+        let state = test_state(16);
+        let req = |overflow_policy| GenRequest {
+            prompt: "abcd".to_string(),
+            max_input_tokens: Some(2),
+            overflow_policy,
+            ..GenRequest::default()
+        };
+
+        let err = prepare_request(&state, &req(None)).err().expect("over-length prompt is rejected");
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+        assert!(err.1.contains("at most 2 input tokens"), "{}", err.1);
+        let prepared = prepare_request(&state, &req(Some(OverflowPolicy::TruncateLeft))).expect("truncated");
+        assert_eq!(prepared.input_ids, [2, 3]);
+    }
+
+    #[tokio::test]
+    async fn top_p_above_one_is_clamped_and_oversized_top_k_rejected() {
+        let state = test_state(16);
+        let req = GenRequest {
+            prompt: "abc".to_string(),
+            top_p: Some(1.5),
+            ..GenRequest::default()
+        };
+        let prepared = prepare_request(&state, &req).expect("top_p is clamped, not rejected");
+        assert_eq!(prepared.params.top_p, 1.0);
+
+        let req = GenRequest {
+            prompt: "abc".to_string(),
+            top_k: Some(17),
+            ..GenRequest::default()
+        };
+        let err = prepare_request(&state, &req).err().expect("top_k above the vocab size is rejected");
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+    }
+
+    async fn sse_body(state: &AppState, req: &GenRequest) -> String {
+        use axum::body::HttpBody;
+
+        let mut body = generate_sse(state, req).expect("stream").into_response().into_body();
+        let mut raw = Vec::new();
+        while let Some(chunk) = body.data().await {
+            raw.extend_from_slice(&chunk.expect("body chunk"));
+        }
+        String::from_utf8(raw).expect("utf-8 body")
+    }
+
+    /// The `(event, data)` pairs of an SSE body; unnamed events are reported as `message`.
+    fn sse_events(raw: &str) -> Vec<(&str, &str)> {
+        raw.split("\n\n")
+            .filter(|event| !event.is_empty())
+            .map(|event| {
+                let kind = event.lines().find_map(|line| line.strip_prefix("event:")).unwrap_or("message");
+                let data = event.lines().find_map(|line| line.strip_prefix("data:")).expect("data line");
+                (kind, data)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn typed_sse_events_name_their_kind() {
+        let state = test_state(16);
+        let req = GenRequest {
+            prompt: "abc".to_string(),
+            max_new_tokens: Some(5),
+            ..GenRequest::default()
+        };
+        let raw = sse_body(&state, &req).await;
+
+        let events = sse_events(&raw);
+
+        let kinds: Vec<&str> = events.iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(kinds.first(), Some(&"meta"));
+        assert_eq!(kinds.last(), Some(&"done"));
+        assert!(kinds[1..kinds.len() - 1].iter().all(|kind| *kind == "token"), "{kinds:?}");
+
+        let meta: MetaEvent = serde_json::from_str(events[0].1).expect("meta json");
+        assert_eq!(meta.prompt_tokens, 3);
+        let tokens: Vec<TokenEvent> = events[1..events.len() - 1]
+            .iter()
+            .map(|(_, data)| serde_json::from_str(data).expect("token json"))
+            .collect();
+        assert!(tokens.iter().all(|token| token.id.is_none() && token.timing.is_none()));
+        let done: DoneEvent = serde_json::from_str(events[events.len() - 1].1).expect("done json");
+        assert_eq!(done.finish_reason, Some(FinishReason::Length));
+        assert_eq!(done.tokens, tokens.len());
+        assert!(done.timing.is_none());
+    }
+
+    #[tokio::test]
+    async fn generation_errors_end_the_stream_with_an_error_event() {
+        let state = test_state(16);
+        // With every token suppressed there is nothing left to sample.
+        let req = GenRequest {
+            prompt: "abc".to_string(),
+            max_new_tokens: Some(5),
+            suppress_tokens: Some((0..16).collect()),
+            ..GenRequest::default()
+        };
+
+        let raw = sse_body(&state, &req).await;
+        let events = sse_events(&raw);
+        let kinds: Vec<&str> = events.iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(kinds, ["error", "done"]);
+        let error: ErrorEvent = serde_json::from_str(events[0].1).expect("error json");
+        assert!(error.error.contains("not finite"), "{}", error.error);
+        let done: DoneEvent = serde_json::from_str(events[1].1).expect("done json");
+        assert_eq!(done.finish_reason, None);
+
+        let legacy = AppState {
+            legacy_sse: true,
+            ..test_state(16)
+        };
+        let raw = sse_body(&legacy, &req).await;
+        assert_eq!(sse_events(&raw), [("error", error.error.as_str())]);
+    }
+
+    #[tokio::test]
+    async fn streamed_timing_increases_across_events() {
+        let state = AppState {
+            legacy_sse: true,
+            ..test_state(16)
+        };
+        let req = GenRequest {
+            prompt: "abc".to_string(),
+            max_new_tokens: Some(5),
+            include_timing: true,
+            ..GenRequest::default()
+        };
+        let raw = sse_body(&state, &req).await;
+
+        let mut timings = Vec::new();
+        let mut summary = None;
+        for event in raw.split("\n\n").filter(|event| !event.is_empty()) {
+            let data = event.lines().find_map(|line| line.strip_prefix("data:")).expect("data line");
+            if event.starts_with("event:timing") {
+                summary = Some(serde_json::from_str::<TimingSummary>(data).expect("summary json"));
+            } else {
+                timings.push(serde_json::from_str::<TokenTiming>(data).expect("timing fields"));
+            }
+        }
+
+        let summary = summary.expect("timing summary event");
+        assert!(!timings.is_empty());
+        assert_eq!(timings.len(), summary.tokens);
+        assert!(timings.iter().all(|timing| timing.inter_token_ms >= 0.0));
+        for pair in timings.windows(2) {
+            assert!(pair[1].ms_since_start >= pair[0].ms_since_start, "{:?}", pair);
+        }
+        assert!(summary.prefill_ms <= timings[0].ms_since_start);
+        assert!(summary.total_ms >= timings[timings.len() - 1].ms_since_start);
+        assert!(summary.tokens_per_sec > 0.0);
+    }
+
+    #[tokio::test]
+    async fn stop_strings_end_generation_and_are_trimmed() {
+        let state = test_state(16);
+        let request = |stop: Option<Vec<String>>, stream: bool| GenRequest {
+            prompt: "abc".to_string(),
+            max_new_tokens: Some(8),
+            temperature: Some(0.0),
+            stop,
+            stream: Some(stream),
+            ..GenRequest::default()
+        };
+        let Json(full) = generate_text(&state, &request(None, false)).await.expect("generate");
+        assert_eq!(full.text.len(), 8, "one letter per token");
+        let (stop, expected) = stop_in_reply(&full.text);
+
+        let Json(stopped) = generate_text(&state, &request(Some(vec![stop.clone()]), false))
+            .await
+            .expect("generate");
+        assert_eq!(stopped.text, expected);
+        assert_eq!(stopped.finish_reason, FinishReason::Stop);
+
+        let raw = sse_body(&state, &request(Some(vec![stop]), true)).await;
+        let events = sse_events(&raw);
+        let streamed: String = events
+            .iter()
+            .filter(|(kind, _)| *kind == "token")
+            .map(|(_, data)| serde_json::from_str::<TokenEvent>(data).expect("token json").token)
+            .collect();
+        assert_eq!(streamed, expected);
+        let done: DoneEvent = serde_json::from_str(events[events.len() - 1].1).expect("done json");
+        assert_eq!(done.finish_reason, Some(FinishReason::Stop));
+    }
+}
@@ -1,12 +1,29 @@
-use tch::{Tensor, Kind, IndexOp};
-use rand::distributions::Distribution;
+use tch::{Tensor, Kind};
+use claude_core::tensor_util::tensor_to_vec_f64;
+use rand::Rng;
+use tokenizer::{is_special_token, BPE};
+
+/// Temperatures below this decode greedily (argmax). Any temperature at or above it samples;
+/// the logits are shifted by their maximum before scaling, so even this small a temperature
+/// can't overflow them to `inf` and turn the softmax into NaNs.
+pub const MIN_SAMPLING_TEMPERATURE: f64 = 1e-5;
 
 #[derive(Debug, Clone)]
 pub struct SamplingParams {
+    /// Softmax temperature. Values below [`MIN_SAMPLING_TEMPERATURE`] mean greedy decoding.
     pub temperature: f64,
     pub top_k: usize,
     pub top_p: f64,
     pub repetition_penalty: f64,
+    /// Added to the logit of the EOS token before sampling: positive values make the model
+    /// stop sooner, negative values keep it going. Ignored if there is no EOS token.
+    pub eos_bias: f64,
+    /// The token that ends generation: once sampled, decoding stops without emitting it.
+    /// `None` uses the model config's `eos_token_id`.
+    pub eos_token_id: Option<i64>,
+    /// Token IDs that are never sampled: their logits are set to `-inf` first.
+    /// Out-of-range IDs are ignored.
+    pub suppress_tokens: Vec<i64>,
 }
 
 impl Default for SamplingParams {
@@ -16,10 +33,67 @@ impl Default for SamplingParams {
             top_k: 40,
             top_p: 0.95,
             repetition_penalty: 1.1,
+            eos_bias: 0.0,
+            eos_token_id: None,
+            suppress_tokens: Vec::new(),
         }
     }
 }
 
+impl SamplingParams {
+    /// Rejects parameters that would make sampling meaningless: a negative or non-finite
+    /// temperature, or a `top_p` that isn't positive. A `top_p` above 1 is clamped to 1.
+    pub fn validate(&mut self) -> anyhow::Result<()> {
+        self.check()?;
+        self.top_p = self.top_p.min(1.0);
+        Ok(())
+    }
+
+    /// Adds every special token of `tokenizer` (see [`is_special_token`]), such as padding
+    /// and `<UNK>`, to `suppress_tokens`. `keep` (usually the EOS token) stays sampleable so
+    /// generation can still stop.
+    pub fn suppress_special_tokens(&mut self, tokenizer: &BPE, keep: Option<i64>) {
+        let mut special: Vec<i64> = tokenizer
+            .vocab
+            .token_to_id
+            .iter()
+            .filter(|(token, _)| is_special_token(token))
+            .map(|(_, &id)| id as i64)
+            .filter(|&id| Some(id) != keep)
+            .collect();
+        special.sort_unstable();
+        self.suppress_tokens.extend(special);
+    }
+
+    fn check(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.temperature.is_finite() && self.temperature >= 0.0,
+            "temperature must be a finite number >= 0, got {}",
+            self.temperature
+        );
+        anyhow::ensure!(self.top_p > 0.0, "top_p must be in (0, 1], got {}", self.top_p);
+        Ok(())
+    }
+}
+
+/// Number of top candidates recorded per [`StepTrace`].
+pub const TRACE_TOP_CANDIDATES: usize = 5;
+
+/// What the sampler saw and chose for one generated token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepTrace {
+    /// Index of the generated token (0 is the first token after the prompt).
+    pub step: usize,
+    /// The most likely `(token_id, prob)` pairs after top-k/top-p filtering, renormalized and
+    /// sorted by probability. Holds the top [`TRACE_TOP_CANDIDATES`], plus the sampled token
+    /// if it ranked lower.
+    pub candidates: Vec<(i64, f64)>,
+    /// The token that was sampled.
+    pub token: i64,
+    /// The uniform `[0, 1)` draw that selected `token`, or `None` for greedy decoding.
+    pub draw: Option<f64>,
+}
+
 pub struct Sampler;
 
 impl Sampler {
@@ -27,34 +101,95 @@ impl Sampler {
     /// logits: [vocab_size] tensor.
     /// history: slice of previously generated token IDs.
     pub fn sample(logits: &Tensor, params: &SamplingParams, history: &[i64]) -> anyhow::Result<i64> {
+        Self::sample_with_rng(logits, params, history, &mut rand::thread_rng())
+    }
+
+    /// Same as [`Sampler::sample`], but draws from the caller's RNG so that
+    /// a seeded RNG gives reproducible samples.
+    pub fn sample_with_rng<R: Rng + ?Sized>(
+        logits: &Tensor,
+        params: &SamplingParams,
+        history: &[i64],
+        rng: &mut R,
+    ) -> anyhow::Result<i64> {
+        Self::sample_impl(logits, params, history, rng, None)
+    }
+
+    /// Same as [`Sampler::sample_with_rng`], but also reports the filtered candidates and
+    /// the random draw. `step` is left at 0 for the caller to fill in.
+    pub fn sample_traced<R: Rng + ?Sized>(
+        logits: &Tensor,
+        params: &SamplingParams,
+        history: &[i64],
+        rng: &mut R,
+    ) -> anyhow::Result<StepTrace> {
+        let mut trace = StepTrace {
+            step: 0,
+            candidates: Vec::new(),
+            token: 0,
+            draw: None,
+        };
+        trace.token = Self::sample_impl(logits, params, history, rng, Some(&mut trace))?;
+        Ok(trace)
+    }
+
+    /// Returns a copy of `logits` (`[vocab_size]`) with `bias` added to the logit of
+    /// `eos_token_id`. Out-of-range IDs leave the logits unchanged.
+    pub fn apply_eos_bias(logits: &Tensor, eos_token_id: i64, bias: f64) -> Tensor {
+        let biased = logits.copy();
+        if (0..logits.size()[0]).contains(&eos_token_id) {
+            let mut eos_logit = biased.narrow(0, eos_token_id, 1);
+            let _ = eos_logit.g_add_scalar_(bias);
+        }
+        biased
+    }
+
+    fn sample_impl<R: Rng + ?Sized>(
+        logits: &Tensor,
+        params: &SamplingParams,
+        history: &[i64],
+        rng: &mut R,
+        trace: Option<&mut StepTrace>,
+    ) -> anyhow::Result<i64> {
         let _guard = tch::no_grad_guard();
+        params.check()?;
 
         // 0. Repetition Penalty
         let logits = if params.repetition_penalty != 1.0 && !history.is_empty() {
-            use std::collections::HashSet;
-            let unique_tokens: HashSet<_> = history.iter().collect();
-            let l = logits.to_device(tch::Device::Cpu);
-            for &&token_id in &unique_tokens {
-                if token_id < 0 { continue; } // Safety
-                let current_val = l.double_value(&[token_id]);
-                let new_val = if current_val < 0.0 {
-                    current_val * params.repetition_penalty
-                } else {
-                    current_val / params.repetition_penalty
-                };
-                let _ = l.i(token_id).fill_(new_val);
-            }
-            l
+            Self::apply_repetition_penalty(logits, history, params.repetition_penalty)
         } else {
             logits.shallow_clone()
         };
+        let logits = if params.suppress_tokens.is_empty() {
+            logits
+        } else {
+            Self::suppress(&logits, &params.suppress_tokens)
+        };
+
+        // top_k = 1 keeps only the most likely token whatever the temperature and top_p, so
+        // skip the softmax, sort and random draw. Non-finite logits fail as they would below.
+        if params.top_k == 1 {
+            let token = logits.argmax(0, false).int64_value(&[]);
+            if !logits.double_value(&[token]).is_finite() {
+                anyhow::bail!("Sampling probabilities are not finite");
+            }
+            if let Some(trace) = trace {
+                trace.candidates = vec![(token, 1.0)];
+            }
+            return Ok(token);
+        }
 
         // 1. Temperature scaling
-        if params.temperature < 1e-5 {
-            return Ok(logits.argmax(0, false).int64_value(&[]));
+        if params.temperature < MIN_SAMPLING_TEMPERATURE {
+            let token = logits.argmax(0, false).int64_value(&[]);
+            if let Some(trace) = trace {
+                trace.candidates = vec![(token, 1.0)];
+            }
+            return Ok(token);
         }
 
-        let scaled_logits = logits / params.temperature;
+        // Shifting by the max leaves the softmax unchanged and keeps every scaled logit <= 0.
+        let scaled_logits = (&logits - logits.max()) / params.temperature;
         
         // 2. Softmax for probabilities
         let probs = scaled_logits.softmax(-1, Kind::Float);
@@ -65,7 +200,7 @@ impl Sampler {
         // Tch doesn't expose easy WeightedIndex on GPU directly in safe Rust without boilerplate.
         // CPU fallback is fine for inference (vocab size < 100k).
         
-        let probs_vec: Vec<f64> = Vec::<f64>::try_from(&probs)?;
+        let probs_vec: Vec<f64> = tensor_to_vec_f64(&probs)?;
         
         // Convert to (prob, index) tuples
         let mut candidates: Vec<(f64, usize)> = probs_vec
@@ -101,14 +236,219 @@ impl Sampler {
         let sum_p: f64 = candidates.iter().map(|(p, _)| p).sum();
         let renorm_probs: Vec<f64> = candidates.iter().map(|(p, _)| p / sum_p).collect();
         
-        // 7. Sample
-        let dist = rand::distributions::WeightedIndex::new(&renorm_probs)
-            .map_err(|e| anyhow::anyhow!("WeightedIndex error: {}", e))?;
-            
-        let mut rng = rand::thread_rng();
-        let sampled_idx_in_subset = dist.sample(&mut rng);
+        if !renorm_probs.iter().all(|p| p.is_finite()) {
+            anyhow::bail!("Sampling probabilities are not finite");
+        }
+        
+        // 7. Sample by inverting the CDF with a single uniform draw
+        let draw: f64 = rng.gen();
+        let mut cumulative = 0.0;
+        let sampled_idx_in_subset = renorm_probs
+            .iter()
+            .position(|p| {
+                cumulative += p;
+                draw < cumulative
+            })
+            .unwrap_or(renorm_probs.len() - 1);
         let global_idx = candidates[sampled_idx_in_subset].1;
 
+        if let Some(trace) = trace {
+            trace.candidates = candidates
+                .iter()
+                .zip(&renorm_probs)
+                .take(TRACE_TOP_CANDIDATES)
+                .map(|(&(_, idx), &p)| (idx as i64, p))
+                .collect();
+            if sampled_idx_in_subset >= TRACE_TOP_CANDIDATES {
+                trace.candidates.push((global_idx as i64, renorm_probs[sampled_idx_in_subset]));
+            }
+            trace.draw = Some(draw);
+        }
+
         Ok(global_idx as i64)
     }
+
+    /// Returns a copy of `logits` with the logits of `tokens` set to `-inf`.
+    pub fn suppress(logits: &Tensor, tokens: &[i64]) -> Tensor {
+        let vocab_size = logits.size()[0];
+        let ids: Vec<i64> = tokens
+            .iter()
+            .copied()
+            .filter(|&id| (0..vocab_size).contains(&id))
+            .collect();
+        if ids.is_empty() {
+            return logits.shallow_clone();
+        }
+        let index = Tensor::from_slice(&ids).to(logits.device());
+        logits.index_fill(0, &index, f64::NEG_INFINITY)
+    }
+
+    /// Penalizes every token that appears in `history`: positive logits are divided by
+    /// `penalty` and negative ones multiplied by it. Runs on the logits' device with a single
+    /// gather/scatter and returns a new tensor, leaving `logits` untouched.
+    /// IDs outside `0..vocab_size` are ignored.
+    pub fn apply_repetition_penalty(logits: &Tensor, history: &[i64], penalty: f64) -> Tensor {
+        let vocab_size = logits.size()[0];
+        let mut ids: Vec<i64> = history
+            .iter()
+            .copied()
+            .filter(|&id| (0..vocab_size).contains(&id))
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        if ids.is_empty() {
+            return logits.shallow_clone();
+        }
+
+        let index = Tensor::from_slice(&ids).to(logits.device());
+        let selected = logits.index_select(0, &index);
+        let penalized = (&selected * penalty).where_self(&selected.lt(0.0), &(&selected / penalty));
+        logits.scatter(0, &index, &penalized)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tch::IndexOp;
+
+    /// The original per-token CPU loop, kept as a reference.
+    fn cpu_loop_penalty(logits: &Tensor, history: &[i64], penalty: f64) -> Tensor {
+        let l = logits.to_device(tch::Device::Cpu).copy();
+        let unique_tokens: std::collections::HashSet<_> = history.iter().collect();
+        for &&token_id in &unique_tokens {
+            if token_id < 0 {
+                continue;
+            }
+            let current_val = l.double_value(&[token_id]);
+            let new_val = if current_val < 0.0 {
+                current_val * penalty
+            } else {
+                current_val / penalty
+            };
+            let _ = l.i(token_id).fill_(new_val);
+        }
+        l
+    }
+
+    #[test]
+    fn on_device_repetition_penalty_matches_cpu_loop() {
+        tch::manual_seed(0);
+        let logits = Tensor::randn([32], (Kind::Float, tch::Device::Cpu)) * 4.0;
+        let original = logits.copy();
+        let history = [3, 7, 7, 0, 31, 12, 3, -1];
+
+        let on_device = Sampler::apply_repetition_penalty(&logits, &history, 1.3);
+        let reference = cpu_loop_penalty(&logits, &history, 1.3);
+
+        assert!(on_device.allclose(&reference, 1e-6, 1e-6, false));
+        assert!(logits.equal(&original), "input logits must not be modified");
+    }
+
+    #[test]
+    fn tiny_temperature_with_large_logits_samples_without_nan() {
+        let logits = Tensor::from_slice(&[1e34f32, 3e34, 0.0, -3e34]);
+        let params = SamplingParams {
+            temperature: 2e-5,
+            top_k: 0,
+            top_p: 1.0,
+            repetition_penalty: 1.0,
+            ..Default::default()
+        };
+        // Dividing these logits by the temperature alone overflows f32.
+        assert_eq!((&logits / params.temperature).isfinite().all().int64_value(&[]), 0);
+
+        let trace = Sampler::sample_traced(&logits, &params, &[], &mut rand::thread_rng()).expect("sample");
+
+        assert_eq!(trace.token, 1);
+        assert!(trace.candidates.iter().all(|&(_, p)| p.is_finite()));
+        assert_eq!(trace.candidates[0], (1, 1.0));
+    }
+
+    #[test]
+    fn validate_rejects_negative_temperature_and_clamps_top_p() {
+        let mut negative = SamplingParams { temperature: -0.5, ..Default::default() };
+        assert!(negative.validate().is_err());
+        let logits = Tensor::zeros([4], (Kind::Float, tch::Device::Cpu));
+        assert!(Sampler::sample(&logits, &negative, &[]).is_err());
+
+        let mut wide = SamplingParams { top_p: 1.5, ..Default::default() };
+        wide.validate().expect("top_p above 1 is clamped");
+        assert_eq!(wide.top_p, 1.0);
+
+        let mut zero = SamplingParams { top_p: 0.0, ..Default::default() };
+        assert!(zero.validate().is_err());
+    }
+
+    #[test]
+    fn top_k_one_takes_the_argmax_after_penalties() {
+        tch::manual_seed(1);
+        let history = [3, 5, 9];
+        let top_k_one = SamplingParams {
+            temperature: 0.7,
+            top_k: 1,
+            top_p: 0.9,
+            repetition_penalty: 1.5,
+            eos_bias: 0.0,
+            eos_token_id: None,
+            suppress_tokens: Vec::new(),
+        };
+        // Same penalties through the general path, with a nucleus that only fits the top token.
+        let general = SamplingParams {
+            top_k: 0,
+            top_p: 1e-9,
+            ..top_k_one.clone()
+        };
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..20 {
+            let mut logits = Tensor::randn([32], (Kind::Float, tch::Device::Cpu)) * 3.0;
+            // Make a penalized token the raw favourite so the penalty decides the outcome.
+            let _ = logits.i(5).fill_(10.0);
+            let penalized = Sampler::apply_repetition_penalty(&logits, &history, 1.5);
+            let expected = penalized.argmax(0, false).int64_value(&[]);
+
+            let token = Sampler::sample_with_rng(&logits, &top_k_one, &history, &mut rng).expect("sample");
+            assert_eq!(token, expected);
+            assert_eq!(Sampler::sample_with_rng(&logits, &general, &history, &mut rng).expect("sample"), token);
+        }
+    }
+
+    #[test]
+    fn suppressed_tokens_are_never_sampled() {
+        let mut logits = Tensor::zeros([8], (Kind::Float, tch::Device::Cpu));
+        let _ = logits.i(2).fill_(5.0);
+        let _ = logits.i(6).fill_(4.0);
+        let params = SamplingParams {
+            temperature: 1.0,
+            top_k: 0,
+            top_p: 1.0,
+            suppress_tokens: vec![2, 6, 99],
+            ..Default::default()
+        };
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..500 {
+            let token = Sampler::sample_with_rng(&logits, &params, &[], &mut rng).expect("sample");
+            assert!(token != 2 && token != 6, "sampled suppressed token {token}");
+        }
+        let greedy = SamplingParams { temperature: 0.0, ..params.clone() };
+        assert_ne!(Sampler::sample(&logits, &greedy, &[]).expect("greedy"), 2);
+        let top_one = SamplingParams { top_k: 1, ..params };
+        assert_ne!(Sampler::sample(&logits, &top_one, &[]).expect("top_k = 1"), 2);
+    }
+
+    #[test]
+    fn suppress_special_tokens_keeps_the_eos_token() {
+        let mut vocab = tokenizer::Vocab::new();
+        for (id, token) in ["<PAD>", "<EOS>", "a", "<0x41>", "<"].iter().enumerate() {
+            vocab.insert(token.to_string(), id as u32);
+        }
+        let tokenizer = BPE::new(vocab, std::collections::HashMap::new());
+        let mut params = SamplingParams::default();
+
+        params.suppress_special_tokens(&tokenizer, Some(1));
+
+        assert_eq!(params.suppress_tokens, vec![0]);
+    }
 }
@@ -0,0 +1,140 @@
+use claude_core::kv_cache::KVCache;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Token history and per-layer KV caches of a conversation, kept between requests so
+/// that a follow-up turn only has to prefill its own tokens.
+pub struct SessionState {
+    /// Every token of the conversation so far: prompts and generated replies.
+    pub tokens: Vec<i64>,
+    /// One cache per layer. Covers a prefix of `tokens`; the rest (normally just the last
+    /// sampled token) is fed through the model at the start of the next turn.
+    pub caches: Vec<KVCache>,
+}
+
+impl SessionState {
+    /// Number of tokens already held in the KV caches.
+    pub fn cached_len(&self) -> usize {
+        self.caches.first().map_or(0, |cache| cache.length)
+    }
+
+    /// Forgets the history from `len` on, along with any cached positions past it.
+    pub fn truncate(&mut self, len: usize) {
+        self.tokens.truncate(len);
+        for cache in &mut self.caches {
+            cache.truncate(len);
+        }
+    }
+
+    /// Copies the history and every cache, so that running a turn on the copy leaves
+    /// `self` untouched.
+    pub fn deep_clone(&self) -> Self {
+        Self {
+            tokens: self.tokens.clone(),
+            caches: self.caches.iter().map(KVCache::deep_clone).collect(),
+        }
+    }
+}
+
+struct StoredSession {
+    state: SessionState,
+    last_used: Instant,
+}
+
+/// Sessions keyed by id, dropped once they have been idle for longer than the TTL.
+pub struct SessionStore {
+    sessions: HashMap<Uuid, StoredSession>,
+    ttl: Duration,
+}
+
+impl SessionStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            ttl,
+        }
+    }
+
+    /// Returns a deep copy of the session to run the next turn on, or `None` if the id is
+    /// unknown or has expired. The stored session stays as it was until [`SessionStore::insert`]
+    /// replaces it, so a failed or cancelled turn doesn't corrupt it.
+    pub fn checkout(&mut self, id: &Uuid) -> Option<SessionState> {
+        self.evict_idle();
+        let session = self.sessions.get_mut(id)?;
+        session.last_used = Instant::now();
+        Some(session.state.deep_clone())
+    }
+
+    /// Stores (or replaces) the state of a session and marks it as used.
+    pub fn insert(&mut self, id: Uuid, state: SessionState) {
+        self.sessions.insert(
+            id,
+            StoredSession {
+                state,
+                last_used: Instant::now(),
+            },
+        );
+    }
+
+    /// Drops sessions idle for longer than the TTL and returns how many were removed.
+    pub fn evict_idle(&mut self) -> usize {
+        let before = self.sessions.len();
+        let ttl = self.ttl;
+        self.sessions.retain(|_, session| session.last_used.elapsed() <= ttl);
+        before - self.sessions.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tch::{Device, Kind, Tensor};
+
+    fn session_with_tokens(tokens: Vec<i64>) -> SessionState {
+        let mut cache = KVCache::new(8, 1, 2, Device::Cpu, Kind::Float);
+        let kv = Tensor::ones([1, 1, tokens.len() as i64, 2], (Kind::Float, Device::Cpu));
+        cache.update(&kv, &kv).expect("cache update");
+        SessionState {
+            tokens,
+            caches: vec![cache],
+        }
+    }
+
+    #[test]
+    fn checkout_returns_an_independent_copy() {
+        let mut store = SessionStore::new(Duration::from_secs(60));
+        let id = Uuid::new_v4();
+        store.insert(id, session_with_tokens(vec![1, 2, 3]));
+
+        let mut copy = store.checkout(&id).expect("session exists");
+        copy.tokens.push(4);
+        let kv = Tensor::zeros([1, 1, 1, 2], (Kind::Float, Device::Cpu));
+        copy.caches[0].update(&kv, &kv).expect("cache update");
+        let _ = copy.caches[0].k.fill_(5.0);
+
+        let stored = store.checkout(&id).expect("session still exists");
+        assert_eq!(stored.tokens, vec![1, 2, 3]);
+        assert_eq!(stored.cached_len(), 3);
+        assert_eq!(stored.caches[0].k.double_value(&[0, 0, 0, 0]), 1.0);
+        assert!(store.checkout(&Uuid::new_v4()).is_none());
+    }
+
+    #[test]
+    fn evict_idle_drops_expired_sessions() {
+        let mut store = SessionStore::new(Duration::ZERO);
+        store.insert(Uuid::new_v4(), session_with_tokens(vec![1]));
+        std::thread::sleep(Duration::from_millis(5));
+
+        assert_eq!(store.evict_idle(), 1);
+        assert!(store.is_empty());
+    }
+}
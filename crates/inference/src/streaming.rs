@@ -0,0 +1,263 @@
+use serde::{Deserialize, Serialize};
+use tokenizer::{StreamDecoder, BPE};
+
+/// How often streamed text is flushed to the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamGranularity {
+    /// One event per generated token.
+    #[default]
+    Token,
+    /// Flush at whitespace, so each event is a whole word (with its leading whitespace).
+    Word,
+    /// Flush after `.`, `!` or `?`.
+    Sentence,
+}
+
+/// Buffers decoded token text and releases it at [`StreamGranularity`] boundaries.
+#[derive(Debug, Default)]
+pub struct TextChunker {
+    granularity: StreamGranularity,
+    buffer: String,
+}
+
+impl TextChunker {
+    pub fn new(granularity: StreamGranularity) -> Self {
+        Self {
+            granularity,
+            buffer: String::new(),
+        }
+    }
+
+    /// Adds the text of one token and returns the text ready to be flushed, if any.
+    pub fn push(&mut self, text: &str) -> Option<String> {
+        self.buffer.push_str(text);
+        let split = match self.granularity {
+            StreamGranularity::Token => Some(self.buffer.len()),
+            // Split before the last whitespace that follows word content; that
+            // whitespace starts the next word.
+            StreamGranularity::Word => {
+                let mut split = None;
+                let mut after_word = false;
+                for (i, c) in self.buffer.char_indices() {
+                    if c.is_whitespace() && after_word {
+                        split = Some(i);
+                    }
+                    after_word = !c.is_whitespace();
+                }
+                split
+            }
+            StreamGranularity::Sentence => self
+                .buffer
+                .rfind(['.', '!', '?'])
+                .map(|i| i + 1),
+        }?;
+
+        let rest = self.buffer.split_off(split);
+        Some(std::mem::replace(&mut self.buffer, rest))
+    }
+
+    /// Returns whatever is still buffered once the stream has ended.
+    pub fn finish(&mut self) -> Option<String> {
+        if self.buffer.is_empty() {
+            None
+        } else {
+            Some(std::mem::take(&mut self.buffer))
+        }
+    }
+}
+
+/// Turns generated token IDs into streamed text: a [`StreamDecoder`] decodes them,
+/// [`StopSequences`] cuts the text at a stop string and a [`TextChunker`] releases it at
+/// its granularity's boundaries.
+#[derive(Debug)]
+pub struct TextStream {
+    decoder: StreamDecoder,
+    stop: StopSequences,
+    chunker: TextChunker,
+}
+
+impl TextStream {
+    pub fn new(granularity: StreamGranularity, stop: StopSequences) -> Self {
+        Self {
+            decoder: StreamDecoder::new(),
+            stop,
+            chunker: TextChunker::new(granularity),
+        }
+    }
+
+    /// Adds one generated token and returns the text ready to be flushed, if any.
+    pub fn push(&mut self, tokenizer: &BPE, id: u32) -> Option<String> {
+        let text = self.stop.push(&self.decoder.push(tokenizer, id))?;
+        self.chunker.push(&text)
+    }
+
+    /// True once a stop string has appeared; later tokens are dropped.
+    pub fn stopped(&self) -> bool {
+        self.stop.stopped()
+    }
+
+    /// Returns the next piece of buffered text once generation has ended: a character the
+    /// last token left unfinished (as U+FFFD), text held back as a possible stop string and
+    /// the chunker's remainder. Call it until it returns `None`.
+    pub fn finish(&mut self) -> Option<String> {
+        let tail = self.decoder.finish();
+        let released = if tail.is_empty() { None } else { self.stop.push(&tail) };
+        let held: String = released.into_iter().chain(self.stop.finish()).collect();
+        if !held.is_empty() {
+            if let Some(chunk) = self.chunker.push(&held) {
+                return Some(chunk);
+            }
+        }
+        self.chunker.finish()
+    }
+}
+
+/// Watches decoded token text for stop strings. Text that could be the start of a stop
+/// string is held back until the next token shows whether it is one, so a stop split
+/// across tokens (`"\n\nUs"` then `"er:"`) is still caught and never streamed.
+#[derive(Debug, Default, Clone)]
+pub struct StopSequences {
+    stops: Vec<String>,
+    held: String,
+    stopped: bool,
+}
+
+impl StopSequences {
+    /// Empty strings are ignored; they would match before any text.
+    pub fn new(stops: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            stops: stops.into_iter().filter(|stop| !stop.is_empty()).collect(),
+            held: String::new(),
+            stopped: false,
+        }
+    }
+
+    /// True once a stop string has appeared; later text is dropped.
+    pub fn stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Byte offset of the earliest stop string in `text`.
+    pub fn find(&self, text: &str) -> Option<usize> {
+        self.stops.iter().filter_map(|stop| text.find(stop.as_str())).min()
+    }
+
+    /// Adds the text of one token and returns the text that can be released, if any. When
+    /// a stop string completes, that is the text before it, and [`StopSequences::stopped`]
+    /// turns true.
+    pub fn push(&mut self, text: &str) -> Option<String> {
+        if self.stopped {
+            return None;
+        }
+        if self.stops.is_empty() {
+            return Some(text.to_string());
+        }
+        self.held.push_str(text);
+        if let Some(pos) = self.find(&self.held) {
+            self.stopped = true;
+            self.held.truncate(pos);
+            return Some(std::mem::take(&mut self.held)).filter(|text| !text.is_empty());
+        }
+
+        // Hold back the longest tail of the text that some stop string starts with.
+        let hold = self
+            .stops
+            .iter()
+            .flat_map(|stop| stop.char_indices().skip(1).map(|(i, _)| &stop[..i]))
+            .filter(|prefix| self.held.ends_with(prefix))
+            .map(str::len)
+            .max()
+            .unwrap_or(0);
+        let rest = self.held.split_off(self.held.len() - hold);
+        Some(std::mem::replace(&mut self.held, rest)).filter(|text| !text.is_empty())
+    }
+
+    /// Returns the held-back text once the stream has ended without a stop.
+    pub fn finish(&mut self) -> Option<String> {
+        if self.held.is_empty() {
+            None
+        } else {
+            Some(std::mem::take(&mut self.held))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk_all(granularity: StreamGranularity, tokens: &[&str]) -> Vec<String> {
+        let mut chunker = TextChunker::new(granularity);
+        let mut events: Vec<String> = tokens.iter().filter_map(|token| chunker.push(token)).collect();
+        events.extend(chunker.finish());
+        events
+    }
+
+    #[test]
+    fn word_mode_flushes_whitespace_delimited_words() {
+        let tokens = ["Hel", "lo", " wor", "ld", ",", " again", "  and", " more"];
+        let events = chunk_all(StreamGranularity::Word, &tokens);
+
+        assert_eq!(events, ["Hello", " world,", " again", "  and", " more"]);
+        assert_eq!(events.concat(), tokens.concat());
+        for event in &events {
+            assert_eq!(event.split_whitespace().count(), 1, "{event:?} is not a single word");
+        }
+    }
+
+    #[test]
+    fn text_stream_chunks_words_split_across_byte_tokens() {
+        let mut vocab = tokenizer::Vocab::new();
+        for (id, token) in ["Caf", "<0xC3>", "<0xA9>", " ok", "<0xE2>"].into_iter().enumerate() {
+            vocab.insert(token.to_string(), id as u32);
+        }
+        let bpe = BPE::new(vocab, Default::default());
+        let mut stream = TextStream::new(StreamGranularity::Word, StopSequences::default());
+
+        let mut events: Vec<String> = [0, 1, 2, 3, 4].into_iter().filter_map(|id| stream.push(&bpe, id)).collect();
+        events.extend(std::iter::from_fn(|| stream.finish()));
+
+        assert_eq!(events, ["Caf\u{e9}", " ok\u{fffd}"]);
+        assert!(!stream.stopped());
+    }
+
+    #[test]
+    fn sentence_mode_flushes_after_terminators() {
+        let tokens = ["Hi", " there", ".", " How", " are", " you", "?", " Fine"];
+        let events = chunk_all(StreamGranularity::Sentence, &tokens);
+
+        assert_eq!(events, ["Hi there.", " How are you?", " Fine"]);
+    }
+
+    #[test]
+    fn token_mode_passes_tokens_through() {
+        let tokens = ["a", " b", ""];
+        assert_eq!(chunk_all(StreamGranularity::Token, &tokens), ["a", " b", ""]);
+    }
+
+    fn stop_all(stops: &[&str], tokens: &[&str]) -> (Vec<String>, bool) {
+        let mut stop = StopSequences::new(stops.iter().map(|s| s.to_string()));
+        let mut events: Vec<String> = tokens.iter().filter_map(|token| stop.push(token)).collect();
+        events.extend(stop.finish());
+        (events, stop.stopped())
+    }
+
+    #[test]
+    fn stop_split_across_tokens_is_caught_and_trimmed() {
+        let (events, stopped) = stop_all(&["\n\nUser:"], &["Sure", ".\n\nUs", "er:", " next"]);
+
+        assert!(stopped);
+        assert_eq!(events, ["Sure", "."]);
+    }
+
+    #[test]
+    fn held_text_that_does_not_become_a_stop_is_released() {
+        let (events, stopped) = stop_all(&["\n\nUser:", "END"], &["a\n", "\nb", " E", "N"]);
+
+        assert!(!stopped);
+        assert_eq!(events, ["a", "\n\nb", " ", "EN"]);
+        assert_eq!(events.concat(), "a\n\nb EN");
+        assert_eq!(stop_all(&[""], &["a", "b"]).0, ["a", "b"]);
+    }
+}
@@ -0,0 +1,204 @@
+use serde::{Deserialize, Serialize};
+
+/// Who wrote a [`ChatMessage`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+impl Role {
+    fn as_str(self) -> &'static str {
+        match self {
+            Role::System => "system",
+            Role::User => "user",
+            Role::Assistant => "assistant",
+        }
+    }
+}
+
+/// One turn of a conversation, e.g. `{"role": "user", "content": "Hi"}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn new(role: Role, content: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+        }
+    }
+}
+
+/// Renders a conversation into the prompt format a model was fine-tuned on.
+pub trait ChatTemplate: Send + Sync {
+    /// The preset's name, as accepted by [`template_by_name`].
+    fn name(&self) -> &'static str;
+
+    /// Renders `messages` in order. With `add_generation_prompt`, the text ends with
+    /// whatever opens the assistant's reply, so the model continues as the assistant.
+    fn render(&self, messages: &[ChatMessage], add_generation_prompt: bool) -> String;
+}
+
+/// Names of the built-in presets, in the order errors list them.
+pub const TEMPLATE_NAMES: [&str; 4] = ["chatml", "alpaca", "llama2", "raw"];
+
+/// Looks up a built-in preset by name (case-insensitive). Fails with the list of available
+/// presets if there is none by that name.
+pub fn template_by_name(name: &str) -> anyhow::Result<Box<dyn ChatTemplate>> {
+    match name.to_ascii_lowercase().as_str() {
+        "chatml" => Ok(Box::new(ChatML)),
+        "alpaca" => Ok(Box::new(Alpaca)),
+        "llama2" => Ok(Box::new(Llama2)),
+        "raw" => Ok(Box::new(Raw)),
+        _ => anyhow::bail!(
+            "unknown chat template '{}'; available presets: {}",
+            name,
+            TEMPLATE_NAMES.join(", ")
+        ),
+    }
+}
+
+/// `<|im_start|>role\ncontent<|im_end|>\n` per message.
+pub struct ChatML;
+
+impl ChatTemplate for ChatML {
+    fn name(&self) -> &'static str {
+        "chatml"
+    }
+
+    fn render(&self, messages: &[ChatMessage], add_generation_prompt: bool) -> String {
+        let mut out = String::new();
+        for message in messages {
+            out.push_str(&format!("<|im_start|>{}\n{}<|im_end|>\n", message.role.as_str(), message.content));
+        }
+        if add_generation_prompt {
+            out.push_str("<|im_start|>assistant\n");
+        }
+        out
+    }
+}
+
+/// Stanford Alpaca: the system prompt as a preamble, then `### Instruction:` and
+/// `### Response:` sections.
+pub struct Alpaca;
+
+impl ChatTemplate for Alpaca {
+    fn name(&self) -> &'static str {
+        "alpaca"
+    }
+
+    fn render(&self, messages: &[ChatMessage], add_generation_prompt: bool) -> String {
+        let mut out = String::new();
+        for message in messages {
+            match message.role {
+                Role::System => out.push_str(&format!("{}\n\n", message.content)),
+                Role::User => out.push_str(&format!("### Instruction:\n{}\n\n", message.content)),
+                Role::Assistant => out.push_str(&format!("### Response:\n{}\n\n", message.content)),
+            }
+        }
+        if add_generation_prompt {
+            out.push_str("### Response:\n");
+        }
+        out
+    }
+}
+
+/// Llama 2 chat: every exchange is `<s>[INST] user [/INST] assistant </s>`, with the system
+/// prompt wrapped in `<<SYS>>` inside the next instruction. The open `[/INST]` already
+/// prompts the reply, so `add_generation_prompt` adds nothing.
+pub struct Llama2;
+
+impl ChatTemplate for Llama2 {
+    fn name(&self) -> &'static str {
+        "llama2"
+    }
+
+    fn render(&self, messages: &[ChatMessage], _add_generation_prompt: bool) -> String {
+        let mut out = String::new();
+        let mut system: Option<&str> = None;
+        for message in messages {
+            match message.role {
+                Role::System => system = Some(message.content.as_str()),
+                Role::User => {
+                    out.push_str("<s>[INST] ");
+                    if let Some(system) = system.take() {
+                        out.push_str(&format!("<<SYS>>\n{}\n<</SYS>>\n\n", system));
+                    }
+                    out.push_str(&format!("{} [/INST]", message.content));
+                }
+                Role::Assistant => out.push_str(&format!(" {} </s>", message.content)),
+            }
+        }
+        out
+    }
+}
+
+/// The contents one per line with no markup, for base models and hand-written prompts.
+pub struct Raw;
+
+impl ChatTemplate for Raw {
+    fn name(&self) -> &'static str {
+        "raw"
+    }
+
+    fn render(&self, messages: &[ChatMessage], _add_generation_prompt: bool) -> String {
+        messages
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conversation() -> Vec<ChatMessage> {
+        vec![
+            ChatMessage::new(Role::System, "Be brief."),
+            ChatMessage::new(Role::User, "Hi"),
+            ChatMessage::new(Role::Assistant, "Hello!"),
+            ChatMessage::new(Role::User, "Bye"),
+        ]
+    }
+
+    #[test]
+    fn presets_render_their_delimiters() {
+        let render = |name: &str| template_by_name(name).expect("preset").render(&conversation(), true);
+
+        assert_eq!(
+            render("chatml"),
+            "<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>user\nHi<|im_end|>\n\
+             <|im_start|>assistant\nHello!<|im_end|>\n<|im_start|>user\nBye<|im_end|>\n\
+             <|im_start|>assistant\n"
+        );
+        assert_eq!(
+            render("alpaca"),
+            "Be brief.\n\n### Instruction:\nHi\n\n### Response:\nHello!\n\n\
+             ### Instruction:\nBye\n\n### Response:\n"
+        );
+        assert_eq!(
+            render("llama2"),
+            "<s>[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\nHi [/INST] Hello! </s><s>[INST] Bye [/INST]"
+        );
+        assert_eq!(render("raw"), "Be brief.\nHi\nHello!\nBye");
+        assert_eq!(render("ChatML"), render("chatml"));
+    }
+
+    #[test]
+    fn unknown_template_lists_the_presets() {
+        let err = template_by_name("vicuna").err().expect("unknown preset").to_string();
+        assert!(err.contains("vicuna"), "{err}");
+        for name in TEMPLATE_NAMES {
+            assert!(err.contains(name), "{err}");
+            assert_eq!(template_by_name(name).expect("preset").name(), name);
+        }
+    }
+}
@@ -0,0 +1,11 @@
+//! Fixtures shared by the unit tests of the library and of the server binary.
+
+/// Picks a stop string for `text`, a reply of one-letter tokens: its third and fourth letters,
+/// so a streamed match is split across two tokens. Returns the stop string and the part of
+/// `text` before its first occurrence.
+pub fn stop_in_reply(text: &str) -> (String, &str) {
+    assert!(text.len() >= 4, "reply {text:?} is too short to hold a stop string");
+    let stop = text[2..4].to_string();
+    let kept = text.find(&stop).expect("stop occurs");
+    (stop, &text[..kept])
+}
@@ -0,0 +1,180 @@
+use claude_core::ClaudeTransformer;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tch::{Device, Kind, Tensor};
+use tokenizer::BPE;
+
+use crate::Document;
+
+/// How [`embed_documents`] reduces per-token hidden states to one vector per document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Pooling {
+    /// Average over the document's tokens, padding excluded.
+    #[default]
+    Mean,
+    /// Hidden state of the last token, the only one that has attended to the whole document.
+    LastToken,
+}
+
+/// Progress reporting and cancellation for [`embed_documents`]; the default has neither.
+#[derive(Default)]
+pub struct EmbedControl<'a> {
+    /// Called after every batch with the number of documents embedded so far and the total.
+    pub progress: Option<&'a mut dyn FnMut(usize, usize)>,
+    /// Checked before each batch; once it is set, the rows embedded so far are returned.
+    pub cancel: Option<Arc<AtomicBool>>,
+}
+
+/// Embeds `docs` with `model`. Each text is tokenized and truncated to the model's context,
+/// the token IDs are right-padded into batches of `batch_size`, run through
+/// [`ClaudeTransformer::encode`] and pooled.
+///
+/// Returns a `[docs.len(), n_embd]` float tensor on `device`, one row per document in
+/// order, ready for [`crate::VectorStore::add_documents`]. Documents that encode to no
+/// tokens get a zero row. A cancelled run (see [`EmbedControl`]) returns fewer rows.
+pub fn embed_documents(
+    model: &ClaudeTransformer,
+    tokenizer: &BPE,
+    docs: &[Document],
+    batch_size: usize,
+    pooling: Pooling,
+    device: Device,
+    control: EmbedControl<'_>,
+) -> Tensor {
+    let EmbedControl { mut progress, cancel } = control;
+    let _guard = tch::no_grad_guard();
+    let max_len = model.config.max_seq_len as usize;
+
+    let mut pooled = Vec::new();
+    let mut embedded = 0;
+    for batch in docs.chunks(batch_size.max(1)) {
+        if cancel.as_ref().is_some_and(|cancel| cancel.load(Ordering::Relaxed)) {
+            break;
+        }
+        let ids: Vec<Vec<i64>> = batch
+            .iter()
+            .map(|doc| tokenizer.encode(&doc.text).into_iter().take(max_len).map(i64::from).collect())
+            .collect();
+        let lengths: Vec<i64> = ids.iter().map(|ids| ids.len() as i64).collect();
+        let width = ids.iter().map(Vec::len).max().unwrap_or(0).max(1);
+
+        // Padding goes after each document, so causal attention never lets a real token see it.
+        let mut flat = Vec::with_capacity(batch.len() * width);
+        for ids in &ids {
+            flat.extend_from_slice(ids);
+            flat.resize(flat.len() + width - ids.len(), 0);
+        }
+        let input = Tensor::from_slice(&flat).view([batch.len() as i64, width as i64]).to(device);
+        let hidden = model.encode(&input).to_kind(Kind::Float);
+        pooled.push(pool(&hidden, &Tensor::from_slice(&lengths).to(device), pooling));
+
+        embedded += batch.len();
+        if let Some(progress) = progress.as_mut() {
+            progress(embedded, docs.len());
+        }
+    }
+
+    if pooled.is_empty() {
+        return Tensor::zeros([0, model.config.n_embd], (Kind::Float, device));
+    }
+    Tensor::cat(&pooled, 0)
+}
+
+/// Pools `hidden` (`[batch, width, n_embd]`) over the first `lengths[i]` positions of each row.
+fn pool(hidden: &Tensor, lengths: &Tensor, pooling: Pooling) -> Tensor {
+    let (batch, width, n_embd) = hidden.size3().expect("hidden states are [batch, seq_len, n_embd]");
+    let pooled = match pooling {
+        Pooling::Mean => {
+            let positions = Tensor::arange(width, (Kind::Int64, hidden.device()));
+            let mask = positions
+                .unsqueeze(0)
+                .lt_tensor(&lengths.unsqueeze(-1))
+                .to_kind(Kind::Float)
+                .unsqueeze(-1);
+            let summed = (hidden * mask).sum_dim_intlist(Some(&[1][..]), false, Kind::Float);
+            summed / lengths.clamp_min(1).to_kind(Kind::Float).unsqueeze(-1)
+        }
+        Pooling::LastToken => {
+            let last = (lengths - 1).clamp_min(0).view([batch, 1, 1]).expand([batch, 1, n_embd], false);
+            hidden.gather(1, &last, false).squeeze_dim(1)
+        }
+    };
+    pooled * lengths.gt(0).to_kind(Kind::Float).unsqueeze(-1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claude_core::ModelConfig;
+    use std::collections::HashMap;
+    use tokenizer::Vocab;
+
+    fn tiny_model_and_tokenizer() -> (ClaudeTransformer, BPE) {
+        let mut vocab = Vocab::new();
+        for id in 0..16u32 {
+            vocab.insert(((b'a' + id as u8) as char).to_string(), id);
+        }
+        let vs = tch::nn::VarStore::new(Device::Cpu);
+        let model = ClaudeTransformer::new_seeded(&vs.root(), &ModelConfig::tiny(16), 0);
+        (model, BPE::new(vocab, HashMap::new()))
+    }
+
+    fn document(text: &str) -> Document {
+        Document {
+            id: text.to_string(),
+            text: text.to_string(),
+            metadata: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn embeds_each_document_into_one_row() {
+        let (model, tokenizer) = tiny_model_and_tokenizer();
+        let docs = [document("abc"), document("de")];
+
+        let embeddings = embed_documents(&model, &tokenizer, &docs, 2, Pooling::Mean, Device::Cpu, EmbedControl::default());
+
+        assert_eq!(embeddings.size(), vec![2, model.config.n_embd]);
+        assert_eq!(embeddings.kind(), Kind::Float);
+    }
+
+    #[test]
+    fn padding_does_not_change_embeddings() {
+        let (model, tokenizer) = tiny_model_and_tokenizer();
+        let docs = [document("abcdef"), document("gh")];
+
+        for pooling in [Pooling::Mean, Pooling::LastToken] {
+            let batched = embed_documents(&model, &tokenizer, &docs, 2, pooling, Device::Cpu, EmbedControl::default());
+            let one_by_one = embed_documents(&model, &tokenizer, &docs, 1, pooling, Device::Cpu, EmbedControl::default());
+            assert!(batched.allclose(&one_by_one, 1e-5, 1e-5, false), "{pooling:?}");
+        }
+    }
+
+    #[test]
+    fn cancelling_after_one_batch_returns_the_rows_embedded_so_far() {
+        let (model, tokenizer) = tiny_model_and_tokenizer();
+        let docs = [document("abc"), document("de"), document("fgh")];
+        let cancel = Arc::new(AtomicBool::new(false));
+        let mut calls = Vec::new();
+        let mut on_batch = |done: usize, total: usize| {
+            calls.push((done, total));
+            cancel.store(true, Ordering::Relaxed);
+        };
+
+        let embeddings = embed_documents(
+            &model,
+            &tokenizer,
+            &docs,
+            1,
+            Pooling::Mean,
+            Device::Cpu,
+            EmbedControl {
+                progress: Some(&mut on_batch),
+                cancel: Some(Arc::clone(&cancel)),
+            },
+        );
+
+        assert_eq!(calls, [(1, 3)]);
+        assert_eq!(embeddings.size(), vec![1, model.config.n_embd]);
+    }
+}
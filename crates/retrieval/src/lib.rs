@@ -1,18 +1,36 @@
+use claude_core::tensor_util::{tensor_to_vec_f64, tensor_to_vec_i64};
 use tch::{Tensor, Device, Kind};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+pub mod embedder;
+pub mod metadata;
+mod mmap;
+
+pub use embedder::{embed_documents, EmbedControl, Pooling};
+pub use metadata::{metadata_from_strings, FilterOp, MetaValue, MetadataFilter};
+pub use mmap::documents_path;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Document {
     pub id: String,
     pub text: String,
-    pub metadata: HashMap<String, String>,
+    pub metadata: HashMap<String, MetaValue>,
 }
 
 pub struct VectorStore {
     documents: Vec<Document>,
     embeddings: Option<Tensor>,
+    /// Device the similarity scores are computed on.
     device: Device,
+    /// Device the embeddings are kept on.
+    storage_device: Device,
+    /// L2-normalize embedding rows in [`VectorStore::add_documents`].
+    normalize_on_add: bool,
+    /// Memory maps that `embeddings` may point into (see [`VectorStore::open_mmap`]),
+    /// kept alive for as long as the store.
+    mmaps: Vec<Arc<memmap2::Mmap>>,
 }
 
 impl VectorStore {
@@ -21,53 +39,336 @@ impl VectorStore {
             documents: Vec::new(),
             embeddings: None,
             device,
+            storage_device: device,
+            normalize_on_add: false,
+            mmaps: Vec::new(),
+        }
+    }
+
+    /// A store that keeps its embeddings in CPU memory and computes scores on `device`.
+    /// Use [`VectorStore::tiled_search`] to search indices that don't fit in GPU memory.
+    pub fn new_offloaded(device: Device) -> Self {
+        Self {
+            documents: Vec::new(),
+            embeddings: None,
+            device,
+            storage_device: Device::Cpu,
+            normalize_on_add: false,
+            mmaps: Vec::new(),
         }
     }
 
+    /// Stores every added embedding row L2-normalized instead of as given, so that
+    /// [`VectorStore::search_dot`] ranks exactly like cosine [`VectorStore::search`].
+    pub fn with_normalize_on_add(mut self, normalize: bool) -> Self {
+        self.normalize_on_add = normalize;
+        self
+    }
+
     pub fn add_documents(&mut self, docs: Vec<Document>, embeddings: Tensor) {
+        let embeddings = if self.normalize_on_add {
+            let norm = embeddings.pow_tensor_scalar(2.0).sum_dim_intlist(Some(&[-1][..]), true, Kind::Float).sqrt();
+            &embeddings / (norm + 1e-8)
+        } else {
+            embeddings
+        };
         self.documents.extend(docs);
         match &mut self.embeddings {
             Some(existing) => {
-                let new_embeddings = embeddings.to(self.device);
+                let new_embeddings = embeddings.to(self.storage_device);
                 *existing = Tensor::cat(&[existing.shallow_clone(), new_embeddings], 0);
             }
             None => {
-                self.embeddings = Some(embeddings.to(self.device));
+                self.embeddings = Some(embeddings.to(self.storage_device));
+            }
+        }
+    }
+
+    /// Appends `other`'s documents and embeddings (moved to this store's storage device),
+    /// e.g. to combine indices built in parallel shards. IDs are kept as they are; with
+    /// `dedup_by_id`, documents of `other` whose ID is already present are skipped.
+    pub fn extend(&mut self, other: VectorStore, dedup_by_id: bool) {
+        // `other`'s embeddings may be kept as they are, so its memory maps must outlive them.
+        self.mmaps.extend(other.mmaps);
+        let Some(embeddings) = other.embeddings else {
+            return;
+        };
+        if !dedup_by_id {
+            self.add_documents(other.documents, embeddings);
+            return;
+        }
+
+        let mut seen: HashSet<String> = self.documents.iter().map(|doc| doc.id.clone()).collect();
+        let mut rows = Vec::new();
+        let mut docs = Vec::new();
+        for (row, doc) in other.documents.into_iter().enumerate() {
+            if seen.insert(doc.id.clone()) {
+                rows.push(row as i64);
+                docs.push(doc);
             }
         }
+        if docs.is_empty() {
+            return;
+        }
+        let rows = Tensor::from_slice(&rows).to(embeddings.device());
+        self.add_documents(docs, embeddings.index_select(0, &rows));
     }
 
     /// Search for most similar documents using cosine similarity
     /// query_embedding: [dim] or [1, dim] tensor
-    pub fn search(&self, query_embedding: &Tensor, top_k: usize) -> Vec<(&Document, f64)> {
+    /// Fails only if the scores can't be copied back from the device.
+    pub fn search(&self, query_embedding: &Tensor, top_k: usize) -> anyhow::Result<Vec<(&Document, f64)>> {
         let embeddings = match &self.embeddings {
             Some(e) => e,
-            None => return Vec::new(),
+            None => return Ok(Vec::new()),
         };
 
+        let q_unit = self.unit_query(query_embedding);
+        let scores = Self::cosine_scores(&q_unit, &embeddings.to_device(self.device));
+        self.top_documents(&scores, top_k, None)
+    }
+
+    /// Ranks documents by the raw dot product with the query, without normalizing the index.
+    /// With [`VectorStore::with_normalize_on_add`] this gives the same ranking as cosine
+    /// [`VectorStore::search`] while skipping the per-row norms.
+    pub fn search_dot(&self, query_embedding: &Tensor, top_k: usize) -> anyhow::Result<Vec<(&Document, f64)>> {
+        let embeddings = match &self.embeddings {
+            Some(e) => e,
+            None => return Ok(Vec::new()),
+        };
+
+        let q = query_embedding.to_device(self.device).view([1, -1]).to_kind(Kind::Double);
+        let scores = q.matmul(&embeddings.to_device(self.device).to_kind(Kind::Double).transpose(0, 1)).view([-1]);
+        self.top_documents(&scores, top_k, None)
+    }
+
+    /// Like [`VectorStore::search`], but only over the documents that match every filter.
+    pub fn search_filtered(
+        &self,
+        query_embedding: &Tensor,
+        top_k: usize,
+        filters: &[MetadataFilter],
+    ) -> anyhow::Result<Vec<(&Document, f64)>> {
+        let rows: Vec<i64> = self
+            .documents
+            .iter()
+            .enumerate()
+            .filter(|(_, doc)| filters.iter().all(|filter| filter.matches(doc)))
+            .map(|(row, _)| row as i64)
+            .collect();
+        let embeddings = match &self.embeddings {
+            Some(e) if !rows.is_empty() => e,
+            _ => return Ok(Vec::new()),
+        };
+
+        let candidates = embeddings
+            .index_select(0, &Tensor::from_slice(&rows).to(embeddings.device()))
+            .to_device(self.device);
+        let scores = Self::cosine_scores(&self.unit_query(query_embedding), &candidates);
+        self.top_documents(&scores, top_k, Some(&rows))
+    }
+
+    /// Same results as [`VectorStore::search`], but moves the embeddings to `self.device`
+    /// `tile_size` rows at a time and keeps a running top-k across tiles, so only one tile
+    /// has to fit in device memory.
+    pub fn tiled_search(
+        &self,
+        query_embedding: &Tensor,
+        top_k: usize,
+        tile_size: usize,
+    ) -> anyhow::Result<Vec<(&Document, f64)>> {
+        let embeddings = match &self.embeddings {
+            Some(e) => e,
+            None => return Ok(Vec::new()),
+        };
+
+        let q_unit = self.unit_query(query_embedding);
+        let n = embeddings.size()[0];
+        let tile_size = tile_size.max(1) as i64;
+        let k = std::cmp::min(top_k, self.documents.len());
+
+        // Running top-k as (score, row), best first.
+        let mut best: Vec<(f64, usize)> = Vec::with_capacity(2 * k);
+        let mut start = 0;
+        while start < n {
+            let len = tile_size.min(n - start);
+            let tile = embeddings.narrow(0, start, len).to_device(self.device);
+            let scores = Self::cosine_scores(&q_unit, &tile);
+            let (tile_scores, tile_indices) = scores.topk(k.min(len as usize) as i64, 0, true, true);
+
+            let scores_vec = tensor_to_vec_f64(&tile_scores)?;
+            let indices_vec = tensor_to_vec_i64(&tile_indices)?;
+            best.extend(
+                scores_vec
+                    .into_iter()
+                    .zip(indices_vec)
+                    .map(|(score, idx)| (score, (start + idx) as usize)),
+            );
+            best.sort_by(|a, b| b.0.total_cmp(&a.0));
+            best.truncate(k);
+
+            start += len;
+        }
+
+        Ok(best.into_iter()
+            .map(|(score, idx)| (&self.documents[idx], score))
+            .collect())
+    }
+
+    /// The `top_k` best of `scores` (one per candidate) as `(document, score)`, best first.
+    /// Candidate `i` is document `rows[i]`, or document `i` without `rows`.
+    fn top_documents(&self, scores: &Tensor, top_k: usize, rows: Option<&[i64]>) -> anyhow::Result<Vec<(&Document, f64)>> {
+        let k = std::cmp::min(top_k, scores.size()[0] as usize);
+        let (top_scores, top_indices) = scores.topk(k as i64, 0, true, true);
+
+        // Scores are computed in double precision.
+        let scores_vec = tensor_to_vec_f64(&top_scores)?;
+        let indices_vec = tensor_to_vec_i64(&top_indices)?;
+        Ok(indices_vec
+            .into_iter()
+            .zip(scores_vec)
+            .map(|(idx, score)| {
+                let row = rows.map_or(idx, |rows| rows[idx as usize]);
+                (&self.documents[row as usize], score)
+            })
+            .collect())
+    }
+
+    /// The query as a unit-length `[1, dim]` row on `self.device`.
+    fn unit_query(&self, query_embedding: &Tensor) -> Tensor {
         let q = query_embedding.to_device(self.device).view([1, -1]);
-        
-        // Normalize for cosine similarity
         let q_norm = q.pow_tensor_scalar(2.0).sum_dim_intlist(Some(&[-1][..]), false, Kind::Double).sqrt();
+        &q / (q_norm + 1e-8)
+    }
+
+    /// Cosine similarity (double precision) of a unit query against every row of `embeddings`.
+    fn cosine_scores(q_unit: &Tensor, embeddings: &Tensor) -> Tensor {
         let e_norm = embeddings.pow_tensor_scalar(2.0).sum_dim_intlist(Some(&[-1][..]), true, Kind::Double).sqrt();
-        
-        let q_unit = &q / (q_norm + 1e-8);
         let e_unit = embeddings / (e_norm + 1e-8);
-        
-        let scores = q_unit.matmul(&e_unit.transpose(0, 1)).view([-1]);
-        let k = std::cmp::min(top_k, self.documents.len());
-        
-        let (top_scores, top_indices) = scores.topk(k as i64, 0, true, true);
-        
-        let scores_vec: Vec<f32> = Vec::<f32>::try_from(&top_scores).unwrap_or_default();
-        let indices_vec: Vec<i64> = Vec::<i64>::try_from(&top_indices).unwrap_or_default();
-        
-        indices_vec.iter().zip(scores_vec.iter())
-            .map(|(&idx, &score)| (&self.documents[idx as usize], score as f64))
-            .collect()
+        q_unit.matmul(&e_unit.transpose(0, 1)).view([-1])
     }
 
     pub fn len(&self) -> usize {
         self.documents.len()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(i: usize) -> Document {
+        Document {
+            id: format!("doc-{i}"),
+            text: format!("document {i}"),
+            metadata: HashMap::new(),
+        }
+    }
+
+    /// A store holding documents `ids` with one-hot embeddings along the same axes.
+    fn one_hot_store(ids: &[usize]) -> VectorStore {
+        let mut store = VectorStore::new(Device::Cpu);
+        let rows = Tensor::from_slice(&ids.iter().map(|&i| i as i64).collect::<Vec<_>>());
+        let embeddings = Tensor::eye(4, (Kind::Float, Device::Cpu)).index_select(0, &rows);
+        store.add_documents(ids.iter().copied().map(document).collect(), embeddings);
+        store
+    }
+
+    #[test]
+    fn extend_merges_shards_into_one_searchable_store() {
+        let mut merged = VectorStore::new(Device::Cpu);
+        merged.extend(VectorStore::new(Device::Cpu), false);
+        merged.extend(one_hot_store(&[0, 1]), false);
+        merged.extend(one_hot_store(&[1, 2]), false);
+        assert_eq!(merged.len(), 4);
+
+        let mut deduped = one_hot_store(&[0, 1]);
+        deduped.extend(one_hot_store(&[1, 2]), true);
+        assert_eq!(deduped.len(), 3);
+
+        for store in [&merged, &deduped] {
+            for (axis, id) in [(0, "doc-0"), (2, "doc-2")] {
+                let query = Tensor::eye(4, (Kind::Float, Device::Cpu)).get(axis);
+                assert_eq!(store.search(&query, 1).expect("search")[0].0.id, id);
+            }
+        }
+    }
+
+    #[test]
+    fn search_filtered_keeps_documents_matching_a_numeric_range() {
+        let mut store = VectorStore::new(Device::Cpu);
+        let docs = [2018, 2020, 2023]
+            .into_iter()
+            .enumerate()
+            .map(|(i, year)| Document {
+                metadata: HashMap::from([("year".to_string(), MetaValue::Int(year))]),
+                ..document(i)
+            })
+            .collect();
+        store.add_documents(docs, Tensor::eye(3, (Kind::Float, Device::Cpu)));
+        let recent = [MetadataFilter::new("year", FilterOp::Ge, 2020)];
+        // Closest to the 2018 document, which the filter excludes.
+        let query = Tensor::from_slice(&[1.0f32, 0.5, 0.1]);
+
+        let results = store.search_filtered(&query, 5, &recent).expect("search");
+
+        let ids: Vec<&str> = results.iter().map(|(doc, _)| doc.id.as_str()).collect();
+        assert_eq!(ids, ["doc-1", "doc-2"]);
+        let before = [MetadataFilter::new("year", FilterOp::Lt, 2020)];
+        assert_eq!(store.search_filtered(&query, 5, &before).expect("search")[0].0.id, "doc-0");
+        assert!(store
+            .search_filtered(&query, 5, &[MetadataFilter::new("year", FilterOp::Gt, 2030)])
+            .expect("search")
+            .is_empty());
+    }
+
+    #[test]
+    fn normalized_store_ranks_the_same_by_dot_product_and_cosine() {
+        tch::manual_seed(3);
+        let n = 20;
+        // Rows of very different lengths, so raw dot products would favour the long ones.
+        let scales = Tensor::arange_start(1, n as i64 + 1, (Kind::Float, Device::Cpu)).unsqueeze(1);
+        let embeddings = Tensor::randn([n as i64, 8], (Kind::Float, Device::Cpu)) * scales;
+        let mut store = VectorStore::new(Device::Cpu).with_normalize_on_add(true);
+        store.add_documents((0..n).map(document).collect(), embeddings);
+        let query = Tensor::randn([8], (Kind::Float, Device::Cpu)) * 5.0;
+
+        let cosine: Vec<&str> = store
+            .search(&query, n)
+            .expect("search")
+            .iter()
+            .map(|(doc, _)| doc.id.as_str())
+            .collect();
+        let dot: Vec<&str> = store
+            .search_dot(&query, n)
+            .expect("search")
+            .iter()
+            .map(|(doc, _)| doc.id.as_str())
+            .collect();
+
+        assert_eq!(dot, cosine);
+        let stored = store.embeddings.as_ref().expect("embeddings");
+        let norms = stored.pow_tensor_scalar(2.0).sum_dim_intlist(Some(&[-1][..]), false, Kind::Float).sqrt();
+        assert!(norms.allclose(&Tensor::ones([n as i64], (Kind::Float, Device::Cpu)), 1e-5, 1e-5, false));
+    }
+
+    #[test]
+    fn tiled_search_matches_whole_index_search() {
+        tch::manual_seed(7);
+        let n = 50;
+        let embeddings = Tensor::randn([n as i64, 16], (Kind::Float, Device::Cpu));
+        let mut store = VectorStore::new_offloaded(Device::Cpu);
+        store.add_documents((0..n).map(document).collect(), embeddings);
+        let query = Tensor::randn([16], (Kind::Float, Device::Cpu));
+
+        let whole = store.search(&query, 5).expect("search");
+        let tiled = store.tiled_search(&query, 5, 8).expect("tiled search");
+
+        assert_eq!(whole.len(), 5);
+        assert_eq!(tiled.len(), 5);
+        for ((whole_doc, whole_score), (tiled_doc, tiled_score)) in whole.iter().zip(&tiled) {
+            assert_eq!(whole_doc.id, tiled_doc.id);
+            assert!((whole_score - tiled_score).abs() < 1e-9);
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use crate::Document;
+
+/// A typed [`Document`] metadata value. Serialized untagged, so JSON metadata reads as
+/// `{"year": 2021, "source": "wiki", "draft": false}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MetaValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+impl MetaValue {
+    /// Reads a string the way older string-only metadata stored values: integers, floats
+    /// and `true`/`false` become typed values, anything else stays a string.
+    pub fn infer(s: &str) -> Self {
+        if let Ok(v) = s.parse::<i64>() {
+            MetaValue::Int(v)
+        } else if let Ok(v) = s.parse::<f64>() {
+            MetaValue::Float(v)
+        } else if let Ok(v) = s.parse::<bool>() {
+            MetaValue::Bool(v)
+        } else {
+            MetaValue::Str(s.to_string())
+        }
+    }
+
+    /// Orders two values of compatible types: numbers (ints and floats mix), strings and
+    /// bools. A numeric string, as older string-only metadata stored numbers, compares with
+    /// a number as the number it spells. `None` for values of different kinds, or NaN.
+    pub fn compare(&self, other: &MetaValue) -> Option<Ordering> {
+        match (self, other) {
+            (MetaValue::Int(a), MetaValue::Int(b)) => Some(a.cmp(b)),
+            (MetaValue::Str(a), MetaValue::Str(b)) => Some(a.cmp(b)),
+            (MetaValue::Bool(a), MetaValue::Bool(b)) => Some(a.cmp(b)),
+            (a, b) => a.numeric()?.partial_cmp(&b.numeric()?),
+        }
+    }
+
+    /// [`MetaValue::as_f64`], also parsing numeric strings.
+    fn numeric(&self) -> Option<f64> {
+        match self {
+            MetaValue::Str(s) => s.trim().parse().ok(),
+            value => value.as_f64(),
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            MetaValue::Int(v) => Some(*v as f64),
+            MetaValue::Float(v) => Some(*v),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for MetaValue {
+    fn from(v: &str) -> Self {
+        MetaValue::Str(v.to_string())
+    }
+}
+
+impl From<String> for MetaValue {
+    fn from(v: String) -> Self {
+        MetaValue::Str(v)
+    }
+}
+
+impl From<i64> for MetaValue {
+    fn from(v: i64) -> Self {
+        MetaValue::Int(v)
+    }
+}
+
+impl From<f64> for MetaValue {
+    fn from(v: f64) -> Self {
+        MetaValue::Float(v)
+    }
+}
+
+impl From<bool> for MetaValue {
+    fn from(v: bool) -> Self {
+        MetaValue::Bool(v)
+    }
+}
+
+/// Converts string-only metadata to typed metadata, inferring each value's type with
+/// [`MetaValue::infer`].
+pub fn metadata_from_strings(metadata: HashMap<String, String>) -> HashMap<String, MetaValue> {
+    metadata
+        .into_iter()
+        .map(|(key, value)| {
+            let value = MetaValue::infer(&value);
+            (key, value)
+        })
+        .collect()
+}
+
+/// Comparison operator of a [`MetadataFilter`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FilterOp {
+    #[serde(rename = "==")]
+    Eq,
+    #[serde(rename = "!=")]
+    Ne,
+    #[serde(rename = "<")]
+    Lt,
+    #[serde(rename = "<=")]
+    Le,
+    #[serde(rename = ">")]
+    Gt,
+    #[serde(rename = ">=")]
+    Ge,
+}
+
+/// A predicate on one metadata key, e.g. `{"key": "year", "op": ">=", "value": 2020}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MetadataFilter {
+    pub key: String,
+    pub op: FilterOp,
+    pub value: MetaValue,
+}
+
+impl MetadataFilter {
+    pub fn new(key: impl Into<String>, op: FilterOp, value: impl Into<MetaValue>) -> Self {
+        Self {
+            key: key.into(),
+            op,
+            value: value.into(),
+        }
+    }
+
+    /// True if `doc` has the key and its value compares to `self.value` as `self.op` asks.
+    /// Values that can't be compared (a string against a number) never match.
+    pub fn matches(&self, doc: &Document) -> bool {
+        let Some(ordering) = doc.metadata.get(&self.key).and_then(|v| v.compare(&self.value)) else {
+            return false;
+        };
+        match self.op {
+            FilterOp::Eq => ordering == Ordering::Equal,
+            FilterOp::Ne => ordering != Ordering::Equal,
+            FilterOp::Lt => ordering == Ordering::Less,
+            FilterOp::Le => ordering != Ordering::Greater,
+            FilterOp::Gt => ordering == Ordering::Greater,
+            FilterOp::Ge => ordering != Ordering::Less,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn string_metadata_migrates_to_typed_values() {
+        let migrated = metadata_from_strings(HashMap::from([
+            ("year".to_string(), "2021".to_string()),
+            ("score".to_string(), "0.5".to_string()),
+            ("draft".to_string(), "false".to_string()),
+            ("source".to_string(), "wiki".to_string()),
+        ]));
+
+        assert_eq!(migrated["year"], MetaValue::Int(2021));
+        assert_eq!(migrated["score"], MetaValue::Float(0.5));
+        assert_eq!(migrated["draft"], MetaValue::Bool(false));
+        assert_eq!(migrated["source"], MetaValue::from("wiki"));
+    }
+
+    #[test]
+    fn filters_deserialize_from_json_predicates() {
+        let filter: MetadataFilter =
+            serde_json::from_str(r#"{"key": "year", "op": ">=", "value": 2020}"#).expect("parse filter");
+        assert_eq!(filter, MetadataFilter::new("year", FilterOp::Ge, 2020));
+
+        let doc: Document = serde_json::from_str(
+            r#"{"id": "a", "text": "t", "metadata": {"year": 2020.0, "source": "wiki"}}"#,
+        )
+        .expect("parse document");
+        assert!(filter.matches(&doc));
+        assert!(!MetadataFilter::new("source", FilterOp::Ge, 2020).matches(&doc));
+    }
+
+    #[test]
+    fn numeric_strings_match_numeric_range_filters() {
+        let doc: Document = serde_json::from_str(
+            r#"{"id": "a", "text": "t", "metadata": {"year": "2021", "score": "0.5"}}"#,
+        )
+        .expect("parse document");
+
+        assert!(MetadataFilter::new("year", FilterOp::Ge, 2020).matches(&doc));
+        assert!(!MetadataFilter::new("year", FilterOp::Lt, 2021).matches(&doc));
+        assert!(MetadataFilter::new("score", FilterOp::Lt, 1.0).matches(&doc));
+        // Two strings still compare as text.
+        assert!(MetadataFilter::new("year", FilterOp::Lt, "3").matches(&doc));
+    }
+}
@@ -0,0 +1,171 @@
+use anyhow::Context;
+use memmap2::MmapOptions;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tch::{Device, Kind, Tensor};
+
+use crate::{Document, VectorStore};
+
+/// First bytes of an embeddings file written by [`VectorStore::save_mmap`].
+const MAGIC: &[u8; 8] = b"VSTORE01";
+/// Magic, row count and dimension (both little-endian `u64`).
+const HEADER_LEN: usize = 24;
+
+/// Where [`VectorStore::save_mmap`] puts the documents for an embeddings file at `path`:
+/// next to it, with the extension `docs.json` (`index.vec` -> `index.docs.json`).
+pub fn documents_path(path: &Path) -> PathBuf {
+    path.with_extension("docs.json")
+}
+
+impl VectorStore {
+    /// Writes the embeddings as raw little-endian `f32` rows behind a small header, and the
+    /// documents as JSON to [`documents_path`], for [`VectorStore::open_mmap`].
+    pub fn save_mmap<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let (rows, dim) = match &self.embeddings {
+            Some(embeddings) => {
+                let (rows, dim) = embeddings.size2()?;
+                (rows as u64, dim as u64)
+            }
+            None => (0, 0),
+        };
+
+        let mut writer = BufWriter::new(File::create(path).with_context(|| format!("Failed to create {:?}", path))?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&rows.to_le_bytes())?;
+        writer.write_all(&dim.to_le_bytes())?;
+        if let Some(embeddings) = &self.embeddings {
+            let values = Vec::<f32>::try_from(&embeddings.to_device(Device::Cpu).to_kind(Kind::Float).view([-1]))?;
+            for value in values {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        }
+        writer.flush()?;
+
+        let docs_path = documents_path(path);
+        let docs = File::create(&docs_path).with_context(|| format!("Failed to create {:?}", docs_path))?;
+        serde_json::to_writer(BufWriter::new(docs), &self.documents)?;
+        Ok(())
+    }
+
+    /// Opens an index written by [`VectorStore::save_mmap`] without reading the embeddings
+    /// into memory: they stay in the memory-mapped file, and pages are read in as searches
+    /// touch them. The documents are loaded from [`documents_path`]. The store is offloaded
+    /// like [`VectorStore::new_offloaded`], so [`VectorStore::tiled_search`] moves one tile
+    /// at a time to `device` and indices larger than RAM can be searched.
+    pub fn open_mmap<P: AsRef<Path>>(path: P, device: Device) -> anyhow::Result<Self> {
+        anyhow::ensure!(cfg!(target_endian = "little"), "memory-mapped indices need a little-endian host");
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+        // Safety: the map is read-only, and the file must not be modified while the store is open.
+        let mmap = unsafe { MmapOptions::new().map(&file)? };
+        anyhow::ensure!(
+            mmap.len() >= HEADER_LEN && &mmap[..MAGIC.len()] == MAGIC,
+            "{:?} is not a vector store embeddings file",
+            path
+        );
+        let read_u64 = |offset: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&mmap[offset..offset + 8]);
+            u64::from_le_bytes(bytes) as usize
+        };
+        let (rows, dim) = (read_u64(8), read_u64(16));
+        let expected_len = rows
+            .checked_mul(dim)
+            .and_then(|n| n.checked_mul(std::mem::size_of::<f32>()))
+            .and_then(|n| n.checked_add(HEADER_LEN));
+        anyhow::ensure!(
+            expected_len == Some(mmap.len()),
+            "{:?} holds {} bytes, expected {} rows of dimension {}",
+            path,
+            mmap.len(),
+            rows,
+            dim
+        );
+
+        let docs_path = documents_path(path);
+        let docs = File::open(&docs_path).with_context(|| format!("Failed to open {:?}", docs_path))?;
+        let documents: Vec<Document> = serde_json::from_reader(std::io::BufReader::new(docs))
+            .with_context(|| format!("Failed to parse {:?}", docs_path))?;
+        anyhow::ensure!(
+            documents.len() == rows,
+            "{:?} has {} documents but the embeddings file has {} rows",
+            docs_path,
+            documents.len(),
+            rows
+        );
+
+        let mut store = VectorStore::new_offloaded(device);
+        if rows > 0 {
+            let mmap = Arc::new(mmap);
+            // Safety: the data is in bounds (checked above), 4-byte aligned (the map is
+            // page-aligned and the header is 24 bytes) and only ever read; `store.mmaps`
+            // keeps the map alive as long as the tensor.
+            let embeddings = unsafe {
+                Tensor::from_blob(
+                    mmap[HEADER_LEN..].as_ptr(),
+                    &[rows as i64, dim as i64],
+                    &[dim as i64, 1],
+                    Kind::Float,
+                    Device::Cpu,
+                )
+            };
+            store.embeddings = Some(embeddings);
+            store.mmaps.push(mmap);
+        }
+        store.documents = documents;
+        Ok(store)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn mmap_opened_store_searches_like_the_in_memory_one() {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_nanos();
+        let path = std::env::temp_dir().join(format!("retrieval_mmap_test_{unique}.vec"));
+
+        tch::manual_seed(11);
+        let n = 30;
+        let mut in_memory = VectorStore::new(Device::Cpu);
+        let docs = (0..n)
+            .map(|i| Document {
+                id: format!("doc-{i}"),
+                text: format!("document {i}"),
+                metadata: HashMap::from([("row".to_string(), i.into())]),
+            })
+            .collect();
+        in_memory.add_documents(docs, Tensor::randn([n, 16], (Kind::Float, Device::Cpu)));
+        in_memory.save_mmap(&path).expect("save index");
+
+        let mapped = VectorStore::open_mmap(&path, Device::Cpu).expect("open index");
+        assert_eq!(mapped.len(), in_memory.len());
+        let query = Tensor::randn([16], (Kind::Float, Device::Cpu));
+        let expected = in_memory.search(&query, 5).expect("search");
+        for results in [
+            mapped.search(&query, 5).expect("search mapped"),
+            mapped.tiled_search(&query, 5, 7).expect("tiled search mapped"),
+        ] {
+            assert_eq!(results.len(), expected.len());
+            for ((doc, score), (expected_doc, expected_score)) in results.iter().zip(&expected) {
+                assert_eq!(doc.id, expected_doc.id);
+                assert_eq!(doc.metadata, expected_doc.metadata);
+                assert!((score - expected_score).abs() < 1e-9);
+            }
+        }
+
+        drop(mapped);
+        fs::remove_file(documents_path(&path)).expect("cleanup temp documents");
+        fs::remove_file(&path).expect("cleanup temp index");
+    }
+}
@@ -1,5 +1,6 @@
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::{BufRead, BufReader};
@@ -12,19 +13,129 @@ use crate::vocab::Vocab;
 #[derive(Serialize, Deserialize)]
 pub struct BPE {
     pub vocab: Vocab,
+    #[serde(with = "merges_serde")]
     pub merges: HashMap<(String, String), u32>,
     #[serde(skip)]
     #[serde(default = "default_cache")]
     pub cache: RwLock<HashMap<String, Vec<String>>>, // Thread-safe cache
+    /// When false, `bpe()` bypasses the cache (and its locks) entirely.
+    #[serde(skip)]
+    #[serde(default = "default_cache_enabled")]
+    pub cache_enabled: bool,
     #[serde(skip)]
     #[serde(default = "default_regex")]
     pub regex: Regex,
+    /// What `encode` does with sub-tokens that aren't in the vocab.
+    #[serde(default)]
+    pub fallback: FallbackStrategy,
+    /// Lowercase every pre-token before encoding, so that differently cased text maps to
+    /// the same IDs. The original casing is not recorded: `decode` returns lowercase text.
+    #[serde(default)]
+    pub lowercase: bool,
+    /// Split every digit into its own pre-token (as Llama does), so numbers are always
+    /// encoded one digit per token and never as merged multi-digit tokens.
+    #[serde(default)]
+    pub split_digits: bool,
+    /// Prepend a space to text that doesn't start with one, so a text's first word is
+    /// encoded like the same word mid-sentence ("hello" as " hello"). `decode` keeps the space.
+    #[serde(default)]
+    pub prefix_space: bool,
+}
+
+/// How [`BPE::encode`] handles a sub-token missing from the vocab.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FallbackStrategy {
+    /// Encode its bytes as `<0xNN>` tokens, using `<UNK>` for bytes missing from the vocab.
+    #[default]
+    ByteFallback,
+    /// Encode it as a single `<UNK>`.
+    Unk,
+    /// Drop it. Dropped sub-tokens are counted in [`Coverage::skipped_tokens`].
+    Skip,
+}
+
+/// How well a text is covered by the vocab, as reported by [`BPE::coverage`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Coverage {
+    /// Total number of token IDs produced.
+    pub total_tokens: usize,
+    /// IDs produced through the `<0xNN>` byte fallback.
+    pub byte_fallback_tokens: usize,
+    /// IDs produced as `<UNK>`.
+    pub unk_tokens: usize,
+    /// Sub-tokens dropped without producing an ID (see [`FallbackStrategy::Skip`]).
+    pub skipped_tokens: usize,
+    /// Fraction of tokens (IDs plus skipped sub-tokens) that came from a fallback or were skipped.
+    pub oov_rate: f64,
+}
+
+/// Serializes merges as a rank-ordered list of `[first, second, rank]` entries, since
+/// JSON maps can't have tuple keys. Human-readable formats also accept a (legacy) map.
+mod merges_serde {
+    use serde::de::{MapAccess, SeqAccess, Visitor};
+    use serde::{Deserializer, Serializer};
+    use std::collections::HashMap;
+    use std::fmt;
+
+    type Merges = HashMap<(String, String), u32>;
+
+    pub fn serialize<S: Serializer>(merges: &Merges, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut sorted: Vec<(&String, &String, u32)> =
+            merges.iter().map(|((a, b), &rank)| (a, b, rank)).collect();
+        sorted.sort_by_key(|&(_, _, rank)| rank);
+        serializer.collect_seq(sorted)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Merges, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(MergesVisitor)
+        } else {
+            deserializer.deserialize_seq(MergesVisitor)
+        }
+    }
+
+    struct MergesVisitor;
+
+    impl<'de> Visitor<'de> for MergesVisitor {
+        type Value = Merges;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a list of [first, second, rank] merges")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Merges, A::Error> {
+            let mut merges = HashMap::new();
+            while let Some((a, b, rank)) = seq.next_element::<(String, String, u32)>()? {
+                merges.insert((a, b), rank);
+            }
+            Ok(merges)
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Merges, A::Error> {
+            // Legacy form: {"first second": rank}
+            let mut merges = HashMap::new();
+            while let Some((pair, rank)) = map.next_entry::<String, u32>()? {
+                if let Some((a, b)) = pair.split_once(' ') {
+                    merges.insert((a.to_string(), b.to_string()), rank);
+                }
+            }
+            Ok(merges)
+        }
+    }
 }
 
 fn default_cache() -> RwLock<HashMap<String, Vec<String>>> {
     RwLock::new(HashMap::new())
 }
 
+fn default_cache_enabled() -> bool {
+    true
+}
+
+/// Cloning snapshots the whole encode cache under a read lock, which gets expensive
+/// once the cache is warm. Share the tokenizer through an `Arc`, or use [`BPE::frozen`]
+/// when an independent copy is needed.
 impl Clone for BPE {
     fn clone(&self) -> Self {
         let cache_snapshot = self
@@ -37,11 +148,67 @@ impl Clone for BPE {
             vocab: self.vocab.clone(),
             merges: self.merges.clone(),
             cache: RwLock::new(cache_snapshot),
+            cache_enabled: self.cache_enabled,
             regex: self.regex.clone(),
+            fallback: self.fallback,
+            lowercase: self.lowercase,
+            split_digits: self.split_digits,
+            prefix_space: self.prefix_space,
         }
     }
 }
 
+/// Name of the `index`-th reserved placeholder token.
+pub fn reserved_token(index: usize) -> String {
+    format!("<reserved_{index}>")
+}
+
+/// Whether `token` is a special token such as `<UNK>`, `</s>`, `<|assistant|>` or
+/// `<reserved_0>`: wrapped in angle brackets, with no whitespace and at least one letter or
+/// digit inside. `<0xNN>` byte-fallback tokens don't count.
+pub fn is_special_token(token: &str) -> bool {
+    let Some(inner) = token.strip_prefix('<').and_then(|rest| rest.strip_suffix('>')) else {
+        return false;
+    };
+    byte_token_value(token).is_none() && !inner.chars().any(char::is_whitespace) && inner.chars().any(char::is_alphanumeric)
+}
+
+/// The byte a `<0xNN>` byte-fallback token stands for, or `None` for any other token.
+fn byte_token_value(token: &str) -> Option<u8> {
+    let hex = token.strip_prefix("<0x")?.strip_suffix('>')?;
+    if hex.len() != 2 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    u8::from_str_radix(hex, 16).ok()
+}
+
+fn is_reserved_token(token: &str) -> bool {
+    token
+        .strip_prefix("<reserved_")
+        .and_then(|rest| rest.strip_suffix('>'))
+        .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Splits `pretoken` so that every numeric character stands alone, keeping the text between
+/// digits together: `" 2024"` becomes `[" ", "2", "0", "2", "4"]`.
+pub(crate) fn split_digits(pretoken: &str) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    for (i, c) in pretoken.char_indices() {
+        if c.is_numeric() {
+            if start < i {
+                pieces.push(&pretoken[start..i]);
+            }
+            start = i + c.len_utf8();
+            pieces.push(&pretoken[i..start]);
+        }
+    }
+    if start < pretoken.len() {
+        pieces.push(&pretoken[start..]);
+    }
+    pieces
+}
+
 fn default_regex() -> Regex {
     Regex::new(r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+").unwrap()
 }
@@ -62,10 +229,69 @@ impl BPE {
             vocab,
             merges,
             cache: default_cache(),
+            cache_enabled: default_cache_enabled(),
             regex: default_regex(),
+            fallback: FallbackStrategy::default(),
+            lowercase: false,
+            split_digits: false,
+            prefix_space: false,
         }
     }
 
+    /// A tokenizer that needs no trained vocab: `<UNK>` (ID 0) followed by the 256
+    /// `<0xNN>` byte tokens (IDs 1 to 256) and no merges. Every character is encoded as the
+    /// byte tokens of its UTF-8 encoding, so any text round-trips through `encode` and
+    /// `decode` exactly. Meant as a fallback when no vocab file is available.
+    pub fn byte_level_identity() -> Self {
+        let mut vocab = Vocab::new();
+        vocab.insert("<UNK>".to_string(), 0);
+        for byte in 0..=255u8 {
+            vocab.insert(format!("<0x{:02X}>", byte), vocab.len() as u32);
+        }
+        Self::new(vocab, HashMap::new())
+    }
+
+    /// Returns a copy of this tokenizer with an empty (but still functional) cache,
+    /// avoiding the cost of cloning a large warm cache.
+    pub fn frozen(&self) -> Self {
+        let mut bpe = Self::new(self.vocab.clone(), self.merges.clone());
+        bpe.cache_enabled = self.cache_enabled;
+        bpe.fallback = self.fallback;
+        bpe.lowercase = self.lowercase;
+        bpe.split_digits = self.split_digits;
+        bpe.prefix_space = self.prefix_space;
+        bpe
+    }
+
+    /// Number of pre-tokens currently memoized by the encode cache.
+    pub fn cache_len(&self) -> usize {
+        self.cache.read().map(|cache| cache.len()).unwrap_or(0)
+    }
+
+    /// Renames the lowest-ID `<reserved_N>` placeholder (see [`crate::Trainer::with_reserved_tokens`])
+    /// to `name`, keeping its ID, and returns that ID. If `name` is already in the vocab its
+    /// existing ID is returned and no slot is used. Returns `None` when no slots are left.
+    pub fn assign_reserved(&mut self, name: &str) -> Option<u32> {
+        if let Some(id) = self.vocab.get_id(name) {
+            return Some(id);
+        }
+
+        let (slot, id) = self
+            .vocab
+            .token_to_id
+            .iter()
+            .filter(|(token, _)| is_reserved_token(token))
+            .min_by_key(|&(_, &id)| id)
+            .map(|(token, &id)| (token.clone(), id))?;
+
+        self.vocab.token_to_id.remove(&slot);
+        self.vocab.insert(name.to_string(), id);
+        if let Ok(mut cache) = self.cache.write() {
+            cache.clear();
+        }
+        Some(id)
+    }
+
     pub fn from_files<P: AsRef<Path>>(vocab_path: P, merges_path: P) -> Result<Self> {
         let vocab = Vocab::load(vocab_path)?;
 
@@ -103,12 +329,27 @@ impl BPE {
     }
 
     fn bpe(&self, token: &str) -> Vec<String> {
-        if let Ok(cache) = self.cache.read() {
-            if let Some(cached) = cache.get(token) {
-                return cached.clone();
+        if self.cache_enabled {
+            if let Ok(cache) = self.cache.read() {
+                if let Some(cached) = cache.get(token) {
+                    return cached.clone();
+                }
+            }
+        }
+
+        let word = self.bpe_uncached(token);
+
+        if self.cache_enabled {
+            if let Ok(mut cache) = self.cache.write() {
+                cache.insert(token.to_string(), word.clone());
             }
         }
 
+        word
+    }
+
+    /// Applies the merges to `token` without touching the cache.
+    fn bpe_uncached(&self, token: &str) -> Vec<String> {
         let mut word: Vec<String> = token.chars().map(|c| c.to_string()).collect();
 
         loop {
@@ -153,36 +394,186 @@ impl BPE {
             }
         }
 
-        if let Ok(mut cache) = self.cache.write() {
-            cache.insert(token.to_string(), word.clone());
-        }
-
         word
     }
 
+    /// `text` with the space [`BPE::prefix_space`] asks for.
+    fn with_prefix_space<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if self.prefix_space && !text.starts_with(' ') {
+            Cow::Owned(format!(" {text}"))
+        } else {
+            Cow::Borrowed(text)
+        }
+    }
+
     pub fn encode(&self, text: &str) -> Vec<u32> {
+        let text = self.with_prefix_space(text);
         let mut ids = Vec::new();
-        for mat in self.regex.find_iter(text) {
-            let token_text = mat.as_str();
-            let bpe_tokens = self.bpe(token_text);
+        for mat in self.regex.find_iter(&text) {
+            self.encode_pretoken(mat.as_str(), &mut ids, None);
+        }
+        ids
+    }
 
-            for token in bpe_tokens {
-                if let Some(id) = self.vocab.get_id(&token) {
-                    ids.push(id);
-                } else {
-                    // Fallback: encode as bytes
+    /// Encodes `text` and reports how many of the resulting tokens had to fall back
+    /// to byte tokens or `<UNK>`. Useful for judging whether a tokenizer fits a new domain.
+    pub fn coverage(&self, text: &str) -> Coverage {
+        let text = self.with_prefix_space(text);
+        let mut ids = Vec::new();
+        let mut coverage = Coverage::default();
+        for mat in self.regex.find_iter(&text) {
+            self.encode_pretoken(mat.as_str(), &mut ids, Some(&mut coverage));
+        }
+
+        coverage.total_tokens = ids.len();
+        let seen = coverage.total_tokens + coverage.skipped_tokens;
+        if seen > 0 {
+            coverage.oov_rate = (coverage.byte_fallback_tokens + coverage.unk_tokens + coverage.skipped_tokens)
+                as f64
+                / seen as f64;
+        }
+        coverage
+    }
+
+    /// Encodes each text and counts how often every token ID occurs across the corpus.
+    pub fn token_frequencies(&self, texts: &[&str]) -> HashMap<u32, u64> {
+        let mut frequencies = HashMap::new();
+        for text in texts {
+            for id in self.encode(text) {
+                *frequencies.entry(id).or_insert(0) += 1;
+            }
+        }
+        frequencies
+    }
+
+    /// Returns up to `n` vocab tokens with the lowest counts in `frequencies` (as produced by
+    /// [`BPE::token_frequencies`]), least used first. Tokens that never occurred count as 0;
+    /// ties are broken by ID.
+    pub fn least_used_tokens(&self, frequencies: &HashMap<u32, u64>, n: usize) -> Vec<(u32, u64)> {
+        let mut counts: Vec<(u32, u64)> = self
+            .vocab
+            .id_to_token
+            .keys()
+            .map(|&id| (id, frequencies.get(&id).copied().unwrap_or(0)))
+            .collect();
+        counts.sort_by_key(|&(id, count)| (count, id));
+        counts.truncate(n);
+        counts
+    }
+
+    /// Encodes a single pre-tokenized chunk (one regex match), appending its IDs to `ids`.
+    /// Sub-tokens missing from the vocab are handled according to `self.fallback`.
+    /// When `coverage` is given, fallback and skipped tokens are tallied into it.
+    fn encode_pretoken(&self, token_text: &str, ids: &mut Vec<u32>, coverage: Option<&mut Coverage>) {
+        self.encode_pretoken_with(token_text, ids, coverage, |piece| self.bpe(piece))
+    }
+
+    /// [`BPE::encode_pretoken`] with the merge step supplied by `bpe`.
+    fn encode_pretoken_with(
+        &self,
+        token_text: &str,
+        ids: &mut Vec<u32>,
+        mut coverage: Option<&mut Coverage>,
+        bpe: impl Fn(&str) -> Vec<String>,
+    ) {
+        let lowered;
+        let token_text = if self.lowercase {
+            lowered = token_text.to_lowercase();
+            &lowered
+        } else {
+            token_text
+        };
+        let bpe_tokens: Vec<String> = if self.split_digits {
+            split_digits(token_text).into_iter().flat_map(&bpe).collect()
+        } else {
+            bpe(token_text)
+        };
+
+        for token in bpe_tokens {
+            if let Some(id) = self.vocab.get_id(&token) {
+                ids.push(id);
+                continue;
+            }
+
+            match self.fallback {
+                FallbackStrategy::ByteFallback => {
                     for byte in token.bytes() {
                         let s = format!("<0x{:02X}>", byte);
                         if let Some(id) = self.vocab.get_id(&s) {
                             ids.push(id);
+                            if let Some(coverage) = coverage.as_deref_mut() {
+                                coverage.byte_fallback_tokens += 1;
+                            }
                         } else if let Some(id) = self.vocab.get_id("<UNK>") {
                             ids.push(id);
+                            if let Some(coverage) = coverage.as_deref_mut() {
+                                coverage.unk_tokens += 1;
+                            }
+                        }
+                    }
+                }
+                FallbackStrategy::Unk => {
+                    // Without an <UNK> entry there is nothing to emit, so the token is skipped.
+                    if let Some(id) = self.vocab.get_id("<UNK>") {
+                        ids.push(id);
+                        if let Some(coverage) = coverage.as_deref_mut() {
+                            coverage.unk_tokens += 1;
                         }
+                    } else if let Some(coverage) = coverage.as_deref_mut() {
+                        coverage.skipped_tokens += 1;
+                    }
+                }
+                FallbackStrategy::Skip => {
+                    if let Some(coverage) = coverage.as_deref_mut() {
+                        coverage.skipped_tokens += 1;
                     }
                 }
             }
         }
-        ids
+    }
+
+    /// Encodes text from `reader` line by line, handing the IDs of each line to `on_ids`
+    /// without holding the whole text in memory.
+    ///
+    /// The last pre-token of every line is carried over to the next one, since a match
+    /// (e.g. a run of whitespace) can span a line break. The concatenated output is
+    /// identical to calling [`BPE::encode`] on the full text.
+    pub fn encode_reader<R: BufRead>(&self, mut reader: R, mut on_ids: impl FnMut(&[u32])) -> Result<()> {
+        let mut carry = String::new();
+        let mut ids = Vec::new();
+        let mut first_line = true;
+
+        while reader.read_line(&mut carry)? > 0 {
+            if first_line {
+                first_line = false;
+                carry = self.with_prefix_space(&carry).into_owned();
+            }
+            let matches: Vec<(usize, usize)> = self
+                .regex
+                .find_iter(&carry)
+                .map(|mat| (mat.start(), mat.end()))
+                .collect();
+
+            if let Some((&(keep_from, _), complete)) = matches.split_last() {
+                for &(start, end) in complete {
+                    self.encode_pretoken(&carry[start..end], &mut ids, None);
+                }
+                carry.drain(..keep_from);
+            }
+
+            if !ids.is_empty() {
+                on_ids(&ids);
+                ids.clear();
+            }
+        }
+
+        for mat in self.regex.find_iter(&carry) {
+            self.encode_pretoken(mat.as_str(), &mut ids, None);
+        }
+        if !ids.is_empty() {
+            on_ids(&ids);
+        }
+        Ok(())
     }
 
     pub fn encode_with_max_tokens(&self, text: &str, max_tokens: usize) -> Vec<u32> {
@@ -193,14 +584,111 @@ impl BPE {
         ids
     }
 
+    /// Encodes every text like [`BPE::encode`], but encodes each distinct pre-token of the
+    /// whole batch only once and assembles the outputs from those results. The shared cache
+    /// is bypassed, so batches full of repeated words cost neither recomputation nor lock
+    /// traffic.
+    pub fn encode_batch_dedup(&self, texts: &[&str]) -> Vec<Vec<u32>> {
+        self.encode_batch_dedup_with(texts, |piece| self.bpe_uncached(piece))
+    }
+
+    /// [`BPE::encode_batch_dedup`], applying the merges to pre-token pieces with `bpe`.
+    fn encode_batch_dedup_with(&self, texts: &[&str], bpe: impl Fn(&str) -> Vec<String>) -> Vec<Vec<u32>> {
+        let texts: Vec<Cow<str>> = texts.iter().map(|text| self.with_prefix_space(text)).collect();
+        let pretokens: Vec<Vec<&str>> = texts
+            .iter()
+            .map(|text| self.regex.find_iter(text).map(|mat| mat.as_str()).collect())
+            .collect();
+
+        let mut encoded: HashMap<&str, Vec<u32>> = HashMap::new();
+        for &pretoken in pretokens.iter().flatten() {
+            encoded.entry(pretoken).or_insert_with(|| {
+                let mut ids = Vec::new();
+                self.encode_pretoken_with(pretoken, &mut ids, None, &bpe);
+                ids
+            });
+        }
+
+        pretokens
+            .iter()
+            .map(|pretokens| pretokens.iter().flat_map(|pretoken| encoded[pretoken].iter().copied()).collect())
+            .collect()
+    }
+
+    /// Encodes a batch of texts, right-padding every sequence with `pad_id` to the
+    /// length of the longest one.
+    ///
+    /// Returns the padded IDs and a parallel attention mask (1 for real tokens, 0 for padding).
+    pub fn encode_batch_padded(&self, texts: &[&str], pad_id: u32) -> (Vec<Vec<u32>>, Vec<Vec<u8>>) {
+        let encoded: Vec<Vec<u32>> = texts.iter().map(|text| self.encode(text)).collect();
+        let max_len = encoded.iter().map(|ids| ids.len()).max().unwrap_or(0);
+
+        let mut batch_ids = Vec::with_capacity(encoded.len());
+        let mut batch_mask = Vec::with_capacity(encoded.len());
+
+        for mut ids in encoded {
+            let real_len = ids.len();
+            let mut mask = vec![1u8; real_len];
+            ids.resize(max_len, pad_id);
+            mask.resize(max_len, 0);
+            batch_ids.push(ids);
+            batch_mask.push(mask);
+        }
+
+        (batch_ids, batch_mask)
+    }
+
+    /// Concatenates the tokens of `ids`, dropping IDs missing from the vocab. `<0xNN>`
+    /// byte tokens are turned back into their bytes, and byte sequences that aren't valid
+    /// UTF-8 (e.g. a character cut off at the end of `ids`) become U+FFFD.
     pub fn decode(&self, ids: &[u32]) -> String {
-        let mut text = String::new();
-        for id in ids {
-            if let Some(token) = self.vocab.get_token(*id) {
-                text.push_str(token);
+        let mut bytes = Vec::new();
+        for &id in ids {
+            self.push_token_bytes(id, &mut bytes);
+        }
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    /// Appends the bytes `id` decodes to, returning false if it is missing from the vocab.
+    fn push_token_bytes(&self, id: u32, bytes: &mut Vec<u8>) -> bool {
+        let Some(token) = self.vocab.get_token(id) else {
+            return false;
+        };
+        match byte_token_value(token) {
+            Some(byte) => bytes.push(byte),
+            None => bytes.extend_from_slice(token.as_bytes()),
+        }
+        true
+    }
+
+    /// Like [`BPE::decode`], but renders IDs missing from the vocab with `placeholder`
+    /// instead of dropping them, e.g. `|id| format!("⟨id:{id}⟩")`.
+    pub fn decode_with_unknown(&self, ids: &[u32], placeholder: impl Fn(u32) -> String) -> String {
+        let mut bytes = Vec::new();
+        for &id in ids {
+            if !self.push_token_bytes(id, &mut bytes) {
+                bytes.extend_from_slice(placeholder(id).as_bytes());
             }
         }
-        text
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    /// Like [`BPE::decode`], but passes every special token (see [`is_special_token`])
+    /// through `render` and inserts its result instead, e.g. to put a turn marker on its own
+    /// line with `|t| format!("\n{t}\n")` or to hide special tokens with `|_| String::new()`.
+    /// Other tokens are decoded as usual.
+    pub fn decode_with_special_rendering(&self, ids: &[u32], render: impl Fn(&str) -> String) -> String {
+        let mut bytes = Vec::new();
+        for &id in ids {
+            match self.vocab.get_token(id) {
+                Some(token) if is_special_token(token) => bytes.extend_from_slice(render(token).as_bytes()),
+                Some(_) => {
+                    self.push_token_bytes(id, &mut bytes);
+                }
+                None => {}
+            }
+        }
+        String::from_utf8_lossy(&bytes).into_owned()
     }
 
     pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
@@ -219,11 +707,90 @@ impl BPE {
         Ok(bpe)
     }
 
+    /// Saves the tokenizer in a compact binary (bincode) format that loads faster than JSON.
+    pub fn save_bin<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let file = File::create(path)?;
+        let writer = std::io::BufWriter::new(file);
+        bincode::serialize_into(writer, self)?;
+        Ok(())
+    }
+
+    pub fn load_bin<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path)?;
+        let reader = std::io::BufReader::new(file);
+        let mut bpe: BPE = bincode::deserialize_from(reader)?;
+        bpe.cache = default_cache();
+        bpe.regex = default_regex();
+        Ok(bpe)
+    }
+
+    /// Loads `json_path`, preferring a binary copy with the same stem and a `.bin`
+    /// extension (e.g. `vocab.bin` next to `vocab.json`) when one exists.
+    pub fn load_prefer_bin<P: AsRef<Path>>(json_path: P) -> Result<Self> {
+        let bin_path = json_path.as_ref().with_extension("bin");
+        if bin_path.exists() {
+            Self::load_bin(bin_path)
+        } else {
+            Self::load(json_path)
+        }
+    }
+
     pub fn vocab(&self) -> &Vocab {
         &self.vocab
     }
 }
 
+/// Decodes generated tokens one at a time. A character whose bytes are split across
+/// `<0xNN>` byte tokens is held back until its last byte arrives, so the pieces
+/// concatenate to what [`BPE::decode`] gives for all the tokens at once.
+#[derive(Debug, Default, Clone)]
+pub struct StreamDecoder {
+    pending: Vec<u8>,
+}
+
+impl StreamDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds one token and returns the text it completes, which may be empty.
+    pub fn push(&mut self, bpe: &BPE, id: u32) -> String {
+        bpe.push_token_bytes(id, &mut self.pending);
+        let mut text = String::new();
+        loop {
+            let error = match std::str::from_utf8(&self.pending) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    self.pending.clear();
+                    return text;
+                }
+                Err(error) => error,
+            };
+            let valid = error.valid_up_to();
+            text.push_str(&String::from_utf8_lossy(&self.pending[..valid]));
+            match error.error_len() {
+                // Bytes that no later byte can make valid.
+                Some(len) => {
+                    text.push(char::REPLACEMENT_CHARACTER);
+                    self.pending.drain(..valid + len);
+                }
+                // The start of a character that the next token may finish.
+                None => {
+                    self.pending.drain(..valid);
+                    return text;
+                }
+            }
+        }
+    }
+
+    /// Returns the bytes of a character left unfinished at the end of the stream as U+FFFD.
+    pub fn finish(&mut self) -> String {
+        let text = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        text
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -242,6 +809,367 @@ mod tests {
         assert_eq!(ids.len(), 3);
     }
 
+    #[test]
+    fn encode_batch_padded_right_pads_and_masks() {
+        let mut vocab = Vocab::new();
+        vocab.insert("<PAD>".to_string(), 0);
+        vocab.insert("a".to_string(), 1);
+        vocab.insert("b".to_string(), 2);
+
+        let bpe = BPE::new(vocab, HashMap::new());
+        let (ids, mask) = bpe.encode_batch_padded(&["ab", "abab"], 0);
+
+        assert_eq!(ids, vec![vec![1, 2, 0, 0], vec![1, 2, 1, 2]]);
+        assert_eq!(mask, vec![vec![1, 1, 0, 0], vec![1, 1, 1, 1]]);
+    }
+
+    #[test]
+    fn encode_batch_dedup_matches_encode_with_fewer_bpe_computations() {
+        let mut vocab = Vocab::new();
+        for (id, token) in ["t", "h", "e", "c", "a", " ", "th", "the", "ca", "cat"].iter().enumerate() {
+            vocab.insert(token.to_string(), id as u32);
+        }
+        let merges: HashMap<(String, String), u32> = [("t", "h"), ("th", "e"), ("c", "a"), ("ca", "t")]
+            .iter()
+            .enumerate()
+            .map(|(rank, (a, b))| ((a.to_string(), b.to_string()), rank as u32))
+            .collect();
+        let bpe = BPE::new(vocab, merges);
+        let texts = ["the cat the cat the cat", "cat the the the", "the", "cat cat cat cat"];
+        let naive: Vec<Vec<u32>> = texts.iter().map(|text| bpe.frozen().encode(text)).collect();
+
+        // The shared cache is bypassed, so nothing gets memoized.
+        assert_eq!(bpe.encode_batch_dedup(&texts), naive);
+        assert_eq!(bpe.cache_len(), 0);
+
+        let computations = std::cell::Cell::new(0);
+        let deduped = bpe.encode_batch_dedup_with(&texts, |piece| {
+            computations.set(computations.get() + 1);
+            bpe.bpe_uncached(piece)
+        });
+        assert_eq!(deduped, naive);
+        assert!(computations.get() <= 4, "one computation per distinct pre-token, got {}", computations.get());
+    }
+
+    #[test]
+    fn decode_with_unknown_renders_placeholder_for_missing_ids() {
+        let mut vocab = Vocab::new();
+        vocab.insert("a".to_string(), 0);
+        vocab.insert("b".to_string(), 1);
+
+        let bpe = BPE::new(vocab, HashMap::new());
+        let text = bpe.decode_with_unknown(&[0, 1234, 1], |id| format!("⟨id:{id}⟩"));
+
+        assert_eq!(text, "a⟨id:1234⟩b");
+        assert_eq!(bpe.decode(&[0, 1234, 1]), "ab");
+    }
+
+    #[test]
+    fn decode_with_special_rendering_only_renders_special_tokens() {
+        let mut vocab = Vocab::new();
+        vocab.insert("<|assistant|>".to_string(), 0);
+        vocab.insert("Hi".to_string(), 1);
+        vocab.insert("</s>".to_string(), 2);
+        vocab.insert("<0x41>".to_string(), 3);
+        vocab.insert("<>".to_string(), 4);
+
+        let bpe = BPE::new(vocab, HashMap::new());
+        let text = bpe.decode_with_special_rendering(&[0, 1, 3, 4, 2], |token| format!("\n[{token}]\n"));
+
+        assert_eq!(text, "\n[<|assistant|>]\nHiA<>\n[</s>]\n");
+        assert_eq!(bpe.decode_with_special_rendering(&[0, 1, 2], |_| String::new()), "Hi");
+    }
+
+    #[test]
+    fn encode_reader_matches_encoding_the_whole_text() {
+        let mut vocab = Vocab::new();
+        for (id, token) in ["a", "b", " ", "\n", "!", "ab", " a"].iter().enumerate() {
+            vocab.insert(token.to_string(), id as u32);
+        }
+        let mut merges = HashMap::new();
+        merges.insert(("a".to_string(), "b".to_string()), 0);
+        merges.insert((" ".to_string(), "a".to_string()), 1);
+        let bpe = BPE::new(vocab, merges);
+
+        let text = "ab ab!\n\n ab  \n\tba\nab";
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_nanos();
+        let path = std::env::temp_dir().join(format!("tokenizer_stream_test_{unique}.txt"));
+        fs::write(&path, text).expect("write corpus");
+
+        let mut streamed = Vec::new();
+        let reader = BufReader::new(File::open(&path).expect("open corpus"));
+        bpe.encode_reader(reader, |ids| streamed.extend_from_slice(ids))
+            .expect("stream encode");
+
+        assert_eq!(streamed, bpe.encode(&fs::read_to_string(&path).expect("read corpus")));
+
+        fs::remove_file(&path).expect("cleanup temp corpus");
+    }
+
+    #[test]
+    fn coverage_reports_fallback_tokens_for_unknown_characters() {
+        let mut vocab = Vocab::new();
+        vocab.insert("<UNK>".to_string(), 0);
+        vocab.insert("a".to_string(), 1);
+        vocab.insert("<0xC3>".to_string(), 2);
+
+        let bpe = BPE::new(vocab, HashMap::new());
+        // "é" is two bytes (0xC3 0xA9): one byte token, one <UNK>.
+        let coverage = bpe.coverage("aé");
+
+        assert_eq!(coverage.total_tokens, 3);
+        assert_eq!(coverage.byte_fallback_tokens, 1);
+        assert_eq!(coverage.unk_tokens, 1);
+        assert!((coverage.oov_rate - 2.0 / 3.0).abs() < 1e-9);
+        assert_eq!(bpe.coverage("aaa").oov_rate, 0.0);
+    }
+
+    #[test]
+    fn byte_level_identity_round_trips_any_text() {
+        let bpe = BPE::byte_level_identity();
+        assert_eq!(bpe.vocab.len(), 257);
+
+        let text = "Hello, wörld! 日本語 🦀\n\ttabs <UNK> 'quotes' 12345\r\n";
+        let ids = bpe.encode(text);
+        assert_eq!(ids.len(), text.len());
+        assert_eq!(bpe.decode(&ids), text);
+        assert_eq!(bpe.coverage(text).unk_tokens, 0);
+    }
+
+    #[test]
+    fn stream_decoder_holds_split_characters_until_complete() {
+        let bpe = BPE::byte_level_identity();
+        let text = "wö 日本 🦀";
+        let mut ids = bpe.encode(text);
+        // A truncated "é" at the end and a stray continuation byte in the middle.
+        ids.push(bpe.vocab.get_id("<0xC3>").unwrap());
+        ids.insert(1, bpe.vocab.get_id("<0xA9>").unwrap());
+
+        let mut decoder = StreamDecoder::new();
+        let mut pieces: Vec<String> = ids.iter().map(|&id| decoder.push(&bpe, id)).collect();
+        pieces.push(decoder.finish());
+
+        // "ö" arrives as two byte tokens: nothing, then the whole character.
+        assert_eq!(pieces[2..4], ["", "ö"]);
+        assert_eq!(pieces.concat(), bpe.decode(&ids));
+        assert_eq!(pieces.concat(), "w\u{FFFD}ö 日本 🦀\u{FFFD}");
+        assert_eq!(bpe.decode_with_unknown(&ids[2..4], |id| format!("<{id}>")), "ö");
+    }
+
+    fn fallback_test_bpe(fallback: FallbackStrategy) -> BPE {
+        let mut vocab = Vocab::new();
+        vocab.insert("<UNK>".to_string(), 0);
+        vocab.insert("a".to_string(), 1);
+        vocab.insert("<0xC3>".to_string(), 2);
+        vocab.insert("<0xA9>".to_string(), 3);
+        let mut bpe = BPE::new(vocab, HashMap::new());
+        bpe.fallback = fallback;
+        bpe
+    }
+
+    #[test]
+    fn byte_fallback_strategy_encodes_unknown_character_as_bytes() {
+        let bpe = fallback_test_bpe(FallbackStrategy::ByteFallback);
+        assert_eq!(bpe.encode("aé"), vec![1, 2, 3]);
+        assert_eq!(bpe.coverage("aé").byte_fallback_tokens, 2);
+    }
+
+    #[test]
+    fn unk_strategy_encodes_unknown_character_as_single_unk() {
+        let bpe = fallback_test_bpe(FallbackStrategy::Unk);
+        assert_eq!(bpe.encode("aé"), vec![1, 0]);
+        assert_eq!(bpe.coverage("aé").unk_tokens, 1);
+    }
+
+    #[test]
+    fn skip_strategy_drops_unknown_character_and_counts_it() {
+        let bpe = fallback_test_bpe(FallbackStrategy::Skip);
+        assert_eq!(bpe.encode("aé"), vec![1]);
+
+        let coverage = bpe.coverage("aé");
+        assert_eq!(coverage.total_tokens, 1);
+        assert_eq!(coverage.skipped_tokens, 1);
+        assert!((coverage.oov_rate - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn fallback_strategy_survives_save_and_load() {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_nanos();
+        let json_path = std::env::temp_dir().join(format!("bpe_fallback_test_{unique}.json"));
+        let bin_path = json_path.with_extension("bin");
+
+        let bpe = fallback_test_bpe(FallbackStrategy::Skip);
+        bpe.save(&json_path).expect("save json");
+        bpe.save_bin(&bin_path).expect("save bin");
+
+        assert_eq!(BPE::load(&json_path).expect("load json").fallback, FallbackStrategy::Skip);
+        assert_eq!(BPE::load_bin(&bin_path).expect("load bin").fallback, FallbackStrategy::Skip);
+
+        fs::remove_file(&json_path).expect("cleanup temp json");
+        fs::remove_file(&bin_path).expect("cleanup temp bin");
+    }
+
+    #[test]
+    fn lowercase_encodes_differently_cased_text_identically() {
+        let mut vocab = Vocab::new();
+        for (id, c) in "helo".chars().enumerate() {
+            vocab.insert(c.to_string(), id as u32);
+        }
+        vocab.insert("H".to_string(), 4);
+        let mut bpe = BPE::new(vocab, HashMap::new());
+        assert_ne!(bpe.encode("Hello"), bpe.encode("hello"));
+
+        bpe.lowercase = true;
+        assert_eq!(bpe.encode("Hello"), bpe.encode("hello"));
+        assert_eq!(bpe.decode(&bpe.encode("Hello")), "hello");
+
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_nanos();
+        let path = std::env::temp_dir().join(format!("bpe_lowercase_test_{unique}.json"));
+        bpe.save(&path).expect("save");
+        assert!(BPE::load(&path).expect("load").lowercase);
+        fs::remove_file(&path).expect("cleanup temp vocab");
+    }
+
+    #[test]
+    fn split_digits_encodes_each_digit_separately() {
+        let mut vocab = Vocab::new();
+        for (id, token) in [" ", "2", "0", "4", "20", "24", "2024", " 2024", "y"].iter().enumerate() {
+            vocab.insert(token.to_string(), id as u32);
+        }
+        let merges: HashMap<(String, String), u32> = [("2", "0"), ("2", "4"), ("20", "24"), (" ", "2024")]
+            .iter()
+            .enumerate()
+            .map(|(rank, (a, b))| ((a.to_string(), b.to_string()), rank as u32))
+            .collect();
+        let mut bpe = BPE::new(vocab, merges);
+        assert_eq!(bpe.encode("2024"), [6]);
+
+        bpe.split_digits = true;
+        assert_eq!(bpe.encode("2024"), [1, 2, 1, 3]);
+        assert_eq!(bpe.encode("y 2024"), [8, 0, 1, 2, 1, 3]);
+        assert_eq!(bpe.decode(&bpe.encode("y 2024")), "y 2024");
+
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_nanos();
+        let path = std::env::temp_dir().join(format!("bpe_split_digits_test_{unique}.json"));
+        bpe.save(&path).expect("save");
+        let loaded = BPE::load(&path).expect("load");
+        assert!(loaded.split_digits);
+        assert_eq!(loaded.encode("2024"), [1, 2, 1, 3]);
+        assert!(bpe.frozen().split_digits);
+        fs::remove_file(&path).expect("cleanup temp vocab");
+    }
+
+    #[test]
+    fn prefix_space_encodes_a_leading_word_like_a_mid_sentence_one() {
+        let mut vocab = Vocab::new();
+        for (id, token) in [" ", "h", "e", "l", "o", " h"].iter().enumerate() {
+            vocab.insert(token.to_string(), id as u32);
+        }
+        let merges = HashMap::from([((" ".to_string(), "h".to_string()), 0)]);
+        let plain = BPE::new(vocab, merges);
+        assert_eq!(plain.encode(" hello"), [5, 2, 3, 3, 4]);
+        assert_ne!(plain.encode("hello"), plain.encode(" hello"));
+
+        let mut bpe = plain.frozen();
+        bpe.prefix_space = true;
+        assert_eq!(bpe.encode("hello"), plain.encode(" hello"));
+        // Text that already starts with a space doesn't get a second one.
+        assert_eq!(bpe.encode(" hello"), plain.encode(" hello"));
+        assert_eq!(bpe.encode_batch_dedup(&["hello"]), [plain.encode(" hello")]);
+        let mut streamed = Vec::new();
+        bpe.encode_reader("hello\nhello".as_bytes(), |ids| streamed.extend_from_slice(ids))
+            .expect("encode reader");
+        assert_eq!(streamed, bpe.encode("hello\nhello"));
+
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_nanos();
+        let path = std::env::temp_dir().join(format!("bpe_prefix_space_test_{unique}.json"));
+        bpe.save(&path).expect("save");
+        assert!(BPE::load(&path).expect("load").prefix_space);
+        fs::remove_file(&path).expect("cleanup temp vocab");
+    }
+
+    #[test]
+    fn frozen_copy_starts_with_empty_cache_and_encodes_identically() {
+        let mut vocab = Vocab::new();
+        vocab.insert("a".to_string(), 0);
+        vocab.insert("b".to_string(), 1);
+        vocab.insert("ab".to_string(), 2);
+        vocab.insert(" ".to_string(), 3);
+        let mut merges = HashMap::new();
+        merges.insert(("a".to_string(), "b".to_string()), 0);
+
+        let bpe = BPE::new(vocab, merges);
+        let expected = bpe.encode("ab ba ab");
+        let frozen = bpe.frozen();
+
+        assert!(frozen.cache.read().expect("cache read lock").is_empty());
+        assert_eq!(frozen.encode("ab ba ab"), expected);
+    }
+
+    #[test]
+    fn binary_format_round_trips_like_json() {
+        let mut vocab = Vocab::new();
+        for (id, token) in ["a", "b", "c", " ", "ab", "abc"].iter().enumerate() {
+            vocab.insert(token.to_string(), id as u32);
+        }
+        let mut merges = HashMap::new();
+        merges.insert(("a".to_string(), "b".to_string()), 0);
+        merges.insert(("ab".to_string(), "c".to_string()), 1);
+        let bpe = BPE::new(vocab, merges);
+
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("tokenizer_bin_test_{unique}"));
+        fs::create_dir_all(&dir).expect("create temp test dir");
+        let json_path = dir.join("vocab.json");
+        let bin_path = dir.join("vocab.bin");
+
+        bpe.save(&json_path).expect("save json");
+        let from_json = BPE::load(&json_path).expect("load json");
+        bpe.save_bin(&bin_path).expect("save bin");
+        let from_bin = BPE::load_prefer_bin(&json_path).expect("load bin");
+
+        assert_eq!(from_bin.merges, bpe.merges);
+        assert_eq!(from_bin.vocab.token_to_id, from_json.vocab.token_to_id);
+        let text = "abc ab cab";
+        assert_eq!(from_bin.encode(text), bpe.encode(text));
+        assert_eq!(from_bin.encode(text), from_json.encode(text));
+
+        fs::remove_dir_all(&dir).expect("cleanup temp test dir");
+    }
+
+    #[test]
+    fn legacy_json_merges_map_still_loads() {
+        let vocab = r#"{"token_to_id": {"a": 0, "b": 1, "c": 2, "ab": 3, "abc": 4}, "id_to_token": {"0": "a", "1": "b", "2": "c", "3": "ab", "4": "abc"}}"#;
+        let legacy: BPE = serde_json::from_str(&format!(r#"{{"vocab": {vocab}, "merges": {{"a b": 0, "ab c": 1}}}}"#))
+            .expect("parse legacy json");
+        let list: BPE = serde_json::from_str(&format!(r#"{{"vocab": {vocab}, "merges": [["a", "b", 0], ["ab", "c", 1]]}}"#))
+            .expect("parse list json");
+
+        assert_eq!(legacy.merges, list.merges);
+        assert_eq!(legacy.encode("abc"), [4]);
+        let empty: BPE = serde_json::from_str(r#"{"vocab": {"token_to_id": {}, "id_to_token": {}}, "merges": {}}"#)
+            .expect("parse empty legacy json");
+        assert!(empty.merges.is_empty());
+    }
+
     #[test]
     fn encode_populates_internal_cache() {
         let mut vocab = Vocab::new();
@@ -254,6 +1182,59 @@ mod tests {
         assert!(cache.contains_key("a"));
     }
 
+    #[test]
+    fn disabling_cache_keeps_encodings_and_leaves_cache_empty() {
+        let mut vocab = Vocab::new();
+        vocab.insert("a".to_string(), 0);
+        vocab.insert("b".to_string(), 1);
+        vocab.insert("ab".to_string(), 2);
+        vocab.insert(" ".to_string(), 3);
+        let mut merges = HashMap::new();
+        merges.insert(("a".to_string(), "b".to_string()), 0);
+
+        let cached = BPE::new(vocab, merges);
+        let mut uncached = cached.frozen();
+        uncached.cache_enabled = false;
+
+        let text = "ab ba ab abab";
+        assert_eq!(uncached.encode(text), cached.encode(text));
+        assert!(cached.cache_len() > 0);
+        assert_eq!(uncached.cache_len(), 0);
+        assert!(!uncached.frozen().cache_enabled);
+    }
+
+    #[test]
+    fn token_frequencies_match_manual_encoding_totals() {
+        let mut vocab = Vocab::new();
+        vocab.insert("a".to_string(), 0);
+        vocab.insert("b".to_string(), 1);
+        vocab.insert("ab".to_string(), 2);
+        vocab.insert(" ".to_string(), 3);
+        vocab.insert("c".to_string(), 4);
+        let mut merges = HashMap::new();
+        merges.insert(("a".to_string(), "b".to_string()), 0);
+        let bpe = BPE::new(vocab, merges);
+
+        let corpus = ["ab ab", "ba", "abab a"];
+        let frequencies = bpe.token_frequencies(&corpus);
+
+        let mut expected: HashMap<u32, u64> = HashMap::new();
+        for text in corpus {
+            for id in bpe.encode(text) {
+                *expected.entry(id).or_insert(0) += 1;
+            }
+        }
+        assert_eq!(frequencies, expected);
+        let total: u64 = frequencies.values().sum();
+        let manual_total: usize = corpus.iter().map(|text| bpe.encode(text).len()).sum();
+        assert_eq!(total, manual_total as u64);
+
+        let least_used = bpe.least_used_tokens(&frequencies, 2);
+        assert_eq!(least_used[0], (4, 0));
+        assert_eq!(least_used.len(), 2);
+        assert!(least_used[1].1 <= frequencies.values().copied().min().expect("non-empty"));
+    }
+
     #[test]
     fn from_files_assigns_contiguous_merge_ranks_ignoring_comments_and_blanks() {
         let unique = SystemTime::now()
@@ -0,0 +1,252 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::bpe::BPE;
+
+/// How many entries of each list [`TokenizerDiff`]'s `Display` prints before summarizing the rest.
+const DISPLAY_LIMIT: usize = 20;
+
+/// A token present in both tokenizers under different IDs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdChange {
+    pub token: String,
+    pub old_id: u32,
+    pub new_id: u32,
+}
+
+/// A merge present in both tokenizers with a different rank (lower ranks apply first).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RankChange {
+    pub pair: (String, String),
+    pub old_rank: u32,
+    pub new_rank: u32,
+}
+
+/// A sample string that encodes to different IDs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SampleChange {
+    pub text: String,
+    pub old_ids: Vec<u32>,
+    pub new_ids: Vec<u32>,
+}
+
+/// What changed between an old and a new tokenizer, as computed by [`TokenizerDiff::new`].
+/// Every list is sorted, so two diffs of the same tokenizers compare equal.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TokenizerDiff {
+    pub added_tokens: Vec<String>,
+    pub removed_tokens: Vec<String>,
+    pub changed_ids: Vec<IdChange>,
+    pub added_merges: Vec<(String, String)>,
+    pub removed_merges: Vec<(String, String)>,
+    pub rank_changes: Vec<RankChange>,
+    /// Number of sample strings compared.
+    pub samples_checked: usize,
+    pub changed_samples: Vec<SampleChange>,
+}
+
+impl TokenizerDiff {
+    /// Compares `old` with `new`: their vocabularies, their merges, and how each of
+    /// `samples` encodes under both.
+    pub fn new(old: &BPE, new: &BPE, samples: &[&str]) -> Self {
+        let old_vocab: BTreeMap<&String, u32> = old.vocab.token_to_id.iter().map(|(t, &id)| (t, id)).collect();
+        let new_vocab: BTreeMap<&String, u32> = new.vocab.token_to_id.iter().map(|(t, &id)| (t, id)).collect();
+
+        let mut diff = Self {
+            samples_checked: samples.len(),
+            ..Self::default()
+        };
+        for (&token, &new_id) in &new_vocab {
+            match old_vocab.get(token) {
+                None => diff.added_tokens.push(token.clone()),
+                Some(&old_id) if old_id != new_id => diff.changed_ids.push(IdChange {
+                    token: token.clone(),
+                    old_id,
+                    new_id,
+                }),
+                Some(_) => {}
+            }
+        }
+        diff.removed_tokens = old_vocab
+            .keys()
+            .filter(|token| !new_vocab.contains_key(*token))
+            .map(|token| (*token).clone())
+            .collect();
+
+        let old_merges: BTreeMap<&(String, String), u32> = old.merges.iter().map(|(p, &r)| (p, r)).collect();
+        let new_merges: BTreeMap<&(String, String), u32> = new.merges.iter().map(|(p, &r)| (p, r)).collect();
+        for (&pair, &new_rank) in &new_merges {
+            match old_merges.get(pair) {
+                None => diff.added_merges.push(pair.clone()),
+                Some(&old_rank) if old_rank != new_rank => diff.rank_changes.push(RankChange {
+                    pair: pair.clone(),
+                    old_rank,
+                    new_rank,
+                }),
+                Some(_) => {}
+            }
+        }
+        diff.removed_merges = old_merges
+            .keys()
+            .filter(|pair| !new_merges.contains_key(*pair))
+            .map(|pair| (*pair).clone())
+            .collect();
+
+        for &text in samples {
+            let old_ids = old.encode(text);
+            let new_ids = new.encode(text);
+            if old_ids != new_ids {
+                diff.changed_samples.push(SampleChange {
+                    text: text.to_string(),
+                    old_ids,
+                    new_ids,
+                });
+            }
+        }
+        diff
+    }
+
+    /// True if data encoded with the old tokenizer still decodes the same way with the new
+    /// one and every sample encodes identically: no token was removed or renumbered.
+    /// Added tokens and merges are allowed as long as the samples are unaffected.
+    pub fn is_backward_compatible(&self) -> bool {
+        self.removed_tokens.is_empty() && self.changed_ids.is_empty() && self.changed_samples.is_empty()
+    }
+}
+
+fn write_limited<T>(
+    f: &mut fmt::Formatter<'_>,
+    title: &str,
+    items: &[T],
+    mut write_item: impl FnMut(&mut fmt::Formatter<'_>, &T) -> fmt::Result,
+) -> fmt::Result {
+    if items.is_empty() {
+        return Ok(());
+    }
+    writeln!(f)?;
+    writeln!(f, "{} ({}):", title, items.len())?;
+    for item in items.iter().take(DISPLAY_LIMIT) {
+        write!(f, "  ")?;
+        write_item(f, item)?;
+        writeln!(f)?;
+    }
+    if items.len() > DISPLAY_LIMIT {
+        writeln!(f, "  ... and {} more", items.len() - DISPLAY_LIMIT)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for TokenizerDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Tokens:  {} added, {} removed, {} with a new ID",
+            self.added_tokens.len(),
+            self.removed_tokens.len(),
+            self.changed_ids.len()
+        )?;
+        writeln!(
+            f,
+            "Merges:  {} added, {} removed, {} re-ranked",
+            self.added_merges.len(),
+            self.removed_merges.len(),
+            self.rank_changes.len()
+        )?;
+        writeln!(
+            f,
+            "Samples: {} of {} encode differently",
+            self.changed_samples.len(),
+            self.samples_checked
+        )?;
+        write!(
+            f,
+            "Backward compatible: {}",
+            if self.is_backward_compatible() { "yes" } else { "no" }
+        )?;
+
+        writeln!(f)?;
+        write_limited(f, "Added tokens", &self.added_tokens, |f, token| write!(f, "+ {:?}", token))?;
+        write_limited(f, "Removed tokens", &self.removed_tokens, |f, token| write!(f, "- {:?}", token))?;
+        write_limited(f, "Tokens with a new ID", &self.changed_ids, |f, change| {
+            write!(f, "{:?}: {} -> {}", change.token, change.old_id, change.new_id)
+        })?;
+        write_limited(f, "Added merges", &self.added_merges, |f, (a, b)| write!(f, "+ {:?} {:?}", a, b))?;
+        write_limited(f, "Removed merges", &self.removed_merges, |f, (a, b)| write!(f, "- {:?} {:?}", a, b))?;
+        write_limited(f, "Re-ranked merges", &self.rank_changes, |f, change| {
+            write!(
+                f,
+                "{:?} {:?}: {} -> {}",
+                change.pair.0, change.pair.1, change.old_rank, change.new_rank
+            )
+        })?;
+        write_limited(f, "Samples that encode differently", &self.changed_samples, |f, change| {
+            write!(f, "{:?}: {:?} -> {:?}", change.text, change.old_ids, change.new_ids)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vocab::Vocab;
+    use std::collections::HashMap;
+
+    fn tokenizer(tokens: &[&str], merges: &[(&str, &str)]) -> BPE {
+        let mut vocab = Vocab::new();
+        for (id, token) in tokens.iter().enumerate() {
+            vocab.insert(token.to_string(), id as u32);
+        }
+        let merges: HashMap<(String, String), u32> = merges
+            .iter()
+            .enumerate()
+            .map(|(rank, (a, b))| ((a.to_string(), b.to_string()), rank as u32))
+            .collect();
+        BPE::new(vocab, merges)
+    }
+
+    #[test]
+    fn reports_added_and_removed_tokens() {
+        let old = tokenizer(&["a", "b", "c", "ab"], &[("a", "b")]);
+        let new = tokenizer(&["a", "b", "c", "bc", "abc"], &[("b", "c"), ("a", "bc")]);
+
+        let diff = TokenizerDiff::new(&old, &new, &["ab", "cab"]);
+
+        assert_eq!(diff.added_tokens, ["abc", "bc"]);
+        assert_eq!(diff.removed_tokens, ["ab"]);
+        assert!(diff.changed_ids.is_empty());
+        assert_eq!(diff.removed_merges, [("a".to_string(), "b".to_string())]);
+        assert_eq!(diff.added_merges.len(), 2);
+        assert_eq!(diff.changed_samples.len(), 2);
+        assert!(!diff.is_backward_compatible());
+    }
+
+    #[test]
+    fn appending_tokens_is_backward_compatible_when_samples_are_unchanged() {
+        let old = tokenizer(&["a", "b"], &[]);
+        let new = tokenizer(&["a", "b", "c"], &[]);
+
+        let diff = TokenizerDiff::new(&old, &new, &["ab", "ba"]);
+
+        assert_eq!(diff.added_tokens, ["c"]);
+        assert!(diff.removed_tokens.is_empty());
+        assert!(diff.is_backward_compatible());
+        assert!(diff.to_string().contains("Samples: 0 of 2 encode differently"));
+    }
+
+    #[test]
+    fn reports_merge_rank_and_id_changes() {
+        let old = tokenizer(&["a", "b", "c", "ab", "bc"], &[("a", "b"), ("b", "c")]);
+        let new = tokenizer(&["a", "b", "c", "bc", "ab"], &[("b", "c"), ("a", "b")]);
+
+        let diff = TokenizerDiff::new(&old, &new, &[]);
+
+        assert_eq!(diff.rank_changes.len(), 2);
+        assert_eq!(
+            diff.changed_ids,
+            [
+                IdChange { token: "ab".to_string(), old_id: 3, new_id: 4 },
+                IdChange { token: "bc".to_string(), old_id: 4, new_id: 3 },
+            ]
+        );
+    }
+}
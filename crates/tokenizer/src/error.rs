@@ -8,6 +8,9 @@ pub enum TokenizerError {
     #[error("JSON serialization error: {0}")]
     Json(#[from] serde_json::Error),
 
+    #[error("Binary serialization error: {0}")]
+    Bincode(#[from] bincode::Error),
+
     #[error("Regex error: {0}")]
     Regex(#[from] regex::Error),
 
@@ -16,6 +19,12 @@ pub enum TokenizerError {
 
     #[error("Token not found: {0}")]
     TokenNotFound(String),
+
+    #[error("Invalid UTF-8 on line {line}")]
+    InvalidUtf8 { line: usize },
+
+    #[error("Invalid weight {weight} for {path}: weights must be finite and non-negative")]
+    InvalidWeight { path: String, weight: f64 },
 }
 
 pub type Result<T> = std::result::Result<T, TokenizerError>;
@@ -2,8 +2,12 @@ pub mod error;
 pub mod vocab;
 pub mod bpe;
 pub mod trainer;
+pub mod lines;
+pub mod diff;
 
-pub use bpe::BPE;
-pub use trainer::Trainer;
+pub use bpe::{is_special_token, Coverage, FallbackStrategy, StreamDecoder, BPE};
+pub use trainer::{IncrementalTrainer, StopReason, TrainLimits, Trainer};
 pub use vocab::Vocab;
+pub use lines::{for_each_line, InvalidUtf8, Utf8Report};
+pub use diff::TokenizerDiff;
 pub use error::TokenizerError;
@@ -0,0 +1,118 @@
+use std::io::BufRead;
+use std::str::FromStr;
+
+use crate::error::TokenizerError;
+
+/// What to do with input lines that are not valid UTF-8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InvalidUtf8 {
+    /// Replace invalid sequences with U+FFFD and keep the line.
+    #[default]
+    Replace,
+    /// Drop the line.
+    Skip,
+    /// Stop with [`TokenizerError::InvalidUtf8`].
+    Error,
+}
+
+impl FromStr for InvalidUtf8 {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "replace" => Ok(InvalidUtf8::Replace),
+            "skip" => Ok(InvalidUtf8::Skip),
+            "error" => Ok(InvalidUtf8::Error),
+            other => Err(format!("unknown invalid-UTF-8 policy {other:?} (expected replace, skip or error)")),
+        }
+    }
+}
+
+/// How many lines [`for_each_line`] had to repair or skip.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Utf8Report {
+    pub repaired_lines: usize,
+    pub skipped_lines: usize,
+}
+
+impl Utf8Report {
+    pub fn is_clean(&self) -> bool {
+        self.repaired_lines == 0 && self.skipped_lines == 0
+    }
+}
+
+/// Reads `reader` line by line as raw bytes and hands each line, without its terminator,
+/// to `on_line`. Lines that aren't valid UTF-8 are handled according to `policy` instead of
+/// aborting the whole read the way [`BufRead::lines`] does.
+pub fn for_each_line<R, E>(
+    mut reader: R,
+    policy: InvalidUtf8,
+    mut on_line: impl FnMut(&str) -> std::result::Result<(), E>,
+) -> std::result::Result<Utf8Report, E>
+where
+    R: BufRead,
+    E: From<std::io::Error> + From<TokenizerError>,
+{
+    let mut report = Utf8Report::default();
+    let mut buf = Vec::new();
+    let mut line_number = 0;
+    loop {
+        buf.clear();
+        if reader.read_until(b'\n', &mut buf)? == 0 {
+            break;
+        }
+        line_number += 1;
+        let content = buf.strip_suffix(b"\n").unwrap_or(&buf);
+        let content = content.strip_suffix(b"\r").unwrap_or(content);
+
+        match std::str::from_utf8(content) {
+            Ok(line) => on_line(line)?,
+            Err(_) => match policy {
+                InvalidUtf8::Replace => {
+                    report.repaired_lines += 1;
+                    on_line(&String::from_utf8_lossy(content))?;
+                }
+                InvalidUtf8::Skip => report.skipped_lines += 1,
+                InvalidUtf8::Error => return Err(TokenizerError::InvalidUtf8 { line: line_number }.into()),
+            },
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const INPUT: &[u8] = b"good line\r\nbad \xFF\xFE line\nlast";
+
+    fn collect(policy: InvalidUtf8) -> crate::error::Result<(Vec<String>, Utf8Report)> {
+        let mut lines = Vec::new();
+        let report = for_each_line(Cursor::new(INPUT), policy, |line| {
+            lines.push(line.to_string());
+            Ok::<_, TokenizerError>(())
+        })?;
+        Ok((lines, report))
+    }
+
+    #[test]
+    fn replace_repairs_invalid_lines() {
+        let (lines, report) = collect(InvalidUtf8::Replace).expect("read");
+        assert_eq!(lines, ["good line", "bad \u{FFFD}\u{FFFD} line", "last"]);
+        assert_eq!(report.repaired_lines, 1);
+    }
+
+    #[test]
+    fn skip_drops_invalid_lines() {
+        let (lines, report) = collect(InvalidUtf8::Skip).expect("read");
+        assert_eq!(lines, ["good line", "last"]);
+        assert_eq!(report.skipped_lines, 1);
+    }
+
+    #[test]
+    fn error_reports_the_line_number() {
+        let err = collect(InvalidUtf8::Error).unwrap_err();
+        assert!(matches!(err, TokenizerError::InvalidUtf8 { line: 2 }), "{err}");
+    }
+}
@@ -1,16 +1,49 @@
 use regex::Regex;
 use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::BufReader;
+use std::time::{Duration, Instant};
 
-use crate::bpe::BPE;
-use crate::error::Result;
+use crate::bpe::{reserved_token, split_digits, BPE};
+use crate::error::{Result, TokenizerError};
+use crate::lines::{for_each_line, InvalidUtf8};
 use crate::vocab::Vocab;
 
+const PRETOKENIZE_PATTERN: &str = r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+";
+
+/// Optional budget for the merge loop, used to stop training early when iterating quickly.
+#[derive(Debug, Clone, Default)]
+pub struct TrainLimits {
+    /// Stop after this many merges have been learned.
+    pub max_merges: Option<usize>,
+    /// Stop once this much wall-clock time has been spent in the merge loop.
+    pub max_duration: Option<Duration>,
+}
+
+/// Why the merge loop of [`Trainer::train_with_limits`] stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The vocab reached the requested size.
+    VocabSize,
+    /// No pair was left that occurs at least `min_frequency` times and fits
+    /// `max_token_length`, so the vocab is smaller than requested.
+    NoMorePairs,
+    /// [`TrainLimits::max_merges`] was reached.
+    MaxMerges,
+    /// [`TrainLimits::max_duration`] ran out.
+    MaxDuration,
+}
+
 pub struct Trainer {
     vocab_size: usize,
     min_frequency: u32,
     special_tokens: Vec<String>,
+    lowercase: bool,
+    split_digits: bool,
+    prefix_space: bool,
+    max_token_length: Option<usize>,
+    invalid_utf8: InvalidUtf8,
+    reserved_tokens: usize,
 }
 
 impl Trainer {
@@ -19,32 +52,146 @@ impl Trainer {
             vocab_size,
             min_frequency,
             special_tokens,
+            lowercase: false,
+            split_digits: false,
+            prefix_space: false,
+            max_token_length: None,
+            invalid_utf8: InvalidUtf8::default(),
+            reserved_tokens: 0,
         }
     }
 
+    /// Reserves `count` IDs right after the special tokens, filled with `<reserved_0>`,
+    /// `<reserved_1>`, ... placeholders. Later special tokens can take over a slot with
+    /// [`BPE::assign_reserved`] without changing the vocab size (and so the embeddings).
+    pub fn with_reserved_tokens(mut self, count: usize) -> Self {
+        self.reserved_tokens = count;
+        self
+    }
+
+    /// How lines of the training files that aren't valid UTF-8 are handled
+    /// (default: repaired with U+FFFD). Repaired and skipped lines are reported per file.
+    pub fn with_invalid_utf8(mut self, policy: InvalidUtf8) -> Self {
+        self.invalid_utf8 = policy;
+        self
+    }
+
+    /// Lowercases the training text before counting words, and produces a tokenizer with
+    /// [`BPE::lowercase`] set so that encoding folds case the same way.
+    pub fn with_lowercase(mut self, lowercase: bool) -> Self {
+        self.lowercase = lowercase;
+        self
+    }
+
+    /// Splits every digit into its own word before counting, so no merge ever joins two
+    /// digits, and produces a tokenizer with [`BPE::split_digits`] set to match.
+    pub fn with_split_digits(mut self, split_digits: bool) -> Self {
+        self.split_digits = split_digits;
+        self
+    }
+
+    /// Counts every training text (each line of a training file) as if it started with a
+    /// space, and produces a tokenizer with [`BPE::prefix_space`] set so that encoding adds
+    /// the same space.
+    pub fn with_prefix_space(mut self, prefix_space: bool) -> Self {
+        self.prefix_space = prefix_space;
+        self
+    }
+
+    /// Never learns a merge whose result is longer than `max` characters; such pairs are
+    /// passed over for the next most frequent one. Once only over-long pairs remain, training
+    /// stops, so the vocab can end up smaller than `vocab_size`.
+    pub fn with_max_token_length(mut self, max: Option<usize>) -> Self {
+        self.max_token_length = max;
+        self
+    }
+
     pub fn train(&self, files: &[String]) -> Result<BPE> {
-        let regex = Regex::new(r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+")?;
+        self.train_with_limits(files, &TrainLimits::default()).map(|(bpe, _)| bpe)
+    }
+
+    /// Same as [`Trainer::train`], but stops the merge loop as soon as either limit is hit,
+    /// returning whatever vocab has been built so far and why the loop stopped.
+    pub fn train_with_limits(&self, files: &[String], limits: &TrainLimits) -> Result<(BPE, StopReason)> {
+        let regex = Regex::new(PRETOKENIZE_PATTERN)?;
         
         // 1. Read files and count words
-        println!("Reading files and counting words...");
+        tracing::info!(files = files.len(), "reading files and counting words");
         let mut word_counts: HashMap<String, u32> = HashMap::new();
         
         for path in files {
-            let file = File::open(path)?;
-            let reader = BufReader::new(file);
-            for line in reader.lines() {
-                let line = line?;
-                for mat in regex.find_iter(&line) {
-                    let word = mat.as_str().to_string();
-                    *word_counts.entry(word).or_insert(0) += 1;
-                }
+            self.count_file(&regex, path, &mut word_counts)?;
+        }
+
+        self.train_from_counts(&word_counts, limits)
+    }
+
+    /// Same as [`Trainer::train`], but each file's word counts are multiplied by its weight
+    /// before merging, so the merges follow the weighted mix of corpora rather than their
+    /// raw sizes. Weighted counts are rounded to the nearest integer, and `min_frequency`
+    /// applies to them: a pair seen 3 times in a file weighted 2.0 counts as 6. A weight of
+    /// 0.0 still adds the file's characters to the base vocab but none of its pairs.
+    pub fn train_weighted(&self, weighted_files: &[(String, f64)]) -> Result<BPE> {
+        let regex = Regex::new(PRETOKENIZE_PATTERN)?;
+
+        tracing::info!(files = weighted_files.len(), "reading weighted files and counting words");
+        let mut weighted_counts: HashMap<String, f64> = HashMap::new();
+
+        for (path, weight) in weighted_files {
+            if !weight.is_finite() || *weight < 0.0 {
+                return Err(TokenizerError::InvalidWeight { path: path.clone(), weight: *weight });
+            }
+            let mut file_counts: HashMap<String, u32> = HashMap::new();
+            self.count_file(&regex, path, &mut file_counts)?;
+            for (word, count) in file_counts {
+                *weighted_counts.entry(word).or_insert(0.0) += count as f64 * weight;
             }
         }
-        println!("Unique words: {}", word_counts.len());
+
+        let word_counts: HashMap<String, u32> = weighted_counts
+            .into_iter()
+            .map(|(word, count)| (word, count.round().min(u32::MAX as f64) as u32))
+            .collect();
+        self.train_from_counts(&word_counts, &TrainLimits::default()).map(|(bpe, _)| bpe)
+    }
+
+    /// Adds the words of one training file to `word_counts`, warning if any of its lines
+    /// had invalid UTF-8.
+    fn count_file(&self, regex: &Regex, path: &str, word_counts: &mut HashMap<String, u32>) -> Result<()> {
+        let file = File::open(path)?;
+        let reader = BufReader::new(file);
+        let report = for_each_line(reader, self.invalid_utf8, |line| {
+            self.count_words(regex, line, word_counts);
+            Ok::<_, TokenizerError>(())
+        })?;
+        if !report.is_clean() {
+            tracing::warn!(
+                file = %path,
+                repaired = report.repaired_lines,
+                skipped = report.skipped_lines,
+                "invalid UTF-8 in training file"
+            );
+        }
+        Ok(())
+    }
+
+    /// Switches to incremental training: word counts are accumulated with
+    /// [`IncrementalTrainer::feed`] and merges are learned once at the end.
+    pub fn incremental(self) -> Result<IncrementalTrainer> {
+        Ok(IncrementalTrainer {
+            trainer: self,
+            regex: Regex::new(PRETOKENIZE_PATTERN)?,
+            word_counts: HashMap::new(),
+        })
+    }
+
+    /// Runs the merge loop over pre-tokenized word counts.
+    fn train_from_counts(&self, word_counts: &HashMap<String, u32>, limits: &TrainLimits) -> Result<(BPE, StopReason)> {
+        tracing::info!(unique_words = word_counts.len(), "counted words");
 
         // 2. Initial split of words into chars
         let mut split_words: HashMap<String, Vec<String>> = HashMap::new();
-        for (word, _) in &word_counts {
+        for word in word_counts.keys() {
             let chars: Vec<String> = word.chars().map(|c| c.to_string()).collect();
             split_words.insert(word.clone(), chars);
         }
@@ -57,6 +204,9 @@ impl Trainer {
         for (i, token) in self.special_tokens.iter().enumerate() {
             vocab.insert(token.clone(), i as u32);
         }
+        for i in 0..self.reserved_tokens {
+            vocab.insert(reserved_token(i), vocab.len() as u32);
+        }
         
         // Add base characters from corpus to vocab
         let mut base_chars: HashSet<String> = HashSet::new();
@@ -80,17 +230,30 @@ impl Trainer {
             }
         }
 
-        println!("Initial vocab size: {}", vocab.len());
+        tracing::info!(vocab_size = vocab.len(), "initial vocab built");
 
         // 4. BPE Training Loop
         let mut current_vocab_size = vocab.len();
         let mut merge_count = 0;
+        let merge_start = Instant::now();
+        let mut stop = StopReason::VocabSize;
         
         while current_vocab_size < self.vocab_size {
+            if limits.max_merges.is_some_and(|max| merge_count as usize >= max) {
+                tracing::info!(merges = merge_count, "reached merge limit, stopping");
+                stop = StopReason::MaxMerges;
+                break;
+            }
+            if limits.max_duration.is_some_and(|max| merge_start.elapsed() >= max) {
+                tracing::info!(merges = merge_count, "reached time limit, stopping");
+                stop = StopReason::MaxDuration;
+                break;
+            }
+
             // Count pairs
             let mut pair_counts: HashMap<(String, String), u32> = HashMap::new();
             
-            for (word, count) in &word_counts {
+            for (word, count) in word_counts {
                 if let Some(tokens) = split_words.get(word) {
                     if tokens.len() < 2 {
                         continue;
@@ -107,6 +270,12 @@ impl Trainer {
             let mut max_count = 0;
             
             for (pair, count) in &pair_counts {
+                if self
+                    .max_token_length
+                    .is_some_and(|max| pair.0.chars().count() + pair.1.chars().count() > max)
+                {
+                    continue;
+                }
                 if *count > max_count && *count >= self.min_frequency {
                     max_count = *count;
                     best_pair = Some(pair.clone());
@@ -114,7 +283,8 @@ impl Trainer {
             }
 
             if best_pair.is_none() {
-                println!("No more pairs to merge. Stopping.");
+                tracing::info!(merges = merge_count, "no more pairs to merge, stopping");
+                stop = StopReason::NoMorePairs;
                 break;
             }
 
@@ -149,11 +319,353 @@ impl Trainer {
             }
 
             current_vocab_size += 1;
-            if current_vocab_size % 100 == 0 {
-                println!("Vocab size: {}", current_vocab_size);
+            if current_vocab_size.is_multiple_of(100) {
+                tracing::info!(vocab_size = current_vocab_size, "merging");
             }
         }
 
-        Ok(BPE::new(vocab, merges))
+        let mut bpe = BPE::new(vocab, merges);
+        bpe.lowercase = self.lowercase;
+        bpe.split_digits = self.split_digits;
+        bpe.prefix_space = self.prefix_space;
+        Ok((bpe, stop))
+    }
+
+    /// Counts pre-tokenized words in `text`. Special tokens are cut out first and never
+    /// counted, so their characters can't end up in merges (they're added to the vocab whole).
+    fn count_words(&self, regex: &Regex, text: &str, word_counts: &mut HashMap<String, u32>) {
+        let prefixed;
+        let text = if self.prefix_space && !text.starts_with(' ') {
+            prefixed = format!(" {text}");
+            &prefixed
+        } else {
+            text
+        };
+        for segment in split_on_special_tokens(text, &self.special_tokens) {
+            for mat in regex.find_iter(segment) {
+                let word = if self.lowercase {
+                    mat.as_str().to_lowercase()
+                } else {
+                    mat.as_str().to_string()
+                };
+                if self.split_digits {
+                    for piece in split_digits(&word) {
+                        *word_counts.entry(piece.to_string()).or_insert(0) += 1;
+                    }
+                } else {
+                    *word_counts.entry(word).or_insert(0) += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Returns the parts of `text` between occurrences of any special token
+/// (the earliest match wins; on ties, the longest token).
+fn split_on_special_tokens<'a>(text: &'a str, special_tokens: &[String]) -> Vec<&'a str> {
+    let mut segments = Vec::new();
+    let mut rest = text;
+    loop {
+        let next = special_tokens
+            .iter()
+            .filter(|token| !token.is_empty())
+            .filter_map(|token| rest.find(token.as_str()).map(|pos| (pos, token.len())))
+            .min_by_key(|&(pos, len)| (pos, std::cmp::Reverse(len)));
+        match next {
+            Some((pos, len)) => {
+                if pos > 0 {
+                    segments.push(&rest[..pos]);
+                }
+                rest = &rest[pos + len..];
+            }
+            None => {
+                if !rest.is_empty() {
+                    segments.push(rest);
+                }
+                return segments;
+            }
+        }
+    }
+}
+
+/// A [`Trainer`] that ingests text from any source, one chunk at a time, and learns
+/// merges once at the end. Created with [`Trainer::incremental`].
+pub struct IncrementalTrainer {
+    trainer: Trainer,
+    regex: Regex,
+    word_counts: HashMap<String, u32>,
+}
+
+impl IncrementalTrainer {
+    /// Adds the words in `text` to the running counts. Chunks are pre-tokenized on their
+    /// own, so split them at whitespace or line boundaries.
+    pub fn feed(&mut self, text: &str) {
+        self.trainer.count_words(&self.regex, text, &mut self.word_counts);
+    }
+
+    /// Number of distinct words seen so far.
+    pub fn unique_words(&self) -> usize {
+        self.word_counts.len()
+    }
+
+    pub fn finalize(self) -> Result<BPE> {
+        self.finalize_with_limits(&TrainLimits::default()).map(|(bpe, _)| bpe)
+    }
+
+    pub fn finalize_with_limits(self, limits: &TrainLimits) -> Result<(BPE, StopReason)> {
+        self.trainer.train_from_counts(&self.word_counts, limits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn train_with_limits_stops_after_max_merges() {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("tokenizer_trainer_test_{unique}"));
+        fs::create_dir_all(&dir).expect("create temp test dir");
+
+        let corpus_path = dir.join("corpus.txt");
+        fs::write(&corpus_path, "the cat sat on the mat\nthe bat ate the rat\n").expect("write corpus");
+        let files = vec![corpus_path.to_string_lossy().into_owned()];
+
+        let trainer = Trainer::new(10_000, 1, vec!["<UNK>".to_string()]);
+        let (base, _) = trainer
+            .train_with_limits(&files, &TrainLimits { max_merges: Some(0), ..Default::default() })
+            .expect("train base vocab");
+        let (limited, stop) = trainer
+            .train_with_limits(&files, &TrainLimits { max_merges: Some(3), ..Default::default() })
+            .expect("train limited vocab");
+
+        assert_eq!(limited.merges.len(), 3);
+        assert_eq!(limited.vocab.len(), base.vocab.len() + 3);
+        assert_eq!(stop, StopReason::MaxMerges);
+        // The tiny corpus runs out of pairs long before 10,000 tokens.
+        let (_, stop) = trainer.train_with_limits(&files, &TrainLimits::default()).expect("train full vocab");
+        assert_eq!(stop, StopReason::NoMorePairs);
+
+        fs::remove_dir_all(&dir).expect("cleanup temp test dir");
+    }
+
+    #[test]
+    fn incremental_feed_matches_training_on_concatenation() {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("tokenizer_incremental_test_{unique}"));
+        fs::create_dir_all(&dir).expect("create temp test dir");
+
+        let chunks = ["the cat sat", " on the mat", " and the bat", " ate the rat"];
+        let corpus_path = dir.join("corpus.txt");
+        fs::write(&corpus_path, chunks.concat()).expect("write corpus");
+
+        let trainer = Trainer::new(10_000, 1, vec!["<UNK>".to_string()]);
+        let mut from_file = HashMap::new();
+        let regex = Regex::new(PRETOKENIZE_PATTERN).expect("pattern");
+        trainer.count_file(&regex, &corpus_path.to_string_lossy(), &mut from_file).expect("count file");
+
+        // Merges learned from equal counts only differ in how equally frequent pairs are
+        // ordered, so compare the counts the merge loop runs on.
+        let mut incremental = trainer.incremental().expect("incremental trainer");
+        for chunk in chunks {
+            incremental.feed(chunk);
+        }
+        assert_eq!(incremental.word_counts, from_file);
+        assert_eq!(incremental.unique_words(), from_file.len());
+        assert!(!incremental.finalize().expect("finalize").merges.is_empty());
+
+        fs::remove_dir_all(&dir).expect("cleanup temp test dir");
+    }
+
+    #[test]
+    fn upweighting_a_small_file_changes_the_learned_merges() {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("tokenizer_weighted_test_{unique}"));
+        fs::create_dir_all(&dir).expect("create temp test dir");
+
+        let large_path = dir.join("large.txt");
+        fs::write(&large_path, "ab\n".repeat(5)).expect("write large corpus");
+        let small_path = dir.join("small.txt");
+        fs::write(&small_path, "xy\n").expect("write small corpus");
+        let weighted = |small_weight: f64| {
+            vec![
+                (large_path.to_string_lossy().into_owned(), 1.0),
+                (small_path.to_string_lossy().into_owned(), small_weight),
+            ]
+        };
+        let xy = ("x".to_string(), "y".to_string());
+
+        // min_frequency applies to the weighted count, so "xy" only merges once up-weighted.
+        let trainer = Trainer::new(10_000, 3, vec!["<UNK>".to_string()]);
+        let even = trainer.train_weighted(&weighted(1.0)).expect("train evenly weighted");
+        assert_eq!(even.merges.get(&("a".to_string(), "b".to_string())), Some(&0));
+        assert_eq!(even.merges.get(&xy), None);
+
+        let boosted = trainer.train_weighted(&weighted(10.0)).expect("train up-weighted");
+        assert_eq!(boosted.merges.get(&xy), Some(&0));
+
+        assert!(matches!(
+            trainer.train_weighted(&weighted(-1.0)),
+            Err(TokenizerError::InvalidWeight { .. })
+        ));
+
+        fs::remove_dir_all(&dir).expect("cleanup temp test dir");
+    }
+
+    #[test]
+    fn special_tokens_are_never_merged() {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("tokenizer_special_test_{unique}"));
+        fs::create_dir_all(&dir).expect("create temp test dir");
+
+        let corpus_path = dir.join("corpus.txt");
+        fs::write(&corpus_path, "<s>the cat sat</s>\n<s>the mat</s> <s>the bat</s>\n").expect("write corpus");
+        let files = vec![corpus_path.to_string_lossy().into_owned()];
+
+        let trainer = Trainer::new(10_000, 1, vec!["<s>".to_string(), "</s>".to_string()]);
+        let bpe = trainer.train(&files).expect("train");
+
+        assert!(!bpe.merges.is_empty());
+        for (first, second) in bpe.merges.keys() {
+            let merged = format!("{first}{second}");
+            assert!(!merged.contains(['<', '>', '/']), "special-token characters merged: {merged:?}");
+        }
+        assert_eq!(bpe.vocab.get_id("<s>"), Some(0));
+        assert_eq!(bpe.vocab.get_id("</s>"), Some(1));
+
+        fs::remove_dir_all(&dir).expect("cleanup temp test dir");
+    }
+
+    #[test]
+    fn lowercase_trainer_folds_case_in_vocab_and_output() {
+        let mut incremental = Trainer::new(10_000, 1, vec!["<UNK>".to_string()])
+            .with_lowercase(true)
+            .incremental()
+            .expect("incremental trainer");
+        incremental.feed("Hello hello HELLO");
+        let bpe = incremental.finalize().expect("finalize");
+
+        assert!(bpe.lowercase);
+        assert_eq!(bpe.vocab.get_id("H"), None);
+        assert_eq!(bpe.encode("Hello"), bpe.encode("hello"));
+    }
+
+    #[test]
+    fn prefix_space_trainer_learns_word_initial_tokens() {
+        let mut incremental = Trainer::new(10_000, 1, vec!["<UNK>".to_string()])
+            .with_prefix_space(true)
+            .incremental()
+            .expect("incremental trainer");
+        incremental.feed("hello hello");
+        let bpe = incremental.finalize().expect("finalize");
+
+        assert!(bpe.prefix_space);
+        assert_eq!(bpe.encode("hello"), bpe.encode(" hello"));
+        assert_eq!(bpe.encode("hello").len(), 1);
+    }
+
+    #[test]
+    fn split_digits_trainer_never_merges_digits() {
+        let mut incremental = Trainer::new(10_000, 1, vec!["<UNK>".to_string()])
+            .with_split_digits(true)
+            .incremental()
+            .expect("incremental trainer");
+        incremental.feed("in 2024 and 2024 and 2024");
+        let bpe = incremental.finalize().expect("finalize");
+
+        assert!(bpe.split_digits);
+        assert_eq!(bpe.vocab.get_id("2024"), None);
+        assert_eq!(bpe.vocab.get_id("20"), None);
+        let ids = bpe.encode("2024");
+        assert_eq!(ids.len(), 4);
+        assert_eq!(bpe.decode(&ids), "2024");
+    }
+
+    #[test]
+    fn max_token_length_caps_merged_tokens() {
+        let mut incremental = Trainer::new(10_000, 1, vec!["<UNK>".to_string()])
+            .with_max_token_length(Some(3))
+            .incremental()
+            .expect("incremental trainer");
+        incremental.feed("abcdefgh abcdefgh abcdefgh tokenizer tokenizer");
+        let bpe = incremental.finalize().expect("finalize");
+
+        assert!(!bpe.merges.is_empty());
+        for (first, second) in bpe.merges.keys() {
+            let merged = format!("{first}{second}");
+            assert!(merged.chars().count() <= 3, "{merged:?} is longer than 3 characters");
+        }
+        assert!(bpe.vocab.len() < 10_000, "training stops once only over-long merges remain");
+        assert_eq!(bpe.decode(&bpe.encode("abcdefgh")), "abcdefgh");
+    }
+
+    #[test]
+    fn training_survives_invalid_utf8_lines() {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("tokenizer_utf8_test_{unique}"));
+        fs::create_dir_all(&dir).expect("create temp test dir");
+
+        let corpus_path = dir.join("corpus.txt");
+        fs::write(&corpus_path, b"the cat sat\nthe \xFF\xFE mat\nthe bat\n").expect("write corpus");
+        let files = vec![corpus_path.to_string_lossy().into_owned()];
+        let new_trainer = || Trainer::new(10_000, 1, vec!["<UNK>".to_string()]);
+
+        let repaired = new_trainer().train(&files).expect("train with repaired lines");
+        assert!(repaired.vocab.get_id("\u{FFFD}").is_some());
+        assert!(repaired.vocab.get_id("m").is_some());
+
+        let skipped = new_trainer()
+            .with_invalid_utf8(InvalidUtf8::Skip)
+            .train(&files)
+            .expect("train with skipped lines");
+        assert!(skipped.vocab.get_id("\u{FFFD}").is_none());
+        assert!(skipped.vocab.get_id("m").is_none());
+
+        assert!(new_trainer().with_invalid_utf8(InvalidUtf8::Error).train(&files).is_err());
+
+        fs::remove_dir_all(&dir).expect("cleanup temp test dir");
+    }
+
+    #[test]
+    fn assigning_a_reserved_slot_keeps_its_id() {
+        let mut incremental = Trainer::new(10_000, 1, vec!["<s>".to_string(), "</s>".to_string()])
+            .with_reserved_tokens(5)
+            .incremental()
+            .expect("incremental trainer");
+        incremental.feed("the cat sat on the mat");
+        let mut bpe = incremental.finalize().expect("finalize");
+
+        let slot_ids: Vec<u32> = (0..5)
+            .map(|i| bpe.vocab.get_id(&reserved_token(i)).expect("reserved slot"))
+            .collect();
+        assert_eq!(slot_ids, vec![2, 3, 4, 5, 6]);
+        let vocab_size = bpe.vocab.len();
+
+        let id = bpe.assign_reserved("<tool_call>").expect("free slot");
+        assert_eq!(id, slot_ids[0]);
+        assert_eq!(bpe.vocab.get_id("<tool_call>"), Some(id));
+        assert_eq!(bpe.vocab.get_token(id).map(String::as_str), Some("<tool_call>"));
+        assert_eq!(bpe.vocab.get_id("<reserved_0>"), None);
+        assert_eq!(bpe.vocab.len(), vocab_size);
+
+        assert_eq!(bpe.assign_reserved("<tool_call>"), Some(id));
+        assert_eq!(bpe.assign_reserved("<tool_result>"), Some(slot_ids[1]));
     }
 }
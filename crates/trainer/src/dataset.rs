@@ -1,11 +1,33 @@
 use tch::{Tensor, Kind, Device};
-use tokenizer::BPE;
-use rand::{thread_rng, Rng};
+use tokenizer::{for_each_line, InvalidUtf8, BPE};
+use rand::rngs::StdRng;
+use rand::{thread_rng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::iter::StepBy;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// Target value that the training loss skips (matches PyTorch's default `ignore_index`).
+pub const IGNORE_INDEX: i64 = -100;
+
+/// Layout of a training data file.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DataFormat {
+    /// Raw text, tokenized as one continuous stream.
+    #[default]
+    PlainText,
+    /// One JSON object per line; the string in `field` is the text of that record.
+    JsonLines { field: String },
+}
 
 pub struct TextDataset {
     tokens: Vec<i64>,
     context_length: usize,
     device: Device,
+    loaded_from_cache: bool,
 }
 
 impl TextDataset {
@@ -19,9 +41,121 @@ impl TextDataset {
             tokens,
             context_length,
             device,
+            loaded_from_cache: false,
         }
     }
 
+    /// Builds the dataset by streaming `path` through the tokenizer line by line,
+    /// so the raw text never has to be held in memory alongside the tokens.
+    pub fn from_file<P: AsRef<Path>>(
+        path: P,
+        tokenizer: &BPE,
+        context_length: usize,
+        device: Device,
+    ) -> anyhow::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut tokens: Vec<i64> = Vec::new();
+        tokenizer.encode_reader(reader, |ids| tokens.extend(ids.iter().map(|&t| t as i64)))?;
+
+        Ok(Self {
+            tokens,
+            context_length,
+            device,
+            loaded_from_cache: false,
+        })
+    }
+
+    /// Like [`TextDataset::from_file`], reading `path` as `format`. JSONL records are
+    /// tokenized one by one and their tokens concatenated. Lines that aren't valid JSON, or
+    /// whose `field` is missing or not a string, are skipped and counted in a warning.
+    pub fn from_file_with_format<P: AsRef<Path>>(
+        path: P,
+        format: &DataFormat,
+        tokenizer: &BPE,
+        context_length: usize,
+        device: Device,
+    ) -> anyhow::Result<Self> {
+        let field = match format {
+            DataFormat::PlainText => return Self::from_file(path, tokenizer, context_length, device),
+            DataFormat::JsonLines { field } => field,
+        };
+
+        let path = path.as_ref();
+        let reader = BufReader::new(File::open(path)?);
+        let mut tokens: Vec<i64> = Vec::new();
+        let mut skipped = 0usize;
+        for_each_line(reader, InvalidUtf8::Replace, |line| {
+            if line.trim().is_empty() {
+                return Ok::<_, anyhow::Error>(());
+            }
+            let text = serde_json::from_str::<serde_json::Value>(line)
+                .ok()
+                .and_then(|record| record.get(field).and_then(|value| value.as_str()).map(str::to_owned));
+            match text {
+                Some(text) => tokens.extend(tokenizer.encode(&text).into_iter().map(|t| t as i64)),
+                None => skipped += 1,
+            }
+            Ok(())
+        })?;
+        if skipped > 0 {
+            tracing::warn!(path = %path.display(), skipped, field = %field, "skipped malformed JSONL lines");
+        }
+
+        Ok(Self {
+            tokens,
+            context_length,
+            device,
+            loaded_from_cache: false,
+        })
+    }
+
+    /// Like [`TextDataset::from_file_with_format`], but keeps the tokenized corpus in
+    /// `cache_dir` and reuses it on later runs. The cache file is keyed by the corpus path,
+    /// size and modification time, `format`, and a hash of the tokenizer's vocab, merges and
+    /// pre-tokenization flags, so changing any of them tokenizes the corpus again.
+    pub fn from_file_cached<P: AsRef<Path>>(
+        path: P,
+        format: &DataFormat,
+        tokenizer: &BPE,
+        context_length: usize,
+        device: Device,
+        cache_dir: &Path,
+    ) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let cache_path = token_cache_path(path, format, tokenizer, cache_dir)?;
+        if let Ok(bytes) = fs::read(&cache_path) {
+            if bytes.len() % 4 == 0 {
+                let tokens = bytes
+                    .chunks_exact(4)
+                    .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as i64)
+                    .collect();
+                tracing::info!(cache = %cache_path.display(), "loaded tokenized corpus from cache");
+                return Ok(Self {
+                    tokens,
+                    context_length,
+                    device,
+                    loaded_from_cache: true,
+                });
+            }
+            tracing::warn!(cache = %cache_path.display(), "ignoring truncated token cache");
+        }
+
+        let dataset = Self::from_file_with_format(path, format, tokenizer, context_length, device)?;
+        fs::create_dir_all(cache_dir)?;
+        let bytes: Vec<u8> = dataset.tokens.iter().flat_map(|&t| (t as u32).to_le_bytes()).collect();
+        // Write then rename, so an interrupted run never leaves a partial cache behind.
+        let tmp_path = cache_path.with_extension("tmp");
+        fs::write(&tmp_path, bytes)?;
+        fs::rename(&tmp_path, &cache_path)?;
+        tracing::info!(cache = %cache_path.display(), tokens = dataset.tokens.len(), "cached tokenized corpus");
+        Ok(dataset)
+    }
+
+    /// Whether the tokens came from the cache of [`TextDataset::from_file_cached`].
+    pub fn loaded_from_cache(&self) -> bool {
+        self.loaded_from_cache
+    }
+
     /// Returns a batch of size `batch_size`.
     /// Each item is (input, target) where:
     /// input: [batch_size, context_length]
@@ -32,20 +166,22 @@ impl TextDataset {
             // Not enough data, return empty or handle gracefully
             // For now, just panic or return zero tensors if really small
             if self.tokens.len() <= 1 {
-                return (
-                    Tensor::zeros(&[batch_size as i64, self.context_length as i64], (Kind::Int64, self.device)),
-                    Tensor::zeros(&[batch_size as i64, self.context_length as i64], (Kind::Int64, self.device))
-                );
+                return self.zero_batch(batch_size);
             }
         }
 
-        let mut inputs = Vec::with_capacity(batch_size * self.context_length);
-        let mut targets = Vec::with_capacity(batch_size * self.context_length);
-
         let mut rng = thread_rng();
+        let starts: Vec<usize> = (0..batch_size).map(|_| rng.gen_range(0..max_start)).collect();
+        self.batch_at(&starts)
+    }
+
+    /// The batch of windows starting at `starts`; each input is `context_length` tokens
+    /// and its target the same window shifted by one.
+    pub fn batch_at(&self, starts: &[usize]) -> (Tensor, Tensor) {
+        let mut inputs = Vec::with_capacity(starts.len() * self.context_length);
+        let mut targets = Vec::with_capacity(starts.len() * self.context_length);
 
-        for _ in 0..batch_size {
-            let start_idx = rng.gen_range(0..max_start);
+        for &start_idx in starts {
             let end_idx = start_idx + self.context_length;
             
             let chunk = &self.tokens[start_idx..end_idx + 1];
@@ -55,13 +191,382 @@ impl TextDataset {
         }
 
         let input_tensor = Tensor::from_slice(&inputs)
-            .view([batch_size as i64, self.context_length as i64])
+            .view([starts.len() as i64, self.context_length as i64])
             .to(self.device);
             
         let target_tensor = Tensor::from_slice(&targets)
-            .view([batch_size as i64, self.context_length as i64])
+            .view([starts.len() as i64, self.context_length as i64])
             .to(self.device);
 
         (input_tensor, target_tensor)
     }
+
+    fn zero_batch(&self, batch_size: usize) -> (Tensor, Tensor) {
+        let shape = [batch_size as i64, self.context_length as i64];
+        (
+            Tensor::zeros(shape, (Kind::Int64, self.device)),
+            Tensor::zeros(shape, (Kind::Int64, self.device)),
+        )
+    }
+
+    /// Start offsets of the non-overlapping windows covering the tokens in file order. A
+    /// trailing remainder too short for a window (plus its shifted target) is left out.
+    pub fn window_starts(&self) -> StepBy<Range<usize>> {
+        (0..self.tokens.len().saturating_sub(self.context_length)).step_by(self.context_length.max(1))
+    }
+
+    /// [`TextDataset::window_starts`] in file order, locally shuffled by a
+    /// [`ShuffleBuffer`] of `buffer_size` windows.
+    pub fn shuffled_windows(&self, buffer_size: usize, seed: u64) -> ShuffleBuffer<StepBy<Range<usize>>> {
+        ShuffleBuffer::new(self.window_starts(), buffer_size, seed)
+    }
+}
+
+/// Yields the items of an iterator in a locally shuffled order while holding at most
+/// `capacity` of them: every item is drawn at random from a buffer that is topped up from
+/// the source in order. Each item comes out exactly once, in an order fixed by the seed.
+pub struct ShuffleBuffer<I: Iterator> {
+    source: I,
+    buffer: Vec<I::Item>,
+    capacity: usize,
+    rng: StdRng,
+}
+
+impl<I: Iterator> ShuffleBuffer<I> {
+    /// A `capacity` of 1 (or 0) keeps the source order.
+    pub fn new(source: I, capacity: usize, seed: u64) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            source,
+            buffer: Vec::with_capacity(capacity),
+            capacity,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl<I: Iterator> Iterator for ShuffleBuffer<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<I::Item> {
+        while self.buffer.len() < self.capacity {
+            match self.source.next() {
+                Some(item) => self.buffer.push(item),
+                None => break,
+            }
+        }
+        if self.buffer.is_empty() {
+            return None;
+        }
+        let index = self.rng.gen_range(0..self.buffer.len());
+        Some(self.buffer.swap_remove(index))
+    }
+}
+
+/// Batches for sequential training: [`TextDataset::shuffled_windows`], one pass after
+/// another, each pass shuffled with the next seed.
+pub struct SequentialBatches<'a> {
+    dataset: &'a TextDataset,
+    buffer_size: usize,
+    seed: u64,
+    windows: ShuffleBuffer<StepBy<Range<usize>>>,
+}
+
+impl<'a> SequentialBatches<'a> {
+    pub fn new(dataset: &'a TextDataset, buffer_size: usize, seed: u64) -> Self {
+        Self {
+            dataset,
+            buffer_size,
+            seed,
+            windows: dataset.shuffled_windows(buffer_size, seed),
+        }
+    }
+
+    /// The next `batch_size` windows, starting a new pass when this one runs out. A dataset
+    /// too short for a single window yields all-zero batches, like [`TextDataset::sample_batch`].
+    pub fn next_batch(&mut self, batch_size: usize) -> (Tensor, Tensor) {
+        if self.dataset.window_starts().next().is_none() {
+            return self.dataset.zero_batch(batch_size);
+        }
+        let mut starts = Vec::with_capacity(batch_size);
+        while starts.len() < batch_size {
+            match self.windows.next() {
+                Some(start) => starts.push(start),
+                None => {
+                    self.seed = self.seed.wrapping_add(1);
+                    self.windows = self.dataset.shuffled_windows(self.buffer_size, self.seed);
+                }
+            }
+        }
+        self.dataset.batch_at(&starts)
+    }
+}
+
+/// Location of the token cache for `path` read as `format` with `tokenizer`.
+fn token_cache_path(path: &Path, format: &DataFormat, tokenizer: &BPE, cache_dir: &Path) -> anyhow::Result<PathBuf> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata.modified()?.duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+    let mut hash = Fnv1a::new();
+    hash.field(fs::canonicalize(path)?.to_string_lossy().as_bytes())
+        .field(&metadata.len().to_le_bytes())
+        .field(&modified.as_nanos().to_le_bytes())
+        .field(&serde_json::to_vec(format)?)
+        .field(&tokenizer_hash(tokenizer)?.to_le_bytes());
+    Ok(cache_dir.join(format!("tokens_{:016x}.bin", hash.finish())))
+}
+
+/// Hash of everything that decides how `tokenizer` encodes text.
+fn tokenizer_hash(tokenizer: &BPE) -> anyhow::Result<u64> {
+    let mut vocab: Vec<_> = tokenizer.vocab.token_to_id.iter().collect();
+    vocab.sort();
+    let mut merges: Vec<_> = tokenizer.merges.iter().collect();
+    merges.sort();
+
+    let mut hash = Fnv1a::new();
+    for (token, id) in vocab {
+        hash.field(token.as_bytes()).field(&id.to_le_bytes());
+    }
+    for ((first, second), rank) in merges {
+        hash.field(first.as_bytes()).field(second.as_bytes()).field(&rank.to_le_bytes());
+    }
+    hash.field(&serde_json::to_vec(&tokenizer.fallback)?)
+        .field(&[tokenizer.lowercase as u8, tokenizer.split_digits as u8, tokenizer.prefix_space as u8]);
+    Ok(hash.finish())
+}
+
+/// 64-bit FNV-1a. Cache file names have to stay the same across builds and Rust
+/// versions, which `DefaultHasher` doesn't promise.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    /// Hashes `bytes` after their length, so adjacent fields can't run into each other.
+    fn field(&mut self, bytes: &[u8]) -> &mut Self {
+        self.write(&(bytes.len() as u64).to_le_bytes());
+        self.write(bytes);
+        self
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Prompt/completion pairs for supervised fine-tuning. Targets at prompt (and padding)
+/// positions are set to [`IGNORE_INDEX`], so the loss only covers the completion.
+pub struct SupervisedDataset {
+    /// Token IDs of prompt + completion, and where the completion starts.
+    examples: Vec<(Vec<i64>, usize)>,
+    context_length: usize,
+    device: Device,
+}
+
+impl SupervisedDataset {
+    /// Examples longer than `context_length + 1` tokens are cut at the end.
+    pub fn new(pairs: &[(&str, &str)], tokenizer: &BPE, context_length: usize, device: Device) -> Self {
+        let examples = pairs
+            .iter()
+            .map(|(prompt, completion)| {
+                let mut ids: Vec<i64> = tokenizer.encode(prompt).into_iter().map(|t| t as i64).collect();
+                let prompt_len = ids.len();
+                ids.extend(tokenizer.encode(completion).into_iter().map(|t| t as i64));
+                ids.truncate(context_length + 1);
+                (ids, prompt_len)
+            })
+            .collect();
+
+        Self {
+            examples,
+            context_length,
+            device,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.examples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.examples.is_empty()
+    }
+
+    /// Returns `(input, target, mask)`, each `[batch_size, context_length]`. `mask` is 1 where
+    /// the target is a completion token and 0 elsewhere; masked-out targets are [`IGNORE_INDEX`].
+    /// Short examples are right-padded with token 0.
+    pub fn sample_batch(&self, batch_size: usize) -> (Tensor, Tensor, Tensor) {
+        let mut rng = thread_rng();
+        let indices: Vec<usize> = (0..batch_size)
+            .map(|_| rng.gen_range(0..self.examples.len()))
+            .collect();
+        self.batch(&indices)
+    }
+
+    /// Builds a batch from the examples at `indices`.
+    pub fn batch(&self, indices: &[usize]) -> (Tensor, Tensor, Tensor) {
+        let len = self.context_length;
+        let mut inputs = Vec::with_capacity(indices.len() * len);
+        let mut targets = Vec::with_capacity(indices.len() * len);
+        let mut mask = Vec::with_capacity(indices.len() * len);
+
+        for &index in indices {
+            let (ids, prompt_len) = &self.examples[index];
+            for pos in 0..len {
+                inputs.push(ids.get(pos).copied().unwrap_or(0));
+                // Target `pos` predicts token `pos + 1`; train on it only inside the completion.
+                let in_completion = pos + 1 >= *prompt_len && pos + 1 < ids.len();
+                targets.push(if in_completion { ids[pos + 1] } else { IGNORE_INDEX });
+                mask.push(in_completion as i64);
+            }
+        }
+
+        let shape = [indices.len() as i64, len as i64];
+        (
+            Tensor::from_slice(&inputs).view(shape).to(self.device),
+            Tensor::from_slice(&targets).view(shape).to(self.device),
+            Tensor::from_slice(&mask).view(shape).to(self.device),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tch::IndexOp;
+    use tokenizer::Vocab;
+
+    fn abcde_tokenizer() -> BPE {
+        let mut vocab = Vocab::new();
+        for (id, token) in ["a", "b", "c", "d", "e"].iter().enumerate() {
+            vocab.insert(token.to_string(), id as u32);
+        }
+        BPE::new(vocab, HashMap::new())
+    }
+
+    #[test]
+    fn json_lines_feed_the_extracted_field_and_skip_malformed_lines() {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_nanos();
+        let path = std::env::temp_dir().join(format!("trainer_jsonl_test_{unique}.jsonl"));
+        fs::write(
+            &path,
+            concat!(
+                "{\"text\": \"abc\", \"source\": \"x\"}\n",
+                "not json\n",
+                "\n",
+                "{\"other\": \"ee\"}\n",
+                "{\"text\": 3}\n",
+                "{\"text\": \"de\"}\n",
+            ),
+        )
+        .expect("write jsonl");
+        let format = DataFormat::JsonLines { field: "text".to_string() };
+
+        let dataset = TextDataset::from_file_with_format(&path, &format, &abcde_tokenizer(), 4, Device::Cpu)
+            .expect("read jsonl");
+
+        assert_eq!(dataset.tokens, vec![0, 1, 2, 3, 4]);
+        fs::remove_file(&path).expect("cleanup temp jsonl");
+    }
+
+    #[test]
+    fn cached_corpus_is_reused_until_the_corpus_or_tokenizer_changes() {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("trainer_token_cache_test_{unique}"));
+        fs::create_dir_all(&dir).expect("create temp test dir");
+        let corpus = dir.join("corpus.txt");
+        let cache_dir = dir.join("cache");
+        fs::write(&corpus, "abcde").expect("write corpus");
+        let tokenizer = abcde_tokenizer();
+        let load = |tokenizer: &BPE| {
+            TextDataset::from_file_cached(&corpus, &DataFormat::PlainText, tokenizer, 4, Device::Cpu, &cache_dir)
+                .expect("load corpus")
+        };
+
+        let first = load(&tokenizer);
+        let second = load(&tokenizer);
+        assert!(!first.loaded_from_cache());
+        assert!(second.loaded_from_cache());
+        assert_eq!(second.tokens, first.tokens);
+
+        let mut other = abcde_tokenizer();
+        other.vocab.insert("ab".to_string(), 5);
+        assert!(!load(&other).loaded_from_cache());
+
+        fs::write(&corpus, "edcbaab").expect("rewrite corpus");
+        let changed = load(&tokenizer);
+        assert!(!changed.loaded_from_cache());
+        assert_eq!(changed.tokens.len(), 7);
+
+        fs::remove_dir_all(&dir).expect("cleanup temp test dir");
+    }
+
+    #[test]
+    fn fnv1a_matches_the_reference_hash() {
+        for (input, expected) in [(&b""[..], 0xcbf2_9ce4_8422_2325), (b"a", 0xaf63_dc4c_8601_ec8c), (b"foobar", 0x8594_4171_f739_67e8)] {
+            let mut hash = Fnv1a::new();
+            hash.write(input);
+            assert_eq!(hash.finish(), expected, "{:?}", input);
+        }
+        // An empty field still hashes its (zero) length.
+        assert_eq!(Fnv1a::new().field(b"").finish(), 0xa8c7_f832_281a_39c5);
+    }
+
+    #[test]
+    fn shuffle_buffer_yields_every_window_once_in_a_seeded_order() {
+        let dataset = TextDataset {
+            tokens: (0..83).collect(),
+            context_length: 4,
+            device: Device::Cpu,
+            loaded_from_cache: false,
+        };
+        let in_order: Vec<usize> = dataset.window_starts().collect();
+        assert_eq!(in_order, (0..80).step_by(4).collect::<Vec<_>>());
+
+        let shuffled: Vec<usize> = dataset.shuffled_windows(5, 7).collect();
+        let mut sorted = shuffled.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, in_order);
+        assert_ne!(shuffled, in_order);
+        assert_eq!(dataset.shuffled_windows(5, 7).collect::<Vec<_>>(), shuffled);
+        assert_ne!(dataset.shuffled_windows(5, 8).collect::<Vec<_>>(), shuffled);
+
+        let mut batches = SequentialBatches::new(&dataset, 5, 7);
+        let (input, target) = batches.next_batch(3);
+        let first: Vec<i64> = Vec::try_from(input.i((.., 0))).expect("window starts");
+        assert_eq!(first, shuffled[..3].iter().map(|&start| start as i64).collect::<Vec<_>>());
+        assert!(target.equal(&(input + 1)));
+    }
+
+    #[test]
+    fn supervised_batch_masks_prompt_and_padding() {
+        let tokenizer = abcde_tokenizer();
+        let dataset = SupervisedDataset::new(&[("abc", "de")], &tokenizer, 6, Device::Cpu);
+
+        let (input, target, mask) = dataset.batch(&[0]);
+
+        assert_eq!(Vec::<i64>::try_from(input.view([-1])).expect("input"), vec![0, 1, 2, 3, 4, 0]);
+        assert_eq!(
+            Vec::<i64>::try_from(target.view([-1])).expect("target"),
+            vec![IGNORE_INDEX, IGNORE_INDEX, 3, 4, IGNORE_INDEX, IGNORE_INDEX]
+        );
+        assert_eq!(Vec::<i64>::try_from(mask.view([-1])).expect("mask"), vec![0, 0, 1, 1, 0, 0]);
+    }
 }
@@ -1,9 +1,12 @@
 pub mod dataset;
 pub mod train;
 
-pub use train::Trainer;
+pub use dataset::DataFormat;
+pub use train::{loss_from_logits, BatchMetrics, EpochMetrics, Trainer};
 
+use anyhow::Context;
 use serde::{Deserialize, Serialize};
+use tch::Device;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TrainerConfig {
@@ -13,8 +16,91 @@ pub struct TrainerConfig {
     pub epochs: usize,
     pub save_every: usize,
     pub checkpoint_dir: String,
+    /// Ramps the learning rate linearly up to `learning_rate` over this many optimizer
+    /// steps (see [`TrainerConfig::learning_rate_at`]). `None` or `0` starts at full rate.
     pub warmup_steps: Option<usize>,
     pub weight_decay: Option<f64>,
+    /// Seeds tch's RNG before the model is built, for reproducible runs. Dropout masks
+    /// come from the same global generator, so with a fixed seed the initial weights and
+    /// every dropout mask repeat exactly on CPU (CUDA kernels may still be nondeterministic).
+    /// Random batch sampling uses its own RNG and is not covered; the shuffle buffer of
+    /// `shuffle_buffer_size` is seeded with this (or a random seed if unset).
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// Label smoothing for the cross-entropy loss, in `[0, 1)`. `0.0` disables it.
+    #[serde(default)]
+    pub label_smoothing: f64,
+    /// How per-token losses are combined into the batch loss.
+    #[serde(default)]
+    pub loss_reduction: Reduction,
+    /// Layout of the files passed to [`Trainer::train_file`].
+    #[serde(default)]
+    pub data_format: DataFormat,
+    /// Directory where [`Trainer::train_file`] caches the tokenized corpus, so later runs on
+    /// the same corpus and tokenizer skip tokenization. `None` disables the cache.
+    #[serde(default)]
+    pub token_cache_dir: Option<String>,
+    /// If set, [`Trainer::train`] and [`Trainer::train_file`] walk the corpus in order, one
+    /// non-overlapping window after another, through a shuffle buffer of this many windows,
+    /// instead of sampling windows at random.
+    #[serde(default)]
+    pub shuffle_buffer_size: Option<usize>,
+    /// Devices to train on, e.g. `["cuda:0", "cuda:1"]`. The first holds the weights and the
+    /// optimizer and every batch is split across all of them (see [`Trainer::with_devices`]).
+    /// Empty trains on CUDA if it is usable, else on the CPU.
+    #[serde(default)]
+    pub devices: Vec<String>,
+}
+
+impl TrainerConfig {
+    /// Parses [`TrainerConfig::devices`]; each entry is `cpu`, `cuda` (device 0) or `cuda:<n>`.
+    pub fn training_devices(&self) -> anyhow::Result<Vec<Device>> {
+        if self.devices.is_empty() {
+            return Ok(vec![claude_core::device::resolve_device(Device::cuda_if_available())]);
+        }
+        self.devices.iter().map(|name| parse_device(name)).collect()
+    }
+
+    /// The learning rate of optimizer step `step` (counting from 1): `learning_rate`
+    /// scaled by `step / warmup_steps` during warm-up, then `learning_rate` itself.
+    pub fn learning_rate_at(&self, step: usize) -> f64 {
+        match self.warmup_steps {
+            Some(warmup) if step < warmup => self.learning_rate * step as f64 / warmup as f64,
+            _ => self.learning_rate,
+        }
+    }
+}
+
+fn parse_device(name: &str) -> anyhow::Result<Device> {
+    match name.split_once(':') {
+        None if name == "cpu" => Ok(Device::Cpu),
+        None if name == "cuda" => Ok(Device::Cuda(0)),
+        Some(("cuda", index)) => index
+            .parse()
+            .map(Device::Cuda)
+            .with_context(|| format!("Invalid CUDA device index in {:?}", name)),
+        _ => anyhow::bail!("Unknown device {:?}; expected cpu, cuda or cuda:<n>", name),
+    }
+}
+
+/// Reduction applied to the per-token cross-entropy losses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reduction {
+    /// Average over the non-ignored tokens.
+    #[default]
+    Mean,
+    /// Sum over the non-ignored tokens.
+    Sum,
+}
+
+impl From<Reduction> for tch::Reduction {
+    fn from(reduction: Reduction) -> Self {
+        match reduction {
+            Reduction::Mean => tch::Reduction::Mean,
+            Reduction::Sum => tch::Reduction::Sum,
+        }
+    }
 }
 
 impl Default for TrainerConfig {
@@ -28,6 +114,13 @@ impl Default for TrainerConfig {
             checkpoint_dir: "./checkpoints".to_string(),
             warmup_steps: Some(0),
             weight_decay: Some(0.01),
+            seed: None,
+            label_smoothing: 0.0,
+            loss_reduction: Reduction::Mean,
+            data_format: DataFormat::PlainText,
+            token_cache_dir: None,
+            shuffle_buffer_size: None,
+            devices: Vec::new(),
         }
     }
 }
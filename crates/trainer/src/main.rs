@@ -1,14 +1,13 @@
 use anyhow::Result;
 use std::fs;
 use std::path::Path;
-use tch::Device;
 
 use claude_core::ModelConfig;
 use tokenizer::{BPE, Trainer as TokenizerTrainer};
 use trainer::{Trainer, TrainerConfig};
 
 fn main() -> Result<()> {
-    env_logger::init();
+    tracing_subscriber::fmt::init();
     
     let dataset_path = "data/claude_system_prompts.txt";
     let vocab_path = "data/vocab.json";
@@ -29,11 +28,10 @@ fn main() -> Result<()> {
     let model_config_path = "configs/model_config.yaml";
     let training_config_path = "configs/training_config.yaml";
     
-    let mut model_config: ModelConfig = if Path::new(model_config_path).exists() {
-        let content = fs::read_to_string(model_config_path)?;
-        serde_yaml::from_str(&content)?
+    let mut model_config = if Path::new(model_config_path).exists() {
+        ModelConfig::from_file(model_config_path)?
     } else {
-        ModelConfig::default()
+        ModelConfig::tiny(tokenizer.vocab.len() as i64)
     };
     // Ensure vocab size matches the recently trained/loaded tokenizer
     model_config.vocab_size = tokenizer.vocab.len() as i64;
@@ -45,16 +43,15 @@ fn main() -> Result<()> {
         TrainerConfig::default()
     };
     
-    let device = Device::cuda_if_available();
-    println!("Using device: {:?}", device);
+    let devices = trainer_config.training_devices()?;
+    println!("Using devices: {:?}", devices);
 
-    let mut trainer = Trainer::new(model_config, trainer_config, device)?;
-    
-    // 4. Load Data
-    let text = fs::read_to_string(dataset_path)?;
+    let (batch_size, context_length) = (trainer_config.batch_size, trainer_config.context_length);
+    let mut trainer = Trainer::with_devices(model_config, trainer_config, devices)?;
+    println!("{}", trainer.model().describe(batch_size as i64, context_length as i64));
     
-    // 5. Train
-    trainer.train(&text, &tokenizer)?;
+    // 4. Train (the dataset is tokenized as a stream)
+    trainer.train_file(dataset_path, &tokenizer)?;
     
     println!("Training complete!");
     
@@ -1,12 +1,77 @@
 use anyhow::Result;
-use std::path::PathBuf;
-use tch::{nn, nn::OptimizerConfig, Device};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tch::{nn, nn::OptimizerConfig, Device, Kind};
 
 use claude_core::{ClaudeTransformer, ModelConfig};
+use claude_core::safetensors_util::save_safetensors;
 use tokenizer::BPE;
 
-use crate::dataset::TextDataset;
-use crate::TrainerConfig;
+use crate::dataset::{SequentialBatches, SupervisedDataset, TextDataset, IGNORE_INDEX};
+use crate::{Reduction, TrainerConfig};
+
+/// Training throughput in tokens per second for a batch of `batch_size` sequences
+/// of `context_length` tokens processed in `elapsed`.
+pub fn tokens_per_sec(batch_size: usize, context_length: usize, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs <= 0.0 {
+        return 0.0;
+    }
+    (batch_size * context_length) as f64 / secs
+}
+
+/// Cross-entropy between `logits` (`[B, T, V]`) and `target` (`[B, T]`). Targets equal to
+/// [`IGNORE_INDEX`] are left out of the loss (and of the count for [`Reduction::Mean`]).
+pub fn loss_from_logits(
+    logits: &tch::Tensor,
+    target: &tch::Tensor,
+    reduction: Reduction,
+    label_smoothing: f64,
+) -> Result<tch::Tensor> {
+    // Reshape for loss: [B*T, V] vs [B*T]
+    let (b, t, v) = logits.size3()?;
+    let logits_flat = logits.view([b * t, v]);
+    let target_flat = target.view([b * t]);
+
+    Ok(logits_flat.cross_entropy_loss::<tch::Tensor>(
+        &target_flat,
+        None,
+        reduction.into(),
+        IGNORE_INDEX,
+        label_smoothing,
+    ))
+}
+
+/// Number of batches [`Trainer`] runs per epoch.
+pub const BATCHES_PER_EPOCH: usize = 100;
+
+/// What a [`Trainer::set_on_batch`] callback sees after every optimizer step.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchMetrics {
+    /// Optimizer steps taken so far, counting this one and all earlier epochs.
+    pub step: usize,
+    pub epoch: usize,
+    pub loss: f64,
+    pub lr: f64,
+    /// L2 norm of all trainable gradients of the primary model, after this step's backward.
+    pub grad_norm: f64,
+}
+
+/// What a [`Trainer::set_on_epoch`] callback sees at the end of every epoch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EpochMetrics {
+    pub epoch: usize,
+    /// Mean batch loss over the epoch.
+    pub loss: f64,
+    pub tokens_per_sec: f64,
+}
+
+/// A copy of the model on an additional device, used for data-parallel training.
+struct Replica {
+    vs: nn::VarStore,
+    model: ClaudeTransformer,
+    device: Device,
+}
 
 pub struct Trainer {
     config: TrainerConfig,
@@ -14,6 +79,9 @@ pub struct Trainer {
     optimizer: nn::Optimizer,
     device: Device,
     vs: nn::VarStore,
+    replicas: Vec<Replica>,
+    on_batch: Option<Box<dyn FnMut(&BatchMetrics) + Send>>,
+    on_epoch: Option<Box<dyn FnMut(&EpochMetrics) + Send>>,
 }
 
 impl Trainer {
@@ -22,58 +90,176 @@ impl Trainer {
         trainer_config: TrainerConfig,
         device: Device,
     ) -> Result<Self> {
+        Self::with_devices(model_config, trainer_config, vec![device])
+    }
+
+    /// Creates a data-parallel trainer. The first device holds the primary weights and the
+    /// optimizer; every other device gets a replica. Each batch is split across devices and
+    /// the replica gradients are summed into the primary before the optimizer step.
+    /// With a single device this is identical to [`Trainer::new`].
+    pub fn with_devices(
+        model_config: ModelConfig,
+        trainer_config: TrainerConfig,
+        devices: Vec<Device>,
+    ) -> Result<Self> {
+        let (&device, replica_devices) = devices
+            .split_first()
+            .ok_or_else(|| anyhow::anyhow!("At least one training device is required"))?;
+
         let vs = nn::VarStore::new(device);
-        let model = ClaudeTransformer::new(&vs.root(), &model_config);
+        let model = match trainer_config.seed {
+            Some(seed) => ClaudeTransformer::new_seeded(&vs.root(), &model_config, seed),
+            None => ClaudeTransformer::new(&vs.root(), &model_config),
+        };
         
         let optimizer = nn::AdamW::default()
             .build(&vs, trainer_config.learning_rate)?;
 
+        let replicas = replica_devices
+            .iter()
+            .map(|&device| {
+                let vs = nn::VarStore::new(device);
+                let model = ClaudeTransformer::new(&vs.root(), &model_config);
+                Replica { vs, model, device }
+            })
+            .collect();
+
         Ok(Self {
             config: trainer_config,
             model,
             optimizer,
             device,
             vs,
+            replicas,
+            on_batch: None,
+            on_epoch: None,
         })
     }
 
+    pub fn model(&self) -> &ClaudeTransformer {
+        &self.model
+    }
+
+    /// Calls `f` after every optimizer step, e.g. for custom logging or to trigger
+    /// checkpoints. Replaces any earlier batch callback.
+    pub fn set_on_batch(&mut self, f: impl FnMut(&BatchMetrics) + Send + 'static) {
+        self.on_batch = Some(Box::new(f));
+    }
+
+    /// Calls `f` at the end of every epoch, before the checkpoint for that epoch is saved.
+    /// Replaces any earlier epoch callback.
+    pub fn set_on_epoch(&mut self, f: impl FnMut(&EpochMetrics) + Send + 'static) {
+        self.on_epoch = Some(Box::new(f));
+    }
+
     pub fn train(&mut self, text: &str, tokenizer: &BPE) -> Result<()> {
         let dataset = TextDataset::new(text, tokenizer, self.config.context_length, self.device);
-        
-        println!("Starting training with configuration: {:?}", self.config);
+        self.train_on_dataset(&dataset)
+    }
+
+    /// Trains on a data file in the configured [`crate::DataFormat`], tokenizing it as a
+    /// stream instead of loading it into memory first. With `token_cache_dir` set, the
+    /// tokens are cached there and reused by later runs.
+    pub fn train_file<P: AsRef<Path>>(&mut self, path: P, tokenizer: &BPE) -> Result<()> {
+        let format = &self.config.data_format;
+        let dataset = match &self.config.token_cache_dir {
+            Some(cache_dir) => TextDataset::from_file_cached(
+                path,
+                format,
+                tokenizer,
+                self.config.context_length,
+                self.device,
+                Path::new(cache_dir),
+            )?,
+            None => TextDataset::from_file_with_format(path, format, tokenizer, self.config.context_length, self.device)?,
+        };
+        self.train_on_dataset(&dataset)
+    }
+
+    /// Random windows, or with `shuffle_buffer_size` set, [`SequentialBatches`].
+    fn train_on_dataset(&mut self, dataset: &TextDataset) -> Result<()> {
+        match self.config.shuffle_buffer_size {
+            None => self.train_on_batches(|batch_size| dataset.sample_batch(batch_size)),
+            Some(buffer_size) => {
+                let seed = self.config.seed.unwrap_or_else(rand::random);
+                let mut batches = SequentialBatches::new(dataset, buffer_size, seed);
+                self.train_on_batches(|batch_size| batches.next_batch(batch_size))
+            }
+        }
+    }
+
+    /// Supervised fine-tuning: the loss only covers completion tokens, never the prompt.
+    pub fn train_supervised(&mut self, dataset: &SupervisedDataset) -> Result<()> {
+        if dataset.is_empty() {
+            anyhow::bail!("Supervised dataset has no examples");
+        }
+        self.train_on_batches(|batch_size| {
+            let (input, target, _mask) = dataset.sample_batch(batch_size);
+            (input, target)
+        })
+    }
+
+    /// Shared epoch loop; `sample_batch` returns `(input, target)` for a batch size.
+    fn train_on_batches(&mut self, mut sample_batch: impl FnMut(usize) -> (tch::Tensor, tch::Tensor)) -> Result<()> {
+        tracing::info!(config = ?self.config, "starting training");
+        let mut step = 0;
         
         for epoch in 0..self.config.epochs {
             // Training Loop
             let mut epoch_loss = 0.0;
-            let num_batches = 100; // Define batches per epoch or iterate fully
+            let num_batches = BATCHES_PER_EPOCH;
+            let epoch_start = Instant::now();
+            let epoch_span = tracing::info_span!("epoch", epoch, loss = tracing::field::Empty);
+            let _epoch_guard = epoch_span.enter();
             
             for batch_idx in 0..num_batches {
-                let (input, target) = dataset.sample_batch(self.config.batch_size);
-                
-                // Forward pass
-                // Returns logits
-                let logits = self.model.forward(&input, None);
-                
-                // Reshape for loss: [B*T, V] vs [B*T]
-                let (b, t, v) = logits.size3()?;
-                let logits_flat = logits.view([b * t, v]);
-                let target_flat = target.view([b * t]);
+                let lr = self.config.learning_rate_at(step + 1);
+                self.optimizer.set_lr(lr);
+                let batch_span = tracing::info_span!(
+                    "batch",
+                    batch = batch_idx,
+                    loss = tracing::field::Empty,
+                    lr
+                );
+                let _batch_guard = batch_span.enter();
+                let batch_start = Instant::now();
+                let (input, target) = sample_batch(self.config.batch_size);
                 
-                // Cross Entropy Loss
-                let loss = logits_flat.cross_entropy_for_logits(&target_flat);
-                
-                // Backward & Step
-                self.optimizer.backward_step(&loss);
-                
-                let loss_val = loss.double_value(&[]);
+                let loss_val = self.train_step(&input, &target)?;
+                batch_span.record("loss", loss_val);
                 epoch_loss += loss_val;
+                step += 1;
+
+                if self.on_batch.is_some() {
+                    let metrics = BatchMetrics {
+                        step,
+                        epoch,
+                        loss: loss_val,
+                        lr,
+                        grad_norm: self.grad_norm(),
+                    };
+                    if let Some(on_batch) = self.on_batch.as_mut() {
+                        on_batch(&metrics);
+                    }
+                }
                 
                 if batch_idx % 10 == 0 {
-                    println!("Epoch {} | Batch {}/{} | Loss: {:.4}", epoch, batch_idx, num_batches, loss_val);
+                    let throughput = tokens_per_sec(self.config.batch_size, self.config.context_length, batch_start.elapsed());
+                    tracing::info!(loss = loss_val, tokens_per_sec = throughput, "batch {}/{}", batch_idx, num_batches);
                 }
             }
             
-            println!("Epoch {} Average Loss: {:.4}", epoch, epoch_loss / num_batches as f64);
+            let epoch_throughput = tokens_per_sec(
+                self.config.batch_size * num_batches,
+                self.config.context_length,
+                epoch_start.elapsed(),
+            );
+            let average_loss = epoch_loss / num_batches as f64;
+            epoch_span.record("loss", average_loss);
+            tracing::info!(loss = average_loss, tokens_per_sec = epoch_throughput, "epoch finished");
+            if let Some(on_epoch) = self.on_epoch.as_mut() {
+                on_epoch(&EpochMetrics { epoch, loss: average_loss, tokens_per_sec: epoch_throughput });
+            }
             
             // Save checkpoint
             if (epoch + 1) % self.config.save_every == 0 {
@@ -84,6 +270,112 @@ impl Trainer {
         Ok(())
     }
 
+    /// Runs one optimizer step on a batch and returns the batch loss.
+    fn train_step(&mut self, input: &tch::Tensor, target: &tch::Tensor) -> Result<f64> {
+        if self.replicas.is_empty() {
+            let loss = Self::compute_loss(&self.config, &self.model, input, target)?;
+            
+            // Backward & Step
+            self.optimizer.backward_step(&loss);
+            return Ok(loss.double_value(&[]));
+        }
+
+        self.optimizer.zero_grad();
+        self.sync_replicas();
+
+        let n_devices = 1 + self.replicas.len() as i64;
+        let counted_targets = |target: &tch::Tensor| target.ne(IGNORE_INDEX).sum(Kind::Int64).int64_value(&[]);
+        let batch_targets = counted_targets(target) as f64;
+        let inputs = input.chunk(n_devices, 0);
+        let targets = target.chunk(n_devices, 0);
+
+        let mut total_loss = 0.0;
+        for (i, (input, target)) in inputs.iter().zip(targets.iter()).enumerate() {
+            let (model, device) = match i {
+                0 => (&self.model, self.device),
+                _ => (&self.replicas[i - 1].model, self.replicas[i - 1].device),
+            };
+            // Weight each shard by its share of the batch's counted (non-ignored) targets so
+            // the summed gradients equal the gradient of the full-batch mean loss. A shard
+            // with nothing to count would add a NaN mean. Summed losses add up as is.
+            let shard_targets = counted_targets(target);
+            if shard_targets == 0 {
+                continue;
+            }
+            let share = match self.config.loss_reduction {
+                Reduction::Mean => shard_targets as f64 / batch_targets,
+                Reduction::Sum => 1.0,
+            };
+            let loss = Self::compute_loss(&self.config, model, &input.to(device), &target.to(device))? * share;
+            loss.backward();
+            total_loss += loss.double_value(&[]);
+        }
+
+        self.reduce_gradients();
+        self.optimizer.step();
+        Ok(total_loss)
+    }
+
+    /// L2 norm over the gradients of every trainable primary variable; variables without a
+    /// gradient are skipped.
+    fn grad_norm(&self) -> f64 {
+        self.vs
+            .trainable_variables()
+            .iter()
+            .map(|var| var.grad())
+            .filter(|grad| grad.defined())
+            .map(|grad| grad.pow_tensor_scalar(2.0).sum(Kind::Double).double_value(&[]))
+            .sum::<f64>()
+            .sqrt()
+    }
+
+    /// Cross-entropy of the model's next-token predictions, with the reduction and label
+    /// smoothing from `config`. See [`loss_from_logits`].
+    fn compute_loss(
+        config: &TrainerConfig,
+        model: &ClaudeTransformer,
+        input: &tch::Tensor,
+        target: &tch::Tensor,
+    ) -> Result<tch::Tensor> {
+        // Padded vocab rows (`pad_vocab_to`) are -inf; label smoothing would average them in.
+        let logits = model.forward_t(input, true).narrow(-1, 0, model.config.vocab_size);
+        loss_from_logits(&logits, target, config.loss_reduction, config.label_smoothing)
+    }
+
+    /// Copies the primary weights into every replica and clears the replica gradients.
+    fn sync_replicas(&mut self) {
+        let primary = self.vs.variables();
+        for replica in &self.replicas {
+            for (name, mut var) in replica.vs.variables() {
+                if let Some(src) = primary.get(&name) {
+                    tch::no_grad(|| {
+                        var.copy_(src);
+                    });
+                }
+                var.zero_grad();
+            }
+        }
+    }
+
+    /// Adds every replica's gradients into the matching primary gradients.
+    fn reduce_gradients(&mut self) {
+        let primary = self.vs.variables();
+        for replica in &self.replicas {
+            for (name, var) in replica.vs.variables() {
+                let grad = var.grad();
+                if !grad.defined() {
+                    continue;
+                }
+                if let Some(dst) = primary.get(&name) {
+                    let mut dst_grad = dst.grad();
+                    tch::no_grad(|| {
+                        let _ = dst_grad.add_(&grad.to_device(self.device));
+                    });
+                }
+            }
+        }
+    }
+
     fn save_checkpoint(&self, epoch: usize) -> Result<()> {
         let path = PathBuf::from(&self.config.checkpoint_dir);
         if !path.exists() {
@@ -91,16 +383,217 @@ impl Trainer {
         }
         
         let filename = path.join(format!("checkpoint_epoch_{}.safetensors", epoch));
-        // self.vs.save(&filename)?; // vs.save saves to .ot (Torch format) usually.
-        // For safetensors, we might need a custom saver or just stick to torch format for now
-        // to be compatible with tch. Let's use vs.save for simplistic restoration.
-        self.vs.save(filename)?;
+        save_safetensors(&self.vs, filename)?;
         
         let config_path = path.join("config.json");
         let config_json = serde_json::to_string_pretty(&self.model.config)?;
         std::fs::write(config_path, config_json)?;
         
-        println!("Saved checkpoint and config to {:?}", path);
+        tracing::info!(dir = %path.display(), "saved checkpoint and config");
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_parallel_step_matches_single_device_step() {
+        let model_config = ModelConfig::tiny(16);
+        let input = tch::Tensor::from_slice(&[1i64, 2, 3, 4, 5, 6, 7, 8]).view([2, 4]);
+        let target = tch::Tensor::from_slice(&[2i64, 3, 4, 5, 6, 7, 8, 9]).view([2, 4]);
+
+        tch::manual_seed(0);
+        let mut single = Trainer::new(model_config.clone(), TrainerConfig::default(), Device::Cpu)
+            .expect("single-device trainer");
+        tch::manual_seed(0);
+        let mut parallel = Trainer::with_devices(model_config, TrainerConfig::default(), vec![Device::Cpu, Device::Cpu])
+            .expect("data-parallel trainer");
+
+        let single_loss = single.train_step(&input, &target).expect("single step");
+        let parallel_loss = parallel.train_step(&input, &target).expect("parallel step");
+        assert!((single_loss - parallel_loss).abs() < 1e-5);
+
+        let parallel_vars = parallel.vs.variables();
+        for (name, var) in single.vs.variables() {
+            assert!(var.allclose(&parallel_vars[&name], 1e-5, 1e-6, false), "{} differs", name);
+        }
+    }
+
+    #[test]
+    fn data_parallel_step_matches_single_device_step_on_masked_targets() {
+        let model_config = ModelConfig::tiny(16);
+        let input = tch::Tensor::from_slice(&[1i64, 2, 3, 4, 5, 6, 7, 8]).view([2, 4]);
+        // The first sequence counts one target, the second three, so an even split by rows
+        // would weight the shards wrongly.
+        let target = tch::Tensor::from_slice(&[IGNORE_INDEX, IGNORE_INDEX, IGNORE_INDEX, 5, 6, 7, 8, IGNORE_INDEX])
+            .view([2, 4]);
+        let trainer_config = TrainerConfig {
+            devices: vec!["cpu".to_string(), "cpu".to_string()],
+            ..TrainerConfig::default()
+        };
+        let devices = trainer_config.training_devices().expect("devices");
+        assert_eq!(devices, [Device::Cpu, Device::Cpu]);
+
+        tch::manual_seed(0);
+        let mut single = Trainer::new(model_config.clone(), trainer_config.clone(), Device::Cpu)
+            .expect("single-device trainer");
+        tch::manual_seed(0);
+        let mut parallel = Trainer::with_devices(model_config, trainer_config, devices).expect("data-parallel trainer");
+
+        let single_loss = single.train_step(&input, &target).expect("single step");
+        let parallel_loss = parallel.train_step(&input, &target).expect("parallel step");
+        assert!((single_loss - parallel_loss).abs() < 1e-5);
+
+        let parallel_vars = parallel.vs.variables();
+        for (name, var) in single.vs.variables() {
+            assert!(var.allclose(&parallel_vars[&name], 1e-5, 1e-6, false), "{} differs", name);
+        }
+        let bad_device = TrainerConfig {
+            devices: vec!["tpu".to_string()],
+            ..TrainerConfig::default()
+        };
+        assert!(bad_device.training_devices().is_err());
+    }
+
+    #[test]
+    fn seeded_training_step_is_reproducible_with_dropout() {
+        let model_config = ModelConfig {
+            dropout: 0.2,
+            ..ModelConfig::tiny(16)
+        };
+        let trainer_config = TrainerConfig {
+            seed: Some(1234),
+            ..TrainerConfig::default()
+        };
+        let input = tch::Tensor::from_slice(&[1i64, 2, 3, 4, 5, 6, 7, 8]).view([2, 4]);
+        let target = tch::Tensor::from_slice(&[2i64, 3, 4, 5, 6, 7, 8, 9]).view([2, 4]);
+
+        let mut first = Trainer::new(model_config.clone(), trainer_config.clone(), Device::Cpu)
+            .expect("first trainer");
+        let first_loss = first.train_step(&input, &target).expect("first step");
+        let mut second = Trainer::new(model_config, trainer_config, Device::Cpu)
+            .expect("second trainer");
+        let second_loss = second.train_step(&input, &target).expect("second step");
+
+        assert_eq!(first_loss, second_loss);
+    }
+
+    #[test]
+    fn supervised_loss_covers_only_completion_tokens() {
+        use std::collections::HashMap;
+        use tokenizer::Vocab;
+
+        let mut vocab = Vocab::new();
+        for id in 0..16u8 {
+            vocab.insert(((b'a' + id) as char).to_string(), id as u32);
+        }
+        let tokenizer = BPE::new(vocab, HashMap::new());
+        let dataset = SupervisedDataset::new(&[("abcdef", "ghij")], &tokenizer, 12, Device::Cpu);
+        let (input, target, mask) = dataset.batch(&[0]);
+
+        let trainer = Trainer::new(ModelConfig::tiny(16), TrainerConfig::default(), Device::Cpu)
+            .expect("trainer");
+        let loss = Trainer::compute_loss(&trainer.config, &trainer.model, &input, &target).expect("loss");
+
+        // Reference: plain cross-entropy over the completion positions only.
+        let positions = mask.view([-1]).nonzero().squeeze_dim(1);
+        let logits = trainer.model.forward(&input, None).expect("forward");
+        let vocab_size = logits.size()[2];
+        let expected = logits
+            .view([-1, vocab_size])
+            .index_select(0, &positions)
+            .cross_entropy_for_logits(&target.view([-1]).index_select(0, &positions));
+
+        assert_eq!(positions.size(), vec![4]);
+        assert!(loss.allclose(&expected, 1e-5, 1e-6, false));
+    }
+
+    #[test]
+    fn label_smoothing_increases_loss_on_confident_correct_predictions() {
+        let target = tch::Tensor::from_slice(&[0i64, 1, 2, 3]).view([1, 4]);
+        // Logits strongly favour the correct class at every position.
+        let logits = tch::Tensor::eye(4, (tch::Kind::Float, Device::Cpu)).view([1, 4, 4]) * 20.0;
+
+        let plain = loss_from_logits(&logits, &target, Reduction::Mean, 0.0)
+            .expect("loss")
+            .double_value(&[]);
+        let smoothed = loss_from_logits(&logits, &target, Reduction::Mean, 0.1)
+            .expect("smoothed loss")
+            .double_value(&[]);
+        let summed = loss_from_logits(&logits, &target, Reduction::Sum, 0.0)
+            .expect("summed loss")
+            .double_value(&[]);
+
+        assert!(smoothed > plain, "smoothed {smoothed} <= plain {plain}");
+        assert!((summed - 4.0 * plain).abs() < 1e-6);
+    }
+
+    #[test]
+    fn label_smoothing_ignores_padded_vocab_rows() {
+        let model_config = ModelConfig {
+            pad_vocab_to: Some(64),
+            ..ModelConfig::tiny(10)
+        };
+        let trainer_config = TrainerConfig {
+            label_smoothing: 0.1,
+            ..TrainerConfig::default()
+        };
+        let input = tch::Tensor::from_slice(&[1i64, 2, 3, 4, 5, 6, 7, 8]).view([2, 4]);
+        let target = tch::Tensor::from_slice(&[2i64, 3, 4, 5, 6, 7, 8, 9]).view([2, 4]);
+
+        let mut trainer = Trainer::new(model_config, trainer_config, Device::Cpu).expect("trainer");
+        let loss = trainer.train_step(&input, &target).expect("step");
+
+        assert!(loss.is_finite() && loss > 0.0, "loss {loss}");
+        for (name, var) in trainer.vs.variables() {
+            assert_eq!(var.isfinite().all().int64_value(&[]), 1, "{} is not finite", name);
+        }
+    }
+
+    #[test]
+    fn batch_callback_runs_once_per_batch_with_increasing_steps() {
+        use std::sync::{Arc, Mutex};
+
+        let trainer_config = TrainerConfig {
+            batch_size: 2,
+            context_length: 4,
+            epochs: 2,
+            warmup_steps: Some(4),
+            ..TrainerConfig::default()
+        };
+        let mut trainer = Trainer::new(ModelConfig::tiny(16), trainer_config, Device::Cpu).expect("trainer");
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let epochs = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&batches);
+        trainer.set_on_batch(move |metrics| seen.lock().unwrap().push(metrics.clone()));
+        let seen = Arc::clone(&epochs);
+        trainer.set_on_epoch(move |metrics| seen.lock().unwrap().push(metrics.epoch));
+
+        trainer
+            .train_on_batches(|_| {
+                let input = tch::Tensor::from_slice(&[1i64, 2, 3, 4, 5, 6, 7, 8]).view([2, 4]);
+                let target = tch::Tensor::from_slice(&[2i64, 3, 4, 5, 6, 7, 8, 9]).view([2, 4]);
+                (input, target)
+            })
+            .expect("train");
+
+        let batches = batches.lock().unwrap();
+        let steps: Vec<usize> = batches.iter().map(|metrics| metrics.step).collect();
+        assert_eq!(steps, (1..=2 * BATCHES_PER_EPOCH).collect::<Vec<_>>());
+        assert_eq!(batches[BATCHES_PER_EPOCH].epoch, 1);
+        assert!(batches.iter().all(|metrics| metrics.grad_norm > 0.0));
+        // The learning rate warms up over the first four steps.
+        let lrs: Vec<f64> = batches.iter().take(5).map(|metrics| metrics.lr).collect();
+        assert_eq!(lrs, [0.75e-4, 1.5e-4, 2.25e-4, 3e-4, 3e-4]);
+        assert!(batches.iter().skip(3).all(|metrics| metrics.lr == 3e-4));
+        assert_eq!(*epochs.lock().unwrap(), [0, 1]);
+    }
+
+    #[test]
+    fn tokens_per_sec_divides_batch_tokens_by_elapsed() {
+        assert_eq!(tokens_per_sec(4, 128, Duration::from_millis(500)), 1024.0);
+        assert_eq!(tokens_per_sec(4, 128, Duration::ZERO), 0.0);
+    }
+}
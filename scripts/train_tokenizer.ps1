@@ -12,5 +12,6 @@ Write-Host "--- Training Tokenizer ---"
 
 Write-Host "--- Copying Vocab to root data/ for TUI/Inference ---"
 Copy-Item "$OUT_DIR/vocab.json" "data/vocab.json" -Force
+Copy-Item "$OUT_DIR/vocab.bin" "data/vocab.bin" -Force
 
 Write-Host "Tokenizer ready."
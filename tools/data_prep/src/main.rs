@@ -1,36 +1,48 @@
 use std::fs::File;
 use std::io::{BufRead, BufReader, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use clap::Parser;
+use tokenizer::{for_each_line, InvalidUtf8, Utf8Report, BPE};
+
+mod stats;
 
 #[derive(Parser)]
 struct Cli {
     #[arg(short, long)]
     input: PathBuf,
-    #[arg(short, long)]
-    output_dir: PathBuf,
+    #[arg(short, long, required_unless_present = "stats")]
+    output_dir: Option<PathBuf>,
     #[arg(short, long, default_value_t = 1000)]
     lines_per_shard: usize,
+    /// Report corpus statistics instead of writing shards
+    #[arg(long)]
+    stats: bool,
+    /// Tokenizer (vocab.json) used to add token counts to --stats
+    #[arg(long, requires = "stats")]
+    tokenizer: Option<PathBuf>,
+    /// Print --stats as JSON
+    #[arg(long, requires = "stats")]
+    json: bool,
+    /// Lines that aren't valid UTF-8: replace, skip or error
+    #[arg(long, default_value = "replace")]
+    invalid_utf8: InvalidUtf8,
 }
 
-fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
-    
-    if !cli.output_dir.exists() {
-        std::fs::create_dir_all(&cli.output_dir)?;
-    }
-    
-    let file = File::open(&cli.input)?;
-    let reader = BufReader::new(file);
-    
+/// Splits the lines of `reader` into `shard_NNNN.txt` files of `lines_per_shard` lines.
+/// Returns the number of shards and lines written, and how many lines had invalid UTF-8.
+fn write_shards<R: BufRead>(
+    reader: R,
+    output_dir: &Path,
+    lines_per_shard: usize,
+    invalid_utf8: InvalidUtf8,
+) -> anyhow::Result<(usize, usize, Utf8Report)> {
     let mut shard_idx = 0;
     let mut line_count = 0;
     let mut writer = None;
     
-    for line in reader.lines() {
-        let line = line?;
-        if line_count % cli.lines_per_shard == 0 {
-            let shard_path = cli.output_dir.join(format!("shard_{:04}.txt", shard_idx));
+    let report = for_each_line(reader, invalid_utf8, |line| {
+        if line_count % lines_per_shard == 0 {
+            let shard_path = output_dir.join(format!("shard_{:04}.txt", shard_idx));
             println!("Creating shard: {:?}", shard_path);
             writer = Some(File::create(shard_path)?);
             shard_idx += 1;
@@ -40,8 +52,76 @@ fn main() -> anyhow::Result<()> {
             writeln!(w, "{}", line)?;
         }
         line_count += 1;
+        Ok::<_, anyhow::Error>(())
+    })?;
+    
+    Ok((shard_idx, line_count, report))
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    
+    if cli.stats {
+        let tokenizer = cli.tokenizer.as_ref().map(BPE::load_prefer_bin).transpose()?;
+        let reader = BufReader::new(File::open(&cli.input)?);
+        let stats = stats::corpus_stats(reader, tokenizer.as_ref(), cli.invalid_utf8)?;
+        if cli.json {
+            println!("{}", serde_json::to_string_pretty(&stats)?);
+        } else {
+            println!("{}", stats);
+        }
+        return Ok(());
+    }
+
+    let output_dir = cli
+        .output_dir
+        .ok_or_else(|| anyhow::anyhow!("--output-dir is required unless --stats is given"))?;
+    if !output_dir.exists() {
+        std::fs::create_dir_all(&output_dir)?;
+    }
+    
+    let file = File::open(&cli.input)?;
+    let reader = BufReader::new(file);
+    
+    let (shard_idx, line_count, report) =
+        write_shards(reader, &output_dir, cli.lines_per_shard, cli.invalid_utf8)?;
+    if !report.is_clean() {
+        println!(
+            "Warning: invalid UTF-8 in {} lines ({} repaired, {} skipped).",
+            report.repaired_lines + report.skipped_lines,
+            report.repaired_lines,
+            report.skipped_lines
+        );
     }
     
     println!("Done. Created {} shards from {} lines.", shard_idx, line_count);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn write_shards_repairs_invalid_utf8_lines() {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("data_prep_utf8_test_{unique}"));
+        std::fs::create_dir_all(&dir).expect("create temp test dir");
+
+        let input: &[u8] = b"one\ntwo \xFF\nthree\n";
+        let (shards, lines, report) =
+            write_shards(Cursor::new(input), &dir, 2, InvalidUtf8::Replace).expect("write shards");
+
+        assert_eq!((shards, lines), (2, 3));
+        assert_eq!(report.repaired_lines, 1);
+        let first = std::fs::read_to_string(dir.join("shard_0000.txt")).expect("read shard");
+        assert_eq!(first, "one\ntwo \u{FFFD}\n");
+
+        std::fs::remove_dir_all(&dir).expect("cleanup temp test dir");
+    }
+}
@@ -0,0 +1,186 @@
+use serde::Serialize;
+use std::fmt;
+use std::io::BufRead;
+use tokenizer::{for_each_line, InvalidUtf8, Utf8Report, BPE};
+
+/// Size characteristics of a line-oriented corpus.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CorpusStats {
+    pub lines: usize,
+    /// Bytes read, including line terminators.
+    pub total_bytes: u64,
+    /// Line lengths in bytes, without the terminator.
+    pub line_bytes: Distribution,
+    /// Token counts, when a tokenizer was given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tokens: Option<TokenStats>,
+    /// Lines that were not valid UTF-8.
+    pub repaired_lines: usize,
+    pub skipped_lines: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TokenStats {
+    pub total: u64,
+    pub per_line: Distribution,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Distribution {
+    pub mean: f64,
+    pub median: usize,
+    pub max: usize,
+}
+
+impl Distribution {
+    fn from_values(mut values: Vec<usize>) -> Self {
+        if values.is_empty() {
+            return Self::default();
+        }
+        values.sort_unstable();
+        Self {
+            mean: values.iter().sum::<usize>() as f64 / values.len() as f64,
+            median: values[values.len() / 2],
+            max: values[values.len() - 1],
+        }
+    }
+}
+
+/// Reads every line of `reader` and collects [`CorpusStats`], tokenizing each line with
+/// `tokenizer` if one is given. Invalid UTF-8 is handled according to `invalid_utf8`.
+pub fn corpus_stats<R: BufRead>(
+    reader: R,
+    tokenizer: Option<&BPE>,
+    invalid_utf8: InvalidUtf8,
+) -> anyhow::Result<CorpusStats> {
+    let mut line_lengths = Vec::new();
+    let mut token_counts = Vec::new();
+
+    let mut counting = CountingReader { inner: reader, bytes: 0 };
+    let Utf8Report {
+        repaired_lines,
+        skipped_lines,
+    } = for_each_line(&mut counting, invalid_utf8, |line| {
+        line_lengths.push(line.len());
+        if let Some(tokenizer) = tokenizer {
+            token_counts.push(tokenizer.encode(line).len());
+        }
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    let tokens = tokenizer.map(|_| TokenStats {
+        total: token_counts.iter().map(|&n| n as u64).sum(),
+        per_line: Distribution::from_values(token_counts),
+    });
+    Ok(CorpusStats {
+        lines: line_lengths.len(),
+        total_bytes: counting.bytes,
+        line_bytes: Distribution::from_values(line_lengths),
+        tokens,
+        repaired_lines,
+        skipped_lines,
+    })
+}
+
+/// Counts the bytes consumed from the wrapped reader.
+struct CountingReader<R> {
+    inner: R,
+    bytes: u64,
+}
+
+impl<R: std::io::Read> std::io::Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for CountingReader<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.bytes += amt as u64;
+        self.inner.consume(amt);
+    }
+}
+
+impl fmt::Display for CorpusStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Lines:            {}", self.lines)?;
+        writeln!(f, "Total bytes:      {}", self.total_bytes)?;
+        write!(
+            f,
+            "Line length:      mean {:.1}, median {}, max {} bytes",
+            self.line_bytes.mean, self.line_bytes.median, self.line_bytes.max
+        )?;
+        if self.repaired_lines + self.skipped_lines > 0 {
+            write!(
+                f,
+                "\nInvalid UTF-8:    {} lines repaired, {} lines skipped",
+                self.repaired_lines, self.skipped_lines
+            )?;
+        }
+        if let Some(tokens) = &self.tokens {
+            writeln!(f)?;
+            writeln!(f, "Total tokens:     {}", tokens.total)?;
+            write!(
+                f,
+                "Tokens per line:  mean {:.1}, median {}, max {}",
+                tokens.per_line.mean, tokens.per_line.median, tokens.per_line.max
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io::Cursor;
+    use tokenizer::Vocab;
+
+    #[test]
+    fn reports_line_count_and_byte_total() {
+        let fixture = "hello world\nab\r\n\nlast line without newline";
+        let stats = corpus_stats(Cursor::new(fixture), None, InvalidUtf8::Replace).expect("stats");
+
+        assert_eq!(stats.lines, 4);
+        assert_eq!(stats.total_bytes, fixture.len() as u64);
+        assert_eq!(stats.line_bytes.max, "last line without newline".len());
+        assert_eq!(stats.line_bytes.median, "hello world".len());
+        assert!(stats.tokens.is_none());
+    }
+
+    #[test]
+    fn reports_lines_with_invalid_utf8() {
+        let fixture: &[u8] = b"ok\nbad \xC3\x28\nok again\n";
+
+        let repaired = corpus_stats(Cursor::new(fixture), None, InvalidUtf8::Replace).expect("stats");
+        assert_eq!(repaired.lines, 3);
+        assert_eq!(repaired.repaired_lines, 1);
+        assert_eq!(repaired.total_bytes, fixture.len() as u64);
+
+        let skipped = corpus_stats(Cursor::new(fixture), None, InvalidUtf8::Skip).expect("stats");
+        assert_eq!(skipped.lines, 2);
+        assert_eq!(skipped.skipped_lines, 1);
+    }
+
+    #[test]
+    fn counts_tokens_per_line_with_a_tokenizer() {
+        let mut vocab = Vocab::new();
+        vocab.insert("a".to_string(), 0);
+        vocab.insert("b".to_string(), 1);
+        let bpe = BPE::new(vocab, HashMap::new());
+
+        let stats = corpus_stats(Cursor::new("ab\nabab\na\n"), Some(&bpe), InvalidUtf8::Replace).expect("stats");
+        let tokens = stats.tokens.expect("token stats");
+
+        assert_eq!(tokens.total, 7);
+        assert_eq!(tokens.per_line.max, 4);
+        assert_eq!(tokens.per_line.median, 2);
+    }
+}
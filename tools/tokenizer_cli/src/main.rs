@@ -4,7 +4,8 @@ use std::collections::HashMap;
 use std::fs::{self, File};
 use std::io::Write;
 use std::path::{Path, PathBuf};
-use tokenizer::{Trainer, BPE};
+use std::time::{Duration, Instant};
+use tokenizer::{InvalidUtf8, StopReason, TokenizerDiff, TrainLimits, Trainer, BPE};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -32,6 +33,34 @@ enum Commands {
         /// Minimum frequency for a pair to be merged
         #[arg(long, default_value_t = 2)]
         min_frequency: u32,
+
+        /// Stop merging after this many seconds (for quick experiments)
+        #[arg(long)]
+        max_seconds: Option<u64>,
+
+        /// Stop after this many merges (for quick experiments)
+        #[arg(long)]
+        max_merges: Option<usize>,
+
+        /// Lowercase text before training and encoding (casing is not restored on decode)
+        #[arg(long)]
+        lowercase: bool,
+
+        /// Split numbers into single-digit tokens
+        #[arg(long)]
+        split_digits: bool,
+
+        /// Never create tokens longer than this many characters (training may stop early)
+        #[arg(long)]
+        max_token_length: Option<usize>,
+
+        /// Lines that aren't valid UTF-8: replace, skip or error
+        #[arg(long, default_value = "replace")]
+        invalid_utf8: InvalidUtf8,
+
+        /// Reserve this many <reserved_N> IDs after the special tokens for later use
+        #[arg(long, default_value_t = 0)]
+        reserved_tokens: usize,
     },
     /// Encode text using existing tokenizer
     Encode {
@@ -61,6 +90,35 @@ enum Commands {
         #[arg(short, long)]
         ids: String,
     },
+    /// Compare two tokenizers and report what changed
+    Diff {
+        /// The old tokenizer: a `train` output directory or a saved tokenizer file
+        #[arg(long)]
+        a: PathBuf,
+
+        /// The new tokenizer, in the same forms as --a
+        #[arg(long)]
+        b: PathBuf,
+
+        /// Text file whose lines are encoded with both tokenizers and compared
+        #[arg(long)]
+        samples: Option<PathBuf>,
+
+        /// A sample string to compare (can be repeated)
+        #[arg(long)]
+        text: Vec<String>,
+    },
+    /// Compare how long a tokenizer takes to load from JSON and from its binary copy
+    BenchLoad {
+        /// Tokenizer saved with `BPE::save`; the binary copy is the `.bin` file next to it,
+        /// written first if it doesn't exist
+        #[arg(long)]
+        tokenizer: PathBuf,
+
+        /// How many times to load each format
+        #[arg(long, default_value_t = 5)]
+        runs: u32,
+    },
 }
 
 fn save_merges(merges: &HashMap<(String, String), u32>, path: impl AsRef<Path>) -> Result<()> {
@@ -77,7 +135,27 @@ fn save_merges(merges: &HashMap<(String, String), u32>, path: impl AsRef<Path>)
     Ok(())
 }
 
+/// Mean time to run `load` over `runs` runs.
+fn time_load(runs: u32, mut load: impl FnMut() -> tokenizer::error::Result<BPE>) -> Result<Duration> {
+    let start = Instant::now();
+    for _ in 0..runs {
+        load()?;
+    }
+    Ok(start.elapsed() / runs.max(1))
+}
+
+/// Loads a tokenizer from a `train` output directory (vocab.json + merges.txt), or from a
+/// file written by `BPE::save`/`BPE::save_bin`.
+fn load_tokenizer(path: &Path) -> tokenizer::error::Result<BPE> {
+    if path.is_dir() {
+        BPE::from_files(path.join("vocab.json"), path.join("merges.txt"))
+    } else {
+        BPE::load_prefer_bin(path)
+    }
+}
+
 fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
     let cli = Cli::parse();
 
     match cli.command {
@@ -86,13 +164,50 @@ fn main() -> Result<()> {
             output_dir,
             vocab_size,
             min_frequency,
+            max_seconds,
+            max_merges,
+            lowercase,
+            split_digits,
+            max_token_length,
+            invalid_utf8,
+            reserved_tokens,
         } => {
             println!("Training tokenizer on {:?}...", files);
-            let trainer = Trainer::new(vocab_size, min_frequency, vec!["<UNK>".to_string(), "<PAD>".to_string(), "<EOS>".to_string()]);
+            let trainer = Trainer::new(vocab_size, min_frequency, vec!["<UNK>".to_string(), "<PAD>".to_string(), "<EOS>".to_string()])
+                .with_lowercase(lowercase)
+                .with_split_digits(split_digits)
+                .with_max_token_length(max_token_length)
+                .with_invalid_utf8(invalid_utf8)
+                .with_reserved_tokens(reserved_tokens);
+            let limits = TrainLimits {
+                max_merges,
+                max_duration: max_seconds.map(Duration::from_secs),
+            };
             // Convert String paths to &str
             // trainer.train expects &[String]
-            match trainer.train(&files) {
-                Ok(bpe) => {
+            match trainer.train_with_limits(&files, &limits) {
+                Ok((bpe, stop)) => {
+                    let limit = match stop {
+                        StopReason::MaxMerges => Some("--max-merges"),
+                        StopReason::MaxDuration => Some("--max-seconds"),
+                        StopReason::VocabSize | StopReason::NoMorePairs => None,
+                    };
+                    if let Some(limit) = limit {
+                        println!(
+                            "Note: training stopped early at {} with vocab size {} (target {}).",
+                            limit,
+                            bpe.vocab.len(),
+                            vocab_size
+                        );
+                    }
+                    if stop == StopReason::NoMorePairs {
+                        println!(
+                            "Note: no pair left to merge at vocab size {} (target {}); the corpus is too small or --min-frequency too high.",
+                            bpe.vocab.len(),
+                            vocab_size
+                        );
+                    }
+
                     fs::create_dir_all(&output_dir)?;
                     let vocab_path = output_dir.join("vocab.json");
                     let merges_path = output_dir.join("merges.txt");
@@ -102,6 +217,10 @@ fn main() -> Result<()> {
 
                     println!("Saving merges to {:?}", merges_path);
                     save_merges(&bpe.merges, &merges_path).context("Failed to save merges")?;
+
+                    let bin_path = output_dir.join("vocab.bin");
+                    println!("Saving binary tokenizer to {:?}", bin_path);
+                    bpe.save_bin(&bin_path).context("Failed to save binary tokenizer")?;
                     
                     println!("Training complete.");
                 }
@@ -125,6 +244,30 @@ fn main() -> Result<()> {
             let text = bpe.decode(&id_list);
             println!("Decoded text: {}", text);
         }
+        Commands::Diff { a, b, samples, text } => {
+            let old = load_tokenizer(&a).with_context(|| format!("Failed to load tokenizer {:?}", a))?;
+            let new = load_tokenizer(&b).with_context(|| format!("Failed to load tokenizer {:?}", b))?;
+            let mut sample_texts = text;
+            if let Some(path) = samples {
+                let content = fs::read_to_string(&path).with_context(|| format!("Failed to read samples {:?}", path))?;
+                sample_texts.extend(content.lines().filter(|line| !line.is_empty()).map(str::to_string));
+            }
+            let sample_refs: Vec<&str> = sample_texts.iter().map(String::as_str).collect();
+            print!("{}", TokenizerDiff::new(&old, &new, &sample_refs));
+        }
+        Commands::BenchLoad { tokenizer, runs } => {
+            let bin_path = tokenizer.with_extension("bin");
+            if !bin_path.exists() {
+                let bpe = BPE::load(&tokenizer).with_context(|| format!("Failed to load tokenizer {:?}", tokenizer))?;
+                println!("Saving binary tokenizer to {:?}", bin_path);
+                bpe.save_bin(&bin_path).context("Failed to save binary tokenizer")?;
+            }
+            let json = time_load(runs, || BPE::load(&tokenizer))?;
+            let bin = time_load(runs, || BPE::load_bin(&bin_path))?;
+            println!("JSON:   {:>10.2} ms", json.as_secs_f64() * 1000.0);
+            println!("bincode:{:>10.2} ms", bin.as_secs_f64() * 1000.0);
+            println!("bincode loads {:.1}x faster", json.as_secs_f64() / bin.as_secs_f64().max(f64::EPSILON));
+        }
     }
 
     Ok(())